@@ -1,6 +1,59 @@
-use std::{env, path::PathBuf, process::Command};
+use std::{
+    env,
+    path::PathBuf,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Output of `cmd`, if it succeeds
+fn output(cmd: &mut Command) -> Option<String> {
+    let out = cmd.output().ok().filter(|o| o.status.success())?;
+    Some(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+/// Exposes the build metadata to the crate as `IP_STORY_*` variables
+fn build_info() {
+    let git = |args: &[&str]| output(Command::new("git").args(args));
+
+    let commit = git(&["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=IP_STORY_GIT_COMMIT={commit}");
+
+    // honors SOURCE_DATE_EPOCH for reproducible builds
+    let built_at = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+    println!("cargo:rustc-env=IP_STORY_BUILT_AT={built_at}");
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let rustc = output(Command::new(rustc).arg("--version")).unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=IP_STORY_RUSTC={rustc}");
+
+    // re-run when a commit is checked out or made
+    if let Some(head) = git(&["rev-parse", "--git-path", "HEAD"]) {
+        println!("cargo:rerun-if-changed={head}");
+    }
+    if let Some(r) = git(&["symbolic-ref", "-q", "HEAD"])
+        && let Some(path) = git(&["rev-parse", "--git-path", &r])
+    {
+        println!("cargo:rerun-if-changed={path}");
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
 
 fn main() {
+    build_info();
+
+    // nothing to build for an API only binary
+    if env::var_os("CARGO_FEATURE_FRONTEND").is_none() {
+        return;
+    }
+
     // CARGO_MANIFEST_DIR points to crate dire
     let workspace_root = env::var("CARGO_MANIFEST_DIR")
         .map(PathBuf::from)
@@ -1,21 +1,37 @@
-use rocket::{Request, response::Responder, serde::json::Json};
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use ip_story_model::ApiResponse;
+use rocket::{
+    Request,
+    data::{self, Data, FromData, ToByteUnit},
+    form::{self, FromFormField, ValueField},
+    http::{ContentType, Header, MediaType, Status},
+    outcome::Outcome,
+    request::{self, FromRequest},
+    response::Responder,
+    serde::json::Json,
+};
+use serde::{Serialize, de::DeserializeOwned};
 use thiserror::Error;
-use utoipa::ToSchema;
+use utoipa::{IntoResponses, ToSchema};
 
+use crate::{audit::Principal, config::Config, session};
+
+/// Builds an [`ApiError`] remembering where it got raised, which is
+/// logged but not returned to the client
 #[macro_export]
 macro_rules! api_error {
+    ($status: expr, $msg: expr) => {
+        $crate::api::ApiError::with_status($status, $msg.to_string()).at(concat!(
+            file!(),
+            ":",
+            line!()
+        ))
+    };
     ($msg: expr) => {
-        $crate::api::ApiError::msg(format!("{}:{} {}", file!(), line!(), $msg))
+        $crate::api::ApiError::msg($msg.to_string()).at(concat!(file!(), ":", line!()))
     };
 }
 
-#[derive(ToSchema, Serialize)]
-pub struct ApiResponse<D: Serialize> {
-    error: Option<String>,
-    data: Option<D>,
-}
-
 #[derive(ToSchema, Serialize)]
 pub enum ApiData<D: Serialize> {
     Some(D),
@@ -46,42 +62,580 @@ where
     }
 }
 
+/// Serialization format of request and response bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl Format {
+    fn of(media: &MediaType) -> Option<Self> {
+        if media.top() != "application" {
+            return None;
+        }
+        match media.sub().as_str() {
+            "json" => Some(Format::Json),
+            "msgpack" | "x-msgpack" | "vnd.msgpack" => Some(Format::MsgPack),
+            "cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Format the responses to `r` are serialized with, JSON unless the
+    /// `Accept` header of the request prefers another one
+    fn accepted(r: &Request<'_>) -> Self {
+        r.accept()
+            .and_then(|a| Format::of(a.preferred().media_type()))
+            .unwrap_or(Format::Json)
+    }
+
+    /// Format of the body of `r`, JSON unless its `Content-Type` is
+    /// another one
+    fn of_body(r: &Request<'_>) -> Self {
+        r.content_type()
+            .and_then(|c| Format::of(c.media_type()))
+            .unwrap_or(Format::Json)
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            Format::Json => ContentType::JSON,
+            Format::MsgPack => ContentType::MsgPack,
+            Format::Cbor => ContentType::new("application", "cbor"),
+        }
+    }
+
+    // binary formats go through JSON values so that every format has
+    // the same shape, with IP addresses and UUIDs as strings
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Format::MsgPack => {
+                let value = serde_json::to_value(value).map_err(|e| e.to_string())?;
+                rmp_serde::to_vec_named(&value).map_err(|e| e.to_string())
+            }
+            Format::Cbor => {
+                let value = serde_json::to_value(value).map_err(|e| e.to_string())?;
+                let mut buf = vec![];
+                ciborium::into_writer(&value, &mut buf).map_err(|e| e.to_string())?;
+                Ok(buf)
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        let value: serde_json::Value = match self {
+            Format::Json => return serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Format::MsgPack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string())?,
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|e| e.to_string())?,
+        };
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+}
+
+/// Responds with `value` in the format the request accepts, JSON by
+/// default, pretty-printed if the request asks for it with the `pretty`
+/// query parameter
+fn respond<T: Serialize>(r: &Request<'_>, value: T) -> rocket::response::Result<'static> {
+    let format = Format::accepted(r);
+    if format != Format::Json {
+        let bytes = format.encode(&value).map_err(|e| {
+            log::error!("failed to serialize response: {e}");
+            Status::InternalServerError
+        })?;
+        return (format.content_type(), bytes).respond_to(r);
+    }
+
+    let pretty = r
+        .query_value::<bool>("pretty")
+        .and_then(Result::ok)
+        .unwrap_or_default();
+    if !pretty {
+        return Json(value).respond_to(r);
+    }
+
+    let s = serde_json::to_string_pretty(&value).map_err(|e| {
+        log::error!("failed to serialize response: {e}");
+        Status::InternalServerError
+    })?;
+    (ContentType::JSON, s).respond_to(r)
+}
+
 impl<'r, D> Responder<'r, 'static> for ApiData<D>
 where
     D: Serialize,
 {
     fn respond_to(self, r: &'r Request<'_>) -> rocket::response::Result<'static> {
-        let json = Json(ApiResponse {
-            data: Option::<D>::from(self),
-            error: None,
-        });
+        Warned::new(self, vec![]).respond_to(r)
+    }
+}
+
+/// Response data along with the questionable inputs of the request
+/// which did not prevent it from succeeding
+pub struct Warned<D: Serialize> {
+    data: ApiData<D>,
+    warnings: Vec<String>,
+}
+
+impl<D: Serialize> Warned<D> {
+    pub fn new(data: ApiData<D>, warnings: Vec<String>) -> Self {
+        Warned { data, warnings }
+    }
+}
 
-        json.respond_to(r)
+impl<'r, D> Responder<'r, 'static> for Warned<D>
+where
+    D: Serialize,
+{
+    fn respond_to(self, r: &'r Request<'_>) -> rocket::response::Result<'static> {
+        respond(
+            r,
+            ApiResponse {
+                data: Option::<D>::from(self.data),
+                error: None,
+                code: None,
+                warnings: self.warnings,
+            },
+        )
     }
 }
 
 pub type ApiResult<T> = Result<ApiData<T>, ApiError>;
 
+/// Wraps a response to set additional headers on it
+pub struct WithHeaders<R> {
+    inner: R,
+    headers: Vec<Header<'static>>,
+}
+
+impl<R> WithHeaders<R> {
+    pub fn new(inner: R) -> Self {
+        WithHeaders {
+            inner,
+            headers: vec![],
+        }
+    }
+
+    pub fn header(mut self, header: Header<'static>) -> Self {
+        self.headers.push(header);
+        self
+    }
+
+    /// Sets the `Last-Modified` header, if `time` is known
+    pub fn last_modified(self, time: Option<DateTime<Utc>>) -> Self {
+        match time {
+            Some(t) => self.header(Header::new(
+                "Last-Modified",
+                t.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            )),
+            None => self,
+        }
+    }
+}
+
+impl<'r, R> Responder<'r, 'static> for WithHeaders<R>
+where
+    R: Responder<'r, 'static>,
+{
+    fn respond_to(self, r: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let mut resp = self.inner.respond_to(r)?;
+        for h in self.headers {
+            resp.set_header(h);
+        }
+        Ok(resp)
+    }
+}
+
 #[derive(Debug, Error)]
-pub enum ApiError {
-    #[error("{0}")]
-    Msg(String),
+#[error("{msg}")]
+pub struct ApiError {
+    /// Plain messages are returned with `200 OK` status
+    status: Status,
+    code: Option<&'static str>,
+    msg: String,
+    /// Source location the error got raised at
+    origin: Option<&'static str>,
 }
 
 impl ApiError {
     pub fn msg<S: AsRef<str>>(s: S) -> Self {
-        ApiError::Msg(s.as_ref().to_string())
+        Self::with_status(Status::Ok, s)
+    }
+
+    pub fn with_status<S: AsRef<str>>(status: Status, s: S) -> Self {
+        ApiError {
+            status,
+            code: None,
+            msg: s.as_ref().to_string(),
+            origin: None,
+        }
+    }
+
+    /// Sets the code returned to clients, instead of the one
+    /// derived from the status
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
     }
+
+    pub fn at(mut self, origin: &'static str) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    /// HTTP status of the error response, plain messages
+    /// are returned with `200 OK` status
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    /// Stable identifier of the error, for clients to branch on
+    pub fn code(&self) -> &'static str {
+        self.code.unwrap_or(match self.status.code {
+            400 => "bad_request",
+            401 => "unauthorized",
+            403 => "forbidden",
+            404 => "not_found",
+            409 => "conflict",
+            412 => "precondition_failed",
+            413 => "payload_too_large",
+            422 => "unprocessable",
+            503 => "read_only",
+            504 => "storage_timeout",
+            500..=599 => "internal_error",
+            _ => "invalid_request",
+        })
+    }
+}
+
+/// Error responses any route can answer with, declared once for all the
+/// paths of the OpenAPI documentation. Other errors are answered with a
+/// `200 OK` status and the error message.
+#[derive(IntoResponses)]
+#[allow(dead_code)] // only used for the documentation
+pub enum ErrorResponses {
+    /// Missing or unknown API key, once API keys are set
+    #[response(status = 401)]
+    Unauthorized(ApiResponse<String>),
+    /// Unknown route
+    #[response(status = 404)]
+    NotFound(ApiResponse<String>),
+    /// Parameters which cannot be parsed
+    #[response(status = 422)]
+    Unprocessable(ApiResponse<String>),
+    /// Unexpected failure
+    #[response(status = 500)]
+    Internal(ApiResponse<String>),
+    /// The storage cannot be reached
+    #[response(status = 503)]
+    StorageUnavailable(ApiResponse<String>),
+    /// The storage did not answer in time
+    #[response(status = 504)]
+    StorageTimeout(ApiResponse<String>),
 }
 
+/// Error responses of the routes modifying the store,
+/// on top of the [`ErrorResponses`]
+#[derive(IntoResponses)]
+#[allow(dead_code)] // only used for the documentation
+pub enum WriteErrorResponses {
+    /// The client is authenticated by a session cookie and did not submit
+    /// its CSRF token
+    #[response(status = 403)]
+    CsrfInvalid(ApiResponse<String>),
+    /// The server is in read-only mode, or the storage cannot be reached
+    #[response(status = 503)]
+    ReadOnly(ApiResponse<String>),
+}
+
+/// Catcher answering the requests rejected by [`Principal`]
+#[rocket::catch(401)]
+pub fn unauthorized(_req: &Request<'_>) -> ApiError {
+    ApiError::with_status(Status::Unauthorized, "missing or unknown API key")
+}
+
+/// Catcher answering unknown routes with a JSON error
+#[rocket::catch(404)]
+pub fn not_found(req: &Request<'_>) -> ApiError {
+    ApiError::with_status(Status::NotFound, format!("{} not found", req.uri().path()))
+}
+
+/// Catcher answering requests whose parameters cannot be parsed
+#[rocket::catch(422)]
+pub fn unprocessable(req: &Request<'_>) -> ApiError {
+    ApiError::with_status(
+        Status::UnprocessableEntity,
+        format!("invalid parameters for {}", req.uri().path()),
+    )
+}
+
+/// Catcher answering requests which failed unexpectedly
+#[rocket::catch(500)]
+pub fn internal_error(_req: &Request<'_>) -> ApiError {
+    ApiError::with_status(Status::InternalServerError, "internal server error")
+}
+
+/// Catcher answering the requests rejected by [`Writable`]
+#[rocket::catch(503)]
+pub fn read_only(_req: &Request<'_>) -> ApiError {
+    ApiError::with_status(Status::ServiceUnavailable, "server is read-only")
+}
+
+/// Error message of the response to a request, kept in the request
+/// local cache so that it can be logged
+pub struct ResponseError(pub Option<String>);
+
 // Implement the ResponseError trait for ApiError
 impl<'r> Responder<'r, 'static> for ApiError {
     fn respond_to(self, r: &'r Request<'_>) -> rocket::response::Result<'static> {
-        let json = Json(ApiResponse::<()> {
-            error: Some(self.to_string()),
-            data: None,
+        r.local_cache(|| {
+            ResponseError(Some(match self.origin {
+                Some(origin) => format!("{self} code={} at={origin}", self.code()),
+                None => format!("{self} code={}", self.code()),
+            }))
         });
 
-        json.respond_to(r)
+        let mut resp = respond(
+            r,
+            ApiResponse::<()> {
+                error: Some(self.to_string()),
+                code: Some(self.code().into()),
+                data: None,
+                warnings: vec![],
+            },
+        )?;
+        resp.set_status(self.status());
+        Ok(resp)
+    }
+}
+
+/// Whether the request carries an `If-None-Match: *` header, asking
+/// for the target resource not to exist yet
+pub struct IfNoneMatchAny(pub bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatchAny {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let any = req.headers().get("If-None-Match").any(|v| v.trim() == "*");
+        Outcome::Success(IfNoneMatchAny(any))
+    }
+}
+
+/// Entity tags listed by the `If-None-Match` headers of the request
+pub struct IfNoneMatch(Vec<String>);
+
+impl IfNoneMatch {
+    /// Whether `etag` is among the tags, compared weakly
+    pub fn matches(&self, etag: &str) -> bool {
+        self.0
+            .iter()
+            .any(|t| t == "*" || t.trim_start_matches("W/") == etag)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let tags = req
+            .headers()
+            .get("If-None-Match")
+            .flat_map(|v| v.split(','))
+            .map(|t| t.trim().to_string())
+            .collect();
+        Outcome::Success(IfNoneMatch(tags))
+    }
+}
+
+/// Modification time the `If-Match` header of the request expects the
+/// target entry to have, as an RFC 3339 timestamp, quoted or not. `*`
+/// only expects the entry to exist. Handlers should take a
+/// `Result<IfMatch, ApiError>` so that invalid headers are reported as
+/// API errors.
+pub struct IfMatch(Option<Option<DateTime<Utc>>>);
+
+impl IfMatch {
+    /// Whether an entry modified at `mtime` satisfies the header, which
+    /// is the case of all entries when the header is missing
+    pub fn matches(&self, mtime: Option<DateTime<Utc>>) -> bool {
+        self.0
+            .is_none_or(|expected| expected.is_none_or(|t| Some(t) == mtime))
+    }
+
+    /// Whether the request carries an `If-Match` header
+    pub fn is_set(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Parses the value of an `If-Match` header
+    pub fn parse(value: &str) -> Result<Self, chrono::ParseError> {
+        let value = value.trim().trim_start_matches("W/").trim_matches('"');
+        if value == "*" {
+            return Ok(IfMatch(Some(None)));
+        }
+        DateTime::parse_from_rfc3339(value).map(|t| IfMatch(Some(Some(t.with_timezone(&Utc)))))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(value) = req.headers().get_one("If-Match") else {
+            return Outcome::Success(IfMatch(None));
+        };
+
+        match IfMatch::parse(value) {
+            Ok(if_match) => Outcome::Success(if_match),
+            Err(e) => {
+                let status = Status::BadRequest;
+                Outcome::Error((
+                    status,
+                    api_error!(status, format!("invalid If-Match header: {e}")),
+                ))
+            }
+        }
+    }
+}
+
+/// Guard of the routes modifying the store, failing with a
+/// `503 Service Unavailable` when the `read_only` setting is on, with a
+/// `401 Unauthorized` when the client is not a known [`Principal`], and
+/// with a `403 Forbidden` when a client authenticated by a session cookie
+/// does not submit its CSRF token
+pub struct Writable;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Writable {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        if let Some(config) = req.rocket().state::<Config>()
+            && config.read_only
+        {
+            return Outcome::Error((Status::ServiceUnavailable, ()));
+        }
+        match req.guard::<Principal>().await {
+            Outcome::Success(p) if p.by_session() && !session::csrf_valid(req) => {
+                Outcome::Error((Status::Forbidden, ()))
+            }
+            outcome => outcome.map(|_| Writable),
+        }
+    }
+}
+
+/// RFC 3339 timestamp usable as a query parameter
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp(pub DateTime<Utc>);
+
+impl<'v> FromFormField<'v> for Timestamp {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        DateTime::parse_from_rfc3339(field.value)
+            .map(|t| Timestamp(t.with_timezone(&Utc)))
+            .map_err(|e| form::Error::validation(e.to_string()).into())
+    }
+}
+
+/// Request body, limited in size by the `body_limit` setting. Bodies are
+/// JSON unless their `Content-Type` is MessagePack or CBOR. Handlers
+/// should take a `Result<Body<T>, ApiError>` so that oversized or invalid
+/// bodies are reported as API errors.
+pub struct Body<T>(pub T);
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for Body<T> {
+    type Error = ApiError;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = req
+            .rocket()
+            .state::<Config>()
+            .map(|c| c.body_limit)
+            .unwrap_or(1.mebibytes());
+
+        let bytes = match data.open(limit).into_bytes().await {
+            Ok(s) if s.is_complete() => s.into_inner(),
+            Ok(_) => {
+                let status = Status::PayloadTooLarge;
+                return Outcome::Error((
+                    status,
+                    api_error!(status, format!("request body exceeds {limit}")),
+                ));
+            }
+            Err(e) => {
+                let status = Status::BadRequest;
+                return Outcome::Error((
+                    status,
+                    api_error!(status, format!("failed to read request body: {e}"))
+                        .with_code("invalid_body"),
+                ));
+            }
+        };
+
+        match Format::of_body(req).decode(&bytes) {
+            Ok(v) => Outcome::Success(Body(v)),
+            Err(e) => {
+                let status = Status::UnprocessableEntity;
+                Outcome::Error((
+                    status,
+                    api_error!(status, format!("invalid request body: {e}"))
+                        .with_code("invalid_body"),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> Option<DateTime<Utc>> {
+        Some(DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc))
+    }
+
+    #[test]
+    fn if_match_accepts_quoted_weak_and_bare_timestamps() {
+        let t = "2024-05-01T12:00:00.123456Z";
+        for value in [
+            t.to_string(),
+            format!("\"{t}\""),
+            format!("W/\"{t}\""),
+            " \"2024-05-01T14:00:00.123456+02:00\" ".to_string(),
+        ] {
+            let if_match = IfMatch::parse(&value).unwrap();
+            assert!(if_match.is_set());
+            assert!(if_match.matches(at(t)), "{value}");
+            assert!(!if_match.matches(at("2024-05-01T12:00:00Z")), "{value}");
+            assert!(!if_match.matches(None), "{value}");
+        }
+    }
+
+    #[test]
+    fn if_match_any_matches_any_modification_time() {
+        let if_match = IfMatch::parse("*").unwrap();
+        assert!(if_match.is_set());
+        assert!(if_match.matches(at("2024-05-01T12:00:00Z")));
+    }
+
+    #[test]
+    fn missing_if_match_matches_everything() {
+        let if_match = IfMatch(None);
+        assert!(!if_match.is_set());
+        assert!(if_match.matches(at("2024-05-01T12:00:00Z")));
+        assert!(if_match.matches(None));
+    }
+
+    #[test]
+    fn invalid_if_match_is_rejected() {
+        for value in ["", "\"\"", "yesterday", "2024-05-01"] {
+            assert!(IfMatch::parse(value).is_err(), "{value}");
+        }
     }
 }
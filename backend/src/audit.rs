@@ -0,0 +1,397 @@
+use std::{collections::HashSet, net::IpAddr, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use log::error;
+use rocket::{
+    State, get,
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    API_MOUNTPOINT, ApiResponse, DataKind, Entry,
+    api::{ApiData, ApiError, ApiResult, ErrorResponses, Timestamp},
+    api_error,
+    config::{ApiKey, Config},
+    session::SESSION_COOKIE,
+    storage::Storage,
+    storage_error,
+};
+
+/// Identity of the client issuing a request, along with the
+/// classifications of the entries it can read and write, all if unset.
+/// Requests carrying no known API key are answered with a `401
+/// Unauthorized` once the api_keys setting is set. With session_cookies,
+/// the key is read from the session cookie when no header carries one.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    name: String,
+    read: Option<HashSet<String>>,
+    write: Option<HashSet<String>>,
+    /// Whether the client is authenticated by a session cookie
+    session: bool,
+}
+
+impl Principal {
+    /// Identity of the changes made by the server itself
+    pub fn system() -> Self {
+        Principal::unrestricted("system".into())
+    }
+
+    fn unrestricted(name: String) -> Self {
+        Principal {
+            name,
+            read: None,
+            write: None,
+            session: false,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the client is authenticated by a session cookie, and
+    /// thus exposed to cross-site request forgery
+    pub fn by_session(&self) -> bool {
+        self.session
+    }
+
+    /// Name of the API key restricting the entries the client reads,
+    /// None if it reads them all
+    pub fn read_restriction(&self) -> Option<&str> {
+        self.read.as_ref().map(|_| self.name.as_str())
+    }
+
+    /// Whether the client can read `entry`
+    pub fn can_read(&self, entry: &Entry) -> bool {
+        cleared(self.read.as_ref(), entry)
+    }
+
+    /// Checks that the client can run the operations on the whole store,
+    /// which only unrestricted keys can
+    pub fn check_admin(&self) -> Result<(), ApiError> {
+        if self.read.is_none() && self.write.is_none() {
+            return Ok(());
+        }
+        Err(api_error!(
+            Status::Forbidden,
+            format!("{} is restricted to some classifications", self.name)
+        )
+        .with_code("admin_required"))
+    }
+
+    /// Checks that the client can write `entry`, be it the entry as it is
+    /// stored or as the client submitted it
+    pub fn check_write(&self, entry: &Entry) -> Result<(), ApiError> {
+        if cleared(self.write.as_ref(), entry) {
+            return Ok(());
+        }
+        Err(api_error!(
+            Status::Forbidden,
+            format!(
+                "{} cannot write {} entries",
+                self.name,
+                entry.classification.as_deref().unwrap_or_default()
+            )
+        )
+        .with_code("classification_forbidden"))
+    }
+}
+
+/// Whether the classification of `entry` is one of `classifications`,
+/// regardless of its case. Entries without classification are cleared
+/// for everyone.
+fn cleared(classifications: Option<&HashSet<String>>, entry: &Entry) -> bool {
+    match (classifications, &entry.classification) {
+        (Some(classifications), Some(c)) => classifications
+            .iter()
+            .any(|cl| cl.eq_ignore_ascii_case(c.trim())),
+        _ => true,
+    }
+}
+
+impl From<&ApiKey> for Principal {
+    fn from(key: &ApiKey) -> Self {
+        Principal {
+            name: key.name.clone(),
+            read: key.read.clone(),
+            write: key.write.clone(),
+            session: false,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Principal {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = req.rocket().state::<Config>();
+        let keys = config.map(|c| &c.api_keys);
+        let Some(keys) = keys.filter(|k| !k.is_empty()) else {
+            // without API keys the client address is
+            // the best identity we have
+            let name = req
+                .client_ip()
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|| "unknown".into());
+            return Outcome::Success(Principal::unrestricted(name));
+        };
+
+        if let Some(key) = req.headers().get_one("X-API-Key") {
+            return match keys.get(key) {
+                Some(key) => Outcome::Success(key.into()),
+                None => Outcome::Error((Status::Unauthorized, ())),
+            };
+        }
+
+        let cookie = req
+            .cookies()
+            .get(SESSION_COOKIE)
+            .map(|c| c.value().to_string());
+        match cookie
+            .filter(|_| config.is_some_and(|c| c.session_cookies))
+            .and_then(|k| keys.get(&k))
+        {
+            Some(key) => Outcome::Success(Principal {
+                session: true,
+                ..Principal::from(key)
+            }),
+            None => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+}
+
+/// A mutation made on the store
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct AuditRecord {
+    timestamp: DateTime<Utc>,
+    principal: String,
+    action: AuditAction,
+    #[schema(value_type = String)]
+    ip: IpAddr,
+    /// UUID of the entry, None when the mutation concerns the IP itself
+    uuid: Option<Uuid>,
+    kind: Option<DataKind>,
+}
+
+impl AuditRecord {
+    pub fn new(principal: &Principal, action: AuditAction, ip: IpAddr) -> Self {
+        AuditRecord {
+            timestamp: Utc::now(),
+            principal: principal.name.clone(),
+            action,
+            ip,
+            uuid: None,
+            kind: None,
+        }
+    }
+
+    pub fn entry(mut self, entry: &Entry) -> Self {
+        self.uuid = entry.uuid;
+        self.kind = Some(entry.data.kind());
+        self
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
+/// Records a mutation in the audit trail. Failing to do so is logged
+/// but does not fail the mutation which has already been made.
+pub fn audit(db: &Storage, record: AuditRecord) {
+    if let Err(e) = db.append_audit(&record) {
+        error!("failed to write audit record {record:?}: {e}");
+    }
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = Option<String>, Query, description = "Only return the records of this IP address"),
+        ("from" = Option<String>, Query, description = "RFC 3339 timestamp of the oldest record to return"),
+        ("to" = Option<String>, Query, description = "RFC 3339 timestamp of the newest record to return"),
+    ),
+    responses(
+        (status = 200, description = "Audit records retrieved successfully", body = ApiResponse<Vec<AuditRecord>>, content_type = "application/json"),
+        (status = 403, description = "The API key only reads some classifications", body = ApiResponse<String>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "Audit",
+    description = "Searches the audit trail of the mutations made on the store, from the oldest to the newest. The records do not carry the classifications of the entries they concern, so that keys only reading some classifications are answered with a 403 Forbidden and the classification_forbidden code."
+)]
+#[get("/audit?<ip>&<from>&<to>")]
+pub async fn audit_search(
+    principal: Principal,
+    ip: Option<IpAddr>,
+    from: Option<Timestamp>,
+    to: Option<Timestamp>,
+    config: &State<Config>,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Vec<AuditRecord>> {
+    // records may concern entries of any classification
+    if principal.read_restriction().is_some() {
+        return Err(api_error!(
+            Status::Forbidden,
+            format!("{} cannot read the audit trail", principal.name())
+        )
+        .with_code("classification_forbidden"));
+    }
+
+    let ip = ip.map(|ip| config.ipv4_mapped.unmap(ip));
+
+    let records = db
+        .audit_range(from.map(|t| t.0), to.map(|t| t.0))
+        .map_err(|e| storage_error!(e, "failed to read audit trail"))?
+        .into_iter()
+        .filter(|r| ip.is_none_or(|ip| r.ip() == ip))
+        .collect();
+
+    Ok(ApiData::Some(records))
+}
+
+#[cfg(test)]
+mod tests {
+    use ip_story_model::Data;
+
+    use super::*;
+
+    fn classified(classification: Option<&str>) -> Entry {
+        Entry {
+            classification: classification.map(String::from),
+            ..Entry::new(Data::Text("scanned our network".into()))
+        }
+    }
+
+    fn cleared_for(read: &[&str], write: &[&str]) -> Principal {
+        let set = |c: &[&str]| Some(c.iter().map(|c| c.to_string()).collect());
+        Principal::from(&ApiKey {
+            name: "soc".into(),
+            read: set(read),
+            write: set(write),
+        })
+    }
+
+    #[test]
+    fn classifications_are_compared_regardless_of_case() {
+        let soc = cleared_for(&["tlp:clear", "TLP:Green"], &["tlp:clear"]);
+
+        assert!(soc.can_read(&classified(Some("TLP:CLEAR"))));
+        assert!(soc.can_read(&classified(Some(" tlp:green "))));
+        assert!(!soc.can_read(&classified(Some("tlp:amber"))));
+
+        assert!(soc.check_write(&classified(Some("Tlp:Clear"))).is_ok());
+        let err = soc.check_write(&classified(Some("tlp:green"))).unwrap_err();
+        assert_eq!(err.status(), Status::Forbidden);
+        assert_eq!(err.code(), "classification_forbidden");
+    }
+
+    #[test]
+    fn unclassified_entries_are_open_to_everyone() {
+        let restricted = cleared_for(&[], &[]);
+        let unclassified = classified(None);
+
+        assert!(restricted.can_read(&unclassified));
+        assert!(restricted.check_write(&unclassified).is_ok());
+        assert!(!restricted.can_read(&classified(Some("tlp:clear"))));
+    }
+
+    #[test]
+    fn unrestricted_keys_access_every_classification() {
+        let admin = Principal::from(&ApiKey {
+            name: "admin".into(),
+            read: None,
+            write: None,
+        });
+        let red = classified(Some("tlp:red"));
+
+        assert!(admin.can_read(&red));
+        assert!(admin.check_write(&red).is_ok());
+        assert_eq!(admin.read_restriction(), None);
+        assert_eq!(cleared_for(&[], &[]).read_restriction(), Some("soc"));
+        assert!(Principal::system().can_read(&red));
+    }
+
+    #[test]
+    fn only_unrestricted_keys_are_admins() {
+        let admin = Principal::from(&ApiKey {
+            name: "admin".into(),
+            read: None,
+            write: None,
+        });
+        assert!(admin.check_admin().is_ok());
+        assert!(Principal::system().check_admin().is_ok());
+
+        for soc in [
+            cleared_for(&["tlp:clear"], &["tlp:clear"]),
+            // restricted in what it writes only
+            Principal::from(&ApiKey {
+                name: "soc".into(),
+                read: None,
+                write: Some(HashSet::from(["tlp:clear".into()])),
+            }),
+        ] {
+            let err = soc.check_admin().unwrap_err();
+            assert_eq!(err.status(), Status::Forbidden);
+            assert_eq!(err.code(), "admin_required");
+        }
+    }
+
+    #[test]
+    fn the_audit_trail_is_refused_to_restricted_keys() {
+        use std::collections::HashMap;
+
+        use rocket::{http::Header, local::blocking::Client};
+
+        let key = |read: Option<HashSet<String>>| ApiKey {
+            name: "soc".into(),
+            read,
+            write: None,
+        };
+        let config = Config {
+            api_keys: HashMap::from([
+                ("restricted".into(), key(Some(HashSet::new()))),
+                ("admin".into(), key(None)),
+            ]),
+            ..Config::default()
+        };
+        let rocket = rocket::build()
+            .mount("/", rocket::routes![audit_search])
+            .register("/", rocket::catchers![crate::api::unauthorized])
+            .manage(config)
+            .manage(Arc::new(Storage::unreachable()));
+        let client = Client::tracked(rocket).unwrap();
+        let code = |key: &'static str| {
+            let resp = client
+                .get("/audit")
+                .header(Header::new("X-API-Key", key))
+                .dispatch();
+            let status = resp.status();
+            let body: serde_json::Value =
+                serde_json::from_str(&resp.into_string().unwrap()).unwrap();
+            (status, body["code"].as_str().map(String::from))
+        };
+
+        let (status, c) = code("restricted");
+        assert_eq!(status, Status::Forbidden);
+        assert_eq!(c.as_deref(), Some("classification_forbidden"));
+
+        // the unrestricted key gets as far as the store
+        let (status, c) = code("admin");
+        assert_eq!(status, Status::ServiceUnavailable);
+        assert_eq!(c.as_deref(), Some("storage_unavailable"));
+    }
+}
@@ -0,0 +1,80 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("invalid prefix length {0}")]
+pub struct InvalidPrefix(u8);
+
+/// Range of IP addresses sharing the same `prefix` leading bits
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Creates the range of the addresses sharing the `prefix` leading
+    /// bits of `addr`, the host bits of `addr` are ignored
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, InvalidPrefix> {
+        let network = match addr {
+            IpAddr::V4(a) if prefix <= 32 => {
+                IpAddr::V4(Ipv4Addr::from(u32::from(a) & mask(prefix, 32) as u32))
+            }
+            IpAddr::V6(a) if prefix <= 128 => {
+                IpAddr::V6(Ipv6Addr::from(u128::from(a) & mask(prefix, 128)))
+            }
+            _ => return Err(InvalidPrefix(prefix)),
+        };
+
+        Ok(Cidr { network, prefix })
+    }
+
+    fn bits(&self) -> u8 {
+        if self.network.is_ipv4() { 32 } else { 128 }
+    }
+
+    /// Number of addresses in the range, saturating for the largest
+    /// IPv6 range
+    pub fn size(&self) -> u128 {
+        1u128
+            .checked_shl((self.bits() - self.prefix) as u32)
+            .unwrap_or(u128::MAX)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(n), IpAddr::V4(ip)) => {
+                u32::from(ip) & mask(self.prefix, 32) as u32 == u32::from(n)
+            }
+            (IpAddr::V6(n), IpAddr::V6(ip)) => {
+                u128::from(ip) & mask(self.prefix, 128) == u128::from(n)
+            }
+            _ => false,
+        }
+    }
+
+    /// Iterates over all the addresses of the range
+    pub fn addresses(&self) -> impl Iterator<Item = IpAddr> + use<> {
+        let network = self.network;
+        (0..self.size()).map(move |i| match network {
+            IpAddr::V4(n) => IpAddr::V4(Ipv4Addr::from(u32::from(n) + i as u32)),
+            IpAddr::V6(n) => IpAddr::V6(Ipv6Addr::from(u128::from(n) + i)),
+        })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Mask of the `prefix` leading bits of a `bits` wide address
+fn mask(prefix: u8, bits: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        (u128::MAX << (128 - prefix as u32)) >> (128 - bits as u32)
+    }
+}
@@ -0,0 +1,431 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    time::Duration,
+};
+
+use chrono::{TimeDelta, Utc};
+use ip_story_model::{DataKind, Entry, SearchOrder};
+use log::LevelFilter;
+use rocket::data::{ByteUnit, ToByteUnit};
+use serde::Deserialize;
+
+use crate::{
+    API_MOUNTPOINT,
+    enrich::EnrichKind,
+    export::RedactField,
+    hooks::BuiltinHook,
+    storage::{Layout, Retry},
+    webhooks::Webhook,
+};
+
+/// What is done with the timestamps of entries too far in the future
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FuturePolicy {
+    /// The entry is rejected
+    Reject,
+    /// The timestamp is replaced by the current time
+    Clamp,
+}
+
+/// What is done with the IPv4-mapped IPv6 addresses, ex: `::ffff:1.2.3.4`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MappedPolicy {
+    /// The address is replaced by the IPv4 address it maps
+    Unmap,
+    /// The address is tracked as is, apart from the IPv4 address it maps
+    Keep,
+    /// The address is rejected
+    Reject,
+}
+
+impl MappedPolicy {
+    /// `ip` as tracked, the IPv4 address it maps if it is an IPv4-mapped
+    /// IPv6 address which the policy unmaps
+    pub fn unmap(self, ip: IpAddr) -> IpAddr {
+        match self {
+            Self::Unmap => ip.to_canonical(),
+            Self::Keep | Self::Reject => ip,
+        }
+    }
+}
+
+/// Access granted to the clients presenting an API key. Entries without
+/// a classification can be read and written by any client.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    /// Name the client is identified by, ex: in the audit trail
+    pub name: String,
+    /// Classifications of the entries the client can read, all if unset
+    #[serde(default)]
+    pub read: Option<HashSet<String>>,
+    /// Classifications of the entries the client can create, modify and
+    /// delete, all if unset
+    #[serde(default)]
+    pub write: Option<HashSet<String>>,
+}
+
+/// Timestamp the history of an IP address is ordered by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryKey {
+    /// Creation time, the order entries got reported in
+    Ctime,
+    /// Modification time, entries never modified being ordered by
+    /// their creation time
+    Mtime,
+}
+
+/// Application settings, extracted from Rocket's configuration sources
+/// (`Rocket.toml` and `ROCKET_*` environment variables), alongside Rocket's
+/// own settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Path the API is mounted at, ex: `/ip-story/api` behind a
+    /// reverse proxy forwarding a sub-path
+    pub api_mountpoint: String,
+    /// Serves the API paths ending with a slash as if they had none
+    pub api_strip_trailing_slash: bool,
+    /// Matches the static segments of the API paths regardless of their
+    /// case, ex: `/api/IP/1.2.3.4/Entry`. Parameters are left as is.
+    pub api_case_insensitive: bool,
+    /// Serves the OpenAPI documentation and its view in the frontend,
+    /// deployments hiding the API surface can turn it off
+    pub openapi_enabled: bool,
+    /// Mounts `POST /admin/seed`, filling the store with example stories
+    /// for demos, never to be enabled in production
+    pub seed_enabled: bool,
+    /// Rejects IP addresses which are not routable (loopback, link-local,
+    /// unspecified, documentation ...)
+    pub reject_reserved_ips: bool,
+    /// What is done with the IPv4-mapped IPv6 addresses, unmapping them
+    /// prevents the history of a host from being split in two
+    pub ipv4_mapped: MappedPolicy,
+    /// Rejects the requests modifying the store, for maintenance
+    /// windows or replica deployments
+    pub read_only: bool,
+    /// API keys, by key. Once set, the requests reading entries or
+    /// modifying the store must carry one in an `X-API-Key` header, and
+    /// are restricted to the classifications of their key. Access is
+    /// unrestricted if none is set.
+    pub api_keys: HashMap<String, ApiKey>,
+    /// Mounts `POST /session`, `DELETE /session` and `GET /csrf` so that
+    /// browsers authenticate with a session cookie holding their API key,
+    /// their requests modifying the store then requiring a CSRF token
+    pub session_cookies: bool,
+    /// Maximum time, in milliseconds, a storage operation can take
+    /// before failing. A value of 0 disables the timeout.
+    pub storage_timeout_ms: u64,
+    /// Maximum number of times a storage operation failing because
+    /// of a connection issue is retried
+    pub storage_retries: u32,
+    /// Delay, in milliseconds, before the first retry, doubled for
+    /// every following retry
+    pub storage_retry_delay_ms: u64,
+    /// Time, in milliseconds, after which a failing storage
+    /// operation is not retried anymore
+    pub storage_retry_max_ms: u64,
+    /// How the stories are laid out in Redis, changing it requires
+    /// to migrate the existing stories
+    pub storage_layout: Layout,
+    /// `COUNT` hint of the `HSCAN`/`SCAN` commands reading the whole
+    /// store, larger values mean fewer round trips but longer commands
+    pub storage_scan_count: usize,
+    /// Timestamp the histories are ordered by, existing stories are
+    /// reordered the next time they are modified
+    pub history_key: HistoryKey,
+    /// Maximum size of a serialized story, writes growing a story past
+    /// it are rejected
+    pub max_story_size: ByteUnit,
+    /// Maximum size of the JSON body of entry submissions
+    pub body_limit: ByteUnit,
+    /// Maximum size of the backups restored by `POST /import/all`
+    pub import_all_limit: ByteUnit,
+    /// Maximum size of the IP lists imported by `POST /import/iplist`
+    pub import_iplist_limit: ByteUnit,
+    /// Number of entries returned by a search not specifying a limit
+    pub search_default_limit: usize,
+    /// Maximum number of entries a search can return, larger
+    /// limits are clamped to it
+    pub search_max_limit: usize,
+    /// Order of the entries returned by searches not giving one
+    pub search_default_order: SearchOrder,
+    /// Maximum number of IP addresses of a batch search
+    pub search_batch_max_ips: usize,
+    /// Maximum number of entries fetched at once by their UUIDs
+    pub entry_fetch_max_uuids: usize,
+    /// Number of events buffered for the event streams, clients
+    /// lagging further behind miss events
+    pub stream_buffer: usize,
+    /// Maximum number of IP addresses a WebSocket connection can
+    /// subscribe to
+    pub ws_max_ips: usize,
+    /// Origins, besides the one of the server, whose pages can open
+    /// WebSockets, ex: `https://soc.example`
+    pub ws_origins: Vec<String>,
+    /// Endpoints the entries created, updated or deleted are posted to
+    pub webhooks: Vec<Webhook>,
+    /// Maximum time, in milliseconds, of a webhook delivery
+    pub webhook_timeout_ms: u64,
+    /// Maximum length of tags in characters, it cannot exceed
+    /// [`Tag::MAX_LEN`](ip_story_model::Tag::MAX_LEN)
+    pub tag_max_len: usize,
+    /// Maximum length of entry descriptions in characters
+    pub description_max_len: usize,
+    /// Rejects owners whose country cannot be normalized to an
+    /// ISO 3166-1 code, instead of storing it as given
+    pub strict_countries: bool,
+    /// Strips the HTML tags of entry descriptions and text data on write
+    pub sanitize_html: bool,
+    /// Unsets the blank strings and empty collections of the optional
+    /// fields of entries and of their owner data on write
+    pub drop_empty_fields: bool,
+    /// Interval, in seconds, at which expired entries are removed
+    /// from the store, 0 disables their removal
+    pub prune_interval_secs: u64,
+    /// Time, in seconds, after which the confidence of an entry not
+    /// modified since is halved when searching, 0 disables the decay
+    pub confidence_half_life_secs: u64,
+    /// Time, in seconds, entry timestamps can be ahead of the server
+    /// clock, to absorb the skew of the clients' clocks
+    pub future_tolerance_secs: u64,
+    /// What is done with the timestamps further in the future
+    pub future_policy: FuturePolicy,
+    /// Level at which requests are logged, `off` disables request logging
+    pub request_log_level: LevelFilter,
+    /// Maximum number of addresses of the ranges operated on
+    pub cidr_max_size: u128,
+    /// WHOIS server (`host:port`) first queried for whois enrichments,
+    /// its referral to the registry in charge is followed
+    pub enrich_whois_server: String,
+    /// Team Cymru compatible WHOIS server (`host:port`) queried for asn
+    /// and geo enrichments
+    pub enrich_asn_server: String,
+    /// Maximum time, in milliseconds, of a single lookup
+    pub enrich_timeout_ms: u64,
+    /// Maximum number of lookups run concurrently by a request
+    pub enrich_concurrency: usize,
+    /// Maximum number of queries per second sent to upstream services
+    pub enrich_rate: u32,
+    /// Enrichers run by the enrichment of a single IP address
+    pub enrich_enabled: Vec<EnrichKind>,
+    /// Kinds of data entries can hold, all kinds are accepted if unset
+    pub allowed_kinds: Option<HashSet<DataKind>>,
+    /// Duration, in seconds, the entries of a kind are kept for after
+    /// their last modification, entries of the kinds missing are kept
+    /// until their `expires_at`, if any
+    pub retention_secs: HashMap<DataKind, u64>,
+    /// Hooks run, in order, on the entries being created
+    pub entry_hooks: Vec<BuiltinHook>,
+    /// Fields stripped from every entry by redacted exports
+    pub export_redact: Vec<RedactField>,
+    /// Fields stripped by redacted exports from the entries of a
+    /// classification, on top of export_redact, ex:
+    /// `{"tlp:amber" = ["description"]}`
+    pub export_redact_classified: HashMap<String, Vec<RedactField>>,
+    /// Duration, in seconds, the statistics requiring a full scan
+    /// of the store are cached for
+    pub stats_ttl_secs: u64,
+    /// OTLP/HTTP endpoint the traces are exported to, ex:
+    /// `http://localhost:4318/v1/traces`, traces are not exported if unset.
+    /// Requires the `otel` feature.
+    pub otel_endpoint: Option<String>,
+    /// Service name the exported traces are attributed to
+    pub otel_service_name: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            api_mountpoint: API_MOUNTPOINT.into(),
+            api_strip_trailing_slash: true,
+            api_case_insensitive: false,
+            openapi_enabled: true,
+            seed_enabled: false,
+            reject_reserved_ips: false,
+            ipv4_mapped: MappedPolicy::Unmap,
+            read_only: false,
+            api_keys: HashMap::new(),
+            session_cookies: false,
+            storage_timeout_ms: 5000,
+            storage_retries: 3,
+            storage_retry_delay_ms: 50,
+            storage_retry_max_ms: 2000,
+            storage_layout: Layout::Hash,
+            storage_scan_count: 100,
+            history_key: HistoryKey::Ctime,
+            max_story_size: 16.mebibytes(),
+            body_limit: 1.mebibytes(),
+            import_all_limit: 1.gibibytes(),
+            import_iplist_limit: 64.mebibytes(),
+            search_default_limit: 100,
+            search_max_limit: 1000,
+            search_default_order: SearchOrder::Asc,
+            search_batch_max_ips: 100,
+            entry_fetch_max_uuids: 1000,
+            stream_buffer: 256,
+            ws_max_ips: 1000,
+            ws_origins: vec![],
+            webhooks: vec![],
+            webhook_timeout_ms: 5000,
+            tag_max_len: 64,
+            description_max_len: 4096,
+            sanitize_html: false,
+            drop_empty_fields: false,
+            strict_countries: false,
+            prune_interval_secs: 3600,
+            confidence_half_life_secs: 0,
+            future_tolerance_secs: 300,
+            future_policy: FuturePolicy::Reject,
+            request_log_level: LevelFilter::Info,
+            cidr_max_size: 1024,
+            enrich_whois_server: "whois.iana.org:43".into(),
+            enrich_asn_server: "whois.cymru.com:43".into(),
+            enrich_timeout_ms: 10000,
+            enrich_concurrency: 4,
+            enrich_rate: 2,
+            enrich_enabled: vec![EnrichKind::Whois, EnrichKind::Asn, EnrichKind::Geo],
+            allowed_kinds: None,
+            retention_secs: HashMap::new(),
+            entry_hooks: vec![],
+            export_redact: vec![
+                RedactField::OwnerAddress,
+                RedactField::OwnerPhone,
+                RedactField::OwnerAbuse,
+            ],
+            export_redact_classified: HashMap::new(),
+            stats_ttl_secs: 300,
+            otel_endpoint: None,
+            otel_service_name: env!("CARGO_PKG_NAME").into(),
+        }
+    }
+}
+
+impl Config {
+    /// Mount point of the API, without trailing slash
+    pub fn api_mountpoint(&self) -> &str {
+        self.api_mountpoint.trim_end_matches('/')
+    }
+
+    pub fn kind_allowed(&self, kind: &DataKind) -> bool {
+        self.allowed_kinds.as_ref().is_none_or(|k| k.contains(kind))
+    }
+
+    /// Time `entry` expires at, its own `expires_at` taking precedence
+    /// over the retention of its kind
+    pub fn expires_at(&self, entry: &Entry) -> Option<chrono::DateTime<Utc>> {
+        entry.expires_at.or_else(|| {
+            let secs = *self.retention_secs.get(&entry.data.kind())?;
+            let last = entry.mtime.or(entry.ctime)?;
+            last.checked_add_signed(TimeDelta::seconds(i64::try_from(secs).ok()?))
+        })
+    }
+
+    /// Confidence in `entry` at `now`, halved every confidence_half_life_secs
+    /// since it was last modified. Only searches see this decayed value,
+    /// the confidence stored is left as is.
+    pub fn confidence_at(&self, entry: &Entry, now: chrono::DateTime<Utc>) -> Option<f64> {
+        let confidence = entry.confidence?.value();
+        let Some(last) = entry
+            .mtime
+            .or(entry.ctime)
+            .filter(|_| self.confidence_half_life_secs > 0)
+        else {
+            return Some(confidence);
+        };
+
+        // entries dated in the future do not gain confidence
+        let age = (now - last).num_milliseconds().max(0) as f64 / 1000.0;
+        Some(confidence * 0.5f64.powf(age / self.confidence_half_life_secs as f64))
+    }
+
+    /// Whether `entry` expired at `now`, see [`Config::expires_at`]
+    pub fn is_expired(&self, entry: &Entry, now: chrono::DateTime<Utc>) -> bool {
+        self.expires_at(entry).is_some_and(|t| t <= now)
+    }
+
+    pub fn storage_timeout(&self) -> Option<Duration> {
+        (self.storage_timeout_ms > 0).then(|| Duration::from_millis(self.storage_timeout_ms))
+    }
+
+    pub fn storage_retry(&self) -> Retry {
+        Retry {
+            attempts: self.storage_retries,
+            base_delay: Duration::from_millis(self.storage_retry_delay_ms),
+            max_time: Duration::from_millis(self.storage_retry_max_ms),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapped_addresses_are_unmapped_by_policy() {
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(MappedPolicy::Unmap.unmap(mapped), v4);
+        assert_eq!(MappedPolicy::Keep.unmap(mapped), mapped);
+        assert_eq!(MappedPolicy::Reject.unmap(mapped), mapped);
+    }
+
+    #[test]
+    fn other_addresses_are_left_as_is() {
+        for ip in ["192.0.2.1", "2001:db8::1", "::192.0.2.1", "::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            for policy in [
+                MappedPolicy::Unmap,
+                MappedPolicy::Keep,
+                MappedPolicy::Reject,
+            ] {
+                assert_eq!(policy.unmap(ip), ip, "{ip} with {policy:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn confidence_halves_every_half_life() {
+        let config = Config {
+            confidence_half_life_secs: 3600,
+            ..Config::default()
+        };
+        let modified = chrono::DateTime::from_timestamp(0, 0);
+        let entry = Entry {
+            ctime: modified,
+            confidence: Some(0.8.try_into().unwrap()),
+            ..Entry::new(ip_story_model::Data::Text("x".into()))
+        };
+        let at =
+            |secs| config.confidence_at(&entry, chrono::DateTime::from_timestamp(secs, 0).unwrap());
+
+        assert_eq!(at(0), Some(0.8));
+        assert_eq!(at(3600), Some(0.4));
+        assert_eq!(at(7200), Some(0.2));
+        // entries dated ahead of the clock keep their confidence
+        assert_eq!(at(-3600), Some(0.8));
+        // the stored confidence is left as is
+        assert_eq!(entry.confidence.map(|c| c.value()), Some(0.8));
+
+        let modified_later = Entry {
+            mtime: chrono::DateTime::from_timestamp(3600, 0),
+            ..entry.clone()
+        };
+        assert_eq!(
+            config.confidence_at(
+                &modified_later,
+                chrono::DateTime::from_timestamp(7200, 0).unwrap()
+            ),
+            Some(0.4)
+        );
+        assert_eq!(
+            Config::default()
+                .confidence_at(&entry, chrono::DateTime::from_timestamp(7200, 0).unwrap()),
+            Some(0.8)
+        );
+    }
+}
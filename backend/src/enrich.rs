@@ -0,0 +1,496 @@
+//! Enrichment of IP addresses with the data of public WHOIS services
+
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use chrono::{TimeDelta, Utc};
+
+use anyhow::{Context, anyhow, bail};
+use ip_story_model::{ApiResponse, Data, Entry, Owner};
+use rocket::{
+    FromForm, FromFormField, State,
+    futures::{StreamExt, stream},
+    post,
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::Mutex,
+        time::{Instant, sleep_until, timeout},
+    },
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    API_MOUNTPOINT, IpStory, add_entry,
+    api::{ApiData, ApiError, ApiResult, ErrorResponses, Writable, WriteErrorResponses},
+    api_error,
+    audit::{AuditAction, AuditRecord, Principal, audit},
+    check_ip, check_kind,
+    cidr::Cidr,
+    config::Config,
+    events::Events,
+    hooks::Hooks,
+    storage::Storage,
+    storage_error,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, FromFormField, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum EnrichKind {
+    /// Owner of the address, from the registry WHOIS
+    Whois,
+    /// Origin AS number
+    Asn,
+    /// Country the address is allocated to, stored as a JSON entry
+    Geo,
+}
+
+/// Spaces the queries sent to upstream services
+struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(per_second: u32) -> Self {
+        RateLimiter {
+            interval: Duration::from_secs(1) / per_second.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits for the next query slot
+    async fn wait(&self) {
+        let at = {
+            let mut next = self.next.lock().await;
+            let at = (*next).max(Instant::now());
+            *next = at + self.interval;
+            at
+        };
+        sleep_until(at).await;
+    }
+}
+
+/// Looks IP addresses up, all the lookups made by the service share
+/// the same rate limit
+pub struct Enricher {
+    limiter: RateLimiter,
+    timeout: Duration,
+    whois_server: String,
+    asn_server: String,
+}
+
+impl Enricher {
+    pub fn new(config: &Config) -> Self {
+        Enricher {
+            limiter: RateLimiter::new(config.enrich_rate),
+            timeout: Duration::from_millis(config.enrich_timeout_ms),
+            whois_server: config.enrich_whois_server.clone(),
+            asn_server: config.enrich_asn_server.clone(),
+        }
+    }
+
+    /// Sends `query` to a WHOIS `server` (`host:port`), returns the response
+    async fn query(&self, server: &str, query: &str) -> anyhow::Result<String> {
+        self.limiter.wait().await;
+
+        let resp = timeout(self.timeout, async {
+            let mut stream = TcpStream::connect(server).await?;
+            stream.write_all(format!("{query}\r\n").as_bytes()).await?;
+            let mut buf = vec![];
+            stream.read_to_end(&mut buf).await?;
+            Ok::<_, std::io::Error>(buf)
+        })
+        .await
+        .map_err(|_| anyhow!("{server} did not answer in time"))?
+        .with_context(|| format!("failed to query {server}"))?;
+
+        Ok(String::from_utf8_lossy(&resp).into_owned())
+    }
+
+    /// Fields of the Team Cymru verbose answer about `ip`:
+    /// AS, IP, prefix, country, registry, allocation date and AS name
+    async fn cymru(&self, ip: IpAddr) -> anyhow::Result<Vec<String>> {
+        let resp = self.query(&self.asn_server, &format!(" -v {ip}")).await?;
+        // the first line is a header
+        let line = resp
+            .lines()
+            .nth(1)
+            .context("empty answer from the asn service")?;
+        Ok(line.split('|').map(|f| f.trim().to_string()).collect())
+    }
+
+    async fn whois(&self, ip: IpAddr) -> anyhow::Result<Owner> {
+        let resp = self.query(&self.whois_server, &ip.to_string()).await?;
+
+        // the first server usually refers to the registry in charge
+        let resp = match whois_values(&resp, &["refer", "whois"]).first() {
+            Some(refer) => {
+                let server = if refer.contains(':') {
+                    refer.clone()
+                } else {
+                    format!("{refer}:43")
+                };
+                self.query(&server, &ip.to_string()).await?
+            }
+            None => resp,
+        };
+
+        parse_owner(&resp).context("no owner found in whois answer")
+    }
+
+    /// Looks `ip` up, returns the data to store
+    pub async fn enrich(&self, kind: EnrichKind, ip: IpAddr) -> anyhow::Result<Data> {
+        match kind {
+            EnrichKind::Whois => Ok(Data::Owner(self.whois(ip).await?)),
+            EnrichKind::Asn => {
+                let fields = self.cymru(ip).await?;
+                match fields.first().map(|asn| asn.parse::<u64>()) {
+                    Some(Ok(asn)) => Ok(Data::Asn(asn)),
+                    _ => bail!("no asn found for {ip}"),
+                }
+            }
+            EnrichKind::Geo => {
+                let fields = self.cymru(ip).await?;
+                match fields.get(3).filter(|cc| !cc.is_empty()) {
+                    Some(cc) => Ok(Data::Json(serde_json::json!({
+                        "country": cc,
+                        "registry": fields.get(4),
+                    }))),
+                    None => bail!("no country found for {ip}"),
+                }
+            }
+        }
+    }
+}
+
+/// Values of the first of `keys` found in a WHOIS answer, keys
+/// are matched ignoring case
+fn whois_values(resp: &str, keys: &[&str]) -> Vec<String> {
+    keys.iter()
+        .map(|key| {
+            resp.lines()
+                .filter_map(|l| l.split_once(':'))
+                .filter(|(k, _)| k.trim().eq_ignore_ascii_case(key))
+                .map(|(_, v)| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .find(|v| !v.is_empty())
+        .unwrap_or_default()
+}
+
+/// Makes an owner out of the most common fields of the RIRs' answers
+fn parse_owner(resp: &str) -> Option<Owner> {
+    let first = |keys: &[&str]| whois_values(resp, keys).into_iter().next();
+
+    let name = first(&["orgname", "org-name", "owner", "descr", "netname"])?;
+    let address = whois_values(resp, &["address"]);
+
+    let mut owner = Owner {
+        name,
+        address: (!address.is_empty()).then(|| address.join(", ")),
+        country: first(&["country"]),
+        country_raw: None,
+        abuse: whois_values(resp, &["orgabuseemail", "abuse-mailbox"]),
+        phone: first(&["orgabusephone", "phone"]),
+    };
+
+    // unknown countries are kept as the registry gives them
+    let _ = owner.normalize_country(false);
+    Some(owner)
+}
+
+#[derive(Debug, FromForm)]
+pub struct EnrichQuery {
+    #[field(name = "type")]
+    kind: EnrichKind,
+    /// Enriches all the addresses of the range, not only the tracked ones
+    #[field(default = false)]
+    all: bool,
+}
+
+/// Outcome of the enrichment of an IP address
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EnrichResult {
+    #[schema(value_type = String)]
+    ip: IpAddr,
+    /// UUID of the entry created
+    uuid: Option<Uuid>,
+    error: Option<String>,
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("addr" = String, Path, description = "Network address of the range"),
+        ("prefix" = u8, Path, description = "Prefix length of the range"),
+        ("type" = EnrichKind, Query, description = "The kind of enrichment to run"),
+        ("all" = Option<bool>, Query, description = "Enriches all the addresses of the range, tracking them if needed, instead of the tracked ones only"),
+    ),
+    responses(
+        (status = 200, description = "Range enriched", body = ApiResponse<Vec<EnrichResult>>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "Enrichment",
+    description = "Enriches the addresses of a range, which cannot be larger than the cidr_max_size setting, by adding them an entry with the result of the lookup. Lookups are run concurrently, within the limits of the enrich_concurrency and enrich_rate settings, and the request only completes once all of them are done. Returns an ApiResponse with the outcome of the enrichment of every address or an error message."
+)]
+#[post("/cidr/<addr>/<prefix>/enrich?<query..>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn cidr_enrich(
+    addr: IpAddr,
+    prefix: u8,
+    query: EnrichQuery,
+    principal: Principal,
+    config: &State<Config>,
+    enricher: &State<Enricher>,
+    events: &State<Events>,
+    hooks: &State<Hooks>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Vec<EnrichResult>> {
+    let cidr = Cidr::new(addr, prefix).map_err(|e| api_error!(e))?;
+    if cidr.size() > config.cidr_max_size {
+        return Err(api_error!(format!(
+            "{cidr} is larger than {} addresses",
+            config.cidr_max_size
+        )));
+    }
+
+    let (kind, all) = (query.kind, query.all);
+    let ips: Vec<IpAddr> = if all {
+        cidr.addresses().collect()
+    } else {
+        db.ips()
+            .map_err(|e| storage_error!(e, "failed to list ips"))?
+            .into_iter()
+            .filter(|ip| cidr.contains(*ip))
+            .collect()
+    };
+
+    let (config, enricher, events, hooks, db, principal) = (
+        config.inner(),
+        enricher.inner(),
+        events.inner(),
+        hooks.inner(),
+        db.inner(),
+        &principal,
+    );
+
+    let mut results: Vec<EnrichResult> = stream::iter(ips)
+        .map(|ip| async move {
+            let res = async {
+                // addresses not tracked yet are subject to the address policy
+                if all {
+                    check_ip(ip, config).map_err(|e| e.to_string())?;
+                }
+
+                let data = enricher
+                    .enrich(kind, ip)
+                    .await
+                    .map_err(|e| format!("{e:#}"))?;
+
+                check_kind(&data, config).map_err(|e| e.to_string())?;
+
+                let mut entry = Entry::new(data);
+                entry.description = Some(format!("{kind:?} enrichment").to_lowercase());
+                if db
+                    .create_hip(IpStory::new(ip))
+                    .map_err(|e| storage_error!(e, "failed to insert new ip").to_string())?
+                {
+                    audit(db, AuditRecord::new(principal, AuditAction::Create, ip));
+                }
+
+                add_entry(db, events, hooks, principal, ip, entry)
+                    .map(|e| e.uuid)
+                    .map_err(|e| e.to_string())
+            }
+            .await;
+
+            match res {
+                Ok(uuid) => EnrichResult {
+                    ip,
+                    uuid,
+                    error: None,
+                },
+                Err(e) => EnrichResult {
+                    ip,
+                    uuid: None,
+                    error: Some(e),
+                },
+            }
+        })
+        .buffer_unordered(config.enrich_concurrency.max(1))
+        .collect()
+        .await;
+
+    results.sort_by_key(|r| r.ip);
+
+    Ok(ApiData::Some(results))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum EnrichStatus {
+    /// An entry got created
+    Success,
+    /// The lookup succeeded but its result is already known or not
+    /// accepted by the instance
+    Skipped,
+    Error,
+}
+
+/// Outcome of one of the enrichers run on an IP address
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EnricherReport {
+    kind: EnrichKind,
+    status: EnrichStatus,
+    /// Why the enricher got skipped or failed
+    reason: Option<String>,
+}
+
+impl EnricherReport {
+    fn new(kind: EnrichKind, status: EnrichStatus, reason: Option<String>) -> Self {
+        EnricherReport {
+            kind,
+            status,
+            reason,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct IpEnrichment {
+    /// Entries created, one for each successful enricher
+    created: Vec<Entry>,
+    enrichers: Vec<EnricherReport>,
+}
+
+/// Whether `a` and `b` hold the same data
+fn same_data(a: &Data, b: &Data) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+    ),
+    responses(
+        (status = 200, description = "IP address enriched", body = ApiResponse<IpEnrichment>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "Enrichment",
+    description = "Runs all the enrichers of the enrich_enabled setting on an IP address, tracking it if needed. Lookups are run concurrently, within the limits of the enrich_concurrency and enrich_rate settings. Results already present in the history are skipped and the new ones are appended at once. Returns an ApiResponse with the entries created and the outcome of every enricher, or an error message."
+)]
+#[post("/ip/<ip>/enrich")]
+#[allow(clippy::too_many_arguments)]
+pub async fn ip_enrich(
+    ip: IpAddr,
+    principal: Principal,
+    config: &State<Config>,
+    enricher: &State<Enricher>,
+    events: &State<Events>,
+    hooks: &State<Hooks>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<IpEnrichment> {
+    check_ip(ip, config)?;
+
+    let enricher = enricher.inner();
+    let lookups: Vec<(EnrichKind, anyhow::Result<Data>)> =
+        stream::iter(config.enrich_enabled.iter().copied())
+            .map(|kind| async move { (kind, enricher.enrich(kind, ip).await) })
+            .buffered(config.enrich_concurrency.max(1))
+            .collect()
+            .await;
+
+    if db
+        .create_hip(IpStory::new(ip))
+        .map_err(|e| storage_error!(e, "failed to insert new ip"))?
+    {
+        audit(db, AuditRecord::new(&principal, AuditAction::Create, ip));
+    }
+
+    let (created, enrichers) = db
+        .update_hip(ip, |ipst| {
+            let mut created = vec![];
+            let mut reports = vec![];
+
+            for (kind, lookup) in lookups.iter() {
+                let data = match lookup {
+                    Ok(data) => data,
+                    Err(e) => {
+                        reports.push(EnricherReport::new(
+                            *kind,
+                            EnrichStatus::Error,
+                            Some(format!("{e:#}")),
+                        ));
+                        continue;
+                    }
+                };
+
+                if let Err(e) = check_kind(data, config) {
+                    reports.push(EnricherReport::new(
+                        *kind,
+                        EnrichStatus::Skipped,
+                        Some(e.to_string()),
+                    ));
+                    continue;
+                }
+
+                if ipst.history.values().any(|e| same_data(&e.data, data)) {
+                    reports.push(EnricherReport::new(
+                        *kind,
+                        EnrichStatus::Skipped,
+                        Some("already present".into()),
+                    ));
+                    continue;
+                }
+
+                let mut entry = Entry::new(data.clone());
+                entry.uuid = Some(Uuid::new_v4());
+                entry.description = Some(format!("{kind:?} enrichment").to_lowercase());
+
+                if let Err(e) = hooks
+                    .run(ip, &mut entry)
+                    .and_then(|_| principal.check_write(&entry))
+                {
+                    reports.push(EnricherReport::new(
+                        *kind,
+                        EnrichStatus::Error,
+                        Some(e.to_string()),
+                    ));
+                    continue;
+                }
+
+                // entries enriched together are spread so that they
+                // do not collide in the history
+                let mut ctime = Utc::now();
+                while ipst.history.contains_key(&ctime) {
+                    ctime += TimeDelta::nanoseconds(1);
+                }
+                entry.ctime = Some(ctime);
+
+                ipst.history.insert(ctime, entry.clone());
+                created.push(entry);
+                reports.push(EnricherReport::new(*kind, EnrichStatus::Success, None));
+            }
+
+            Ok::<_, ApiError>((created, reports))
+        })
+        .map_err(|e| storage_error!(e, "failed to add enrichments"))??;
+
+    for entry in &created {
+        audit(
+            db,
+            AuditRecord::new(&principal, AuditAction::Create, ip).entry(entry),
+        );
+        events.publish(AuditAction::Create, ip, entry.clone());
+    }
+
+    Ok(ApiData::Some(IpEnrichment { created, enrichers }))
+}
@@ -0,0 +1,153 @@
+use std::{net::IpAddr, sync::Arc};
+
+use ip_story_model::Entry;
+use rocket::{
+    Shutdown, State, get,
+    response::stream::{Event, EventStream},
+    tokio::{
+        select,
+        sync::broadcast::{self, error::RecvError},
+    },
+};
+use serde::Serialize;
+
+use crate::{
+    API_MOUNTPOINT,
+    api::{ApiError, ErrorResponses, Timestamp},
+    audit::{AuditAction, Principal},
+    storage::Storage,
+    storage_error,
+};
+
+/// Entry created, updated or deleted in the history of an IP address
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryEvent {
+    pub ip: IpAddr,
+    pub action: AuditAction,
+    pub entry: Entry,
+}
+
+/// Broadcasts the changes of the entries of the store to the connected
+/// streams. The channel is bounded, a consumer too slow to keep up
+/// misses the oldest events and is notified of it.
+#[derive(Clone)]
+pub struct Events(broadcast::Sender<EntryEvent>);
+
+impl Events {
+    pub fn new(capacity: usize) -> Self {
+        Events(broadcast::channel(capacity.max(1)).0)
+    }
+
+    pub fn publish(&self, action: AuditAction, ip: IpAddr, entry: Entry) {
+        // failing only means nobody is listening
+        let _ = self.0.send(EntryEvent { ip, action, entry });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<EntryEvent> {
+        self.0.subscribe()
+    }
+}
+
+/// Turns the creations selected by `filter` into SSE events, until
+/// the channel closes or the server shuts down. Clients disconnecting
+/// drop the stream, and with it the subscription.
+fn entry_events(
+    events: &Events,
+    replay: Vec<EntryEvent>,
+    filter: impl Fn(&EntryEvent) -> bool + Send + 'static,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut rx = events.subscribe();
+
+    EventStream! {
+        for ev in replay {
+            yield Event::json(&ev).event("entry");
+        }
+
+        loop {
+            let ev = select! {
+                ev = rx.recv() => ev,
+                _ = &mut shutdown => break,
+            };
+
+            match ev {
+                Ok(ev) if matches!(ev.action, AuditAction::Create) && filter(&ev) => yield Event::json(&ev).event("entry"),
+                Ok(_) => {}
+                // tells the client how many events it missed
+                Err(RecvError::Lagged(n)) => yield Event::data(n.to_string()).event("lagged"),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("since" = Option<String>, Query, description = "RFC 3339 timestamp, entries created since then are sent first"),
+    ),
+    responses(
+        (status = 200, description = "Stream of the entries added to the IP address, as `entry` events holding the IP address and the entry", content_type = "text/event-stream"),
+        ErrorResponses,
+    ),
+    tag = "Events",
+    description = "Streams, as Server-Sent Events, the entries added to an IP address. Clients missing events because they are too slow receive a `lagged` event holding the number of events missed."
+)]
+#[get("/ip/<ip>/stream?<since>")]
+pub async fn ip_stream(
+    ip: IpAddr,
+    since: Option<Timestamp>,
+    principal: Principal,
+    events: &State<Events>,
+    db: &State<Arc<Storage>>,
+    shutdown: Shutdown,
+) -> Result<EventStream![], ApiError> {
+    let replay = match since {
+        Some(since) => {
+            let ipst = db
+                .get_hip(ip)
+                .map_err(|e| storage_error!(e, "failed to get data from db"))?
+                .readable_by(&principal);
+            ipst.history
+                .range(since.0..)
+                .map(|(_, e)| EntryEvent {
+                    ip,
+                    action: AuditAction::Create,
+                    entry: e.clone(),
+                })
+                .collect()
+        }
+        None => vec![],
+    };
+
+    Ok(entry_events(
+        events,
+        replay,
+        move |ev| ev.ip == ip && principal.can_read(&ev.entry),
+        shutdown,
+    ))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 200, description = "Stream of the entries added to any IP address, as `entry` events holding the IP address and the entry", content_type = "text/event-stream"),
+        ErrorResponses,
+    ),
+    tag = "Events",
+    description = "Streams, as Server-Sent Events, the entries added to any IP address. Clients missing events because they are too slow receive a `lagged` event holding the number of events missed."
+)]
+#[get("/stream")]
+pub async fn stream(
+    principal: Principal,
+    events: &State<Events>,
+    shutdown: Shutdown,
+) -> EventStream![] {
+    entry_events(
+        events,
+        vec![],
+        move |ev| principal.can_read(&ev.entry),
+        shutdown,
+    )
+}
@@ -0,0 +1,135 @@
+//! Export of the whole store, for backups or for sharing
+
+use std::sync::Arc;
+
+use ip_story_model::{Data, Entry};
+use rocket::{State, get, http::ContentType, response::stream::TextStream};
+use serde::Deserialize;
+
+use crate::{
+    API_MOUNTPOINT, IpStory, api::ErrorResponses, audit::Principal, config::Config,
+    storage::Storage,
+};
+
+/// Fields of the entries stripped by redacted exports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RedactField {
+    OwnerAddress,
+    OwnerPhone,
+    OwnerAbuse,
+    Description,
+}
+
+impl RedactField {
+    fn apply(self, entry: &mut Entry) {
+        match (self, &mut entry.data) {
+            (Self::OwnerAddress, Data::Owner(owner)) => owner.address = None,
+            (Self::OwnerPhone, Data::Owner(owner)) => owner.phone = None,
+            (Self::OwnerAbuse, Data::Owner(owner)) => owner.abuse.clear(),
+            (Self::Description, _) => entry.description = None,
+            _ => {}
+        }
+    }
+}
+
+/// Strips the fields of `entry` listed by export_redact, along with the
+/// ones listed for its classification by export_redact_classified
+fn redact(entry: &mut Entry, config: &Config) {
+    let classified = entry.classification.as_ref().and_then(|c| {
+        config
+            .export_redact_classified
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(c))
+            .map(|(_, fields)| fields.clone())
+    });
+
+    for field in config
+        .export_redact
+        .iter()
+        .chain(classified.iter().flatten())
+    {
+        field.apply(entry);
+    }
+}
+
+/// Version of the serialized story `s` holding the entries `principal`
+/// can read, redacted according to `redact` if set
+fn rewrite_story(
+    s: &str,
+    principal: &Principal,
+    redact: Option<&Config>,
+) -> Result<String, serde_json::Error> {
+    let mut hip = serde_json::from_str::<IpStory>(s)?.readable_by(principal);
+    if let Some(config) = redact {
+        hip.history
+            .values_mut()
+            .for_each(|e| self::redact(e, config));
+    }
+    serde_json::to_string(&hip)
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 200, description = "Stories exported", body = String, content_type = "application/x-ndjson"),
+        ErrorResponses,
+    ),
+    params(
+        ("redact" = Option<bool>, Query, description = "Strips the fields listed by the export_redact and export_redact_classified settings, to share the stories outside of the organization"),
+    ),
+    tag = "Export",
+    description = "Streams every story of the store as NDJSON, one IP address and its whole history per line, to back the store up. Stories are read a page at a time so the store is never loaded at once, and the export is not a snapshot: stories modified while it runs may be exported before or after the modification. A storage failure ends the stream early, so a backup is only complete if the response completed. The backup is restored with POST /import/all. With redact, the contact details of owners (or any field listed by export_redact) are stripped, as well as the fields listed by export_redact_classified for the classification of each entry, ex: the description of tlp:amber entries. A redacted export is meant to be shared and cannot be used as a backup, and neither can the export of an API key restricted to some classifications, which leaves out the entries of the others."
+)]
+#[get("/export/all?<redact>")]
+pub async fn export_all(
+    redact: Option<bool>,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+    config: &State<Config>,
+) -> (ContentType, TextStream![String]) {
+    let db = db.inner().clone();
+    let config = redact.unwrap_or_default().then(|| config.inner().clone());
+    // stories are only parsed when there is something to rewrite
+    let rewrite = config.is_some() || principal.read_restriction().is_some();
+
+    let stories = TextStream! {
+        for instance in 0.. {
+            let mut cursor = 0;
+            loop {
+                let page = db.scan_hips(instance, cursor);
+                let (next, stories) = match page {
+                    Ok(Some(page)) => page,
+                    Ok(None) => return,
+                    Err(e) => {
+                        log::error!("export interrupted: {e}");
+                        return;
+                    }
+                };
+
+                for s in stories {
+                    let s = if rewrite {
+                        match rewrite_story(&s, &principal, config.as_ref()) {
+                            Ok(s) => s,
+                            // leaking the story is worse than ending the export
+                            Err(e) => {
+                                log::error!("redacted export interrupted: {e}");
+                                return;
+                            }
+                        }
+                    } else {
+                        s
+                    };
+                    yield s + "\n";
+                }
+
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+    };
+
+    (ContentType::new("application", "x-ndjson"), stories)
+}
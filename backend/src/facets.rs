@@ -0,0 +1,241 @@
+//! Distinct values of the fields of the entries, to build filters
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use ip_story_model::{ApiResponse, Data, Entry};
+use rocket::{FromFormField, State, get};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+use crate::{
+    API_MOUNTPOINT, IpStory,
+    api::{ApiData, ApiResult, ErrorResponses},
+    audit::Principal,
+    config::Config,
+    stats::scan_cursor,
+    storage::Storage,
+    storage_error,
+};
+
+/// Field of the entries whose values are counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, FromFormField, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Facet {
+    /// Country of the owner entries
+    #[field(value = "owner_country")]
+    OwnerCountry,
+    /// AS number of the asn entries
+    Asn,
+    /// Kind of data of the entries
+    Kind,
+    /// Tags of the entries
+    Tag,
+}
+
+impl Facet {
+    /// Values of the field in `entry`
+    fn values(self, entry: &Entry) -> Vec<String> {
+        match (self, &entry.data) {
+            (Facet::OwnerCountry, Data::Owner(owner)) => owner.country.iter().cloned().collect(),
+            (Facet::Asn, Data::Asn(asn)) => vec![asn.to_string()],
+            (Facet::Kind, data) => serde_json::to_value(data.kind())
+                .ok()
+                .and_then(|v| v.as_str().map(String::from))
+                .into_iter()
+                .collect(),
+            (Facet::Tag, _) => entry
+                .tags
+                .iter()
+                .flatten()
+                .map(|t| t.as_str().to_string())
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// A value of a field and the number of entries having it
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FacetValue {
+    value: String,
+    count: usize,
+}
+
+/// Counts the values of `facet` in `entries`, expired ones being left out
+fn tally<'a>(
+    facet: Facet,
+    config: &Config,
+    counts: &mut BTreeMap<String, usize>,
+    entries: impl Iterator<Item = &'a Entry>,
+) {
+    let now = Utc::now();
+    for e in entries.filter(|e| !config.is_expired(e, now)) {
+        for value in facet.values(e) {
+            *counts.entry(value).or_default() += 1;
+        }
+    }
+}
+
+/// Values of `counts`, most frequent first
+fn sorted(counts: BTreeMap<String, usize>) -> Vec<FacetValue> {
+    let mut values: Vec<FacetValue> = counts
+        .into_iter()
+        .map(|(value, count)| FacetValue { value, count })
+        .collect();
+    // values are already sorted, so ties stay sorted by value
+    values.sort_by_key(|v| Reverse(v.count));
+    values
+}
+
+/// Counts of the values of a field in a page of the scan of the store
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FacetsPage {
+    /// Values of the page and their number of entries, most frequent first
+    values: Vec<FacetValue>,
+    /// Cursor of the next page, unset once the whole store is scanned
+    next: Option<String>,
+}
+
+/// Values of a field counted at some point in time
+type Counted = (Instant, Vec<FacetValue>);
+
+/// Last counts of the values of each facet across the store, reused
+/// until they expire
+pub struct FacetsCache {
+    ttl: Duration,
+    /// Counts per field and per API key restricting the entries read
+    last: Mutex<HashMap<(Facet, Option<String>), Counted>>,
+}
+
+impl FacetsCache {
+    pub fn new(ttl: Duration) -> Self {
+        FacetsCache {
+            ttl,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("field" = Facet, Query, description = "The field whose values are counted"),
+    ),
+    responses(
+        (status = 200, description = "Values counted successfully", body = ApiResponse<Vec<FacetValue>>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Counts the distinct values of a field across the history of an IP address, to build filter menus without fetching the entries. Expired entries are left out. Returns an ApiResponse with the values and their number of entries, most frequent first, an empty list if the IP address is not tracked, or an error message."
+)]
+#[get("/ip/<ip>/facets?<field>")]
+pub async fn ip_facets(
+    ip: IpAddr,
+    field: Facet,
+    principal: Principal,
+    config: &State<Config>,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Vec<FacetValue>> {
+    let hips = db
+        .get_hips(&[ip])
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?;
+
+    let mut counts = BTreeMap::new();
+    for ipst in hips.values() {
+        let readable = ipst.history.values().filter(|e| principal.can_read(e));
+        tally(field, config, &mut counts, readable);
+    }
+
+    Ok(ApiData::Some(sorted(counts)))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("field" = Facet, Query, description = "The field whose values are counted"),
+    ),
+    responses(
+        (status = 200, description = "Values counted successfully", body = ApiResponse<Vec<FacetValue>>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "Statistics",
+    description = "Counts the distinct values of a field across all the IP addresses. This requires to scan the whole store, so counts are cached for the duration of the stats_ttl_secs setting. Expired entries are left out. Returns an ApiResponse with the values and their number of entries, most frequent first, or an error message."
+)]
+#[get("/facets?<field>")]
+pub async fn facets(
+    field: Facet,
+    principal: Principal,
+    cache: &State<FacetsCache>,
+    config: &State<Config>,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Vec<FacetValue>> {
+    let key = (field, principal.read_restriction().map(String::from));
+    let mut last = cache.last.lock().await;
+    if let Some((at, values)) = last.get(&key)
+        && at.elapsed() < cache.ttl
+    {
+        return Ok(ApiData::Some(values.clone()));
+    }
+
+    let mut counts = BTreeMap::new();
+    db.for_each_hip(|ipst| {
+        let readable = ipst.history.values().filter(|e| principal.can_read(e));
+        tally(field, config, &mut counts, readable)
+    })
+    .map_err(|e| storage_error!(e, "failed to scan the store"))?;
+
+    let values = sorted(counts);
+    last.insert(key, (Instant::now(), values.clone()));
+
+    Ok(ApiData::Some(values))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("field" = Facet, Query, description = "The field whose values are counted"),
+        ("cursor" = Option<String>, Query, description = "Cursor returned as next by the previous page, the scan starts over if unset"),
+    ),
+    responses(
+        (status = 200, description = "Page scanned successfully", body = ApiResponse<FacetsPage>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "Statistics",
+    description = "Counts the distinct values of a field like GET /facets, a page of the store at a time, for clients to render them progressively on large stores, ex: a tag cloud with the tag field. Every call reads storage_scan_count stories at most, starting at cursor, and returns the values counted in that page only, along with the cursor of the next page, unset once the scan is over. Clients add up the counts of the pages themselves. The store is live and not locked between pages: entries modified during the scan may be counted before or after the modification, and stories created or deleted may be missed or, rarely, counted twice, so totals are approximate. Pages may be empty while the scan is not over. Expired entries are left out and results are not cached. Returns an ApiResponse with the values of the page, or an error message, with the cursor_invalid code if the cursor is not one returned by a previous page."
+)]
+#[get("/facets/scan?<field>&<cursor>")]
+pub async fn facets_scan(
+    field: Facet,
+    cursor: Option<&str>,
+    principal: Principal,
+    config: &State<Config>,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<FacetsPage> {
+    let at = scan_cursor(cursor)?;
+
+    let (stories, next) = db
+        .scan_page(at)
+        .map_err(|e| storage_error!(e, "failed to scan the store"))?;
+
+    let mut counts = BTreeMap::new();
+    for s in stories {
+        let ipst: IpStory = serde_json::from_str(&s)
+            .map_err(|e| storage_error!(e.into(), "failed to scan the store"))?;
+        let readable = ipst.history.values().filter(|e| principal.can_read(e));
+        tally(field, config, &mut counts, readable);
+    }
+
+    Ok(ApiData::Some(FacetsPage {
+        values: sorted(counts),
+        next: next.map(|n| n.to_string()),
+    }))
+}
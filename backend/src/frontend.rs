@@ -0,0 +1,192 @@
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use rocket::{
+    Responder, State, get,
+    http::{ContentType, Header},
+};
+use rust_embed::Embed;
+
+use crate::config::Config;
+
+#[derive(Embed)]
+#[folder = "../target/frontend"]
+struct FrontendAssets;
+
+/// Embedded asset along with its caching policy
+#[derive(Responder)]
+pub struct Asset {
+    data: Cow<'static, [u8]>,
+    content_type: ContentType,
+    cache_control: Header<'static>,
+}
+
+impl Asset {
+    fn new(data: Cow<'static, [u8]>, content_type: ContentType, immutable: bool) -> Self {
+        let cache_control = if immutable {
+            "public, max-age=31536000, immutable"
+        } else {
+            "no-cache"
+        };
+
+        Asset {
+            data,
+            content_type,
+            cache_control: Header::new("Cache-Control", cache_control),
+        }
+    }
+}
+
+/// Returns true if the file name contains a content hash, as emitted by
+/// Vite for bundled assets (ex: `index-BXnB3xKm.js`). Such files can be
+/// cached forever as any change in their content changes their name.
+fn is_content_hashed(path: &Path) -> bool {
+    const HASH_LEN: usize = 8;
+
+    let Some(stem) = path.file_stem().and_then(OsStr::to_str) else {
+        return false;
+    };
+
+    let Some(sep) = stem.len().checked_sub(HASH_LEN + 1) else {
+        return false;
+    };
+
+    let (name, hash) = stem.split_at(sep);
+    !name.is_empty()
+        && hash.starts_with('-')
+        && hash[1..]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Advertises the mount point of the API and whether the OpenAPI
+/// documentation is served to the frontend, through `<meta
+/// name="api-mountpoint">` and `<meta name="openapi-enabled">` tags
+/// added to `index.html`
+fn with_settings(index: &[u8], config: &Config) -> Vec<u8> {
+    String::from_utf8_lossy(index)
+        .replacen(
+            "</head>",
+            &format!(
+                r#"<meta name="api-mountpoint" content="{}"><meta name="openapi-enabled" content="{}"></head>"#,
+                config.api_mountpoint(),
+                config.openapi_enabled
+            ),
+            1,
+        )
+        .into_bytes()
+}
+
+// Catch-all route to serve index.html for Vue routes
+#[get("/<path..>")]
+pub async fn serve_assets(path: PathBuf, config: &State<Config>) -> Option<Asset> {
+    // unknown API routes are left to the API catchers
+    if path.starts_with(config.api_mountpoint().trim_start_matches('/')) {
+        return None;
+    }
+    if !config.openapi_enabled && path == Path::new("openapi") {
+        return None;
+    }
+
+    let filename = path.display().to_string();
+
+    // if the asset exist we serve it
+    if let Some(asset) = FrontendAssets::get(&filename) {
+        let content_type = path
+            .extension()
+            .and_then(OsStr::to_str)
+            .and_then(ContentType::from_extension)
+            .unwrap_or(ContentType::Bytes);
+        Some(Asset::new(
+            asset.data,
+            content_type,
+            is_content_hashed(&path),
+        ))
+    } else {
+        // if the asset doesn't exist we serve index.html
+        // we delegate page routing to Vue
+        let index = FrontendAssets::get("index.html")?;
+        Some(Asset::new(
+            with_settings(&index.data, config).into(),
+            ContentType::HTML,
+            false,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn serve(path: &str, config: &Config) -> Option<Asset> {
+        serve_assets(PathBuf::from(path), config.into()).await
+    }
+
+    #[tokio::test]
+    async fn api_paths_are_left_to_the_api() {
+        let config = Config::default();
+
+        for path in ["api", "api/ip/192.0.2.1/unknown", "api/unknown"] {
+            assert!(serve(path, &config).await.is_none(), "{path}");
+        }
+        // only whole segments are matched
+        assert!(serve("apidocs", &config).await.is_some());
+        assert!(serve("ip/192.0.2.1", &config).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn api_paths_follow_the_mountpoint() {
+        let config = Config {
+            api_mountpoint: "/v1/api/".into(),
+            ..Config::default()
+        };
+
+        assert!(serve("v1/api/ip", &config).await.is_none());
+        assert!(serve("api/ip", &config).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn openapi_page_follows_the_setting() {
+        let enabled = Config {
+            openapi_enabled: true,
+            ..Config::default()
+        };
+        let disabled = Config {
+            openapi_enabled: false,
+            ..Config::default()
+        };
+
+        assert!(serve("openapi", &enabled).await.is_some());
+        assert!(serve("openapi", &disabled).await.is_none());
+    }
+    #[test]
+    fn unknown_api_routes_get_a_json_error() {
+        use rocket::{http::Status, local::blocking::Client};
+
+        let config = Config::default();
+        let rocket = rocket::build()
+            .mount("/", rocket::routes![serve_assets])
+            .register(
+                config.api_mountpoint().to_string(),
+                rocket::catchers![crate::api::not_found],
+            )
+            .manage(config);
+        let client = Client::tracked(rocket).unwrap();
+
+        let resp = client.get("/api/foo").dispatch();
+        assert_eq!(resp.status(), Status::NotFound);
+        assert_eq!(resp.content_type(), Some(ContentType::JSON));
+        let body: serde_json::Value = serde_json::from_str(&resp.into_string().unwrap()).unwrap();
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["error"], "/api/foo not found");
+        assert!(body["data"].is_null());
+
+        // the pages of the frontend are still served
+        let resp = client.get("/ip/192.0.2.1").dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+        assert_eq!(resp.content_type(), Some(ContentType::HTML));
+    }
+}
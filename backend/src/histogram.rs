@@ -0,0 +1,134 @@
+//! Number of entries of an IP address over time
+
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc};
+
+use chrono::{DateTime, Datelike, Months, NaiveTime, TimeDelta, Utc};
+use ip_story_model::{ApiResponse, DataKind};
+use rocket::{FromFormField, State, get};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    API_MOUNTPOINT,
+    api::{ApiData, ApiResult, ErrorResponses, Timestamp},
+    audit::Principal,
+    storage::Storage,
+    storage_error,
+};
+
+/// Time span covered by each bar of a histogram
+#[derive(Debug, Clone, Copy, Deserialize, FromFormField, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bucket {
+    Day,
+    /// Weeks start on Monday
+    Week,
+    Month,
+}
+
+impl Bucket {
+    /// Start of the bucket holding `t`
+    fn start(self, t: DateTime<Utc>) -> DateTime<Utc> {
+        let date = t.date_naive();
+        let date = match self {
+            Bucket::Day => date,
+            Bucket::Week => date - TimeDelta::days(date.weekday().num_days_from_monday() as i64),
+            Bucket::Month => date.with_day(1).unwrap_or(date),
+        };
+        date.and_time(NaiveTime::MIN).and_utc()
+    }
+
+    /// Start of the bucket following the one starting at `start`
+    fn next(self, start: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Bucket::Day => start + TimeDelta::days(1),
+            Bucket::Week => start + TimeDelta::weeks(1),
+            Bucket::Month => start
+                .checked_add_months(Months::new(1))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Bar {
+    /// Start of the time span of the bar
+    start: DateTime<Utc>,
+    /// Number of entries created within the time span
+    count: usize,
+    /// Number of entries per kind of data, only when split by kind
+    kinds: Option<BTreeMap<DataKind, usize>>,
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("bucket" = Bucket, Query, description = "Time span covered by each bar"),
+        ("from" = Option<String>, Query, description = "RFC 3339 timestamp of the oldest entry to count"),
+        ("to" = Option<String>, Query, description = "RFC 3339 timestamp of the newest entry to count"),
+        ("by_kind" = Option<bool>, Query, description = "Also counts the entries per kind of data"),
+    ),
+    responses(
+        (status = 200, description = "Histogram computed successfully", body = ApiResponse<Vec<Bar>>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Counts the entries of an IP address per day, week or month, according to their timestamp. Bars are returned in chronological order, from the bucket of the oldest entry counted to the one of the newest, buckets without entries in between have a count of zero. Returns an ApiResponse with the bars, empty if no entry is counted, or an error message."
+)]
+#[get("/ip/<ip>/entry/histogram?<bucket>&<from>&<to>&<by_kind>")]
+pub async fn ip_entry_histogram(
+    ip: IpAddr,
+    bucket: Bucket,
+    from: Option<Timestamp>,
+    to: Option<Timestamp>,
+    by_kind: Option<bool>,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Vec<Bar>> {
+    let ipst = db
+        .get_hip(ip)
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?
+        .readable_by(&principal);
+
+    let by_kind = by_kind.unwrap_or_default();
+    let entries = ipst
+        .history
+        .iter()
+        .filter(|(t, _)| from.is_none_or(|f| **t >= f.0) && to.is_none_or(|u| **t <= u.0));
+
+    let mut bars: Vec<Bar> = vec![];
+    for (t, entry) in entries {
+        let start = bucket.start(*t);
+
+        // fills the gap up to the bucket of the entry
+        while let Some(last) = bars.last()
+            && last.start < start
+        {
+            let next = bucket.next(last.start);
+            bars.push(Bar {
+                start: next,
+                count: 0,
+                kinds: by_kind.then(BTreeMap::new),
+            });
+        }
+
+        if bars.is_empty() {
+            bars.push(Bar {
+                start,
+                count: 0,
+                kinds: by_kind.then(BTreeMap::new),
+            });
+        }
+
+        // history is ordered so the entry falls in the last bar
+        if let Some(bar) = bars.last_mut() {
+            bar.count += 1;
+            if let Some(kinds) = bar.kinds.as_mut() {
+                *kinds.entry(entry.data.kind()).or_default() += 1;
+            }
+        }
+    }
+
+    Ok(ApiData::Some(bars))
+}
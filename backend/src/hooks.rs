@@ -0,0 +1,94 @@
+//! Custom processing of the entries being created
+
+use std::net::IpAddr;
+
+use ip_story_model::{Entry, Tag};
+use serde::Deserialize;
+
+use crate::{api::ApiError, api_error, config::Config};
+
+/// Logic run on every entry before it gets created, whatever the way
+/// it is created (API, imports, enrichments). A hook failing rejects
+/// the entry, with its error message.
+pub trait EntryHook: Send + Sync {
+    /// Name of the hook, reported along its errors
+    fn name(&self) -> &str;
+
+    fn on_create(&self, ip: IpAddr, entry: &mut Entry) -> Result<(), String>;
+}
+
+/// Tags entries with the kind of their data, ex: `kind:asn`
+pub struct AutoTagKind;
+
+impl EntryHook for AutoTagKind {
+    fn name(&self) -> &str {
+        "auto-tag-kind"
+    }
+
+    fn on_create(&self, _ip: IpAddr, entry: &mut Entry) -> Result<(), String> {
+        let kind = serde_json::to_value(entry.data.kind()).map_err(|e| e.to_string())?;
+        let tag = Tag::try_from(format!("kind:{}", kind.as_str().unwrap_or_default()))
+            .map_err(|e| e.to_string())?;
+        entry.tags.get_or_insert_default().insert(tag);
+        Ok(())
+    }
+}
+
+/// Unsets the empty description, tags and links of entries
+pub struct StripEmpty;
+
+impl EntryHook for StripEmpty {
+    fn name(&self) -> &str {
+        "strip-empty"
+    }
+
+    fn on_create(&self, _ip: IpAddr, entry: &mut Entry) -> Result<(), String> {
+        entry.description.take_if(|d| d.trim().is_empty());
+        entry.tags.take_if(|t| t.is_empty());
+        entry.links.take_if(|l| l.is_empty());
+        Ok(())
+    }
+}
+
+/// Hooks shipped with the server, enabled by the entry_hooks setting
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuiltinHook {
+    AutoTagKind,
+    StripEmpty,
+}
+
+impl BuiltinHook {
+    fn hook(self) -> Box<dyn EntryHook> {
+        match self {
+            Self::AutoTagKind => Box::new(AutoTagKind),
+            Self::StripEmpty => Box::new(StripEmpty),
+        }
+    }
+}
+
+/// Hooks run, in order, on the entries being created
+pub struct Hooks(Vec<Box<dyn EntryHook>>);
+
+impl Hooks {
+    pub fn new(config: &Config) -> Self {
+        Hooks(config.entry_hooks.iter().map(|h| h.hook()).collect())
+    }
+
+    /// Adds a hook run after the ones already registered
+    #[allow(dead_code)] // for builds registering their own hooks
+    pub fn register(&mut self, hook: impl EntryHook + 'static) {
+        self.0.push(Box::new(hook));
+    }
+
+    /// Runs the hooks on `entry`, stopping at the first failing one
+    pub fn run(&self, ip: IpAddr, entry: &mut Entry) -> Result<(), ApiError> {
+        for hook in &self.0 {
+            hook.on_create(ip, entry).map_err(|e| {
+                api_error!(format!("entry rejected by hook {}: {e}", hook.name()))
+                    .with_code("hook_rejected")
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+//! Import of entries exported from another instance or of lists of IP
+//! addresses, and restore of the backups of the whole store
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    net::IpAddr,
+    sync::Arc,
+};
+
+use chrono::{TimeDelta, Utc};
+use ip_story_model::{ApiResponse, Entry};
+use rocket::{Data, State, http::Status, post};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    API_MOUNTPOINT, IpStory,
+    api::{ApiData, ApiError, ApiResult, Body, ErrorResponses, Writable, WriteErrorResponses},
+    api_error,
+    audit::{AuditAction, AuditRecord, Principal, audit},
+    check_ip, check_kind, check_links, check_tags, check_text, check_times,
+    config::Config,
+    events::Events,
+    hooks::Hooks,
+    storage::Storage,
+    storage_error,
+};
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    request_body(content = Vec<Entry>, description = "The entries to import, as returned by searches"),
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("preserve_ids" = Option<bool>, Query, description = "Keeps the UUIDs and the creation and modification times of the entries"),
+    ),
+    responses(
+        (status = 200, description = "Entries imported", body = ApiResponse<Vec<Uuid>>, content_type = "application/json"),
+        (status = 409, description = "With preserve_ids, a UUID or a creation time is already used", body = ApiResponse<String>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "Import",
+    description = "Imports entries into the history of an IP address, tracking it if needed. By default entries are created as new ones, getting new UUIDs and creation times. With preserve_ids, their UUIDs and creation and modification times are kept so that exporting and importing entries is lossless, entries without UUID or creation time get new ones. The UUIDs must then be unique within the merged history and unused by other IP addresses, and the creation times must not collide with existing entries, otherwise nothing is imported. Returns an ApiResponse with the UUIDs of the imported entries or an error message."
+)]
+#[post("/import/ip/<ip>?<preserve_ids>", data = "<entries>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn import_entries(
+    ip: IpAddr,
+    preserve_ids: Option<bool>,
+    entries: Result<Body<Vec<Entry>>, ApiError>,
+    principal: Principal,
+    config: &State<Config>,
+    events: &State<Events>,
+    hooks: &State<Hooks>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Vec<Uuid>> {
+    check_ip(ip, config)?;
+
+    let preserve_ids = preserve_ids.unwrap_or_default();
+    let mut entries = entries?.0;
+
+    let mut uuids = HashSet::new();
+    for entry in entries.iter_mut() {
+        entry.data.validate().map_err(|e| api_error!(e))?;
+        entry
+            .data
+            .normalize(config.strict_countries)
+            .map_err(|e| api_error!(e))?;
+        check_kind(&entry.data, config)?;
+        check_tags(entry.tags.iter().flatten(), config)?;
+        check_text(entry, config)?;
+
+        if preserve_ids {
+            let uuid = *entry.uuid.get_or_insert_with(Uuid::new_v4);
+            if !uuids.insert(uuid) {
+                return Err(api_error!(
+                    Status::Conflict,
+                    format!("entry {uuid} is imported twice")
+                )
+                .with_code("uuid_conflict"));
+            }
+        } else {
+            entry.uuid = Some(Uuid::new_v4());
+            entry.ctime = None;
+            entry.mtime = None;
+        }
+        check_times(entry, config)?;
+        hooks.run(ip, entry)?;
+        principal.check_write(entry)?;
+    }
+
+    for entry in entries.iter() {
+        // links between imported entries are only kept with their uuids
+        if entry.links.iter().flatten().all(|l| uuids.contains(l)) {
+            continue;
+        }
+        let mut external = entry.clone();
+        external.links = entry
+            .links
+            .as_ref()
+            .map(|l| l.iter().filter(|l| !uuids.contains(l)).copied().collect());
+        check_links(db, &external)?;
+    }
+
+    for uuid in uuids.iter() {
+        if let Some(other) = db
+            .entry_ip(*uuid)
+            .map_err(|e| storage_error!(e, "failed to resolve entry"))?
+            && other != ip
+        {
+            return Err(
+                api_error!(Status::Conflict, format!("entry {uuid} belongs to {other}"))
+                    .with_code("uuid_conflict"),
+            );
+        }
+    }
+
+    if db
+        .create_hip(IpStory::new(ip))
+        .map_err(|e| storage_error!(e, "failed to insert new ip"))?
+    {
+        audit(db, AuditRecord::new(&principal, AuditAction::Create, ip));
+    }
+
+    let imported = db
+        .update_hip(ip, |ipst| {
+            if let Some(uuid) = ipst.uuids().intersection(&uuids).next() {
+                return Err(api_error!(
+                    Status::Conflict,
+                    format!("entry {uuid} is already present")
+                )
+                .with_code("uuid_conflict"));
+            }
+
+            let mut imported = vec![];
+            for mut entry in entries.iter().cloned() {
+                let ctime = match entry.ctime {
+                    Some(ctime) if ipst.history.contains_key(&ctime) => {
+                        return Err(api_error!(
+                            Status::Conflict,
+                            format!("an entry created at {ctime} is already present")
+                        )
+                        .with_code("timestamp_conflict"));
+                    }
+                    Some(ctime) => ctime,
+                    None => {
+                        // entries imported together are spread so that they
+                        // do not collide in the history
+                        let mut ctime = Utc::now();
+                        while ipst.history.contains_key(&ctime) {
+                            ctime += TimeDelta::nanoseconds(1);
+                        }
+                        ctime
+                    }
+                };
+                entry.ctime = Some(ctime);
+
+                ipst.history.insert(ctime, entry.clone());
+                imported.push(entry);
+            }
+
+            Ok::<_, ApiError>(imported)
+        })
+        .map_err(|e| storage_error!(e, "failed to import entries"))??;
+
+    for entry in &imported {
+        audit(
+            db,
+            AuditRecord::new(&principal, AuditAction::Create, ip).entry(entry),
+        );
+        events.publish(AuditAction::Create, ip, entry.clone());
+    }
+
+    Ok(ApiData::Some(
+        imported.into_iter().filter_map(|e| e.uuid).collect(),
+    ))
+}
+
+/// Line of a backup which could not be restored
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RestoreError {
+    /// Number of the line, starting at 1
+    line: usize,
+    error: String,
+}
+
+/// Outcome of the restore of a backup
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct Restore {
+    /// Number of stories restored
+    restored: usize,
+    errors: Vec<RestoreError>,
+}
+
+/// Replaces the story of its IP address by the one serialized in `line`
+fn restore_line(
+    db: &Storage,
+    config: &Config,
+    principal: &Principal,
+    line: &str,
+) -> Result<(), ApiError> {
+    let hip: IpStory =
+        serde_json::from_str(line).map_err(|e| api_error!(format!("invalid story: {e}")))?;
+    check_ip(hip.ip, config)?;
+
+    let created = db
+        .create_hip(IpStory::new(hip.ip))
+        .map_err(|e| storage_error!(e, "failed to insert new ip"))?;
+
+    db.update_hip(hip.ip, |ipst| {
+        // the story replaced is as much written as the one restored
+        for entry in ipst.history.values().chain(hip.history.values()) {
+            principal.check_write(entry)?;
+        }
+        ipst.history = hip.history.clone();
+        Ok::<_, ApiError>(())
+    })
+    .map_err(|e| storage_error!(e, "failed to restore story"))??;
+
+    let action = if created {
+        AuditAction::Create
+    } else {
+        AuditAction::Update
+    };
+    audit(db, AuditRecord::new(principal, action, hip.ip));
+
+    Ok(())
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    request_body(content = String, description = "A backup made with GET /export/all, one story per line", content_type = "application/x-ndjson"),
+    responses(
+        (status = 200, description = "Backup restored", body = ApiResponse<Restore>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "Import",
+    description = "Restores a backup made with GET /export/all, reading it a line at a time. The story of every IP address of the backup replaces the one in the store, if any, while the IP addresses missing from the backup are left untouched, so backups are meant to be restored into an empty store. A line which cannot be restored, such as one holding or replacing entries of classifications the API key cannot write, does not stop the restore. Backups larger than the import_all_limit setting are rejected past the limit. Returns an ApiResponse with the number of stories restored and the lines which could not be, or an error message."
+)]
+#[post("/import/all", data = "<backup>")]
+pub async fn import_all(
+    backup: Data<'_>,
+    principal: Principal,
+    config: &State<Config>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Restore> {
+    let limit = config.import_all_limit;
+    let mut lines = BufReader::new(backup.open(limit)).lines();
+
+    let mut restore = Restore::default();
+    let (mut n, mut read) = (0, 0);
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .map_err(|e| api_error!(format!("failed to read backup: {e}")))?
+    {
+        n += 1;
+        read += line.len() + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // a truncated last line would be a partial story
+        if read > limit.as_u64() as usize {
+            return Err(api_error!(
+                Status::PayloadTooLarge,
+                format!(
+                    "backup larger than {limit}, {} stories restored",
+                    restore.restored
+                )
+            ));
+        }
+
+        match restore_line(db, config, &principal, &line) {
+            Ok(()) => restore.restored += 1,
+            Err(e) => restore.errors.push(RestoreError {
+                line: n,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    Ok(ApiData::Some(restore))
+}
+
+/// Line of an IP list which is not a valid IP address
+#[derive(Debug, Serialize, ToSchema)]
+pub struct InvalidLine {
+    /// Number of the line, starting at 1
+    line: usize,
+    error: String,
+}
+
+/// Outcome of the import of an IP list
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct IpListImport {
+    /// Number of IP addresses newly tracked
+    created: usize,
+    /// Number of IP addresses already tracked or listed twice
+    skipped: usize,
+    /// Number of lines which are not valid IP addresses
+    invalid: usize,
+    errors: Vec<InvalidLine>,
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    request_body(content = String, description = "The IP addresses to track, one per line", content_type = "text/plain"),
+    responses(
+        (status = 200, description = "IP list imported", body = ApiResponse<IpListImport>, content_type = "application/json"),
+        (status = 413, description = "The list is larger than the import_iplist_limit setting", body = ApiResponse<String>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "Import",
+    description = "Tracks the IP addresses of a plain text list, such as a blocklist, one IP address per line. Everything following a `#` is a comment, and blank lines are ignored. Lines which are not valid IP addresses, or which are rejected as reserved with the reject_reserved_ips setting, are reported and do not stop the import. IPv4-mapped IPv6 addresses are handled according to the ipv4_mapped setting. New IP addresses are created with an empty story in a single write to the store. Returns an ApiResponse with the number of IP addresses created, the ones skipped as already tracked or duplicated, and the invalid lines, or an error message."
+)]
+#[post("/import/iplist", data = "<list>")]
+pub async fn import_iplist(
+    list: Data<'_>,
+    principal: Principal,
+    config: &State<Config>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<IpListImport> {
+    let limit = config.import_iplist_limit;
+    let list = list.open(limit).into_string().await.map_err(|e| {
+        api_error!(format!("failed to read ip list: {e}")).with_code("invalid_body")
+    })?;
+    if !list.is_complete() {
+        return Err(api_error!(
+            Status::PayloadTooLarge,
+            format!("ip list larger than {limit}")
+        ));
+    }
+
+    let mut import = IpListImport::default();
+    let mut ips = BTreeSet::new();
+    for (n, line) in list.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let ip = line
+            .parse::<IpAddr>()
+            .map(|ip| config.ipv4_mapped.unmap(ip))
+            .map_err(|e| api_error!(format!("invalid ip address {line}: {e}")))
+            .and_then(|ip| check_ip(ip, config).map(|_| ip));
+        match ip {
+            Ok(ip) if !ips.insert(ip) => import.skipped += 1,
+            Ok(_) => {}
+            Err(e) => {
+                import.invalid += 1;
+                import.errors.push(InvalidLine {
+                    line: n + 1,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    let ips: Vec<IpAddr> = ips.into_iter().collect();
+    let created = db
+        .create_hips(&ips)
+        .map_err(|e| storage_error!(e, "failed to insert new ips"))?;
+
+    for (&ip, &c) in created.iter() {
+        if c {
+            import.created += 1;
+            audit(db, AuditRecord::new(&principal, AuditAction::Create, ip));
+        } else {
+            import.skipped += 1;
+        }
+    }
+
+    Ok(ApiData::Some(import))
+}
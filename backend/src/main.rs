@@ -1,235 +1,855 @@
 #![deny(unused_imports)]
 
 use std::{
-    borrow::Cow,
-    collections::{BTreeMap, HashSet},
-    env,
-    ffi::OsStr,
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     net::IpAddr,
-    path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
-use api::{ApiData, ApiResult};
-use chrono::Utc;
-use redis::{Client, Commands, RedisError};
+use api::{
+    ApiData, ApiError, ApiResult, Body, ErrorResponses, IfMatch, IfNoneMatchAny, Timestamp, Warned,
+    WithHeaders, Writable, WriteErrorResponses,
+};
+use audit::{AuditAction, AuditRecord, Principal, audit, audit_search};
+use chrono::{TimeDelta, Utc};
+use config::{Config, FuturePolicy, HistoryKey, MappedPolicy};
+use enrich::Enricher;
+use events::Events;
+use facets::{Facet, FacetsCache};
+use histogram::Bucket;
+use hooks::Hooks;
+use ip_story_model::{
+    ApiResponse, Changes, Confidence, Cve, Data, DataKind, Entry, EntrySummary, IpActivity,
+    IpSortBy, NewIp, SearchOrder, Severity, SortBy, Tag, TagMode,
+};
+use openapi::OpenApiSpec;
+use paths::NormalizePaths;
+use request_log::RequestLogger;
 use rocket::{
-    FromFormField, State, delete, error, get, http::ContentType, post, put, request::FromParam,
-    routes, serde::json::Json,
+    FromForm, State, delete, get,
+    http::{Header, Status},
+    post, put, routes,
 };
-use rust_embed::Embed;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
-use url::Url;
+use serde_json_path::JsonPath;
+use stats::StatsCache;
+use storage::{
+    Layout, Migration, Repair, Storage, StorageError, connect_to_redis, connect_to_redis_v6,
+};
 use utoipa::{OpenApi, ToSchema};
 use uuid::Uuid;
 
 mod api;
+mod audit;
+mod cidr;
+mod config;
+mod enrich;
+mod events;
+mod export;
+mod facets;
+#[cfg(feature = "frontend")]
+mod frontend;
+mod histogram;
+mod hooks;
+mod import;
+mod misp;
+mod openapi;
+mod paths;
+mod prune;
+mod request_log;
+mod seed;
+mod session;
+mod stats;
+mod storage;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod template;
+mod webhooks;
+mod ws;
 
-use api::ApiResponse;
+type History = BTreeMap<chrono::DateTime<Utc>, Entry>;
+/// Entry along with the key it is stored under in a [`History`]
+type KeyedEntry = (chrono::DateTime<Utc>, Entry);
 
-#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
-pub struct Owner {
-    name: String,
-    address: Option<String>,
-    country: Option<String>,
-    abuse: Option<String>,
-    phone: Option<String>,
-}
+/// Maximum number of deletions a story remembers for its revisions
+const MAX_DELETIONS: usize = 1000;
 
-#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
-#[serde(rename_all = "kebab-case")]
-pub enum TicketId {
-    Id(u64),
-    Uuid(Uuid),
+#[derive(Debug, Serialize, Deserialize)]
+struct IpStory {
+    ip: IpAddr,
+    history: History,
+    /// Incremented every time the story changes, starting at 0 when the
+    /// IP address gets tracked
+    #[serde(default)]
+    revision: u64,
+    /// UUIDs of the entries deleted, along with the revision they got
+    /// deleted at, the oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deleted: Vec<(u64, Uuid)>,
+    /// Latest revision whose deletions were forgotten
+    #[serde(default, skip_serializing_if = "is_zero")]
+    forgotten: u64,
+    /// Free-form notes about the IP address itself, ex: "known corporate
+    /// VPN egress", kept apart from the timestamped history
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    meta: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
-pub struct MispEvent {
-    server: Option<Url>,
-    uuid: Uuid,
+fn is_zero(n: &u64) -> bool {
+    *n == 0
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
-pub struct Ticket {
-    server: Option<Url>,
-    id: TicketId,
+impl IpStory {
+    fn new(ip: IpAddr) -> Self {
+        IpStory {
+            ip,
+            history: BTreeMap::new(),
+            revision: 0,
+            deleted: vec![],
+            forgotten: 0,
+            meta: None,
+        }
+    }
+
+    /// Records the changes made since `prev` under a new revision: the
+    /// entries created or modified get the revision and the deleted ones
+    /// are remembered, at most [`MAX_DELETIONS`] of them
+    fn revise(&mut self, prev: &IpStory) {
+        self.revision = prev.revision + 1;
+        let revision = self.revision;
+
+        let before: HashMap<Uuid, String> = prev
+            .history
+            .values()
+            .filter_map(|e| Some((e.uuid?, serde_json::to_string(e).ok()?)))
+            .collect();
+        for entry in self.history.values_mut() {
+            let unchanged = entry
+                .uuid
+                .and_then(|u| before.get(&u))
+                .is_some_and(|b| serde_json::to_string(entry).is_ok_and(|s| &s == b));
+            if !unchanged {
+                entry.revision = Some(revision);
+            }
+        }
+
+        let uuids = self.uuids();
+        self.deleted.extend(
+            before
+                .keys()
+                .filter(|u| !uuids.contains(u))
+                .map(|u| (revision, *u)),
+        );
+        if let Some(n) = self.deleted.len().checked_sub(MAX_DELETIONS)
+            && let Some((r, _)) = self.deleted.drain(..n).next_back()
+        {
+            self.forgotten = r;
+        }
+    }
+
+    /// Changes of the history since `revision`, failing if the deletions
+    /// since then are not all known or if the story got tracked again
+    /// since, its revision being reset
+    fn changes(self, since: Option<u64>) -> Result<Changes, ApiError> {
+        let Some(since) = since else {
+            return Ok(Changes {
+                revision: self.revision,
+                entries: self.history.into_values().collect(),
+                deleted: vec![],
+            });
+        };
+
+        if since < self.forgotten || since > self.revision {
+            return Err(api_error!(
+                Status::Gone,
+                format!(
+                    "changes since revision {since} of {} are not known, the current revision is {}",
+                    self.ip, self.revision
+                )
+            )
+            .with_code("revision_expired"));
+        }
+
+        Ok(Changes {
+            revision: self.revision,
+            entries: self
+                .history
+                .into_values()
+                .filter(|e| e.revision.is_some_and(|r| r > since))
+                .collect(),
+            deleted: self
+                .deleted
+                .into_iter()
+                .filter(|(r, _)| *r > since)
+                .map(|(_, u)| u)
+                .collect(),
+        })
+    }
+
+    /// Uuids of the entries of the history
+    fn uuids(&self) -> HashSet<Uuid> {
+        self.history.values().filter_map(|e| e.uuid).collect()
+    }
+
+    /// ASNs of the entries of the history
+    fn asns(&self) -> HashSet<u64> {
+        self.history
+            .values()
+            .filter_map(|e| match e.data {
+                Data::Asn(asn) => Some(asn),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// CVE identifiers mentioned by the entries of the history
+    fn cves(&self) -> HashSet<Cve> {
+        self.history.values().flat_map(|e| e.data.cves()).collect()
+    }
+
+    /// Links of the entries of the history, as (from, to) uuid pairs
+    fn links(&self) -> HashSet<(Uuid, Uuid)> {
+        self.history
+            .values()
+            .filter_map(|e| Some((e.uuid?, e.links.as_ref()?)))
+            .flat_map(|(from, links)| links.iter().map(move |to| (from, *to)))
+            .collect()
+    }
+
+    /// Most recent creation or modification time of the entries, None if
+    /// the history is empty. It is computed from the remaining entries, so
+    /// deleting the most recent one moves it back, and the last-seen index
+    /// with it.
+    fn mtime(&self) -> Option<chrono::DateTime<Utc>> {
+        self.history
+            .values()
+            .flat_map(|e| [e.ctime, e.mtime])
+            .flatten()
+            .max()
+    }
+
+    /// Keys the entries of the history on their `key` timestamp, or on
+    /// their creation time if they lack it. Entries sharing a timestamp
+    /// are spread by a nanosecond so that none is lost.
+    fn rekey(&mut self, key: HistoryKey) {
+        let history = std::mem::take(&mut self.history);
+        for (prev, entry) in history {
+            let mut t = match key {
+                HistoryKey::Ctime => entry.ctime,
+                HistoryKey::Mtime => entry.mtime.or(entry.ctime),
+            }
+            .unwrap_or(prev);
+            while self.history.contains_key(&t) {
+                t += TimeDelta::nanoseconds(1);
+            }
+            self.history.insert(t, entry);
+        }
+    }
+
+    /// The story as `principal` can read it, without the entries of
+    /// the classifications it is not cleared for. It is never stored.
+    fn readable_by(mut self, principal: &Principal) -> Self {
+        self.history.retain(|_, e| principal.can_read(e));
+        self
+    }
+
+    /// Mutable reference to the entry with the given uuid
+    fn entry_mut(&mut self, uuid: Uuid) -> Option<&mut Entry> {
+        self.history.values_mut().find(|e| e.uuid == Some(uuid))
+    }
+
+    /// Updates the tags of the entry with the given `uuid` with `f`,
+    /// returns its resulting tags and, if they changed, the entry
+    /// modified at `now`
+    fn retag(
+        &mut self,
+        uuid: Uuid,
+        f: impl Fn(&mut HashSet<Tag>),
+        now: chrono::DateTime<Utc>,
+    ) -> Option<(HashSet<Tag>, Option<Entry>)> {
+        let entry = self.entry_mut(uuid)?;
+
+        let mut tags = entry.tags.clone().unwrap_or_default();
+        f(&mut tags);
+
+        if tags == entry.tags.clone().unwrap_or_default() {
+            return Some((tags, None));
+        }
+        entry.tags = (!tags.is_empty()).then(|| tags.clone());
+        entry.mtime = Some(now);
+        Some((tags, Some(entry.clone())))
+    }
+
+    /// Moves the modification time of the entry with the given `uuid`
+    /// forward to `now`, leaving its content as is, returns the entry
+    fn touch(&mut self, uuid: Uuid, now: chrono::DateTime<Utc>) -> Option<&Entry> {
+        let entry = self.entry_mut(uuid)?;
+        entry.mtime = entry.mtime.max(Some(now));
+        Some(entry)
+    }
+
+    /// Looks for inconsistencies in the history
+    fn check(&self) -> CheckReport {
+        let mut report = CheckReport::default();
+        let mut seen = HashSet::new();
+
+        for (key, entry) in self.history.iter() {
+            match entry.uuid {
+                Some(uuid) => {
+                    if !seen.insert(uuid) && !report.duplicate_uuids.contains(&uuid) {
+                        report.duplicate_uuids.push(uuid);
+                    }
+                }
+                None => report.missing_uuid.push(*key),
+            }
+
+            if let (Some(ctime), Some(mtime)) = (entry.ctime, entry.mtime)
+                && mtime < ctime
+            {
+                report.mtime_before_ctime.push(*key);
+            }
+        }
+
+        report
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
-#[serde(rename_all = "kebab-case")]
-pub enum Data {
-    Owner(Owner),
-    Asn(u64),
-    MispEvent(MispEvent),
-    Ticket(Ticket),
-    Vulnerable(String),
-    Text(String),
-    Json(serde_json::Value),
+/// Inconsistencies found in the history of an IP address, entries
+/// are referred to by the timestamp they are stored at when their
+/// UUID cannot be relied on
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct CheckReport {
+    /// UUIDs shared by several entries
+    duplicate_uuids: Vec<Uuid>,
+    /// Entries without UUID
+    missing_uuid: Vec<chrono::DateTime<Utc>>,
+    /// Entries with a modification time older than their creation time
+    mtime_before_ctime: Vec<chrono::DateTime<Utc>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, FromFormField, ToSchema)]
-#[serde(rename_all = "kebab-case")]
-pub enum SearchOrder {
-    Asc,
-    Desc,
+/// Default mount point of the API, the one the OpenAPI documentation
+/// is generated for. Its paths are rewritten at startup when the
+/// api_mountpoint setting differs.
+const API_MOUNTPOINT: &str = "/api";
+
+/// Minimum number of hex digits of the UUID prefixes entries are
+/// looked up with, shorter ones would match most entries
+const UUID_PREFIX_MIN_LEN: usize = 4;
+
+/// Fraction of the maximum description length past which entries are
+/// created with a warning
+const DESCRIPTION_LEN_WARNING: f64 = 0.8;
+
+/// Returns the reason why `ip` is not a routable address, if any
+fn reserved_reason(ip: IpAddr) -> Option<&'static str> {
+    if ip.is_unspecified() {
+        return Some("unspecified address");
+    }
+    if ip.is_loopback() {
+        return Some("loopback address");
+    }
+    if ip.is_multicast() {
+        return Some("multicast address");
+    }
+
+    match ip {
+        IpAddr::V4(ip) => {
+            if ip.is_link_local() {
+                Some("link-local address")
+            } else if ip.is_broadcast() {
+                Some("broadcast address")
+            } else if ip.is_documentation() {
+                Some("documentation address")
+            } else {
+                None
+            }
+        }
+        IpAddr::V6(ip) => {
+            if ip.is_unicast_link_local() {
+                Some("link-local address")
+            // 2001:db8::/32 documentation prefix
+            } else if ip.segments()[..2] == [0x2001, 0xdb8] {
+                Some("documentation address")
+            } else {
+                None
+            }
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, FromFormField, ToSchema)]
-#[serde(rename_all = "kebab-case")]
-pub enum DataKind {
-    Owner,
-    Asn,
-    // for FromFormField
-    #[field(value = "misp-event")]
-    MispEvent,
-    Ticket,
-    Vulnerable,
-    Text,
-    Json,
+/// Checks `ip` against the configured address policy
+fn check_ip(ip: IpAddr, config: &Config) -> Result<(), ApiError> {
+    if config.ipv4_mapped == MappedPolicy::Reject
+        && let IpAddr::V6(v6) = ip
+        && let Some(v4) = v6.to_ipv4_mapped()
+    {
+        return Err(api_error!(format!(
+            "{ip} is rejected: ipv4-mapped address, use {v4} instead"
+        ))
+        .with_code("ip_rejected"));
+    }
+    if config.reject_reserved_ips
+        && let Some(reason) = reserved_reason(ip)
+    {
+        return Err(api_error!(format!("{ip} is rejected: {reason}")).with_code("ip_rejected"));
+    }
+    Ok(())
 }
 
-impl<'r> FromParam<'r> for DataKind {
-    type Error = &'r str;
+/// Appends a new entry to the history of `ip`, giving it a new uuid and
+/// a creation time if it has none. Returns the entry as stored.
+fn add_entry(
+    db: &Storage,
+    events: &Events,
+    hooks: &Hooks,
+    principal: &Principal,
+    ip: IpAddr,
+    mut entry: Entry,
+) -> Result<Entry, ApiError> {
+    hooks.run(ip, &mut entry)?;
+    principal.check_write(&entry)?;
+
+    // we must create a new uuid
+    entry.uuid = Some(Uuid::new_v4());
+    entry.severity = entry.severity.or(entry.data.default_severity());
+    let timestamp = *entry.ctime.get_or_insert_with(Utc::now);
 
-    fn from_param(param: &'r str) -> Result<Self, Self::Error> {
-        match param {
-            "owner" => Ok(DataKind::Owner),
-            "asn" => Ok(DataKind::Asn),
-            "misp-event" => Ok(DataKind::MispEvent),
-            "ticket" => Ok(DataKind::Ticket),
-            "vulnerable" => Ok(DataKind::Vulnerable),
-            "text" => Ok(DataKind::Text),
-            "json" => Ok(DataKind::Json),
-            _ => Err(param),
+    db.update_hip(ip, |ipst| {
+        if ipst.history.contains_key(&timestamp) {
+            return Err(
+                api_error!("an entry with this timestamp is already present")
+                    .with_code("timestamp_conflict"),
+            );
         }
-    }
+
+        ipst.history.insert(timestamp, entry.clone());
+        Ok(())
+    })
+    .map_err(|e| storage_error!(e, "failed to add entry"))??;
+
+    audit(
+        db,
+        AuditRecord::new(principal, AuditAction::Create, ip).entry(&entry),
+    );
+    events.publish(AuditAction::Create, ip, entry.clone());
+
+    Ok(entry)
 }
 
-impl Data {
-    fn kind(&self) -> DataKind {
-        match self {
-            Self::Owner(_) => DataKind::Owner,
-            Self::Asn(_) => DataKind::Asn,
-            Self::MispEvent(_) => DataKind::MispEvent,
-            Self::Ticket(_) => DataKind::Ticket,
-            Self::Vulnerable(_) => DataKind::Vulnerable,
-            Self::Text(_) => DataKind::Text,
-            Self::Json(_) => DataKind::Json,
+/// Checks that the entries linked by `entry` exist
+fn check_links(db: &Storage, entry: &Entry) -> Result<(), ApiError> {
+    for link in entry.links.iter().flatten() {
+        if entry.uuid == Some(*link) {
+            return Err(api_error!("an entry cannot link to itself"));
+        }
+
+        if db
+            .entry_ip(*link)
+            .map_err(|e| storage_error!(e, "failed to resolve linked entry"))?
+            .is_none()
+        {
+            return Err(api_error!(format!("linked entry {link} does not exist")));
         }
     }
+    Ok(())
 }
 
-#[derive(Hash, Debug, PartialEq, Eq, Clone, ToSchema)]
-struct Tag(String);
+/// Finds an entry from its uuid, whatever the IP address it belongs to
+fn find_entry(db: &Storage, uuid: Uuid) -> Result<Option<Entry>, ApiError> {
+    let Some(ip) = db
+        .entry_ip(uuid)
+        .map_err(|e| storage_error!(e, "failed to resolve entry"))?
+    else {
+        return Ok(None);
+    };
+
+    let ipst = db
+        .get_hip(ip)
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?;
 
-impl From<String> for Tag {
-    fn from(value: String) -> Self {
-        Tag(value.to_ascii_lowercase())
-    }
+    Ok(entry_with_uuid(ipst, uuid))
 }
 
-impl<'de> Deserialize<'de> for Tag {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        Ok(Tag(String::deserialize(deserializer)?))
-    }
+/// Takes the entry of `ipst` with the given `uuid`, expired or not, as
+/// an entry asked for by UUID is known to the client
+fn entry_with_uuid(ipst: IpStory, uuid: Uuid) -> Option<Entry> {
+    ipst.history.into_values().find(|e| e.uuid == Some(uuid))
 }
 
-impl Serialize for Tag {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        self.0.to_ascii_lowercase().serialize(serializer)
+/// Checks that the `current` entry with the given `uuid`, if any, satisfies
+/// the `If-Match` header of the request, missing entries only satisfying a
+/// missing header
+fn check_if_match(if_match: &IfMatch, current: Option<&Entry>, uuid: Uuid) -> Result<(), ApiError> {
+    let matching = match current {
+        Some(e) => if_match.matches(e.mtime.or(e.ctime)),
+        None => !if_match.is_set(),
+    };
+    if !matching {
+        return Err(api_error!(
+            Status::PreconditionFailed,
+            format!("entry {uuid} does not match If-Match, it was modified or deleted")
+        )
+        .with_code("entry_modified"));
     }
+    Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
-pub struct Entry {
-    uuid: Option<Uuid>,
-    description: Option<String>,
-    /// Creation timestamp
-    ctime: Option<chrono::DateTime<Utc>>,
-    /// Modification timestamp
-    mtime: Option<chrono::DateTime<Utc>>,
-    tags: Option<HashSet<Tag>>,
-    data: Data,
+/// Checks `data` is of one of the configured allowed kinds
+fn check_kind(data: &Data, config: &Config) -> Result<(), ApiError> {
+    let kind = data.kind();
+    if !config.kind_allowed(&kind) {
+        return Err(api_error!(format!(
+            "{kind:?} entries are not accepted by this instance"
+        ))
+        .with_code("kind_not_allowed"));
+    }
+    Ok(())
 }
 
-type History = BTreeMap<chrono::DateTime<Utc>, Entry>;
+/// Checks that the `new` data of an entry is of the kind of its `prev`
+/// data, unless changing it is allowed, as consumers may track entries
+/// by kind
+fn check_kind_change(prev: &Data, new: &Data, allowed: Option<bool>) -> Result<(), ApiError> {
+    if prev.kind() != new.kind() && !allowed.unwrap_or(false) {
+        return Err(api_error!(format!(
+            "entry holds {:?} data, changing its kind must be allowed",
+            prev.kind()
+        )));
+    }
+    Ok(())
+}
 
-#[derive(Debug, Serialize, Deserialize)]
-struct IpStory {
-    ip: IpAddr,
-    history: History,
+/// Checks `tags` against the configured maximum tag length
+fn check_tags<'a>(
+    tags: impl IntoIterator<Item = &'a Tag>,
+    config: &Config,
+) -> Result<(), ApiError> {
+    if let Some(tag) = tags.into_iter().find(|t| t.len() > config.tag_max_len) {
+        return Err(api_error!(format!(
+            "tag {} is longer than {} characters",
+            tag.as_str(),
+            config.tag_max_len
+        )));
+    }
+    Ok(())
 }
 
-impl IpStory {
-    fn new(ip: IpAddr) -> Self {
-        IpStory {
-            ip,
-            history: BTreeMap::new(),
+/// Removes the HTML tags of `s`, an unclosed tag runs up to the end
+fn strip_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut in_tag = false;
+    for c in s.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => out.push(c),
+            _ => {}
         }
     }
+    out
 }
 
-const API_MOUNTPOINT: &str = "/api";
-const MAP_NAME: &str = "ip-story";
+/// Strips the HTML of text data if the sanitize_html setting is on and
+/// drops its empty fields if the drop_empty_fields one is
+fn sanitize_data(data: &mut Data, config: &Config) {
+    if config.sanitize_html
+        && let Data::Text(text) = data
+    {
+        *text = strip_html(text);
+    }
+    if config.drop_empty_fields {
+        data.drop_empty();
+    }
+}
 
-fn connect_to_redis() -> anyhow::Result<redis::Client> {
-    // Get the Redis URL from the environment variable
-    let redis_url = env::var("REDIS_URL")?;
+/// Sanitizes the free text of `entry`, drops its empty fields if
+/// configured so and checks its description against the configured
+/// maximum length
+fn check_text(entry: &mut Entry, config: &Config) -> Result<(), ApiError> {
+    sanitize_data(&mut entry.data, config);
 
-    // Create a Redis client
-    let client = Client::open(redis_url)?;
+    if config.sanitize_html
+        && let Some(description) = entry.description.as_mut()
+    {
+        *description = strip_html(description);
+    }
+    // after stripping the HTML, as a description may only hold tags
+    if config.drop_empty_fields {
+        entry.drop_empty();
+    }
 
-    Ok(client)
-}
+    let Some(description) = entry.description.as_ref() else {
+        return Ok(());
+    };
 
-fn get_hip(ip: IpAddr, client: &mut redis::Client) -> Result<IpStory, RedisError> {
-    let s: String = client.hget(MAP_NAME, ip.to_string())?;
-    Ok(serde_json::from_str(&s).unwrap())
+    if description.chars().count() > config.description_max_len {
+        return Err(api_error!(format!(
+            "description is longer than {} characters",
+            config.description_max_len
+        )));
+    }
+    Ok(())
 }
 
-fn hip_exists(ip: IpAddr, client: &mut redis::Client) -> Result<bool, RedisError> {
-    let s = client.hexists(MAP_NAME, ip.to_string())?;
-    Ok(s)
+/// Checks that the timestamps of `entry` are not further in the future
+/// than the configured tolerance, clamping them to now if configured so.
+/// Entries from a client with a skewed clock would otherwise sort after
+/// all the others.
+fn check_times(entry: &mut Entry, config: &Config) -> Result<(), ApiError> {
+    let now = Utc::now();
+    let max = now + Duration::from_secs(config.future_tolerance_secs);
+
+    for (name, time) in [("ctime", &mut entry.ctime), ("mtime", &mut entry.mtime)] {
+        let Some(t) = time.as_mut() else {
+            continue;
+        };
+        if *t <= max {
+            continue;
+        }
+        match config.future_policy {
+            FuturePolicy::Reject => {
+                return Err(api_error!(format!(
+                    "{name} {t} is more than {}s ahead of the server time {now}, check the client clock",
+                    config.future_tolerance_secs
+                ))
+                .with_code("timestamp_in_future"));
+            }
+            FuturePolicy::Clamp => *t = now,
+        }
+    }
+    Ok(())
 }
 
-fn store_hip(hip: IpStory, client: &mut redis::Client) -> Result<(), RedisError> {
-    client.hset(
-        MAP_NAME,
-        hip.ip.to_string(),
-        serde_json::to_string(&hip).unwrap(),
-    )
+/// Questionable values of `entry` which are accepted anyway, to be
+/// checked before [`check_times`] clamps the timestamps
+fn entry_warnings(entry: &Entry, config: &Config) -> Vec<String> {
+    let mut warnings = vec![];
+    let now = Utc::now();
+    let max = now + Duration::from_secs(config.future_tolerance_secs);
+
+    for (name, time) in [("ctime", entry.ctime), ("mtime", entry.mtime)] {
+        match time {
+            Some(t) if t > max => warnings.push(format!(
+                "{name} {t} is ahead of the server time {now}, it got clamped"
+            )),
+            Some(t) if t > now => warnings.push(format!(
+                "{name} {t} is ahead of the server time {now}, check the client clock"
+            )),
+            _ => {}
+        }
+    }
+
+    if let Data::Owner(owner) = &entry.data
+        && let Some(country) = &owner.country
+        && ip_story_model::country::alpha2(country).is_none()
+    {
+        warnings.push(format!("unknown country {country}, it is stored as is"));
+    }
+
+    if let Some(description) = &entry.description {
+        let len = description.chars().count();
+        if len as f64 > config.description_max_len as f64 * DESCRIPTION_LEN_WARNING {
+            warnings.push(format!(
+                "description takes {len} of the {} characters allowed",
+                config.description_max_len
+            ));
+        }
+    }
+
+    warnings
 }
 
 #[utoipa::path(
     context_path = API_MOUNTPOINT,
     params(
         ("ip" = String, Path, description = "The IP address to add or update"),
+        ("If-None-Match" = Option<String>, Header, description = "`*` makes the request fail with a 409 Conflict if the IP address already exists"),
     ),
     responses(
-        (status = 200, description = "IP address processed successfully", body = ApiResponse<String>, content_type = "application/json"),
+        (status = 200, description = "IP address processed successfully", body = ApiResponse<NewIp>, content_type = "application/json"),
+        (status = 409, description = "IP address already exists while `If-None-Match: *` was given", body = ApiResponse<String>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
     ),
     tag = "IP Management",
-    description = "Adds a new IP address to the database if it does not already exist. Returns an ApiResponse with the IP address or an error message."
+    description = "Adds a new IP address to the database if it does not already exist. Returns an ApiResponse with the IP address and whether it got created, or an error message."
 )]
 #[put("/ip/<ip>")]
-async fn ip_new(ip: IpAddr, db: &State<Arc<Mutex<redis::Client>>>) -> ApiResult<IpAddr> {
-    let mut db = db.lock().await;
-    if !hip_exists(ip, &mut db)
-        .inspect_err(|e| error!("failed to insert new ip: {e}"))
-        .map_err(|_| api_error!("failed to insert new ip"))?
-    {
-        store_hip(IpStory::new(ip), &mut db)
-            .inspect_err(|e| error!("failed to insert new ip: {e}"))
-            .map_err(|_| api_error!("failed to insert new ip"))?;
+async fn ip_new(
+    ip: IpAddr,
+    if_none_match: IfNoneMatchAny,
+    principal: Principal,
+    config: &State<Config>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<NewIp> {
+    check_ip(ip, config)?;
+    let created = db
+        .create_hip(IpStory::new(ip))
+        .map_err(|e| storage_error!(e, "failed to insert new ip"))?;
+
+    if created {
+        audit(db, AuditRecord::new(&principal, AuditAction::Create, ip));
+    } else if if_none_match.0 {
+        return Err(
+            api_error!(Status::Conflict, format!("{ip} already exists")).with_code("ip_exists")
+        );
+    }
+
+    Ok(ApiData::Some(NewIp { ip, created }))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    request_body(content = Vec<String>, description = "The IP addresses to add", content_type = "application/json"),
+    responses(
+        (status = 200, description = "IP addresses processed successfully", body = ApiResponse<BTreeMap<String, bool>>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Adds many IP addresses to the database at once, in a single write to the store, for instance to seed it from a blocklist. IPv4-mapped IPv6 addresses are handled according to the ipv4_mapped setting and duplicates are only added once. Returns an ApiResponse with, for every IP address, whether it got created or was already tracked, or an error message."
+)]
+#[put("/ip", data = "<ips>")]
+async fn ip_new_many(
+    ips: Result<Body<Vec<IpAddr>>, ApiError>,
+    principal: Principal,
+    config: &State<Config>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<BTreeMap<IpAddr, bool>> {
+    let ips: BTreeSet<IpAddr> = ips?
+        .0
+        .into_iter()
+        .map(|ip| config.ipv4_mapped.unmap(ip))
+        .collect();
+    for &ip in &ips {
+        check_ip(ip, config)?;
+    }
+    let ips: Vec<IpAddr> = ips.into_iter().collect();
+    let created = db
+        .create_hips(&ips)
+        .map_err(|e| storage_error!(e, "failed to insert new ips"))?;
+
+    for (&ip, _) in created.iter().filter(|(_, c)| **c) {
+        audit(db, AuditRecord::new(&principal, AuditAction::Create, ip));
     }
-    Ok(ApiData::Some(ip))
+
+    Ok(ApiData::Some(created))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("active_since" = Option<String>, Query, description = "RFC 3339 timestamp, only returns the IP addresses last seen at or after it"),
+        ("active_before" = Option<String>, Query, description = "RFC 3339 timestamp, only returns the IP addresses last seen before it"),
+        ("sort_by" = Option<IpSortBy>, Query, description = "The field to sort on, IP addresses by default. IP addresses without entries come last when sorted by last-seen time."),
+        ("order" = Option<SearchOrder>, Query, description = "The order in which to return the IP addresses, ascending by default"),
+        ("limit" = Option<usize>, Query, description = "The maximum number of IP addresses to return, defaults to the search_default_limit setting and is clamped to the search_max_limit one"),
+        ("offset" = Option<usize>, Query, description = "The number of IP addresses to skip"),
+    ),
+    responses(
+        (status = 200, description = "IP addresses retrieved successfully", body = ApiResponse<Vec<IpActivity>>, content_type = "application/json",
+            headers(
+                ("X-Total-Count" = usize, description = "Number of IP addresses matching the criteria, regardless of offset and limit"),
+            )),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Lists the tracked IP addresses along with the last time one of their entries got created or modified, for instance to find the IP addresses active in the last day. Last-seen times are read from an index, without loading the stories, and have a microsecond precision. Deleting entries moves the last-seen time back to the one of the remaining entries. With active_since or active_before, IP addresses without entries are left out. Returns an ApiResponse with the IP addresses or an error message."
+)]
+#[get("/ips?<active_since>&<active_before>&<sort_by>&<order>&<limit>&<offset>")]
+#[allow(clippy::too_many_arguments)]
+async fn ip_list(
+    active_since: Option<Timestamp>,
+    active_before: Option<Timestamp>,
+    sort_by: Option<IpSortBy>,
+    order: Option<SearchOrder>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    config: &State<Config>,
+    db: &State<Arc<Storage>>,
+) -> Result<WithHeaders<ApiData<Vec<IpActivity>>>, ApiError> {
+    let (since, before) = (active_since.map(|t| t.0), active_before.map(|t| t.0));
+    let seen = db
+        .seen_ips(since, before)
+        .map_err(|e| storage_error!(e, "failed to list ips"))?;
+
+    let mut ips: Vec<IpActivity> = if since.is_some() || before.is_some() {
+        seen.into_iter()
+            .map(|(ip, t)| IpActivity {
+                ip,
+                last_seen: Some(t),
+            })
+            .collect()
+    } else {
+        let seen: HashMap<IpAddr, chrono::DateTime<Utc>> = seen.into_iter().collect();
+        db.ips()
+            .map_err(|e| storage_error!(e, "failed to list ips"))?
+            .into_iter()
+            .map(|ip| IpActivity {
+                ip,
+                last_seen: seen.get(&ip).copied(),
+            })
+            .collect()
+    };
+
+    let order = order.unwrap_or(SearchOrder::Asc);
+    ips.sort_by(|a, b| match (sort_by.unwrap_or_default(), order) {
+        (IpSortBy::Ip, SearchOrder::Asc) => a.ip.cmp(&b.ip),
+        (IpSortBy::Ip, SearchOrder::Desc) => b.ip.cmp(&a.ip),
+        // IP addresses without entries come last whatever the order
+        (IpSortBy::LastSeen, order) => match (a.last_seen, b.last_seen, order) {
+            (Some(a), Some(b), SearchOrder::Asc) => a.cmp(&b),
+            (Some(a), Some(b), SearchOrder::Desc) => b.cmp(&a),
+            (Some(_), None, _) => Ordering::Less,
+            (None, Some(_), _) => Ordering::Greater,
+            (None, None, _) => Ordering::Equal,
+        }
+        .then(a.ip.cmp(&b.ip)),
+    });
+
+    let total = ips.len();
+    let limit = limit
+        .unwrap_or(config.search_default_limit)
+        .min(config.search_max_limit);
+    let ips: Vec<IpActivity> = ips
+        .into_iter()
+        .skip(offset.unwrap_or_default())
+        .take(limit)
+        .collect();
+
+    Ok(
+        WithHeaders::new(ApiData::Some(ips))
+            .header(Header::new("X-Total-Count", total.to_string())),
+    )
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 200, description = "Index rebuilt successfully", body = ApiResponse<usize>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Rebuilds the index holding the last-seen time of every IP address, which stories stored by older versions are missing from until they are modified. Returns an ApiResponse with the number of IP addresses indexed or an error message. Only unrestricted API keys can run it, the others being answered with a 403 Forbidden and the admin_required code."
+)]
+#[post("/ips/index/rebuild")]
+async fn ip_list_index_rebuild(
+    _writable: Writable,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<usize> {
+    principal.check_admin()?;
+
+    let n = db
+        .rebuild_seen_index()
+        .map_err(|e| storage_error!(e, "failed to rebuild last-seen index"))?;
+
+    Ok(ApiData::Some(n))
 }
 
 #[utoipa::path(
@@ -237,44 +857,53 @@ async fn ip_new(ip: IpAddr, db: &State<Arc<Mutex<redis::Client>>>) -> ApiResult<
     request_body = Entry,
     params(
         ("ip" = String, Path, description = "The IP address"),
+        ("template" = Option<bool>, Query, description = "Renders the text of the entry as a template, replacing `{name}` by the value of the variable `name`, `{{` and `}}` standing for literal braces. The server sets `now`, `date`, `principal` and `ip`."),
+        ("vars" = Option<HashMap<String, String>>, Query, description = "Variables of the template given by the client, ex: `vars.ticket=INC-42`"),
     ),
     responses(
-        (status = 200, description = "Entry addition response", body = ApiResponse<bool>, content_type = "application/json"),
+        (status = 200, description = "Entry added successfully", body = ApiResponse<Entry>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
     ),
     tag = "IP Management",
-    description = "Adds a new entry associated with an IP address. Returns an ApiResponse with a boolean indicating success or an error message."
+    description = "Adds a new entry associated with an IP address. With template, text entries are rendered before being stored, and templates using unknown variables are rejected. Questionable values which are accepted anyway, such as timestamps ahead of the server time within the tolerance or clamped, unknown countries or descriptions close to the maximum length, are reported in the warnings of the response, which are absent if there are none. Returns an ApiResponse with the entry as stored, or an error message."
 )]
-#[post("/ip/<ip>/entry", data = "<entry>")]
+#[post("/ip/<ip>/entry?<template>&<vars>", data = "<entry>")]
+#[allow(clippy::too_many_arguments)]
 async fn ip_add_entry(
     ip: IpAddr,
-    entry: Json<Entry>,
-    db: &State<Arc<Mutex<redis::Client>>>,
-) -> ApiResult<bool> {
-    let mut db = db.lock().await;
-
-    let mut ipst = get_hip(ip, &mut db)
-        .inspect_err(|e| error!("failed to get data from db: {e}"))
-        .map_err(|_| api_error!("failed to get data from db"))?;
+    template: Option<bool>,
+    vars: HashMap<String, String>,
+    entry: Result<Body<Entry>, ApiError>,
+    principal: Principal,
+    config: &State<Config>,
+    events: &State<Events>,
+    hooks: &State<Hooks>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> Result<Warned<Entry>, ApiError> {
+    check_ip(ip, config)?;
 
     // we append entry
-    let mut entry = entry.0;
-    // we must create a new uuid
-    entry.uuid = Some(Uuid::new_v4());
-    let timestamp = entry.ctime.get_or_insert_with(Utc::now);
-
-    if ipst.history.contains_key(timestamp) {
-        return Err(api_error!(
-            "an entry with this timestamp is already present"
-        ));
+    let mut entry = entry?.0;
+    if template.unwrap_or_default() {
+        template::render_text(&mut entry.data, ip, &principal, &vars)?;
     }
+    entry.data.validate().map_err(|e| api_error!(e))?;
+    entry
+        .data
+        .normalize(config.strict_countries)
+        .map_err(|e| api_error!(e))?;
+    check_kind(&entry.data, config)?;
+    check_tags(entry.tags.iter().flatten(), config)?;
+    check_text(&mut entry, config)?;
+    let warnings = entry_warnings(&entry, config);
+    check_times(&mut entry, config)?;
+    check_links(db, &entry)?;
 
-    ipst.history.insert(*timestamp, entry);
+    let entry = add_entry(db, events, hooks, &principal, ip, entry)?;
 
-    store_hip(ipst, &mut db)
-        .inspect_err(|e| error!("failed to insert new ip: {e}"))
-        .map_err(|_| api_error!("failed to insert new ip"))?;
-
-    Ok(ApiData::Some(true))
+    Ok(Warned::new(ApiData::Some(entry), warnings))
 }
 
 #[utoipa::path(
@@ -282,195 +911,2583 @@ async fn ip_add_entry(
     request_body = Entry,
     params(
         ("ip" = String, Path, description = "The IP address"),
+        ("allow_kind_change" = Option<bool>, Query, description = "Accepts data of another kind than the one of the existing entry"),
     ),
     responses(
         (status = 200, description = "Entry update response", body = ApiResponse<bool>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
     ),
     tag = "IP Management",
-    description = "Updates an existing entry associated with an IP address. Returns an ApiResponse with a boolean indicating success or an error message."
+    description = "Updates an existing entry associated with an IP address. As consumers may filter entries by kind, data of another kind than the existing one is rejected unless explicitly allowed. Returns an ApiResponse with a boolean indicating success or an error message."
 )]
-#[post("/ip/<ip>/entry/update", data = "<entry>")]
+#[post("/ip/<ip>/entry/update?<allow_kind_change>", data = "<entry>")]
+#[allow(clippy::too_many_arguments)]
 async fn ip_update_entry(
     ip: IpAddr,
-    entry: Json<Entry>,
-    db: &State<Arc<Mutex<redis::Client>>>,
+    allow_kind_change: Option<bool>,
+    entry: Result<Body<Entry>, ApiError>,
+    principal: Principal,
+    config: &State<Config>,
+    events: &State<Events>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
 ) -> ApiResult<bool> {
-    let mut db = db.lock().await;
-
-    let mut ipst = get_hip(ip, &mut db)
-        .inspect_err(|e| error!("failed to get data from db: {e}"))
-        .map_err(|_| api_error!("failed to get data from db"))?;
+    let mut entry = entry?.0;
+    entry.data.validate().map_err(|e| api_error!(e))?;
+    entry
+        .data
+        .normalize(config.strict_countries)
+        .map_err(|e| api_error!(e))?;
+    check_kind(&entry.data, config)?;
+    check_tags(entry.tags.iter().flatten(), config)?;
+    check_text(&mut entry, config)?;
+    check_times(&mut entry, config)?;
+    check_links(db, &entry)?;
+    principal.check_write(&entry)?;
+    entry.mtime = Some(Utc::now());
 
-    let mut entry = entry.0;
+    let updated = db
+        .update_hip(ip, |ipst| {
+            // we search the key of an existing entry (by its uuid)
+            // searching by UUID allows changing the creation time
+            // without delete + create
+            let Some((key, prev)) = ipst.history.iter().find(|(_, v)| v.uuid == entry.uuid) else {
+                return Ok::<_, ApiError>(false);
+            };
 
-    // we search the key of an existing entry (by its uuid)
-    // searching by UUID allows changing the creation time
-    // without delete + create
-    let Some(key) = ipst
-        .history
-        .iter()
-        .find(|(_, v)| v.uuid == entry.uuid)
-        .map(|(k, _)| k)
-    else {
-        return Ok(ApiData::Some(false));
-    };
+            principal.check_write(prev)?;
+            check_kind_change(&prev.data, &entry.data, allow_kind_change)?;
 
-    entry.mtime = Some(Utc::now());
-    ipst.history.insert(*key, entry);
+            let key = *key;
+            ipst.history.insert(key, entry.clone());
+            Ok(true)
+        })
+        .map_err(|e| storage_error!(e, "failed to update entry"))??;
 
-    store_hip(ipst, &mut db)
-        .inspect_err(|e| error!("failed to insert new ip: {e}"))
-        .map_err(|_| api_error!("failed to insert new ip"))?;
+    if updated {
+        audit(
+            db,
+            AuditRecord::new(&principal, AuditAction::Update, ip).entry(&entry),
+        );
+        events.publish(AuditAction::Update, ip, entry);
+    }
 
-    Ok(ApiData::Some(true))
+    Ok(ApiData::Some(updated))
 }
 
 #[utoipa::path(
     context_path = API_MOUNTPOINT,
+    request_body = Entry,
     params(
         ("ip" = String, Path, description = "The IP address"),
-        ("kind" = Option<DataKind>, Query, description = "The kind of data to search for"),
-        ("limit" = Option<usize>, Query, description = "The maximum number of entries to return"),
-        ("offset" = Option<usize>, Query, description = "The number of entries to skip"),
-        ("order" = Option<SearchOrder>, Query, description = "The order in which to return the entries")
+        ("uuid" = Uuid, Path, description = "The UUID of the entry to create or update"),
+        ("allow_kind_change" = Option<bool>, Query, description = "Accepts data of another kind than the one of the existing entry"),
     ),
     responses(
-        (status = 200, description = "Entries retrieved successfully", body = ApiResponse<Vec<Entry>>, content_type = "application/json"),
+        (status = 200, description = "Entry upsert response", body = ApiResponse<Entry>, content_type = "application/json"),
+        (status = 409, description = "The UUID is already used by an entry of another IP address", body = ApiResponse<String>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
     ),
     tag = "IP Management",
-    description = "Searches for entries associated with an IP address based on the given criteria."
+    description = "Creates an entry with the given UUID if the IP address has none, or updates the existing one, so that clients generating their own UUIDs can safely retry. The creation time is set on creation, the modification time on update. As consumers may filter entries by kind, an update bringing data of another kind is rejected unless explicitly allowed. Returns an ApiResponse with the entry as stored or an error message."
 )]
-#[get("/ip/<ip>/entry/search?<kind>&<offset>&<limit>&<order>")]
-async fn ip_search_entry(
+#[put("/ip/<ip>/entry/<uuid>?<allow_kind_change>", data = "<entry>")]
+#[allow(clippy::too_many_arguments)]
+async fn ip_upsert_entry(
     ip: IpAddr,
-    kind: Option<DataKind>,
-    limit: Option<usize>,
-    offset: Option<usize>,
-    order: Option<SearchOrder>,
-    db: &State<Arc<Mutex<redis::Client>>>,
-) -> ApiResult<Vec<Entry>> {
-    let mut db = db.lock().await;
+    uuid: Uuid,
+    allow_kind_change: Option<bool>,
+    entry: Result<Body<Entry>, ApiError>,
+    principal: Principal,
+    config: &State<Config>,
+    events: &State<Events>,
+    hooks: &State<Hooks>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Entry> {
+    if uuid.is_nil() {
+        return Err(api_error!("entry uuid cannot be nil"));
+    }
 
-    let limit = limit.unwrap_or(usize::MAX);
-    let offset = offset.unwrap_or_default();
-    let order = order.unwrap_or(SearchOrder::Asc);
+    let mut entry = entry?.0;
+    if entry.uuid.is_some_and(|u| u != uuid) {
+        return Err(api_error!("entry uuid does not match the one of the path"));
+    }
+    entry.uuid = Some(uuid);
 
-    let ipst = get_hip(ip, &mut db).map_err(|_| api_error!("failed to get data from db"))?;
+    entry.data.validate().map_err(|e| api_error!(e))?;
 
-    let iter: Box<dyn Iterator<Item = _>> = match order {
-        SearchOrder::Asc => Box::new(ipst.history.iter()),
-        SearchOrder::Desc => Box::new(ipst.history.iter().rev()),
-    };
+    entry
+        .data
+        .normalize(config.strict_countries)
+        .map_err(|e| api_error!(e))?;
+    check_kind(&entry.data, config)?;
+    check_tags(entry.tags.iter().flatten(), config)?;
+    check_text(&mut entry, config)?;
+    check_times(&mut entry, config)?;
+    check_links(db, &entry)?;
+    principal.check_write(&entry)?;
 
-    let hist: Vec<Entry> = iter
-        // filter by kind
-        .filter(|(_, e)| {
-            if let Some(kind) = &kind {
-                &e.data.kind() == kind
-            } else {
-                true
+    if let Some(other) = db
+        .entry_ip(uuid)
+        .map_err(|e| storage_error!(e, "failed to resolve entry"))?
+        && other != ip
+    {
+        return Err(
+            api_error!(Status::Conflict, format!("entry {uuid} belongs to {other}"))
+                .with_code("uuid_conflict"),
+        );
+    }
+
+    let (created, entry) = db
+        .update_hip(ip, |ipst| {
+            let mut entry = entry.clone();
+
+            let existing = ipst.history.iter().find(|(_, e)| e.uuid == Some(uuid));
+            if let Some((key, prev)) = existing {
+                principal.check_write(prev)?;
+                check_kind_change(&prev.data, &entry.data, allow_kind_change)?;
+                let key = *key;
+                // an update keeps the creation time unless given another one
+                entry.ctime = entry.ctime.or(prev.ctime);
+                entry.mtime = Some(Utc::now());
+                ipst.history.insert(key, entry.clone());
+                return Ok((false, entry));
+            }
+
+            hooks.run(ip, &mut entry)?;
+            principal.check_write(&entry)?;
+            let timestamp = *entry.ctime.get_or_insert_with(Utc::now);
+            entry.mtime = None;
+            entry.severity = entry.severity.or(entry.data.default_severity());
+            if ipst.history.contains_key(&timestamp) {
+                return Err(
+                    api_error!("an entry with this timestamp is already present")
+                        .with_code("timestamp_conflict"),
+                );
             }
+            ipst.history.insert(timestamp, entry.clone());
+            Ok((true, entry))
         })
-        // start at offset
-        .skip(offset)
-        // take only limit
-        .take(limit)
-        .map(|(_, e)| e.clone())
-        .collect();
+        .map_err(|e| storage_error!(e, "failed to upsert entry"))??;
+
+    let action = if created {
+        AuditAction::Create
+    } else {
+        AuditAction::Update
+    };
+    audit(db, AuditRecord::new(&principal, action, ip).entry(&entry));
+    events.publish(action, ip, entry.clone());
 
-    Ok(ApiData::Some(hist))
+    Ok(ApiData::Some(entry))
 }
 
 #[utoipa::path(
     context_path = API_MOUNTPOINT,
+    request_body = Data,
     params(
         ("ip" = String, Path, description = "The IP address"),
-        ("uuid" = Option<Uuid>, Path, description = "The UUID of the entry to delete")
+        ("uuid" = Uuid, Path, description = "The UUID of the entry"),
+        ("allow_kind_change" = Option<bool>, Query, description = "Accepts data of another kind than the current one"),
     ),
     responses(
-        (status = 200, description = "Entry deletion response", body = ApiResponse<Entry>, content_type = "application/json"),
+        (status = 200, description = "Entry data replaced successfully", body = ApiResponse<Entry>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
     ),
     tag = "IP Management",
-    description = "Deletes an entry associated with an IP address. Returns an ApiResponse with an optional deleted entry data or an error message."
+    description = "Replaces the data of an entry, leaving its other fields untouched. As consumers may filter entries by kind, data of another kind is rejected unless explicitly allowed. Returns an ApiResponse with the updated entry, no data if the entry does not exist, or an error message."
 )]
-#[delete("/ip/<ip>/entry/<uuid>")]
-async fn ip_del_entry(
+#[put("/ip/<ip>/entry/<uuid>/data?<allow_kind_change>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn ip_entry_set_data(
     ip: IpAddr,
-    uuid: Option<Uuid>,
-    db: &State<Arc<Mutex<redis::Client>>>,
+    uuid: Uuid,
+    allow_kind_change: Option<bool>,
+    data: Result<Body<Data>, ApiError>,
+    principal: Principal,
+    config: &State<Config>,
+    events: &State<Events>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
 ) -> ApiResult<Entry> {
-    let mut db = db.lock().await;
+    let mut data = data?.0;
+    data.validate().map_err(|e| api_error!(e))?;
+    data.normalize(config.strict_countries)
+        .map_err(|e| api_error!(e))?;
+    check_kind(&data, config)?;
+    sanitize_data(&mut data, config);
 
-    let mut ipst = get_hip(ip, &mut db).map_err(|_| api_error!("failed to get data from db"))?;
+    let entry = db
+        .update_hip(ip, |ipst| {
+            let Some(entry) = ipst.entry_mut(uuid) else {
+                return Ok(None);
+            };
 
-    let Some(key) = ipst
-        .history
-        .iter()
-        .find(|(_, v)| v.uuid == uuid)
-        .map(|(k, _)| k)
-        .cloned()
-    else {
-        return Ok(ApiData::None);
-    };
+            principal.check_write(entry)?;
+            check_kind_change(&entry.data, &data, allow_kind_change)?;
 
-    Ok(ApiData::from(ipst.history.remove(&key)))
+            entry.data = data.clone();
+            entry.mtime = Some(Utc::now());
+            Ok(Some(entry.clone()))
+        })
+        .map_err(|e| storage_error!(e, "failed to update entry data"))??;
+
+    if let Some(entry) = &entry {
+        audit(
+            db,
+            AuditRecord::new(&principal, AuditAction::Update, ip).entry(entry),
+        );
+        events.publish(AuditAction::Update, ip, entry.clone());
+    }
+
+    Ok(ApiData::from(entry))
+}
+
+/// Query parameters of entry searches
+#[derive(Debug, Default, FromForm, Deserialize, ToSchema)]
+#[serde(default)]
+struct SearchQuery {
+    kind: Option<DataKind>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    order: Option<SearchOrder>,
+    sort_by: Option<SortBy>,
+    has_tags: Option<bool>,
+    has_description: Option<bool>,
+    /// Comma separated tags the entries must have
+    tags: Option<String>,
+    /// Whether entries must have any or all of the tags
+    tag_mode: Option<TagMode>,
+    /// Comma separated data fields the entries must have
+    has: Option<String>,
+    /// Comma separated data fields the entries must not have
+    missing: Option<String>,
+    /// JSONPath expression selecting at least one node of the
+    /// entries' JSON data
+    jsonpath: Option<String>,
+    /// Also returns the expired entries
+    include_expired: Option<bool>,
+    /// Minimum severity of the entries, entries without any never match
+    min_severity: Option<Severity>,
+    /// Minimum confidence of the entries once decayed by their age,
+    /// entries without any never match
+    min_confidence: Option<f64>,
+}
+
+/// Splits a comma separated list of fields
+fn fields(list: &Option<String>) -> Vec<&str> {
+    list.iter()
+        .flat_map(|l| l.split(','))
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .collect()
+}
+
+/// Entry found by a search, along with its confidence decayed by its
+/// age, the confidence stored being left as is
+#[derive(Debug, Serialize, ToSchema)]
+struct SearchHit {
+    #[serde(flatten)]
+    entry: Entry,
+    /// Confidence halved every confidence_half_life_secs since the last
+    /// modification of the entry, the stored one without half-life
+    #[serde(skip_serializing_if = "Option::is_none")]
+    effective_confidence: Option<f64>,
+}
+
+impl SearchHit {
+    fn new(entry: Entry, config: &Config, now: chrono::DateTime<Utc>) -> Self {
+        SearchHit {
+            effective_confidence: config.confidence_at(&entry, now),
+            entry,
+        }
+    }
+}
+
+/// Entries found by a search, or their summaries
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+enum SearchResults {
+    Entries(Vec<SearchHit>),
+    Summaries(Vec<EntrySummary>),
+    /// Entries along with the timestamp they are stored under
+    #[schema(value_type = Vec<(String, SearchHit)>)]
+    KeyedEntries(Vec<(chrono::DateTime<Utc>, SearchHit)>),
+    /// Summaries along with the timestamp their entry is stored under
+    #[schema(value_type = Vec<(String, EntrySummary)>)]
+    KeyedSummaries(Vec<(chrono::DateTime<Utc>, EntrySummary)>),
+}
+
+impl SearchQuery {
+    /// Predicate telling whether an entry matches the filters of the
+    /// query, paging and sorting left aside
+    fn matcher<'a>(&'a self, config: &'a Config) -> Result<impl Fn(&Entry) -> bool + 'a, ApiError> {
+        let SearchQuery {
+            kind,
+            has_tags,
+            has_description,
+            tags,
+            tag_mode,
+            has,
+            missing,
+            jsonpath,
+            include_expired,
+            min_severity,
+            min_confidence,
+            ..
+        } = self;
+        let (has, missing) = (fields(has), fields(missing));
+        // tags are normalized as they are on write
+        let tags = fields(tags)
+            .into_iter()
+            .map(Tag::try_from)
+            .collect::<Result<Vec<Tag>, _>>()
+            .map_err(|e| api_error!(format!("invalid tag: {e}")))?;
+        let tag_mode = tag_mode.unwrap_or_default();
+        let jsonpath = jsonpath
+            .as_deref()
+            .map(JsonPath::parse)
+            .transpose()
+            .map_err(|e| api_error!(format!("invalid jsonpath: {e}")))?;
+        let include_expired = include_expired.unwrap_or_default();
+        let min_confidence = min_confidence
+            .map(Confidence::try_from)
+            .transpose()
+            .map_err(|e| api_error!(format!("invalid min_confidence: {e}")))?
+            .map(Confidence::value);
+
+        let now = Utc::now();
+        Ok(move |e: &Entry| {
+            let has_tag = |t: &Tag| e.tags.as_ref().is_some_and(|et| et.contains(t));
+
+            // leave expired entries out
+            (include_expired || !config.is_expired(e, now))
+                // filter by kind
+                && kind.as_ref().is_none_or(|k| &e.data.kind() == k)
+                // filter by presence of the fields
+                && has_tags.is_none_or(|h| e.tags.as_ref().is_some_and(|t| !t.is_empty()) == h)
+                && has_description
+                    .is_none_or(|h| e.description.as_ref().is_some_and(|d| !d.is_empty()) == h)
+                && has.iter().all(|f| e.data.has_field(f))
+                && !missing.iter().any(|f| e.data.has_field(f))
+                // filter by tags
+                && (tags.is_empty()
+                    || match tag_mode {
+                        TagMode::Any => tags.iter().any(has_tag),
+                        TagMode::All => tags.iter().all(has_tag),
+                    })
+                // filter by severity
+                && min_severity.is_none_or(|min| e.severity.is_some_and(|s| s >= min))
+                // filter by decayed confidence
+                && min_confidence
+                    .is_none_or(|min| config.confidence_at(e, now).is_some_and(|c| c >= min))
+                // filter json data by path, other kinds never match
+                && match (&jsonpath, &e.data) {
+                    (None, _) => true,
+                    (Some(path), Data::Json(value)) => !path.query(value).is_empty(),
+                    (Some(_), _) => false,
+                }
+        })
+    }
+
+    /// Entries of `ipst` matching the criteria, paged and keyed by the
+    /// timestamp they are stored under, along with the number of entries
+    /// matching regardless of paging
+    fn run(&self, ipst: &IpStory, config: &Config) -> Result<(Vec<KeyedEntry>, usize), ApiError> {
+        let matches = self.matcher(config)?;
+
+        let limit = self
+            .limit
+            .unwrap_or(config.search_default_limit)
+            .min(config.search_max_limit);
+        let offset = self.offset.unwrap_or_default();
+        let order = self.order.as_ref().unwrap_or(&config.search_default_order);
+
+        let filtered = ipst.history.iter().filter(|(_, e)| matches(e));
+
+        let iter: Box<dyn Iterator<Item = _>> = match (&self.sort_by, order) {
+            (Some(sort_by), order) => {
+                let mut sorted: Vec<_> = filtered.collect();
+                // ties are broken by uuid so that pages are stable
+                sorted.sort_by(|(_, a), (_, b)| {
+                    sort_by.compare(a, b, order).then(a.uuid.cmp(&b.uuid))
+                });
+                Box::new(sorted.into_iter())
+            }
+            (None, SearchOrder::Asc) => Box::new(filtered),
+            (None, SearchOrder::Desc) => Box::new(filtered.rev()),
+        };
+
+        let matching: Vec<_> = iter.collect();
+        let total = matching.len();
+
+        let hist: Vec<KeyedEntry> = matching
+            .into_iter()
+            // start at offset
+            .skip(offset)
+            // take only limit
+            .take(limit)
+            .map(|(k, e)| (*k, e.clone()))
+            .collect();
+
+        Ok((hist, total))
+    }
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("kind" = Option<DataKind>, Query, description = "The kind of data to search for"),
+        ("limit" = Option<usize>, Query, description = "The maximum number of entries to return, defaults to the search_default_limit setting and is clamped to the search_max_limit one"),
+        ("offset" = Option<usize>, Query, description = "The number of entries to skip"),
+        ("order" = Option<SearchOrder>, Query, description = "The order in which to return the entries, defaults to the search_default_order setting"),
+        ("sort_by" = Option<SortBy>, Query, description = "The entry field to sort on, entries are sorted by timestamp if not set. Entries missing the field come last."),
+        ("has_tags" = Option<bool>, Query, description = "Only returns the entries having tags, or not having any if false"),
+        ("has_description" = Option<bool>, Query, description = "Only returns the entries having a description, or not having any if false"),
+        ("tags" = Option<String>, Query, description = "Comma separated tags the entries must have, normalized as tags are on write, ex: `botnet,scanner`"),
+        ("tag_mode" = Option<TagMode>, Query, description = "Whether the entries must have `all` the tags, by default, or `any` of them. It only combines the tags, the entries must still match all the other filters."),
+        ("has" = Option<String>, Query, description = "Comma separated fields of the data the entries must have, ex: `country,abuse` for owners"),
+        ("missing" = Option<String>, Query, description = "Comma separated fields of the data the entries must not have, ex: `country` for owners"),
+        ("jsonpath" = Option<String>, Query, description = "RFC 9535 JSONPath expression which must select at least one node of the data, ex: `$.tags[?@ == 'scanner']`. Only JSON entries can match it."),
+        ("include_expired" = Option<bool>, Query, description = "Also returns the entries past their expiration time, given by their expires_at or the retention_secs setting, which are left out by default"),
+        ("min_severity" = Option<Severity>, Query, description = "Only returns the entries at least this severe, entries without severity are left out"),
+        ("min_confidence" = Option<f64>, Query, description = "Only returns the entries whose confidence, halved every confidence_half_life_secs since their last modification, is at least this value between 0 and 1, entries without confidence are left out"),
+        ("summary" = Option<bool>, Query, description = "Returns summaries of the entries, without their data, instead of the full entries"),
+        ("include_key" = Option<bool>, Query, description = "Returns `[timestamp, entry]` pairs instead of bare entries, the timestamp being the key the entry is stored under in the history. It can differ from the ctime of the entry when several entries were created at the same time."),
+    ),
+    responses(
+        (status = 200, description = "Entries retrieved successfully", body = ApiResponse<SearchResults>, content_type = "application/json",
+            headers(
+                ("Last-Modified" = String, description = "Most recent creation or modification time of the entries of the IP address"),
+                ("X-Total-Count" = usize, description = "Number of entries matching the criteria, regardless of offset and limit"),
+                ("X-Truncated" = bool, description = "Whether entries matching the criteria remain after the returned ones"),
+            )),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Searches for entries associated with an IP address based on the given criteria. All the filters must match, filters which are not given match any entry. Data fields are considered missing when they are null or empty. Filters apply before sorting and paging, so X-Total-Count is the number of entries matching all of them. Entries carrying a confidence are returned with their effective_confidence, decayed by their age as min_confidence sees it."
+)]
+#[get("/ip/<ip>/entry/search?<summary>&<include_key>&<query..>")]
+async fn ip_search_entry(
+    ip: IpAddr,
+    summary: Option<bool>,
+    include_key: Option<bool>,
+    query: SearchQuery,
+    principal: Principal,
+    config: &State<Config>,
+    db: &State<Arc<Storage>>,
+) -> Result<WithHeaders<ApiData<SearchResults>>, ApiError> {
+    let ipst = db
+        .get_hip(ip)
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?
+        .readable_by(&principal);
+
+    let offset = query.offset.unwrap_or_default();
+    let (hist, total) = query.run(&ipst, config)?;
+    let truncated = offset.saturating_add(hist.len()) < total;
+
+    let now = Utc::now();
+    let hit = |e| SearchHit::new(e, config, now);
+    let results = match (summary.unwrap_or_default(), include_key.unwrap_or_default()) {
+        (false, false) => SearchResults::Entries(hist.into_iter().map(|(_, e)| hit(e)).collect()),
+        (true, false) => {
+            SearchResults::Summaries(hist.iter().map(|(_, e)| EntrySummary::from(e)).collect())
+        }
+        (false, true) => {
+            SearchResults::KeyedEntries(hist.into_iter().map(|(k, e)| (k, hit(e))).collect())
+        }
+        (true, true) => SearchResults::KeyedSummaries(
+            hist.iter()
+                .map(|(k, e)| (*k, EntrySummary::from(e)))
+                .collect(),
+        ),
+    };
+
+    Ok(WithHeaders::new(ApiData::Some(results))
+        .last_modified(ipst.mtime())
+        .header(Header::new("X-Total-Count", total.to_string()))
+        .header(Header::new("X-Truncated", truncated.to_string())))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("kind" = Option<DataKind>, Query, description = "The kind of data to search for"),
+        ("has_tags" = Option<bool>, Query, description = "Only returns the entries having tags, or not having any if false"),
+        ("has_description" = Option<bool>, Query, description = "Only returns the entries having a description, or not having any if false"),
+        ("tags" = Option<String>, Query, description = "Comma separated tags the entries must have, normalized as tags are on write, ex: `botnet,scanner`"),
+        ("tag_mode" = Option<TagMode>, Query, description = "Whether the entries must have `all` the tags, by default, or `any` of them. It only combines the tags, the entries must still match all the other filters."),
+        ("has" = Option<String>, Query, description = "Comma separated fields of the data the entries must have, ex: `country,abuse` for owners"),
+        ("missing" = Option<String>, Query, description = "Comma separated fields of the data the entries must not have, ex: `country` for owners"),
+        ("jsonpath" = Option<String>, Query, description = "RFC 9535 JSONPath expression which must select at least one node of the data, ex: `$.tags[?@ == 'scanner']`. Only JSON entries can match it."),
+        ("include_expired" = Option<bool>, Query, description = "Also returns the entries past their expiration time, given by their expires_at or the retention_secs setting, which are left out by default"),
+        ("min_severity" = Option<Severity>, Query, description = "Only returns the entries at least this severe, entries without severity are left out"),
+        ("min_confidence" = Option<f64>, Query, description = "Only returns the entries whose confidence, halved every confidence_half_life_secs since their last modification, is at least this value between 0 and 1, entries without confidence are left out"),
+    ),
+    responses(
+        (status = 200, description = "Entries counted successfully", body = ApiResponse<usize>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Counts the entries of an IP address matching the filters of `GET /ip/{ip}/entry/search`, for badges and pagination, without returning them. Paging and sorting parameters are ignored. Returns an ApiResponse with the number of matching entries, 0 if the IP address is not tracked, or an error message."
+)]
+#[get("/ip/<ip>/entry/count?<query..>")]
+async fn ip_entry_count(
+    ip: IpAddr,
+    query: SearchQuery,
+    principal: Principal,
+    config: &State<Config>,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<usize> {
+    let matches = query.matcher(config)?;
+
+    let ipst = match db.get_hip(ip) {
+        Ok(ipst) => ipst,
+        Err(StorageError::NotFound(_)) => return Ok(ApiData::Some(0)),
+        Err(e) => return Err(storage_error!(e, "failed to get data from db")),
+    };
+
+    Ok(ApiData::Some(
+        ipst.history
+            .values()
+            .filter(|e| principal.can_read(e) && matches(e))
+            .count(),
+    ))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("since" = Option<u64>, Query, description = "The revision of the story the changes are asked from, every entry is returned if unset"),
+    ),
+    responses(
+        (status = 200, description = "Changes retrieved successfully", body = ApiResponse<Changes>, content_type = "application/json"),
+        (status = 410, description = "The changes since the revision are not known, the whole history must be fetched again", body = ApiResponse<String>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Retrieves the changes of the history of an IP address since a revision, to replicate it incrementally. Every story has a revision, starting at 0 when the IP address gets tracked and incremented every time the story changes, whatever the way (API, imports, enrichments, pruning). Entries carry the revision they were last created or modified at. Without since, every entry is returned along with the current revision, later requests then ask for the changes since the revision they got: the entries created or modified after it, expired ones included, and the UUIDs of the ones deleted. Only the last 1000 deletions of a story are remembered, and the revision restarts at 0 when an IP address is tracked again or restored from an older backup, in which case the request fails with the revision_expired code and the client has to fetch the whole history again. Returns an ApiResponse with the current revision and the changes, or an error message."
+)]
+#[get("/ip/<ip>/entry/changes?<since>")]
+async fn ip_entry_changes(
+    ip: IpAddr,
+    since: Option<u64>,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Changes> {
+    let ipst = db
+        .get_hip(ip)
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?
+        .readable_by(&principal);
+
+    Ok(ApiData::Some(ipst.changes(since)?))
+}
+
+/// IP addresses to search at once, along with the criteria
+/// applied to each of them
+#[derive(Debug, Deserialize, ToSchema)]
+struct BatchSearch {
+    #[schema(value_type = Vec<String>)]
+    ips: Vec<IpAddr>,
+    #[serde(flatten)]
+    query: SearchQuery,
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    request_body(content = BatchSearch, description = "The IP addresses to search and the criteria of `GET /ip/{ip}/entry/search`, as JSON fields", content_type = "application/json"),
+    responses(
+        (status = 200, description = "Entries retrieved successfully", body = ApiResponse<BTreeMap<String, Vec<SearchHit>>>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Searches for the entries of several IP addresses at once, fetching their stories in a single round trip to the store. The criteria apply to every IP address as they do for a single one, limit and offset included. At most search_batch_max_ips addresses can be searched at once. Returns an ApiResponse with the matching entries of every tracked IP address, untracked ones being left out, or an error message."
+)]
+#[post("/ip/search", data = "<search>")]
+async fn ip_batch_search(
+    search: Result<Body<BatchSearch>, ApiError>,
+    principal: Principal,
+    config: &State<Config>,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<BTreeMap<IpAddr, Vec<SearchHit>>> {
+    let BatchSearch { ips, query } = search?.0;
+    let ips: Vec<IpAddr> = ips
+        .into_iter()
+        .map(|ip| config.ipv4_mapped.unmap(ip))
+        .collect();
+
+    if ips.len() > config.search_batch_max_ips {
+        return Err(api_error!(format!(
+            "too many ip addresses: {} > {}",
+            ips.len(),
+            config.search_batch_max_ips
+        )));
+    }
+
+    let hips = db
+        .get_hips(&ips)
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?;
+
+    let now = Utc::now();
+    let mut entries = BTreeMap::new();
+    for (ip, ipst) in hips {
+        entries.insert(
+            ip,
+            query
+                .run(&ipst.readable_by(&principal), config)?
+                .0
+                .into_iter()
+                .map(|(_, e)| SearchHit::new(e, config, now))
+                .collect(),
+        );
+    }
+
+    Ok(ApiData::Some(entries))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    request_body(content = Vec<Uuid>, description = "The UUIDs of the entries to fetch", content_type = "application/json"),
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+    ),
+    responses(
+        (status = 200, description = "Entries retrieved successfully", body = ApiResponse<Vec<Entry>>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Retrieves the current state of a set of entries of an IP address at once, ex: to refresh the entries of a previous search cached by a client. Entries are returned in the order of the UUIDs, duplicated UUIDs being returned once, and the entries which are no longer present, or expired, are left out. At most entry_fetch_max_uuids UUIDs can be fetched at once. Returns an ApiResponse with the entries, none if the IP address is not tracked, or an error message."
+)]
+#[post("/ip/<ip>/entry/fetch", data = "<uuids>")]
+async fn ip_entry_fetch(
+    ip: IpAddr,
+    uuids: Result<Body<Vec<Uuid>>, ApiError>,
+    principal: Principal,
+    config: &State<Config>,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Vec<Entry>> {
+    let uuids = uuids?.0;
+
+    if uuids.len() > config.entry_fetch_max_uuids {
+        return Err(api_error!(format!(
+            "too many uuids: {} > {}",
+            uuids.len(),
+            config.entry_fetch_max_uuids
+        )));
+    }
+
+    let ipst = match db.get_hip(ip) {
+        Ok(ipst) => ipst,
+        Err(StorageError::NotFound(_)) => return Ok(ApiData::Some(vec![])),
+        Err(e) => return Err(storage_error!(e, "failed to get data from db")),
+    };
+
+    let now = Utc::now();
+    let mut by_uuid: HashMap<Uuid, Entry> = ipst
+        .history
+        .into_values()
+        .filter(|e| principal.can_read(e) && !config.is_expired(e, now))
+        .filter_map(|e| Some((e.uuid?, e)))
+        .collect();
+
+    // entries are removed as they are taken so duplicates are returned once
+    Ok(ApiData::Some(
+        uuids.iter().filter_map(|u| by_uuid.remove(u)).collect(),
+    ))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("kind" = Option<DataKind>, Query, description = "The kind of data the entry must hold"),
+    ),
+    responses(
+        (status = 200, description = "Entry retrieved successfully", body = ApiResponse<Entry>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Retrieves the most recent entry of an IP address, by timestamp, optionally of a given kind. Expired entries are left out. Returns an ApiResponse with the entry, no data if none matches, or an error message."
+)]
+#[get("/ip/<ip>/latest?<kind>")]
+async fn ip_latest(
+    ip: IpAddr,
+    kind: Option<DataKind>,
+    principal: Principal,
+    config: &State<Config>,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Entry> {
+    let ipst = db
+        .get_hip(ip)
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?
+        .readable_by(&principal);
+
+    let now = Utc::now();
+    Ok(ApiData::from(ipst.history.into_values().rev().find(|e| {
+        !config.is_expired(e, now) && kind.as_ref().is_none_or(|k| &e.data.kind() == k)
+    })))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("uuid_prefix" = String, Query, description = "The first hex digits of the UUID of the entry, dashes are ignored, at least 4 digits must be given"),
+        ("first" = Option<bool>, Query, description = "Returns the oldest of the matching entries instead of failing when several match"),
+    ),
+    responses(
+        (status = 200, description = "Entry retrieved successfully", body = ApiResponse<Entry>, content_type = "application/json"),
+        (status = 409, description = "Several entries match the prefix while first is not set", body = ApiResponse<String>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Retrieves an entry of an IP address from the beginning of its UUID, as copied from logs. Returns an ApiResponse with the entry, no data if none matches, or an error message listing the matching UUIDs if the prefix is ambiguous."
+)]
+#[get("/ip/<ip>/entry?<uuid_prefix>&<first>")]
+async fn ip_entry_by_prefix(
+    ip: IpAddr,
+    uuid_prefix: &str,
+    first: Option<bool>,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Entry> {
+    let prefix = uuid_prefix.replace('-', "").to_ascii_lowercase();
+    if !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(api_error!(format!("invalid uuid prefix: {uuid_prefix}")));
+    }
+    if prefix.len() < UUID_PREFIX_MIN_LEN {
+        return Err(api_error!(format!(
+            "uuid prefix must have at least {UUID_PREFIX_MIN_LEN} hex digits"
+        )));
+    }
+
+    let ipst = db
+        .get_hip(ip)
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?
+        .readable_by(&principal);
+
+    let mut matching: Vec<Entry> = ipst
+        .history
+        .into_values()
+        .filter(|e| {
+            e.uuid
+                .is_some_and(|u| u.simple().to_string().starts_with(&prefix))
+        })
+        .collect();
+
+    if matching.len() > 1 && !first.unwrap_or_default() {
+        let uuids: Vec<String> = matching
+            .iter()
+            .filter_map(|e| e.uuid)
+            .map(|u| u.to_string())
+            .collect();
+        return Err(api_error!(
+            Status::Conflict,
+            format!(
+                "{uuid_prefix} is ambiguous, it matches {}",
+                uuids.join(", ")
+            )
+        ));
+    }
+
+    Ok(ApiData::from(
+        (!matching.is_empty()).then(|| matching.remove(0)),
+    ))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+    ),
+    responses(
+        (status = 200, description = "Modification time retrieved successfully", body = ApiResponse<String>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Retrieves the most recent creation or modification time of the entries of an IP address the client can read, cheaply telling clients whether something changed. Returns an ApiResponse with the timestamp, no data if the history is empty, or an error message."
+)]
+#[get("/ip/<ip>/mtime")]
+async fn ip_mtime(
+    ip: IpAddr,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<chrono::DateTime<Utc>> {
+    let ipst = db
+        .get_hip(ip)
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?;
+
+    Ok(ApiData::from(ipst.readable_by(&principal).mtime()))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+    ),
+    responses(
+        (status = 200, description = "Number of entries retrieved successfully", body = ApiResponse<usize>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Retrieves the number of entries of an IP address without loading its history, unless the API key of the client only reads some classifications, in which case only the entries it can read are counted. Returns an ApiResponse with the number of entries, no data if the IP address is not tracked, or an error message."
+)]
+#[get("/ip/<ip>/count")]
+async fn ip_count(ip: IpAddr, principal: Principal, db: &State<Arc<Storage>>) -> ApiResult<usize> {
+    let count = db
+        .entry_count(ip)
+        .map_err(|e| storage_error!(e, "failed to count entries"))?;
+
+    // the index counts the entries of every classification
+    if count.is_some() && principal.read_restriction().is_some() {
+        let ipst = db
+            .get_hip(ip)
+            .map_err(|e| storage_error!(e, "failed to get data from db"))?;
+        return Ok(ApiData::Some(ipst.readable_by(&principal).history.len()));
+    }
+
+    Ok(ApiData::from(count))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("uuid" = Uuid, Path, description = "The UUID of the entry to delete, it cannot be nil"),
+        ("force" = Option<bool>, Query, description = "Deletes the entry even if other entries link to it"),
+        ("dry_run" = Option<bool>, Query, description = "Returns the entry which would be deleted without deleting it"),
+        ("If-Match" = Option<String>, Header, description = "Modification time the entry is expected to have, as returned in its mtime (its ctime if it was never modified), the deletion failing with a 412 Precondition Failed if it changed since"),
+    ),
+    responses(
+        (status = 200, description = "Entry deletion response", body = ApiResponse<Entry>, content_type = "application/json"),
+        (status = 409, description = "The entry is linked by other entries or its UUID is shared by several entries", body = ApiResponse<String>, content_type = "application/json"),
+        (status = 412, description = "The entry does not match the `If-Match` header", body = ApiResponse<String>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Deletes an entry associated with an IP address. Entries linked by other entries are only deleted when forced, otherwise a 409 Conflict is returned. If several entries share the UUID none is deleted and a 409 Conflict is returned, the check endpoint of the IP address reports them. With an `If-Match` header holding the modification time the client last read, the entry is only deleted if it was not modified since, otherwise a 412 Precondition Failed with the entry_modified code is returned, and so it is if the entry does not exist; `If-Match: *` only requires the entry to exist. A dry run goes through the same checks but leaves the entry in place, writing no audit record and publishing no event. Returns an ApiResponse with an optional deleted entry data or an error message."
+)]
+#[delete("/ip/<ip>/entry/<uuid>?<force>&<dry_run>")]
+#[allow(clippy::too_many_arguments)]
+async fn ip_del_entry(
+    ip: IpAddr,
+    uuid: Uuid,
+    force: bool,
+    dry_run: bool,
+    if_match: Result<IfMatch, ApiError>,
+    principal: Principal,
+    events: &State<Events>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Entry> {
+    let if_match = if_match?;
+    check_deletable(uuid)?;
+
+    if !force {
+        let backlinks = db
+            .backlinks(uuid)
+            .map_err(|e| storage_error!(e, "failed to get entry backlinks"))?;
+        if !backlinks.is_empty() {
+            return Err(api_error!(
+                Status::Conflict,
+                format!("entry is linked by {backlinks:?}, use force to delete it anyway")
+            ));
+        }
+    }
+
+    let deleted = db
+        .update_hip(ip, |ipst| {
+            take_entry(ipst, uuid, &if_match, &principal, dry_run)
+        })
+        .map_err(|e| storage_error!(e, "failed to delete entry"))??;
+
+    record_deletions(db, events, &principal, ip, &deleted, dry_run);
+
+    Ok(ApiData::from(deleted))
+}
+
+/// Removes the entry with the given `uuid` from `ipst` and returns it,
+/// after checking that the client can delete it. A dry run only returns
+/// it, and a story left unchanged is not stored.
+fn take_entry(
+    ipst: &mut IpStory,
+    uuid: Uuid,
+    if_match: &IfMatch,
+    principal: &Principal,
+    dry_run: bool,
+) -> Result<Option<Entry>, ApiError> {
+    let key = deletion_key(ipst, uuid)?;
+    let current = key.and_then(|k| ipst.history.get(&k));
+    check_if_match(if_match, current, uuid)?;
+    if let Some(current) = current {
+        principal.check_write(current)?;
+    }
+
+    Ok(key.and_then(|k| {
+        if dry_run {
+            ipst.history.get(&k).cloned()
+        } else {
+            ipst.history.remove(&k)
+        }
+    }))
+}
+
+/// Records the deletion of `entries` from the story of `ip` in the audit
+/// trail and publishes it, unless it is a dry run and nothing got deleted
+fn record_deletions<'a>(
+    db: &Storage,
+    events: &Events,
+    principal: &Principal,
+    ip: IpAddr,
+    entries: impl IntoIterator<Item = &'a Entry>,
+    dry_run: bool,
+) {
+    if dry_run {
+        return;
+    }
+    for entry in entries {
+        audit(
+            db,
+            AuditRecord::new(principal, AuditAction::Delete, ip).entry(entry),
+        );
+        events.publish(AuditAction::Delete, ip, entry.clone());
+    }
+}
+
+/// Checks that `uuid` can designate an entry to delete, the nil UUID
+/// being the one of entries stored without any
+fn check_deletable(uuid: Uuid) -> Result<(), ApiError> {
+    if uuid.is_nil() {
+        return Err(api_error!("entry uuid cannot be nil"));
+    }
+    Ok(())
+}
+
+/// Key of the entry of `ipst` with the given `uuid`, failing when several
+/// entries share it so that none gets deleted
+fn deletion_key(ipst: &IpStory, uuid: Uuid) -> Result<Option<chrono::DateTime<Utc>>, ApiError> {
+    check_deletable(uuid)?;
+
+    let keys: Vec<_> = ipst
+        .history
+        .iter()
+        .filter(|(_, v)| v.uuid == Some(uuid))
+        .map(|(k, _)| *k)
+        .collect();
+
+    if keys.len() > 1 {
+        return Err(api_error!(
+            Status::Conflict,
+            format!(
+                "{} entries share uuid {uuid}, none is deleted, see /ip/{}/check",
+                keys.len(),
+                ipst.ip
+            )
+        )
+        .with_code("uuid_conflict"));
+    }
+    Ok(keys.first().copied())
+}
+
+/// Entries a bulk tag operation applies to, every entry if unset
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default)]
+struct TagFilter {
+    /// Kind of data the entries must hold
+    kind: Option<DataKind>,
+    /// Tag the entries must have
+    tag: Option<Tag>,
+    /// Time the entries must have been created before
+    before: Option<chrono::DateTime<Utc>>,
+}
+
+impl TagFilter {
+    fn matches(&self, entry: &Entry) -> bool {
+        self.kind.as_ref().is_none_or(|k| &entry.data.kind() == k)
+            && self
+                .tag
+                .as_ref()
+                .is_none_or(|t| entry.tags.as_ref().is_some_and(|tags| tags.contains(t)))
+            && self
+                .before
+                .is_none_or(|b| entry.ctime.is_some_and(|c| c < b))
+    }
+}
+
+/// Tags added to and removed from the entries of an IP address
+#[derive(Debug, Deserialize, ToSchema)]
+struct BulkTags {
+    #[serde(default)]
+    add: Vec<Tag>,
+    #[serde(default)]
+    remove: Vec<Tag>,
+    #[serde(default, rename = "where")]
+    filter: TagFilter,
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    request_body(content = BulkTags, description = "The tags to add and remove, and the entries to modify", content_type = "application/json"),
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+    ),
+    responses(
+        (status = 200, description = "Tags changed successfully", body = ApiResponse<usize>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Adds and removes tags on every entry of an IP address matching the where filter, ex: `{\"add\": [\"legacy\"], \"where\": {\"kind\": \"vulnerable\"}}`, in a single write to the store. The filter selects the entries holding data of a kind, having a tag and created before a time, each criterion being optional. Tags are normalized before being applied and a tag cannot be both added and removed. Only the entries whose tags change are modified, getting a new modification time, and the entries of classifications the API key cannot write are left untouched. Returns an ApiResponse with the number of entries modified, or an error message."
+)]
+#[post("/ip/<ip>/tags/bulk", data = "<bulk>")]
+#[allow(clippy::too_many_arguments)]
+async fn ip_bulk_tags(
+    ip: IpAddr,
+    bulk: Result<Body<BulkTags>, ApiError>,
+    principal: Principal,
+    config: &State<Config>,
+    events: &State<Events>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<usize> {
+    let BulkTags {
+        add,
+        remove,
+        filter,
+    } = bulk?.0;
+    check_tags(&add, config)?;
+    if let Some(tag) = add.iter().find(|t| remove.contains(t)) {
+        return Err(api_error!(format!(
+            "tag {} cannot be both added and removed",
+            tag.as_str()
+        )));
+    }
+
+    let modified = db
+        .update_hip(ip, |ipst| {
+            let now = Utc::now();
+            let mut modified = vec![];
+            let writable = ipst
+                .history
+                .values_mut()
+                .filter(|e| principal.check_write(e).is_ok());
+            for entry in writable.filter(|e| filter.matches(e)) {
+                let mut tags = entry.tags.clone().unwrap_or_default();
+                tags.extend(add.iter().cloned());
+                tags.retain(|t| !remove.contains(t));
+
+                if tags != entry.tags.clone().unwrap_or_default() {
+                    entry.tags = (!tags.is_empty()).then_some(tags);
+                    entry.mtime = Some(now);
+                    modified.push(entry.clone());
+                }
+            }
+            Ok::<_, ApiError>(modified)
+        })
+        .map_err(|e| storage_error!(e, "failed to update entry tags"))??;
+
+    for entry in &modified {
+        audit(
+            db,
+            AuditRecord::new(&principal, AuditAction::Update, ip).entry(entry),
+        );
+        events.publish(AuditAction::Update, ip, entry.clone());
+    }
+
+    Ok(ApiData::Some(modified.len()))
+}
+
+/// Applies `f` on the tags of an entry, the entry modification time is
+/// updated only if its tags changed. Returns the resulting tags or None
+/// if the entry does not exist.
+fn update_tags(
+    db: &Storage,
+    events: &Events,
+    ip: IpAddr,
+    uuid: Uuid,
+    principal: &Principal,
+    f: impl Fn(&mut HashSet<Tag>),
+) -> Result<Option<HashSet<Tag>>, ApiError> {
+    // the modified entry is returned by the update, which may run several
+    // times when the story changes concurrently
+    let Some((tags, modified)) = db
+        .update_hip(ip, |ipst| {
+            if let Some(entry) = ipst.entry_mut(uuid) {
+                principal.check_write(entry)?;
+            }
+            Ok::<_, ApiError>(ipst.retag(uuid, &f, Utc::now()))
+        })
+        .map_err(|e| storage_error!(e, "failed to update entry tags"))??
+    else {
+        return Ok(None);
+    };
+
+    if let Some(entry) = &modified {
+        audit(
+            db,
+            AuditRecord::new(principal, AuditAction::Update, ip).entry(entry),
+        );
+        events.publish(AuditAction::Update, ip, entry.clone());
+    }
+
+    Ok(Some(tags))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    request_body = Vec<String>,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("uuid" = Uuid, Path, description = "The UUID of the entry"),
+    ),
+    responses(
+        (status = 200, description = "Tags added successfully", body = ApiResponse<Vec<String>>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Adds tags to an entry, adding an already present tag is a no-op. Returns an ApiResponse with the resulting tags of the entry, no data if the entry does not exist, or an error message."
+)]
+#[post("/ip/<ip>/entry/<uuid>/tags", data = "<tags>")]
+#[allow(clippy::too_many_arguments)]
+async fn ip_entry_add_tags(
+    ip: IpAddr,
+    uuid: Uuid,
+    tags: Result<Body<Vec<Tag>>, ApiError>,
+    principal: Principal,
+    config: &State<Config>,
+    events: &State<Events>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<HashSet<Tag>> {
+    let new = tags?.0;
+    check_tags(&new, config)?;
+
+    let tags = update_tags(db, events, ip, uuid, &principal, |tags| {
+        tags.extend(new.iter().cloned())
+    })?;
+
+    Ok(ApiData::from(tags))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("uuid" = Uuid, Path, description = "The UUID of the entry"),
+        ("tag" = String, Path, description = "The tag to remove"),
+    ),
+    responses(
+        (status = 200, description = "Tag removed successfully", body = ApiResponse<Vec<String>>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Removes a tag from an entry, removing an absent tag is a no-op. Returns an ApiResponse with the resulting tags of the entry, no data if the entry does not exist, or an error message."
+)]
+#[delete("/ip/<ip>/entry/<uuid>/tags/<tag>")]
+async fn ip_entry_del_tag(
+    ip: IpAddr,
+    uuid: Uuid,
+    tag: String,
+    principal: Principal,
+    events: &State<Events>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<HashSet<Tag>> {
+    let tag = Tag::try_from(tag).map_err(|e| api_error!(e))?;
+
+    let tags = update_tags(db, events, ip, uuid, &principal, |tags| {
+        tags.remove(&tag);
+    })?;
+
+    Ok(ApiData::from(tags))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+    ),
+    responses(
+        (status = 200, description = "History checked successfully", body = ApiResponse<CheckReport>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Checks the entries of an IP address the client can read for inconsistencies: duplicate UUIDs, entries without UUID and entries modified before being created. Returns an ApiResponse with a report of the issues found or an error message."
+)]
+#[get("/ip/<ip>/check")]
+async fn ip_check(
+    ip: IpAddr,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<CheckReport> {
+    let ipst = db
+        .get_hip(ip)
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?;
+
+    Ok(ApiData::Some(ipst.readable_by(&principal).check()))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+    ),
+    responses(
+        (status = 200, description = "Notes retrieved successfully", body = ApiResponse<serde_json::Value>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Retrieves the notes kept about an IP address itself, ex: `{\"note\": \"known corporate VPN egress\"}`, which are not part of its history. Notes have no classification, every client can read them. Returns an ApiResponse with the notes, no data if none were set, or an error message."
+)]
+#[get("/ip/<ip>/meta")]
+async fn ip_meta(
+    ip: IpAddr,
+    _principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<serde_json::Value> {
+    let ipst = db
+        .get_hip(ip)
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?;
+
+    Ok(ApiData::from(ipst.meta))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+    ),
+    request_body(content = Option<serde_json::Value>, description = "The notes, any JSON value, null removing them", content_type = "application/json"),
+    responses(
+        (status = 200, description = "Notes set successfully", body = ApiResponse<serde_json::Value>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Sets the notes kept about an IP address itself, replacing the previous ones, for durable context such as `{\"note\": \"known corporate VPN egress\"}` which is not an event of its history. The notes are stored along with the history, so they count in the maximum size of the story, are exported and restored with it, and are deleted with the IP address, but they are neither entries nor searched. A null body removes them. Returns an ApiResponse with the notes, no data if they got removed, or an error message."
+)]
+#[put("/ip/<ip>/meta", data = "<meta>")]
+async fn ip_set_meta(
+    ip: IpAddr,
+    meta: Result<Body<Option<serde_json::Value>>, ApiError>,
+    principal: Principal,
+    config: &State<Config>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<serde_json::Value> {
+    check_ip(ip, config)?;
+    let meta = meta?.0;
+
+    let changed = db
+        .update_hip(ip, |ipst| {
+            let changed = ipst.meta != meta;
+            ipst.meta = meta.clone();
+            Ok::<_, std::convert::Infallible>(changed)
+        })
+        .map_err(|e| storage_error!(e, "failed to set notes"))?
+        .unwrap_or_else(|e| match e {});
+
+    if changed {
+        audit(db, AuditRecord::new(&principal, AuditAction::Update, ip));
+    }
+
+    Ok(ApiData::from(meta))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("uuid" = Uuid, Path, description = "The UUID of the entry"),
+    ),
+    responses(
+        (status = 200, description = "Entry touched successfully", body = ApiResponse<Entry>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Sets the modification time of an entry to the current time without changing its content, to flag it as still valid. Touching an entry again only moves its modification time forward. Returns an ApiResponse with the entry, no data if it does not exist, or an error message."
+)]
+#[post("/ip/<ip>/entry/<uuid>/touch")]
+async fn ip_entry_touch(
+    ip: IpAddr,
+    uuid: Uuid,
+    principal: Principal,
+    events: &State<Events>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Entry> {
+    let entry = db
+        .update_hip(ip, |ipst| {
+            if let Some(entry) = ipst.entry_mut(uuid) {
+                principal.check_write(entry)?;
+            }
+            Ok::<_, ApiError>(ipst.touch(uuid, Utc::now()).cloned())
+        })
+        .map_err(|e| storage_error!(e, "failed to touch entry"))??;
+
+    if let Some(entry) = &entry {
+        audit(
+            db,
+            AuditRecord::new(&principal, AuditAction::Update, ip).entry(entry),
+        );
+        events.publish(AuditAction::Update, ip, entry.clone());
+    }
+
+    Ok(ApiData::from(entry))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address the entry belongs to"),
+        ("uuid" = Uuid, Path, description = "The UUID of the entry"),
+        ("dst" = String, Path, description = "The IP address the entry is copied to"),
+        ("link" = Option<bool>, Query, description = "Links the copy to the copied entry"),
+    ),
+    responses(
+        (status = 200, description = "Entry copied successfully", body = ApiResponse<Entry>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Copies an entry to another IP address, tracking it if needed, when the same observation applies to several addresses. The copy keeps the data, description, tags, links, severity, classification and expiration of the entry, and gets a new UUID and the current time as creation and modification times. With link, the copy also links to the copied entry. The copy is a new entry of the destination, so the entry hooks run on it. Returns an ApiResponse with the copy, no data if the entry does not exist, or an error message."
+)]
+#[post("/ip/<ip>/entry/<uuid>/copy/<dst>?<link>")]
+#[allow(clippy::too_many_arguments)]
+async fn ip_entry_copy(
+    ip: IpAddr,
+    uuid: Uuid,
+    dst: IpAddr,
+    link: Option<bool>,
+    principal: Principal,
+    config: &State<Config>,
+    events: &State<Events>,
+    hooks: &State<Hooks>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Entry> {
+    let dst = config.ipv4_mapped.unmap(dst);
+    check_ip(dst, config)?;
+
+    let ipst = db
+        .get_hip(ip)
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?
+        .readable_by(&principal);
+    let Some(mut entry) = ipst.history.into_values().find(|e| e.uuid == Some(uuid)) else {
+        return Ok(ApiData::None);
+    };
+    check_kind(&entry.data, config)?;
+
+    if link.unwrap_or_default() {
+        let links = entry.links.get_or_insert_default();
+        if !links.contains(&uuid) {
+            links.push(uuid);
+        }
+    }
+    let now = Utc::now();
+    entry.ctime = Some(now);
+    entry.mtime = Some(now);
+
+    if db
+        .create_hip(IpStory::new(dst))
+        .map_err(|e| storage_error!(e, "failed to insert new ip"))?
+    {
+        audit(db, AuditRecord::new(&principal, AuditAction::Create, dst));
+    }
+
+    let copy = add_entry(db, events, hooks, &principal, dst, entry)?;
+
+    Ok(ApiData::Some(copy))
 }
 
-#[derive(Embed)]
-#[folder = "../target/frontend"]
-struct FrontendAssets;
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("ip" = String, Path, description = "The IP address"),
+        ("uuid" = Uuid, Path, description = "The UUID of the entry"),
+    ),
+    responses(
+        (status = 200, description = "Linked entries retrieved successfully", body = ApiResponse<Vec<Entry>>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "IP Management",
+    description = "Retrieves the entries linked by an entry, links to entries which do not exist anymore are skipped. Returns an ApiResponse with the linked entries, no data if the entry does not exist, or an error message."
+)]
+#[get("/ip/<ip>/entry/<uuid>/links")]
+async fn ip_entry_links(
+    ip: IpAddr,
+    uuid: Uuid,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Vec<Entry>> {
+    let ipst = db
+        .get_hip(ip)
+        .map_err(|e| storage_error!(e, "failed to get data from db"))?
+        .readable_by(&principal);
+
+    let Some(entry) = ipst.history.values().find(|e| e.uuid == Some(uuid)) else {
+        return Ok(ApiData::None);
+    };
+
+    let mut linked = vec![];
+    for link in entry.links.iter().flatten() {
+        linked.extend(find_entry(db, *link)?.filter(|e| principal.can_read(e)));
+    }
 
-// Catch-all route to serve index.html for Vue routes
-#[get("/<path..>")]
-async fn serve_assets(path: PathBuf) -> Option<(ContentType, Cow<'static, [u8]>)> {
-    let filename = path.display().to_string();
+    Ok(ApiData::Some(linked))
+}
 
-    // if the asset exist we serve it
-    if let Some(asset) = FrontendAssets::get(&filename) {
-        let content_type = path
-            .extension()
-            .and_then(OsStr::to_str)
-            .and_then(ContentType::from_extension)
-            .unwrap_or(ContentType::Bytes);
-        Some((content_type, asset.data))
-    } else {
-        // if the asset doesn't exist we serve index.html
-        // we delegate page routing to Vue
-        let index = FrontendAssets::get("index.html")?;
-        Some((ContentType::HTML, index.data))
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("uuid" = Uuid, Path, description = "The UUID of the entry"),
+    ),
+    responses(
+        (status = 200, description = "Entry retrieved successfully", body = ApiResponse<Entry>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "Entry Management",
+    description = "Retrieves an entry from its UUID only, without knowing the IP address it belongs to. Returns an ApiResponse with an optional entry or an error message."
+)]
+#[get("/entry/<uuid>")]
+async fn entry_get(uuid: Uuid, principal: Principal, db: &State<Arc<Storage>>) -> ApiResult<Entry> {
+    Ok(ApiData::from(
+        find_entry(db, uuid)?.filter(|e| principal.can_read(e)),
+    ))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 200, description = "Index rebuilt successfully", body = ApiResponse<usize>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "Entry Management",
+    description = "Rebuilds the indexes used to resolve entries from their UUID and to find the entries linking to an entry. Returns an ApiResponse with the number of entries indexed or an error message. Only unrestricted API keys can run it, the others being answered with a 403 Forbidden and the admin_required code."
+)]
+#[post("/entry/index/rebuild")]
+async fn entry_index_rebuild(
+    _writable: Writable,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<usize> {
+    principal.check_admin()?;
+
+    let n = db
+        .rebuild_uuid_index()
+        .map_err(|e| storage_error!(e, "failed to rebuild entry index"))?;
+    db.rebuild_backlinks_index()
+        .map_err(|e| storage_error!(e, "failed to rebuild entry index"))?;
+
+    Ok(ApiData::Some(n))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("asn" = u64, Path, description = "The AS number"),
+    ),
+    responses(
+        (status = 200, description = "IP addresses retrieved successfully", body = ApiResponse<Vec<String>>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "ASN",
+    description = "Retrieves the IP addresses having an ASN entry with the given AS number which the client can read. Returns an ApiResponse with the IP addresses or an error message."
+)]
+#[get("/asn/<asn>/ips")]
+async fn asn_ips(
+    asn: u64,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Vec<IpAddr>> {
+    let mut ips = db
+        .asn_ips(asn)
+        .map_err(|e| storage_error!(e, "failed to get asn ips"))?;
+    ips.sort();
+
+    // the index does not know the classification of the asn entries
+    if principal.read_restriction().is_some() {
+        let hips = db
+            .get_hips(&ips)
+            .map_err(|e| storage_error!(e, "failed to get data from db"))?;
+        ips.retain(|ip| {
+            hips.get(ip).is_some_and(|ipst| {
+                ipst.history
+                    .values()
+                    .any(|e| matches!(e.data, Data::Asn(a) if a == asn) && principal.can_read(e))
+            })
+        });
+    }
+
+    Ok(ApiData::Some(ips))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 200, description = "Index rebuilt successfully", body = ApiResponse<usize>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "ASN",
+    description = "Rebuilds the index used to find the IP addresses associated with an AS number. Returns an ApiResponse with the number of IP addresses indexed or an error message. Only unrestricted API keys can run it, the others being answered with a 403 Forbidden and the admin_required code."
+)]
+#[post("/asn/index/rebuild")]
+async fn asn_index_rebuild(
+    _writable: Writable,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<usize> {
+    principal.check_admin()?;
+
+    let n = db
+        .rebuild_asn_index()
+        .map_err(|e| storage_error!(e, "failed to rebuild asn index"))?;
+
+    Ok(ApiData::Some(n))
+}
+
+/// Entries of an IP address matching a search
+#[derive(Debug, Serialize, ToSchema)]
+struct IpEntries {
+    #[schema(value_type = String)]
+    ip: IpAddr,
+    entries: Vec<Entry>,
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("cve" = String, Path, description = "The CVE identifier, ex: CVE-2021-44228"),
+    ),
+    responses(
+        (status = 200, description = "Entries retrieved successfully", body = ApiResponse<Vec<IpEntries>>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "CVE",
+    description = "Retrieves, across all IP addresses, the vulnerable entries mentioning a CVE. Identifiers are matched in their canonical form, so neither their case nor their separators matter and they can be part of a longer text, ex: `Log4Shell (cve_2021_44228)`. Returns an ApiResponse with the matching entries by IP address or an error message."
+)]
+#[get("/cve/<cve>/entries")]
+async fn cve_entries(
+    cve: &str,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Vec<IpEntries>> {
+    let cve = Cve::try_from(cve).map_err(|e| api_error!(e))?;
+
+    let mut ips = db
+        .cve_ips(&cve)
+        .map_err(|e| storage_error!(e, "failed to get cve ips"))?;
+    ips.sort();
+
+    let mut found = vec![];
+    for ip in ips {
+        let ipst = db
+            .get_hip(ip)
+            .map_err(|e| storage_error!(e, "failed to get data from db"))?;
+
+        let entries: Vec<Entry> = ipst
+            .history
+            .into_values()
+            .filter(|e| principal.can_read(e) && e.data.cves().contains(&cve))
+            .collect();
+        // the index may be stale
+        if !entries.is_empty() {
+            found.push(IpEntries { ip, entries });
+        }
     }
+
+    Ok(ApiData::Some(found))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 200, description = "Index rebuilt successfully", body = ApiResponse<usize>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "CVE",
+    description = "Rebuilds the index used to find the IP addresses having entries mentioning a CVE. Returns an ApiResponse with the number of IP addresses indexed or an error message. Only unrestricted API keys can run it, the others being answered with a 403 Forbidden and the admin_required code."
+)]
+#[post("/cve/index/rebuild")]
+async fn cve_index_rebuild(
+    _writable: Writable,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<usize> {
+    principal.check_admin()?;
+
+    let n = db
+        .rebuild_cve_index()
+        .map_err(|e| storage_error!(e, "failed to rebuild cve index"))?;
+
+    Ok(ApiData::Some(n))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("from" = Layout, Query, description = "The layout to move the stories from"),
+    ),
+    responses(
+        (status = 200, description = "Stories migrated successfully", body = ApiResponse<Migration>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "Storage",
+    description = "Moves the stories laid out according to another layout to the one of the storage_layout setting, to be run after changing the setting. A story present in both layouts is left in place unless both copies are the same, so an interrupted migration can be run again. Returns an ApiResponse with the number of stories moved and the conflicting IP addresses, or an error message. Only unrestricted API keys can run it, the others being answered with a 403 Forbidden and the admin_required code."
+)]
+#[post("/storage/migrate?<from>")]
+async fn storage_migrate(
+    from: Layout,
+    _writable: Writable,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Migration> {
+    principal.check_admin()?;
+
+    let migration = db
+        .migrate(from)
+        .map_err(|e| storage_error!(e, "failed to migrate stories"))?;
+
+    Ok(ApiData::Some(migration))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("fix" = Option<bool>, Query, description = "Moves the corrupt records to the quarantine hash, they are only reported by default"),
+    ),
+    responses(
+        (status = 200, description = "Store checked successfully", body = ApiResponse<Repair>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "Storage",
+    description = "Checks that every record of the store can be loaded as the story of the IP address it is stored under, records left by older versions or edited by hand may not. Corrupt records are only reported unless fix is set, in which case they are moved, as is, to the ip-story:quarantine hash from where they can be inspected and restored. Returns an ApiResponse with the number of records checked and the corrupt ones, or an error message. Only unrestricted API keys can run it, the others being answered with a 403 Forbidden and the admin_required code."
+)]
+#[post("/admin/repair?<fix>")]
+async fn admin_repair(
+    fix: Option<bool>,
+    _writable: Writable,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Repair> {
+    principal.check_admin()?;
+
+    let repair = db
+        .repair(fix.unwrap_or_default())
+        .map_err(|e| storage_error!(e, "failed to repair store"))?;
+
+    Ok(ApiData::Some(repair))
+}
+
+/// Build metadata of the running server
+#[derive(Debug, Serialize, ToSchema)]
+struct Version {
+    /// Version of the crate
+    version: &'static str,
+    /// Commit the server got built from, `unknown` outside of a git checkout
+    git_commit: &'static str,
+    built_at: chrono::DateTime<Utc>,
+    /// Version of the Rust compiler used
+    rustc: &'static str,
 }
 
-#[get("/openapi/json")]
-async fn openapi() -> ApiResult<utoipa::openapi::OpenApi> {
-    Ok(ApiData::Some(ApiDoc::openapi()))
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 200, description = "Build metadata retrieved successfully", body = ApiResponse<Version>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "Server",
+    description = "Reports which build of the server is running. Returns an ApiResponse with the crate version, the git commit, the build time and the Rust compiler version."
+)]
+#[get("/version")]
+async fn version() -> ApiResult<Version> {
+    Ok(ApiData::Some(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("IP_STORY_GIT_COMMIT"),
+        built_at: env!("IP_STORY_BUILT_AT")
+            .parse()
+            .ok()
+            .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+            .unwrap_or_default(),
+        rustc: env!("IP_STORY_RUSTC"),
+    }))
 }
 
 #[derive(OpenApi)]
 #[openapi(
-    components(schemas(DataKind, SearchOrder)),
-    paths(ip_new, ip_add_entry, ip_search_entry, ip_update_entry, ip_del_entry,)
+    components(schemas(
+        Bucket,
+        DataKind,
+        Facet,
+        IpSortBy,
+        Layout,
+        SearchOrder,
+        Severity,
+        SortBy,
+        TagMode
+    )),
+    paths(
+        ip_new,
+        ip_new_many,
+        ip_list,
+        ip_list_index_rebuild,
+        ip_add_entry,
+        ip_search_entry,
+        ip_entry_count,
+        ip_entry_changes,
+        ip_batch_search,
+        ip_latest,
+        ip_entry_fetch,
+        ip_entry_by_prefix,
+        ip_mtime,
+        ip_count,
+        histogram::ip_entry_histogram,
+        facets::ip_facets,
+        ip_update_entry,
+        ip_upsert_entry,
+        ip_entry_set_data,
+        ip_del_entry,
+        ip_entry_add_tags,
+        ip_entry_del_tag,
+        ip_bulk_tags,
+        ip_entry_touch,
+        ip_entry_copy,
+        ip_entry_links,
+        ip_check,
+        ip_meta,
+        ip_set_meta,
+        entry_get,
+        entry_index_rebuild,
+        asn_ips,
+        asn_index_rebuild,
+        cve_entries,
+        cve_index_rebuild,
+        storage_migrate,
+        admin_repair,
+        prune::admin_prune,
+        seed::admin_seed,
+        session::session_open,
+        session::session_close,
+        session::csrf_token,
+        version,
+        audit::audit_search,
+        events::ip_stream,
+        events::stream,
+        ws::ws_stream,
+        enrich::cidr_enrich,
+        enrich::ip_enrich,
+        import::import_entries,
+        import::import_all,
+        import::import_iplist,
+        export::export_all,
+        misp::import_misp,
+        stats::stats,
+        stats::stats_scan,
+        stats::count_index_rebuild,
+        facets::facets,
+        facets::facets_scan,
+    )
 )]
 struct ApiDoc;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let db = connect_to_redis()?;
-
-    rocket::build()
-        .mount("/", routes![serve_assets])
-        .mount(
-            API_MOUNTPOINT,
-            routes![
-                openapi,
-                ip_new,
-                ip_add_entry,
-                ip_search_entry,
-                ip_update_entry,
-                ip_del_entry,
-            ],
-        )
-        .manage(Arc::new(Mutex::new(db)))
-        .launch()
-        .await?;
+    let rocket = rocket::build();
+    let config: Config = rocket.figment().extract()?;
+
+    if !config.api_mountpoint().starts_with('/') {
+        anyhow::bail!("api_mountpoint must be an absolute path other than /");
+    }
+
+    // sessions hold an API key, without keys there is nothing to hold
+    if config.session_cookies && config.api_keys.is_empty() {
+        anyhow::bail!("session_cookies requires api_keys");
+    }
+
+    if config.read_only {
+        log::warn!("read-only mode enabled, requests modifying the store are rejected");
+    }
+
+    let db = Storage::new(
+        connect_to_redis()?,
+        connect_to_redis_v6()?,
+        config.storage_timeout(),
+        config.storage_retry(),
+        config.storage_layout,
+        config.history_key,
+        config.max_story_size.as_u64() as usize,
+        config.storage_scan_count,
+    );
+
+    let db = Arc::new(db);
+    let events = Events::new(config.stream_buffer);
+    if config.prune_interval_secs > 0 {
+        prune::spawn(db.clone(), events.clone(), config.clone());
+    }
+    webhooks::spawn(&events, &config)?;
+
+    #[cfg(feature = "otel")]
+    let tracer = telemetry::init(&config)?;
+    #[cfg(not(feature = "otel"))]
+    if config.otel_endpoint.is_some() {
+        log::warn!("otel_endpoint ignored, traces export requires the otel feature");
+    }
+
+    let mountpoint = config.api_mountpoint().to_string();
+    let mut routes = routes![
+        ip_new,
+        ip_new_many,
+        ip_list,
+        ip_list_index_rebuild,
+        ip_add_entry,
+        ip_search_entry,
+        ip_entry_count,
+        ip_entry_changes,
+        ip_batch_search,
+        ip_latest,
+        ip_entry_fetch,
+        ip_entry_by_prefix,
+        ip_mtime,
+        ip_count,
+        histogram::ip_entry_histogram,
+        facets::ip_facets,
+        ip_update_entry,
+        ip_upsert_entry,
+        ip_entry_set_data,
+        ip_del_entry,
+        ip_entry_add_tags,
+        ip_entry_del_tag,
+        ip_bulk_tags,
+        ip_entry_touch,
+        ip_entry_copy,
+        ip_entry_links,
+        ip_check,
+        ip_meta,
+        ip_set_meta,
+        entry_get,
+        entry_index_rebuild,
+        asn_ips,
+        asn_index_rebuild,
+        cve_entries,
+        cve_index_rebuild,
+        storage_migrate,
+        admin_repair,
+        prune::admin_prune,
+        version,
+        audit_search,
+        events::ip_stream,
+        events::stream,
+        ws::ws_stream,
+        enrich::cidr_enrich,
+        enrich::ip_enrich,
+        import::import_entries,
+        import::import_all,
+        import::import_iplist,
+        export::export_all,
+        misp::import_misp,
+        stats::stats,
+        stats::stats_scan,
+        stats::count_index_rebuild,
+        facets::facets,
+        facets::facets_scan,
+    ];
+    // the documentation is not mounted at all when disabled
+    if config.openapi_enabled {
+        routes.extend(routes![openapi::openapi, openapi::openapi_version]);
+    }
+    // example data must not end up in production stores by mistake
+    if config.seed_enabled {
+        routes.extend(routes![seed::admin_seed]);
+    }
+    if config.session_cookies {
+        routes.extend(routes![
+            session::session_open,
+            session::session_close,
+            session::csrf_token
+        ]);
+    }
+    #[cfg(feature = "otel")]
+    let routes = telemetry::traced(routes);
+
+    let rocket = rocket
+        .mount(&mountpoint, routes)
+        .manage(db.clone())
+        .manage(OpenApiSpec::new(&mountpoint)?)
+        .attach(NormalizePaths::new(&config))
+        .attach(RequestLogger::new(config.request_log_level))
+        .manage(events)
+        .manage(Enricher::new(&config))
+        .manage(Hooks::new(&config))
+        .manage(StatsCache::new(Duration::from_secs(config.stats_ttl_secs)))
+        .manage(FacetsCache::new(Duration::from_secs(config.stats_ttl_secs)))
+        .manage(config);
+
+    // API errors are always answered with JSON
+    let rocket = rocket.register(
+        &mountpoint,
+        rocket::catchers![
+            api::unauthorized,
+            session::csrf_rejected,
+            api::not_found,
+            api::unprocessable,
+            api::internal_error,
+            api::read_only
+        ],
+    );
+
+    #[cfg(feature = "frontend")]
+    let rocket = rocket.mount("/", routes![frontend::serve_assets]);
+    // without frontend, unknown paths are answered with a JSON error
+    #[cfg(not(feature = "frontend"))]
+    let rocket = rocket.register("/", rocket::catchers![api::not_found]);
+
+    rocket.launch().await?;
+
+    // flushes the spans not exported yet
+    #[cfg(feature = "otel")]
+    if let Some(tracer) = tracer {
+        tracer.shutdown()?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use ip_story_model::Owner;
+
+    use super::*;
+
+    fn owner() -> Data {
+        Data::Owner(Owner {
+            name: "Example Hosting".into(),
+            address: None,
+            country: None,
+            country_raw: None,
+            abuse: vec![],
+            phone: None,
+        })
+    }
+
+    /// Text entry created `secs` after the epoch, with the given tags
+    fn text(secs: i64, tags: &[&str]) -> Entry {
+        Entry {
+            uuid: Some(Uuid::new_v4()),
+            ctime: chrono::DateTime::from_timestamp(secs, 0),
+            tags: (!tags.is_empty())
+                .then(|| tags.iter().map(|t| Tag::try_from(*t).unwrap()).collect()),
+            ..Entry::new(Data::Text(format!("entry {secs}")))
+        }
+    }
+
+    fn story(entries: impl IntoIterator<Item = Entry>) -> IpStory {
+        let mut ipst = IpStory::new("192.0.2.1".parse().unwrap());
+        for e in entries {
+            ipst.history.insert(e.ctime.unwrap(), e);
+        }
+        ipst
+    }
+
+    /// Creation times of the entries of `ipst` found by `query`
+    fn search(ipst: &IpStory, query: SearchQuery) -> Vec<i64> {
+        let (found, _) = query.run(ipst, &Config::default()).unwrap();
+        found.iter().map(|(k, _)| k.timestamp()).collect()
+    }
+
+    #[test]
+    fn expired_entries_are_left_out_of_search_only() {
+        let mut expired = text(1, &[]);
+        expired.expires_at = chrono::DateTime::from_timestamp(2, 0);
+        let uuid = expired.uuid.unwrap();
+        let ipst = story([expired, text(3, &[])]);
+
+        assert_eq!(search(&ipst, SearchQuery::default()), [3]);
+        assert_eq!(
+            search(
+                &ipst,
+                SearchQuery {
+                    include_expired: Some(true),
+                    ..Default::default()
+                }
+            ),
+            [1, 3]
+        );
+        assert_eq!(entry_with_uuid(ipst, uuid).and_then(|e| e.uuid), Some(uuid));
+    }
+
+    #[test]
+    fn if_match_mismatches_fail_the_precondition() {
+        let mut e = text(1, &[]);
+        e.mtime = chrono::DateTime::from_timestamp(2, 0);
+        let uuid = e.uuid.unwrap();
+        let if_match = |value: &str| IfMatch::parse(value).unwrap();
+
+        assert!(check_if_match(&if_match("1970-01-01T00:00:02Z"), Some(&e), uuid).is_ok());
+        assert!(check_if_match(&if_match("*"), Some(&e), uuid).is_ok());
+
+        for (if_match, current) in [
+            // the ctime is not used once the entry was modified
+            (if_match("1970-01-01T00:00:01Z"), Some(&e)),
+            (if_match("*"), None),
+            (if_match("1970-01-01T00:00:02Z"), None),
+        ] {
+            let err = check_if_match(&if_match, current, uuid).unwrap_err();
+            assert_eq!(err.status(), Status::PreconditionFailed);
+            assert_eq!(err.code(), "entry_modified");
+        }
+    }
+
+    #[test]
+    fn mapped_addresses_are_rejected_by_policy() {
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        let config = |ipv4_mapped| Config {
+            ipv4_mapped,
+            ..Config::default()
+        };
+
+        assert!(check_ip(mapped, &config(MappedPolicy::Unmap)).is_ok());
+        assert!(check_ip(mapped, &config(MappedPolicy::Keep)).is_ok());
+        let err = check_ip(mapped, &config(MappedPolicy::Reject)).unwrap_err();
+        assert_eq!(err.code(), "ip_rejected");
+        assert!(check_ip("192.0.2.1".parse().unwrap(), &config(MappedPolicy::Reject)).is_ok());
+    }
+
+    #[test]
+    fn sort_ties_are_broken_by_uuid() {
+        let ipst = story((1..=20).map(|secs| text(secs, &[])));
+        let mut uuids: Vec<_> = ipst.history.values().filter_map(|e| e.uuid).collect();
+        uuids.sort();
+        let run = |order, offset, limit| {
+            let query = SearchQuery {
+                sort_by: Some(SortBy::Kind),
+                order: Some(order),
+                offset: Some(offset),
+                limit: Some(limit),
+                ..Default::default()
+            };
+            let (found, total) = query.run(&ipst, &Config::default()).unwrap();
+            assert_eq!(total, 20);
+            found.iter().filter_map(|(_, e)| e.uuid).collect::<Vec<_>>()
+        };
+
+        for order in [SearchOrder::Asc, SearchOrder::Desc] {
+            // all the entries are of the same kind
+            assert_eq!(run(order, 0, 20), uuids);
+            assert_eq!(run(order, 0, 20), run(order, 0, 20));
+            // pages neither repeat nor skip entries
+            let pages: Vec<_> = (0..4).flat_map(|p| run(order, p * 5, 5)).collect();
+            assert_eq!(pages, uuids);
+        }
+    }
+
+    #[test]
+    fn default_order_comes_from_the_config() {
+        let ipst = story([text(1, &[]), text(2, &[]), text(3, &[])]);
+        let search = |search_default_order| {
+            let config = Config {
+                search_default_order,
+                ..Config::default()
+            };
+            let (found, _) = SearchQuery::default().run(&ipst, &config).unwrap();
+            found.iter().map(|(k, _)| k.timestamp()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(search(SearchOrder::Asc), [1, 2, 3]);
+        assert_eq!(search(SearchOrder::Desc), [3, 2, 1]);
+    }
+
+    fn described(description: &str) -> Entry {
+        Entry {
+            description: Some(description.into()),
+            ..Entry::new(Data::Text("<b>bold</b> text".into()))
+        }
+    }
+
+    #[test]
+    fn descriptions_are_limited_in_characters() {
+        let config = Config {
+            description_max_len: 4,
+            ..Config::default()
+        };
+
+        // multi-byte characters count as one
+        assert!(check_text(&mut described("éèàù"), &config).is_ok());
+        let err = check_text(&mut described("abcde"), &config).unwrap_err();
+        assert_eq!(err.code(), "invalid_request");
+        assert!(check_text(&mut Entry::new(Data::Text("abcde".into())), &config).is_ok());
+    }
+
+    #[test]
+    fn html_is_stripped_if_configured() {
+        assert_eq!(strip_html("<p>seen <b>scanning</b></p>"), "seen scanning");
+        assert_eq!(strip_html("1 > 0 <script"), "1 > 0 ");
+        assert_eq!(strip_html("no tags"), "no tags");
+
+        let mut e = described("<i>x</i>");
+        check_text(&mut e, &Config::default()).unwrap();
+        assert_eq!(e.description.as_deref(), Some("<i>x</i>"));
+
+        let config = Config {
+            sanitize_html: true,
+            ..Config::default()
+        };
+        let mut e = described("<i>x</i>");
+        check_text(&mut e, &config).unwrap();
+        assert_eq!(e.description.as_deref(), Some("x"));
+        assert!(matches!(&e.data, Data::Text(t) if t == "bold text"));
+    }
+
+    #[test]
+    fn length_is_checked_after_stripping() {
+        let config = Config {
+            description_max_len: 4,
+            sanitize_html: true,
+            drop_empty_fields: true,
+            ..Config::default()
+        };
+
+        let mut e = described("<a href=\"https://example.com\">link</a>");
+        check_text(&mut e, &config).unwrap();
+        assert_eq!(e.description.as_deref(), Some("link"));
+
+        // a description only holding tags is dropped
+        let mut e = described("<br/>");
+        check_text(&mut e, &config).unwrap();
+        assert!(e.description.is_none());
+    }
+
+    #[test]
+    fn nil_uuids_cannot_be_deleted() {
+        let mut legacy = text(1, &[]);
+        legacy.uuid = Some(Uuid::nil());
+        let ipst = story([legacy]);
+
+        assert_eq!(
+            check_deletable(Uuid::nil()).unwrap_err().code(),
+            "invalid_request"
+        );
+        assert_eq!(
+            deletion_key(&ipst, Uuid::nil()).unwrap_err().code(),
+            "invalid_request"
+        );
+    }
+
+    #[test]
+    fn shared_uuids_are_not_deleted() {
+        let first = text(1, &[]);
+        let uuid = first.uuid.unwrap();
+        let shared = Entry {
+            uuid: Some(uuid),
+            ..text(2, &[])
+        };
+        let other = text(3, &[]);
+        let other_uuid = other.uuid.unwrap();
+
+        let err = deletion_key(&story([first.clone(), shared, other.clone()]), uuid).unwrap_err();
+        assert_eq!(err.status(), Status::Conflict);
+        assert_eq!(err.code(), "uuid_conflict");
+
+        let ipst = story([first, other]);
+        assert_eq!(
+            deletion_key(&ipst, uuid).unwrap(),
+            chrono::DateTime::from_timestamp(1, 0)
+        );
+        assert_eq!(
+            deletion_key(&ipst, other_uuid).unwrap(),
+            chrono::DateTime::from_timestamp(3, 0)
+        );
+        assert_eq!(deletion_key(&ipst, Uuid::new_v4()).unwrap(), None);
+    }
+
+    fn timed(ahead_secs: i64) -> Entry {
+        let t = Utc::now() + TimeDelta::seconds(ahead_secs);
+        Entry {
+            ctime: Some(t - TimeDelta::hours(1)),
+            mtime: Some(t),
+            ..Entry::new(Data::Text("x".into()))
+        }
+    }
+
+    fn future_config(future_policy: FuturePolicy) -> Config {
+        Config {
+            future_tolerance_secs: 300,
+            future_policy,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn timestamps_within_the_tolerance_are_kept() {
+        for policy in [FuturePolicy::Reject, FuturePolicy::Clamp] {
+            for ahead in [-3600, 0, 200] {
+                let mut e = timed(ahead);
+                let before = (e.ctime, e.mtime);
+                check_times(&mut e, &future_config(policy)).unwrap();
+                assert_eq!((e.ctime, e.mtime), before, "{ahead}s ahead with {policy:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn future_timestamps_are_rejected() {
+        let mut e = timed(600);
+        let err = check_times(&mut e, &future_config(FuturePolicy::Reject)).unwrap_err();
+        assert_eq!(err.code(), "timestamp_in_future");
+    }
+
+    #[test]
+    fn future_timestamps_are_clamped() {
+        let mut e = timed(600);
+        let ctime = e.ctime;
+        let before = Utc::now();
+        check_times(&mut e, &future_config(FuturePolicy::Clamp)).unwrap();
+
+        // only the timestamp ahead of the tolerance is clamped
+        assert_eq!(e.ctime, ctime);
+        let mtime = e.mtime.unwrap();
+        assert!(before <= mtime && mtime <= Utc::now());
+    }
+
+    #[test]
+    fn touch_moves_the_modification_time_forward() {
+        let at = |secs| chrono::DateTime::from_timestamp(secs, 0);
+        let e = text(1, &["botnet"]);
+        let uuid = e.uuid.unwrap();
+        let mut ipst = story([e]);
+
+        assert_eq!(ipst.touch(uuid, at(10).unwrap()).unwrap().mtime, at(10));
+        // an earlier time leaves it as is
+        assert_eq!(ipst.touch(uuid, at(5).unwrap()).unwrap().mtime, at(10));
+        assert_eq!(ipst.touch(uuid, at(20).unwrap()).unwrap().mtime, at(20));
+
+        let e = ipst.entry_mut(uuid).unwrap();
+        assert_eq!(e.ctime, at(1));
+        assert!(matches!(&e.data, Data::Text(t) if t == "entry 1"));
+        assert_eq!(e.tags.as_ref().map(|t| t.len()), Some(1));
+        assert!(ipst.touch(Uuid::new_v4(), at(30).unwrap()).is_none());
+    }
+
+    #[test]
+    fn mtime_follows_the_remaining_entries() {
+        let mut modified = text(1, &[]);
+        modified.mtime = chrono::DateTime::from_timestamp(5, 0);
+        let mut ipst = story([modified, text(3, &[])]);
+        assert_eq!(ipst.mtime(), chrono::DateTime::from_timestamp(5, 0));
+
+        ipst.history.pop_first();
+        assert_eq!(ipst.mtime(), chrono::DateTime::from_timestamp(3, 0));
+        ipst.history.pop_first();
+        assert_eq!(ipst.mtime(), None);
+    }
+
+    #[test]
+    fn retag_reports_the_entry_only_when_modified() {
+        let now = Utc::now();
+        let e = text(1, &["botnet"]);
+        let uuid = e.uuid.unwrap();
+        let mut ipst = story([e]);
+        let add = |tags: &mut HashSet<Tag>| {
+            tags.insert(Tag::try_from("scanner").unwrap());
+        };
+
+        let (tags, modified) = ipst.retag(uuid, add, now).unwrap();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(modified.and_then(|e| e.mtime), Some(now));
+
+        // updating again, as a retried update does, changes nothing
+        let (tags, modified) = ipst.retag(uuid, add, now).unwrap();
+        assert_eq!(tags.len(), 2);
+        assert!(modified.is_none());
+
+        let (tags, modified) = ipst.retag(uuid, |t| t.clear(), now).unwrap();
+        assert!(tags.is_empty());
+        assert!(modified.is_some_and(|e| e.tags.is_none()));
+
+        assert!(ipst.retag(Uuid::new_v4(), add, now).is_none());
+    }
+
+    #[test]
+    fn stories_are_read_without_the_entries_of_other_classifications() {
+        let classified = |secs, classification: &str| Entry {
+            classification: Some(classification.into()),
+            ..text(secs, &[])
+        };
+        let ipst = || {
+            story([
+                classified(1, "tlp:amber"),
+                classified(2, "tlp:clear"),
+                text(3, &[]),
+            ])
+        };
+        let soc = Principal::from(&config::ApiKey {
+            name: "soc".into(),
+            read: Some(HashSet::from(["tlp:clear".into()])),
+            write: None,
+        });
+
+        assert_eq!(
+            search(&ipst().readable_by(&soc), SearchQuery::default()),
+            [2, 3]
+        );
+        assert_eq!(
+            search(
+                &ipst().readable_by(&Principal::system()),
+                SearchQuery::default()
+            ),
+            [1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn min_confidence_filters_on_the_decayed_confidence() {
+        let now = Utc::now().timestamp();
+        let confident = |secs, confidence: f64| Entry {
+            confidence: Some(confidence.try_into().unwrap()),
+            ..text(secs, &[])
+        };
+        // two half-lives old, its confidence decayed to 0.225
+        let ipst = story([
+            confident(now - 7200, 0.9),
+            confident(now, 0.6),
+            text(now + 1, &[]),
+        ]);
+        let config = Config {
+            confidence_half_life_secs: 3600,
+            ..Config::default()
+        };
+        let search = |min_confidence| {
+            let query = SearchQuery {
+                min_confidence: Some(min_confidence),
+                ..Default::default()
+            };
+            let (found, _) = query.run(&ipst, &config).unwrap();
+            found
+                .iter()
+                .map(|(k, _)| k.timestamp() - now)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(search(0.5), [0]);
+        assert_eq!(search(0.2), [-7200, 0]);
+
+        // hits carry the decayed confidence, not the stored one
+        let (found, _) = SearchQuery::default().run(&ipst, &config).unwrap();
+        let hits: Vec<_> = found
+            .into_iter()
+            .map(|(_, e)| serde_json::to_value(SearchHit::new(e, &config, Utc::now())).unwrap())
+            .collect();
+        let decayed = hits[0]["effective_confidence"].as_f64().unwrap();
+        assert!((decayed - 0.225).abs() < 0.001, "{decayed}");
+        assert_eq!(hits[0]["confidence"], 0.9);
+        assert!(hits[2].get("effective_confidence").is_none());
+
+        let invalid = SearchQuery {
+            min_confidence: Some(1.5),
+            ..Default::default()
+        };
+        assert!(invalid.run(&ipst, &config).is_err());
+    }
+
+    #[test]
+    fn reads_of_restricted_keys_leave_the_other_classifications_out() {
+        let amber = Entry {
+            classification: Some("tlp:amber".into()),
+            mtime: chrono::DateTime::from_timestamp(9, 0),
+            ..text(1, &[])
+        };
+        let ipst = || story([amber.clone(), text(2, &[])]);
+        let soc = Principal::from(&config::ApiKey {
+            name: "soc".into(),
+            read: Some(HashSet::from(["tlp:clear".into()])),
+            write: None,
+        });
+
+        // the hidden entry neither moves the last change nor gets checked
+        let readable = ipst().readable_by(&soc);
+        assert_eq!(readable.mtime(), chrono::DateTime::from_timestamp(2, 0));
+        assert_eq!(ipst().mtime(), chrono::DateTime::from_timestamp(9, 0));
+        let duplicated = || {
+            let mut ipst = ipst();
+            ipst.history.insert(
+                chrono::DateTime::from_timestamp(3, 0).unwrap(),
+                amber.clone(),
+            );
+            ipst
+        };
+        assert_eq!(duplicated().check().duplicate_uuids, [amber.uuid.unwrap()]);
+        assert!(
+            duplicated()
+                .readable_by(&soc)
+                .check()
+                .duplicate_uuids
+                .is_empty()
+        );
+
+        let s = serde_json::to_string(&ipst()).unwrap();
+        let mut scan = stats::ScanStats::default();
+        scan.add(&s, &soc).unwrap();
+        assert_eq!(scan.kinds.get(&DataKind::Text), Some(&1));
+        let mut scan = stats::ScanStats::default();
+        scan.add(&s, &Principal::system()).unwrap();
+        assert_eq!(scan.kinds.get(&DataKind::Text), Some(&2));
+    }
+
+    #[test]
+    fn store_wide_operations_are_refused_to_restricted_keys() {
+        use std::collections::{HashMap, HashSet};
+
+        use rocket::{http::Header, local::blocking::Client};
+
+        use crate::config::ApiKey;
+
+        let client = |api_keys| {
+            let config = Config {
+                api_keys,
+                ..Config::default()
+            };
+            let rocket = rocket::build()
+                .mount("/", rocket::routes![admin_repair, cve_index_rebuild])
+                .register("/", rocket::catchers![api::unauthorized])
+                .manage(config)
+                .manage(Arc::new(Storage::unreachable()));
+            Client::tracked(rocket).unwrap()
+        };
+        let key = |write: Option<HashSet<String>>| ApiKey {
+            name: "soc".into(),
+            read: None,
+            write,
+        };
+        let keyed = client(HashMap::from([
+            ("restricted".into(), key(Some(HashSet::new()))),
+            ("admin".into(), key(None)),
+        ]));
+        let post = |client: &Client, uri: &'static str, key: &'static str| {
+            let resp = client
+                .post(uri)
+                .header(Header::new("X-API-Key", key))
+                .dispatch();
+            let status = resp.status();
+            let body: serde_json::Value =
+                serde_json::from_str(&resp.into_string().unwrap()).unwrap();
+            (status, body["code"].as_str().map(String::from))
+        };
+
+        for uri in ["/admin/repair", "/cve/index/rebuild"] {
+            let (status, code) = post(&keyed, uri, "restricted");
+            assert_eq!(status, Status::Forbidden);
+            assert_eq!(code.as_deref(), Some("admin_required"));
+
+            // the others get as far as the store
+            let (_, code) = post(&keyed, uri, "admin");
+            assert_eq!(code.as_deref(), Some("storage_unavailable"));
+            let (_, code) = post(&client(HashMap::new()), uri, "");
+            assert_eq!(code.as_deref(), Some("storage_unavailable"));
+        }
+    }
+
+    #[test]
+    fn dry_run_deletions_leave_the_entry_and_record_nothing() {
+        let e = text(1, &[]);
+        let uuid = e.uuid.unwrap();
+        let mut ipst = story([e]);
+        let ip = ipst.ip;
+        let if_match = IfMatch::parse("*").unwrap();
+        let principal = Principal::system();
+
+        let deleted = take_entry(&mut ipst, uuid, &if_match, &principal, true).unwrap();
+        assert_eq!(deleted.as_ref().and_then(|e| e.uuid), Some(uuid));
+        assert_eq!(ipst.history.len(), 1);
+
+        // nothing reaches the store nor the subscribers
+        let db = Storage::unreachable();
+        let events = Events::new(8);
+        let mut rx = events.subscribe();
+        record_deletions(&db, &events, &principal, ip, &deleted, true);
+        assert!(rx.try_recv().is_err());
+
+        let deleted = take_entry(&mut ipst, uuid, &if_match, &principal, false).unwrap();
+        assert!(ipst.history.is_empty());
+        record_deletions(&db, &events, &principal, ip, &deleted, false);
+        let ev = rx.try_recv().unwrap();
+        assert!(matches!(ev.action, AuditAction::Delete));
+        assert_eq!(ev.entry.uuid, Some(uuid));
+    }
+
+    #[test]
+    fn unreachable_stores_are_reported_as_unavailable() {
+        use rocket::local::blocking::Client;
+
+        let rocket = rocket::build()
+            .mount("/", rocket::routes![ip_mtime])
+            .manage(Config::default())
+            .manage(Arc::new(Storage::unreachable()));
+        let client = Client::tracked(rocket).unwrap();
+
+        let resp = client.get("/ip/192.0.2.1/mtime").dispatch();
+        assert_eq!(resp.status(), Status::ServiceUnavailable);
+        let body: serde_json::Value = serde_json::from_str(&resp.into_string().unwrap()).unwrap();
+        assert_eq!(body["code"], "storage_unavailable");
+        assert_eq!(body["error"], "failed to get data from db");
+        assert!(body["data"].is_null());
+    }
+
+    #[test]
+    fn tag_mode_combines_the_tags() {
+        let ipst = story([
+            text(1, &["botnet"]),
+            text(2, &["scanner"]),
+            text(3, &["botnet", "scanner"]),
+            text(4, &[]),
+        ]);
+        let query = |tag_mode| SearchQuery {
+            tags: Some("botnet, Scanner".into()),
+            tag_mode,
+            ..Default::default()
+        };
+
+        assert_eq!(search(&ipst, query(None)), [3]);
+        assert_eq!(search(&ipst, query(Some(TagMode::All))), [3]);
+        assert_eq!(search(&ipst, query(Some(TagMode::Any))), [1, 2, 3]);
+    }
+
+    #[test]
+    fn tag_mode_does_not_relax_the_other_filters() {
+        let mut ipst = story([text(1, &["botnet"]), text(2, &["scanner"])]);
+        let mut asn = text(3, &["botnet"]);
+        asn.data = Data::Asn(64496);
+        ipst.history.insert(asn.ctime.unwrap(), asn);
+
+        let query = SearchQuery {
+            tags: Some("botnet,scanner".into()),
+            tag_mode: Some(TagMode::Any),
+            kind: Some(DataKind::Text),
+            ..Default::default()
+        };
+        assert_eq!(search(&ipst, query), [1, 2]);
+    }
+
+    #[test]
+    fn kind_change_is_rejected_by_default() {
+        let text = Data::Text("owner contacted".into());
+
+        assert!(check_kind_change(&owner(), &text, None).is_err());
+        assert!(check_kind_change(&owner(), &text, Some(false)).is_err());
+        assert!(check_kind_change(&owner(), &text, Some(true)).is_ok());
+        assert!(check_kind_change(&owner(), &owner(), None).is_ok());
+    }
+}
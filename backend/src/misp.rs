@@ -0,0 +1,304 @@
+//! Import of the IP addresses of MISP events
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::IpAddr,
+    sync::Arc,
+};
+
+use chrono::{TimeDelta, Utc};
+use ip_story_model::{ApiResponse, Data, Entry, MispEvent};
+use rocket::{FromForm, State, post};
+use serde::{Deserialize, Serialize};
+use url::Url;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    API_MOUNTPOINT, IpStory,
+    api::{ApiData, ApiError, ApiResult, Body, ErrorResponses, Writable, WriteErrorResponses},
+    api_error,
+    audit::{AuditAction, AuditRecord, Principal, audit},
+    check_ip, check_kind,
+    config::Config,
+    events::Events,
+    hooks::Hooks,
+    storage::Storage,
+    storage_error,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct Attribute {
+    #[serde(rename = "type")]
+    kind: String,
+    value: String,
+}
+
+impl Attribute {
+    /// IP address held by the attribute, if any
+    fn ip(&self) -> Option<IpAddr> {
+        let value = match self.kind.as_str() {
+            "ip-src" | "ip-dst" => self.value.as_str(),
+            "ip-src|port" | "ip-dst|port" => self.value.split('|').next()?,
+            "domain|ip" => self.value.split('|').nth(1)?,
+            _ => return None,
+        };
+        value.trim().parse().ok()
+    }
+
+    fn asn(&self) -> Option<u64> {
+        if self.kind != "AS" {
+            return None;
+        }
+        let value = self.value.trim();
+        value
+            .strip_prefix("AS")
+            .or_else(|| value.strip_prefix("as"))
+            .unwrap_or(value)
+            .parse()
+            .ok()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Object {
+    #[serde(rename = "Attribute", default)]
+    attributes: Vec<Attribute>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Event {
+    uuid: Uuid,
+    #[serde(rename = "Attribute", default)]
+    attributes: Vec<Attribute>,
+    #[serde(rename = "Object", default)]
+    objects: Vec<Object>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventWrapper {
+    #[serde(rename = "Event")]
+    event: Event,
+}
+
+/// A single MISP event, as exported by MISP, or a list of
+/// events as found in feeds
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MispImport {
+    Event(EventWrapper),
+    Events(Vec<EventWrapper>),
+}
+
+impl MispImport {
+    fn events(self) -> Vec<Event> {
+        match self {
+            Self::Event(e) => vec![e.event],
+            Self::Events(v) => v.into_iter().map(|e| e.event).collect(),
+        }
+    }
+}
+
+/// Data to import for an IP address
+#[derive(Debug, Default)]
+struct Imported {
+    events: BTreeSet<Uuid>,
+    asns: BTreeSet<u64>,
+}
+
+impl Imported {
+    fn into_data(self, server: &Option<Url>) -> Vec<Data> {
+        let events = self.events.into_iter().map(|uuid| {
+            Data::MispEvent(MispEvent {
+                server: server.clone(),
+                uuid,
+            })
+        });
+        events.chain(self.asns.into_iter().map(Data::Asn)).collect()
+    }
+}
+
+/// Whether `entry` holds the same MISP event or ASN as `data`
+fn is_same(entry: &Entry, data: &Data) -> bool {
+    match (&entry.data, data) {
+        (Data::MispEvent(a), Data::MispEvent(b)) => a.uuid == b.uuid && a.server == b.server,
+        (Data::Asn(a), Data::Asn(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[derive(Debug, FromForm)]
+pub struct ImportQuery {
+    /// MISP server the events come from
+    server: Option<String>,
+    /// Also imports the AS numbers found in the objects holding IP addresses
+    #[field(default = false)]
+    related: bool,
+}
+
+/// Outcome of the import of an IP address
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImportResult {
+    #[schema(value_type = String)]
+    ip: IpAddr,
+    /// UUIDs of the entries created, data already present is skipped
+    created: Vec<Uuid>,
+    error: Option<String>,
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    request_body(content = Object, description = "A MISP event (`{\"Event\": {...}}`) or a list of MISP events"),
+    params(
+        ("server" = Option<String>, Query, description = "URL of the MISP server the events come from"),
+        ("related" = Option<bool>, Query, description = "Also imports, as ASN entries, the AS attributes found in the objects holding IP addresses"),
+    ),
+    responses(
+        (status = 200, description = "Events imported", body = ApiResponse<Vec<ImportResult>>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "Import",
+    description = "Imports the IP addresses found in the attributes of MISP events (ip-src, ip-dst, ip-src|port, ip-dst|port and domain|ip), adding them a MISP event entry, and tracking them if needed. Other attributes are skipped, as well as the events already present on an IP address, so that importing the same events again is a no-op. Returns an ApiResponse with the outcome of the import of every IP address or an error message."
+)]
+#[post("/import/misp?<query..>", data = "<events>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn import_misp(
+    events: Result<Body<MispImport>, ApiError>,
+    query: ImportQuery,
+    principal: Principal,
+    config: &State<Config>,
+    stream: &State<Events>,
+    hooks: &State<Hooks>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Vec<ImportResult>> {
+    let server = query
+        .server
+        .as_deref()
+        .map(Url::parse)
+        .transpose()
+        .map_err(|e| api_error!(format!("invalid server url: {e}")))?;
+
+    let mut imports: BTreeMap<IpAddr, Imported> = BTreeMap::new();
+    for event in events?.0.events() {
+        for attr in &event.attributes {
+            if let Some(ip) = attr.ip().map(|ip| config.ipv4_mapped.unmap(ip)) {
+                imports.entry(ip).or_default().events.insert(event.uuid);
+            }
+        }
+
+        for object in &event.objects {
+            let ips: Vec<IpAddr> = object
+                .attributes
+                .iter()
+                .filter_map(|a| a.ip())
+                .map(|ip| config.ipv4_mapped.unmap(ip))
+                .collect();
+            let asns: Vec<u64> = if query.related {
+                object.attributes.iter().filter_map(|a| a.asn()).collect()
+            } else {
+                vec![]
+            };
+
+            for ip in ips {
+                let imported = imports.entry(ip).or_default();
+                imported.events.insert(event.uuid);
+                imported.asns.extend(&asns);
+            }
+        }
+    }
+
+    let mut results = vec![];
+    for (ip, imported) in imports {
+        let res = import_ip(
+            db,
+            config,
+            stream,
+            hooks,
+            &principal,
+            ip,
+            imported.into_data(&server),
+        );
+
+        results.push(match res {
+            Ok(created) => ImportResult {
+                ip,
+                created,
+                error: None,
+            },
+            Err(e) => ImportResult {
+                ip,
+                created: vec![],
+                error: Some(e.to_string()),
+            },
+        });
+    }
+
+    Ok(ApiData::Some(results))
+}
+
+/// Adds an entry for each of `data` missing from the history of `ip`,
+/// returns the uuids of the entries created
+fn import_ip(
+    db: &Storage,
+    config: &Config,
+    stream: &Events,
+    hooks: &Hooks,
+    principal: &Principal,
+    ip: IpAddr,
+    data: Vec<Data>,
+) -> Result<Vec<Uuid>, ApiError> {
+    check_ip(ip, config)?;
+    for d in &data {
+        check_kind(d, config)?;
+    }
+
+    if db
+        .create_hip(IpStory::new(ip))
+        .map_err(|e| storage_error!(e, "failed to insert new ip"))?
+    {
+        audit(db, AuditRecord::new(principal, AuditAction::Create, ip));
+    }
+
+    let added = db
+        .update_hip(ip, |ipst| {
+            let mut added = vec![];
+
+            for d in data.iter() {
+                if ipst.history.values().any(|e| is_same(e, d)) {
+                    continue;
+                }
+
+                let mut entry = Entry::new(d.clone());
+                entry.uuid = Some(Uuid::new_v4());
+                entry.description = Some("imported from MISP".into());
+                hooks.run(ip, &mut entry)?;
+                principal.check_write(&entry)?;
+
+                // entries imported together are spread so that they
+                // do not collide in the history
+                let mut ctime = Utc::now();
+                while ipst.history.contains_key(&ctime) {
+                    ctime += TimeDelta::nanoseconds(1);
+                }
+                entry.ctime = Some(ctime);
+
+                ipst.history.insert(ctime, entry.clone());
+                added.push(entry);
+            }
+
+            Ok::<_, ApiError>(added)
+        })
+        .map_err(|e| storage_error!(e, "failed to import entries"))??;
+
+    for entry in &added {
+        audit(
+            db,
+            AuditRecord::new(principal, AuditAction::Create, ip).entry(entry),
+        );
+        stream.publish(AuditAction::Create, ip, entry.clone());
+    }
+
+    Ok(added.into_iter().filter_map(|e| e.uuid).collect())
+}
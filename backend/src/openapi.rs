@@ -0,0 +1,89 @@
+//! Serving of the OpenAPI documentation of the API
+
+use ip_story_model::ApiResponse;
+use rocket::{
+    Either, State, get,
+    http::{ContentType, Header, Status},
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use utoipa::OpenApi;
+
+use crate::{
+    API_MOUNTPOINT, ApiDoc,
+    api::{ApiData, ApiResult, IfNoneMatch, WithHeaders},
+};
+
+/// OpenAPI documentation, generated and serialized once at startup
+pub struct OpenApiSpec {
+    /// Serialized [`ApiResponse`] holding the documentation
+    json: String,
+    /// Hex encoded SHA-256 of `json`
+    hash: String,
+}
+
+impl OpenApiSpec {
+    /// Generates the documentation of the API mounted at `mountpoint`
+    pub fn new(mountpoint: &str) -> serde_json::Result<Self> {
+        let mut doc = ApiDoc::openapi();
+        if mountpoint != API_MOUNTPOINT {
+            doc.paths.paths = std::mem::take(&mut doc.paths.paths)
+                .into_iter()
+                .map(|(path, item)| match path.strip_prefix(API_MOUNTPOINT) {
+                    Some(p) => (format!("{mountpoint}{p}"), item),
+                    None => (path, item),
+                })
+                .collect();
+        }
+
+        let json = serde_json::to_string(&ApiResponse {
+            error: None,
+            code: None,
+            data: Some(doc),
+            warnings: vec![],
+        })?;
+        let hash = format!("{:x}", Sha256::digest(json.as_bytes()));
+
+        Ok(OpenApiSpec { json, hash })
+    }
+
+    /// Entity tag of the documentation, changing with the crate
+    /// version or whenever the documentation itself changes
+    fn etag(&self) -> String {
+        format!("\"{}-{}\"", env!("CARGO_PKG_VERSION"), &self.hash[..16])
+    }
+}
+
+#[get("/openapi/json")]
+pub async fn openapi(
+    spec: &State<OpenApiSpec>,
+    if_none_match: IfNoneMatch,
+) -> Either<WithHeaders<(ContentType, String)>, WithHeaders<(Status, ())>> {
+    let etag = spec.etag();
+
+    if if_none_match.matches(&etag) {
+        return Either::Right(
+            WithHeaders::new((Status::NotModified, ())).header(Header::new("ETag", etag)),
+        );
+    }
+
+    Either::Left(
+        WithHeaders::new((ContentType::JSON, spec.json.clone())).header(Header::new("ETag", etag)),
+    )
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpenApiVersion {
+    /// Version of the server
+    version: &'static str,
+    /// Hex encoded SHA-256 of the documentation
+    hash: String,
+}
+
+#[get("/openapi/version")]
+pub async fn openapi_version(spec: &State<OpenApiSpec>) -> ApiResult<OpenApiVersion> {
+    Ok(ApiData::Some(OpenApiVersion {
+        version: env!("CARGO_PKG_VERSION"),
+        hash: spec.hash.clone(),
+    }))
+}
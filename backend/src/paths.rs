@@ -0,0 +1,295 @@
+//! Normalization of the paths of the API requests
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::OnceLock,
+};
+
+use rocket::{
+    Data, Orbit, Request, Rocket,
+    fairing::{Fairing, Info, Kind},
+    http::{RawStr, uri::Origin},
+};
+
+use crate::config::{Config, MappedPolicy};
+
+/// Rewrites the paths of the API requests before they are routed, so
+/// that clients adding a trailing slash (ex: `/api/ip/1.2.3.4/entry/search/`)
+/// or using another case for the static segments (ex: `/api/IP/1.2.3.4`)
+/// reach the same routes, and IPv4-mapped IPv6 addresses reach the story
+/// of the IPv4 address they map unless the ipv4_mapped policy keeps them
+/// apart. Paths outside of the API, served by the frontend, are left as is.
+pub struct NormalizePaths {
+    mountpoint: String,
+    trailing_slash: bool,
+    case_insensitive: bool,
+    ipv4_mapped: MappedPolicy,
+    /// Segments of the paths of the API routes, known once Rocket
+    /// is launched
+    routes: OnceLock<Vec<Vec<Segment>>>,
+}
+
+/// Segment of the path of a route
+#[derive(Debug, PartialEq)]
+enum Segment {
+    /// Static segment, in lowercase
+    Static(String),
+    /// Parameter matching a single segment, ex: `<ip>`
+    Param,
+    /// Parameter matching all the remaining segments, ex: `<path..>`
+    Rest,
+}
+
+/// Segments of a route path, ex: `/api/ip/<ip>`
+fn route(path: &str) -> Vec<Segment> {
+    path.split('/')
+        .map(|s| match s {
+            s if s.starts_with('<') && s.ends_with("..>") => Segment::Rest,
+            s if s.starts_with('<') => Segment::Param,
+            s => Segment::Static(s.to_ascii_lowercase()),
+        })
+        .collect()
+}
+
+/// Whether the segments of `path` match those of `route`,
+/// regardless of the case of its static segments
+fn matches(route: &[Segment], path: &[String]) -> bool {
+    let mut path = path.iter();
+    for segment in route {
+        match (segment, path.next()) {
+            (Segment::Rest, _) => return true,
+            (Segment::Static(s), Some(p)) if s.eq_ignore_ascii_case(p) => {}
+            (Segment::Param, Some(_)) => {}
+            _ => return false,
+        }
+    }
+    path.next().is_none()
+}
+
+impl NormalizePaths {
+    pub fn new(config: &Config) -> Self {
+        NormalizePaths {
+            mountpoint: config.api_mountpoint().to_string(),
+            trailing_slash: config.api_strip_trailing_slash,
+            case_insensitive: config.api_case_insensitive,
+            ipv4_mapped: config.ipv4_mapped,
+            routes: OnceLock::new(),
+        }
+    }
+
+    fn is_api(&self, path: &str) -> bool {
+        let len = self.mountpoint.len();
+        let matches = path.get(..len).is_some_and(|p| {
+            if self.case_insensitive {
+                p.eq_ignore_ascii_case(&self.mountpoint)
+            } else {
+                p == self.mountpoint
+            }
+        });
+        matches && (path.len() == len || path[len..].starts_with('/'))
+    }
+
+    /// Normalized version of `path`, if it differs
+    fn normalize(&self, path: &str) -> Option<String> {
+        if !self.is_api(path) {
+            return None;
+        }
+
+        let trimmed = match self.trailing_slash {
+            true => path.trim_end_matches('/'),
+            false => path,
+        };
+        let mut segments: Vec<String> = trimmed
+            .split('/')
+            .map(|s| {
+                self.unmapped(s)
+                    .map_or_else(|| s.to_string(), |v4| v4.to_string())
+            })
+            .collect();
+
+        // only the static segments of the routes the path matches are
+        // folded, parameters keep their case
+        if self.case_insensitive
+            && let Some(routes) = self.routes.get()
+        {
+            for route in routes {
+                if !matches(route, &segments) {
+                    continue;
+                }
+                for (segment, r) in segments.iter_mut().zip(route) {
+                    if let Segment::Static(s) = r {
+                        segment.clone_from(s);
+                    }
+                }
+            }
+        }
+
+        let new = segments.join("/");
+        (new != path).then_some(new)
+    }
+
+    /// IPv4 address mapped by `segment`, if it is an IPv4-mapped IPv6
+    /// address to unmap
+    fn unmapped(&self, segment: &str) -> Option<Ipv4Addr> {
+        if self.ipv4_mapped != MappedPolicy::Unmap {
+            return None;
+        }
+        RawStr::new(segment)
+            .percent_decode()
+            .ok()?
+            .parse::<Ipv6Addr>()
+            .ok()?
+            .to_ipv4_mapped()
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for NormalizePaths {
+    fn info(&self) -> Info {
+        Info {
+            name: "Path Normalizer",
+            kind: Kind::Liftoff | Kind::Request,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let routes = rocket
+            .routes()
+            .map(|r| r.uri.path())
+            .filter(|p| self.is_api(p))
+            .map(route)
+            .collect();
+        let _ = self.routes.set(routes);
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(path) = self.normalize(req.uri().path().as_str()) else {
+            return;
+        };
+
+        let uri = match req.uri().query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path,
+        };
+        match Origin::parse_owned(uri) {
+            Ok(uri) => req.set_uri(uri),
+            Err(e) => log::warn!("failed to normalize {}: {e}", req.uri()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalizer(ipv4_mapped: MappedPolicy) -> NormalizePaths {
+        NormalizePaths::new(&Config {
+            ipv4_mapped,
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn mapped_addresses_reach_the_ipv4_story() {
+        let paths = normalizer(MappedPolicy::Unmap);
+
+        for path in [
+            "/api/ip/::ffff:192.0.2.1/entry",
+            "/api/ip/%3A%3Affff%3A192.0.2.1/entry",
+            "/api/ip/::FFFF:c000:201/entry",
+        ] {
+            assert_eq!(
+                paths.normalize(path).as_deref(),
+                Some("/api/ip/192.0.2.1/entry"),
+                "{path}"
+            );
+        }
+        assert_eq!(paths.normalize("/api/ip/192.0.2.1/entry"), None);
+        assert_eq!(paths.normalize("/api/ip/2001:db8::1/entry"), None);
+    }
+
+    #[test]
+    fn mapped_addresses_are_kept_apart_unless_unmapped() {
+        for policy in [MappedPolicy::Keep, MappedPolicy::Reject] {
+            let paths = normalizer(policy);
+            assert_eq!(paths.normalize("/api/ip/::ffff:192.0.2.1/entry"), None);
+        }
+    }
+
+    #[test]
+    fn frontend_paths_are_left_as_is() {
+        let paths = normalizer(MappedPolicy::Unmap);
+        assert_eq!(paths.normalize("/ip/::ffff:192.0.2.1/"), None);
+    }
+
+    /// Case insensitive normalizer of the paths of `routes`
+    fn case_insensitive(routes: &[&str]) -> NormalizePaths {
+        let paths = NormalizePaths::new(&Config {
+            api_case_insensitive: true,
+            ..Config::default()
+        });
+        let _ = paths.routes.set(routes.iter().map(|r| route(r)).collect());
+        paths
+    }
+
+    #[test]
+    fn trailing_slashes_are_stripped() {
+        let paths = normalizer(MappedPolicy::Unmap);
+
+        assert_eq!(
+            paths.normalize("/api/ip/1.2.3.4/entry/search/").as_deref(),
+            Some("/api/ip/1.2.3.4/entry/search")
+        );
+        assert_eq!(paths.normalize("/api/ip//").as_deref(), Some("/api/ip"));
+        assert_eq!(paths.normalize("/api/ip/1.2.3.4/entry/search"), None);
+
+        let kept = NormalizePaths::new(&Config {
+            api_strip_trailing_slash: false,
+            ..Config::default()
+        });
+        assert_eq!(kept.normalize("/api/ip/1.2.3.4/"), None);
+    }
+
+    #[test]
+    fn static_segments_are_folded_to_lowercase() {
+        let paths = case_insensitive(&["/api/ip/<ip>/entry/search", "/api/ip/<ip>/entry/<uuid>"]);
+
+        assert_eq!(
+            paths.normalize("/API/IP/1.2.3.4/Entry/SEARCH/").as_deref(),
+            Some("/api/ip/1.2.3.4/entry/search")
+        );
+        // parameters keep their case, ex: hexadecimal IPv6 addresses
+        assert_eq!(
+            paths.normalize("/api/IP/2001:DB8::1/entry/ABCD").as_deref(),
+            Some("/api/ip/2001:DB8::1/entry/ABCD")
+        );
+    }
+
+    #[test]
+    fn parameters_named_like_static_segments_keep_their_case() {
+        let paths =
+            case_insensitive(&["/api/ip/<ip>/entry", "/api/ip/<ip>/entry/<uuid>/tags/<tag>"]);
+
+        assert_eq!(
+            paths
+                .normalize("/api/IP/1.2.3.4/entry/d0e1/TAGS/Entry")
+                .as_deref(),
+            Some("/api/ip/1.2.3.4/entry/d0e1/tags/Entry")
+        );
+        assert_eq!(
+            paths.normalize("/api/ip/1.2.3.4/entry/d0e1/tags/Entry"),
+            None
+        );
+        // paths of no route are left to the 404 catcher
+        assert_eq!(paths.normalize("/api/IPS/1.2.3.4"), None);
+    }
+
+    #[test]
+    fn asset_paths_are_left_alone() {
+        let paths = case_insensitive(&["/api/ip/<ip>", "/<path..>"]);
+
+        for path in ["/assets/Index-BXnB3xKm.js", "/IP/1.2.3.4/", "/API-docs/"] {
+            assert_eq!(paths.normalize(path), None, "{path}");
+        }
+    }
+}
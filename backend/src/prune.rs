@@ -0,0 +1,242 @@
+//! Removal of the expired entries, in the background and on request
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use ip_story_model::{ApiResponse, Entry};
+use log::{error, info};
+use rocket::{State, post};
+
+use crate::{
+    API_MOUNTPOINT, IpEntries, IpStory,
+    api::{ApiData, ApiError, ApiResult, ErrorResponses, Writable, WriteErrorResponses},
+    audit::Principal,
+    config::Config,
+    events::Events,
+    record_deletions,
+    storage::Storage,
+    storage_error,
+};
+
+/// Removes the expired entries every `prune_interval_secs`
+pub fn spawn(db: Arc<Storage>, events: Events, config: Config) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(config.prune_interval_secs));
+        loop {
+            ticker.tick().await;
+            match prune(&db, &events, &config, &Principal::system(), false) {
+                Ok(pruned) => {
+                    let n: usize = pruned.iter().map(|p| p.entries.len()).sum();
+                    if n > 0 {
+                        info!("pruned {n} expired entries");
+                    }
+                }
+                Err(e) => error!("failed to prune expired entries: {e}"),
+            }
+        }
+    });
+}
+
+/// Removes the expired entries of every IP address, according to their
+/// `expires_at` or to the retention of their kind, returns the entries
+/// removed. A dry run only returns them.
+fn prune(
+    db: &Storage,
+    events: &Events,
+    config: &Config,
+    principal: &Principal,
+    dry_run: bool,
+) -> Result<Vec<IpEntries>, ApiError> {
+    let ips = db
+        .ips()
+        .map_err(|e| storage_error!(e, "failed to list ips"))?;
+
+    let mut pruned = vec![];
+    for ip in ips {
+        let now = Utc::now();
+
+        let expired = db
+            .update_hip(ip, |ipst| {
+                Ok::<_, ApiError>(take_expired(ipst, config, now, dry_run))
+            })
+            .map_err(|e| storage_error!(e, "failed to prune entries"))??;
+
+        record_deletions(db, events, principal, ip, &expired, dry_run);
+        if !expired.is_empty() {
+            pruned.push(IpEntries {
+                ip,
+                entries: expired,
+            });
+        }
+    }
+
+    Ok(pruned)
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("dry_run" = Option<bool>, Query, description = "Returns the entries which would be removed without removing them"),
+    ),
+    responses(
+        (status = 200, description = "Expired entries removed successfully", body = ApiResponse<Vec<IpEntries>>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "Storage",
+    description = "Removes the expired entries of every IP address right away, as the pruner does every prune_interval_secs, according to their expires_at or to the retention of their kind. A dry run selects the same entries but leaves them in place, writing no audit record and publishing no event. Returns an ApiResponse with the entries removed by IP address or an error message. Only unrestricted API keys can run it, the others being answered with a 403 Forbidden and the admin_required code."
+)]
+#[post("/admin/prune?<dry_run>")]
+pub async fn admin_prune(
+    dry_run: bool,
+    _writable: Writable,
+    principal: Principal,
+    config: &State<Config>,
+    events: &State<Events>,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Vec<IpEntries>> {
+    principal.check_admin()?;
+
+    Ok(ApiData::Some(prune(
+        db, events, config, &principal, dry_run,
+    )?))
+}
+
+/// Removes the entries of `ipst` expired at `now` and returns them. A dry
+/// run only returns them, and a story left unchanged is not stored.
+fn take_expired(
+    ipst: &mut IpStory,
+    config: &Config,
+    now: DateTime<Utc>,
+    dry_run: bool,
+) -> Vec<Entry> {
+    let keys: Vec<_> = ipst
+        .history
+        .iter()
+        .filter(|(_, e)| config.is_expired(e, now))
+        .map(|(k, _)| *k)
+        .collect();
+
+    if dry_run {
+        return keys
+            .iter()
+            .filter_map(|k| ipst.history.get(k).cloned())
+            .collect();
+    }
+    keys.iter().filter_map(|k| ipst.history.remove(k)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use ip_story_model::{Data, DataKind};
+
+    use super::*;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    fn entry(secs: i64, data: Data, expires_at: Option<i64>) -> Entry {
+        Entry {
+            ctime: Some(at(secs)),
+            expires_at: expires_at.map(at),
+            ..Entry::new(data)
+        }
+    }
+
+    fn story(entries: impl IntoIterator<Item = Entry>) -> IpStory {
+        let mut ipst = IpStory::new("192.0.2.1".parse().unwrap());
+        for e in entries {
+            ipst.history.insert(e.ctime.unwrap(), e);
+        }
+        ipst
+    }
+
+    fn ctimes<'a>(entries: impl IntoIterator<Item = &'a Entry>) -> Vec<i64> {
+        entries
+            .into_iter()
+            .map(|e| e.ctime.unwrap().timestamp())
+            .collect()
+    }
+
+    #[test]
+    fn entries_past_their_expiration_are_taken() {
+        let mut ipst = story([
+            entry(1, Data::Text("expired".into()), Some(100)),
+            entry(2, Data::Text("expires now".into()), Some(200)),
+            entry(3, Data::Text("later".into()), Some(300)),
+            entry(4, Data::Text("forever".into()), None),
+        ]);
+
+        let taken = take_expired(&mut ipst, &Config::default(), at(200), false);
+
+        assert_eq!(ctimes(&taken), [1, 2]);
+        assert_eq!(ctimes(ipst.history.values()), [3, 4]);
+    }
+
+    #[test]
+    fn dry_runs_take_the_same_entries_but_leave_them() {
+        let mut config = Config::default();
+        config.retention_secs.insert(DataKind::Text, 100);
+        let mut ipst = story([
+            entry(1, Data::Text("old".into()), None),
+            entry(2, Data::Json(serde_json::json!({"old": true})), Some(100)),
+            entry(150, Data::Text("recent".into()), None),
+        ]);
+
+        let taken = take_expired(&mut ipst, &config, at(200), true);
+
+        assert_eq!(ctimes(&taken), [1, 2]);
+        assert_eq!(ctimes(ipst.history.values()), [1, 2, 150]);
+        let taken = take_expired(&mut ipst, &config, at(200), false);
+        assert_eq!(ctimes(&taken), [1, 2]);
+        assert_eq!(ctimes(ipst.history.values()), [150]);
+    }
+
+    #[test]
+    fn retention_applies_to_its_kind_only() {
+        let mut config = Config::default();
+        config.retention_secs.insert(DataKind::Text, 100);
+        let mut ipst = story([
+            entry(1, Data::Text("old".into()), None),
+            entry(150, Data::Text("recent".into()), None),
+            entry(2, Data::Json(serde_json::json!({"old": true})), None),
+        ]);
+
+        let taken = take_expired(&mut ipst, &config, at(200), false);
+
+        assert_eq!(ctimes(&taken), [1]);
+        assert_eq!(ctimes(ipst.history.values()), [2, 150]);
+    }
+
+    #[test]
+    fn expires_at_takes_precedence_over_retention() {
+        let mut config = Config::default();
+        config.retention_secs.insert(DataKind::Text, 100);
+        let mut ipst = story([
+            entry(1, Data::Text("kept".into()), Some(1000)),
+            entry(190, Data::Text("taken".into()), Some(199)),
+        ]);
+
+        let taken = take_expired(&mut ipst, &config, at(200), false);
+
+        assert_eq!(ctimes(&taken), [190]);
+        assert_eq!(ctimes(ipst.history.values()), [1]);
+    }
+
+    #[test]
+    fn retention_counts_from_the_last_modification() {
+        let mut config = Config::default();
+        config.retention_secs.insert(DataKind::Text, 100);
+        let mut modified = entry(1, Data::Text("modified".into()), None);
+        modified.mtime = Some(at(1) + TimeDelta::seconds(150));
+        let mut ipst = story([modified]);
+
+        assert!(take_expired(&mut ipst, &config, at(200), false).is_empty());
+        assert_eq!(
+            ctimes(&take_expired(&mut ipst, &config, at(251), false)),
+            [1]
+        );
+    }
+}
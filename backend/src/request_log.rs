@@ -0,0 +1,55 @@
+use std::time::Instant;
+
+use log::LevelFilter;
+use rocket::{
+    Data, Request, Response,
+    fairing::{Fairing, Info, Kind},
+};
+
+use crate::api::ResponseError;
+
+/// Time at which a request started to be processed
+struct Start(Instant);
+
+/// Logs the method, path, status and processing time of every request,
+/// along with the error returned by the API if any
+pub struct RequestLogger(LevelFilter);
+
+impl RequestLogger {
+    pub fn new(level: LevelFilter) -> Self {
+        RequestLogger(level)
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Logger",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(|| Start(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Some(level) = self.0.to_level() else {
+            return;
+        };
+
+        let elapsed = req.local_cache(|| Start(Instant::now())).0.elapsed();
+        let status = res.status().code;
+
+        match &req.local_cache(|| ResponseError(None)).0 {
+            Some(err) => log::log!(
+                level,
+                "{} {} {status} {elapsed:?} error={err}",
+                req.method(),
+                req.uri()
+            ),
+            None => log::log!(level, "{} {} {status} {elapsed:?}", req.method(), req.uri()),
+        }
+    }
+}
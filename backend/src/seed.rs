@@ -0,0 +1,227 @@
+//! Example stories for demos and manual testing, NOT meant for production
+//! stores
+
+use std::{net::IpAddr, sync::Arc};
+
+use ip_story_model::{ApiResponse, Entry};
+use rocket::{State, post};
+use serde::Serialize;
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::{
+    API_MOUNTPOINT, IpStory,
+    api::{ApiData, ApiResult, ErrorResponses, Writable, WriteErrorResponses},
+    api_error,
+    audit::{AuditAction, AuditRecord, Principal, audit},
+    check_ip,
+    config::Config,
+    events::Events,
+    storage::Storage,
+    storage_error,
+};
+
+/// Stories seeded, with IP addresses of the documentation ranges and fixed
+/// UUIDs and times so that every seeded store holds the same data
+fn fixtures() -> serde_json::Value {
+    json!([
+        {
+            "ip": "192.0.2.10",
+            "entries": [
+                {
+                    "uuid": "5eed0000-0000-4000-8000-000000000001",
+                    "ctime": "2025-01-06T09:00:00Z",
+                    "description": "registration of the network",
+                    "tags": ["demo", "whois"],
+                    "data": {"owner": {
+                        "name": "Example Hosting",
+                        "address": "1 Example Street, Luxembourg",
+                        "country": "LU",
+                        "abuse": ["abuse@hosting.example"],
+                        "phone": "+352 00 00 00"
+                    }}
+                },
+                {
+                    "uuid": "5eed0000-0000-4000-8000-000000000002",
+                    "ctime": "2025-01-06T09:00:01Z",
+                    "tags": ["demo"],
+                    "data": {"asn": 64496}
+                },
+                {
+                    "uuid": "5eed0000-0000-4000-8000-000000000003",
+                    "ctime": "2025-01-07T14:30:00Z",
+                    "description": "ssh brute force reported by the honeypot",
+                    "tags": ["demo", "bruteforce"],
+                    "severity": "medium",
+                    "classification": "tlp:green",
+                    "data": {"misp-event": {
+                        "server": "https://misp.example",
+                        "uuid": "5eed0000-0000-4000-8000-0000000000e1"
+                    }}
+                },
+                {
+                    "uuid": "5eed0000-0000-4000-8000-000000000004",
+                    "ctime": "2025-01-07T15:00:00Z",
+                    "description": "abuse report sent to the hoster",
+                    "tags": ["demo"],
+                    "links": ["5eed0000-0000-4000-8000-000000000003"],
+                    "data": {"ticket": {
+                        "server": "https://tracker.example",
+                        "id": {"id": 4242}
+                    }}
+                }
+            ]
+        },
+        {
+            "ip": "198.51.100.7",
+            "entries": [
+                {
+                    "uuid": "5eed0000-0000-4000-8000-000000000005",
+                    "ctime": "2025-02-03T08:15:00Z",
+                    "description": "outdated web server found by the scan",
+                    "tags": ["demo", "scan"],
+                    "classification": "tlp:amber",
+                    "data": {"vulnerable": "CVE-2021-41773"}
+                },
+                {
+                    "uuid": "5eed0000-0000-4000-8000-000000000006",
+                    "ctime": "2025-02-03T10:00:00Z",
+                    "tags": ["demo"],
+                    "data": {"text": "owner contacted, patch planned for next week"}
+                }
+            ]
+        },
+        {
+            "ip": "2001:db8::53",
+            "entries": [
+                {
+                    "uuid": "5eed0000-0000-4000-8000-000000000007",
+                    "ctime": "2025-03-12T18:45:00Z",
+                    "description": "open resolver used in an amplification attack",
+                    "tags": ["demo", "ddos"],
+                    "severity": "high",
+                    "data": {"json": {
+                        "port": 53,
+                        "protocol": "udp",
+                        "amplification": 54,
+                        "sensor": "edge-1"
+                    }}
+                },
+                {
+                    "uuid": "5eed0000-0000-4000-8000-000000000008",
+                    "ctime": "2025-03-13T07:00:00Z",
+                    "tags": ["demo"],
+                    "links": ["5eed0000-0000-4000-8000-000000000007"],
+                    "data": {"text": "resolver closed by its owner"}
+                }
+            ]
+        }
+    ])
+}
+
+#[derive(serde::Deserialize)]
+struct Fixture {
+    ip: IpAddr,
+    entries: Vec<Entry>,
+}
+
+/// Outcome of the seeding of the store
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Seed {
+    /// IP addresses holding example entries
+    #[schema(value_type = Vec<String>)]
+    ips: Vec<IpAddr>,
+    /// Number of entries created, 0 when the store was already seeded
+    created: usize,
+    /// Number of entries not created as their kind is not allowed
+    skipped: usize,
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 200, description = "Store seeded", body = ApiResponse<Seed>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "Storage",
+    description = "NOT FOR PRODUCTION: fills the store with example stories, for demos and manual testing. The route is only mounted when the seed_enabled setting is on. The stories are the same on every store: IP addresses of the documentation ranges (192.0.2.0/24, 198.51.100.0/24 and 2001:db8::/32) holding entries of every kind, with fixed UUIDs and creation times, all tagged demo. Seeding is idempotent, the entries already present are left as they are, so seeding twice creates nothing the second time. Entries of kinds not allowed by allowed_kinds are skipped. Returns an ApiResponse with the seeded IP addresses and the number of entries created, or an error message."
+)]
+#[post("/admin/seed")]
+pub async fn admin_seed(
+    principal: Principal,
+    config: &State<Config>,
+    events: &State<Events>,
+    _writable: Writable,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Seed> {
+    let fixtures: Vec<Fixture> = serde_json::from_value(fixtures())
+        .map_err(|e| api_error!(format!("invalid fixtures: {e}")))?;
+    let mut seed = Seed {
+        ips: vec![],
+        created: 0,
+        skipped: 0,
+    };
+
+    for Fixture { ip, mut entries } in fixtures {
+        check_ip(ip, config)?;
+
+        let before = entries.len();
+        entries.retain(|e| config.kind_allowed(&e.data.kind()));
+        seed.skipped += before - entries.len();
+        for entry in entries.iter_mut() {
+            entry
+                .data
+                .normalize(config.strict_countries)
+                .map_err(|e| api_error!(e))?;
+            entry.severity = entry.severity.or(entry.data.default_severity());
+        }
+
+        if db
+            .create_hip(IpStory::new(ip))
+            .map_err(|e| storage_error!(e, "failed to insert new ip"))?
+        {
+            audit(db, AuditRecord::new(&principal, AuditAction::Create, ip));
+        }
+
+        let created = db
+            .update_hip(ip, |ipst| {
+                let present = ipst.uuids();
+                let mut created = vec![];
+                for entry in entries.iter() {
+                    if entry.uuid.is_some_and(|u| present.contains(&u)) {
+                        continue;
+                    }
+                    let Some(mut ctime) = entry.ctime else {
+                        continue;
+                    };
+                    // entries added since the fixtures may use their times
+                    while ipst.history.contains_key(&ctime) {
+                        ctime += chrono::TimeDelta::nanoseconds(1);
+                    }
+                    let entry = Entry {
+                        ctime: Some(ctime),
+                        ..entry.clone()
+                    };
+                    ipst.history.insert(ctime, entry.clone());
+                    created.push(entry);
+                }
+                Ok::<_, std::convert::Infallible>(created)
+            })
+            .map_err(|e| storage_error!(e, "failed to seed entries"))?
+            .unwrap_or_else(|e| match e {});
+
+        for entry in &created {
+            audit(
+                db,
+                AuditRecord::new(&principal, AuditAction::Create, ip).entry(entry),
+            );
+            events.publish(AuditAction::Create, ip, entry.clone());
+        }
+
+        seed.created += created.len();
+        seed.ips.push(ip);
+    }
+
+    Ok(ApiData::Some(seed))
+}
@@ -0,0 +1,277 @@
+//! Cookie sessions of browser clients, along with the double-submit
+//! tokens protecting them against cross-site request forgery
+
+use ip_story_model::ApiResponse;
+use rocket::{
+    delete, get,
+    http::{Cookie, CookieJar, SameSite, Status},
+    post,
+    request::{self, FromRequest, Outcome, Request},
+};
+use uuid::Uuid;
+
+use crate::{
+    API_MOUNTPOINT,
+    api::{ApiData, ApiError, ApiResult, ErrorResponses},
+    api_error,
+    audit::Principal,
+};
+
+/// Cookie holding the API key of a session
+pub const SESSION_COOKIE: &str = "ip-story-session";
+/// Cookie holding the CSRF token, readable by the frontend
+const CSRF_COOKIE: &str = "ip-story-csrf";
+/// Header the CSRF token is submitted in
+const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Cookie of a session named `name`, hidden from scripts if `http_only`.
+/// Removals must build the cookie the same way, browsers ignoring the
+/// removals of secure cookies which are not secure themselves.
+fn cookie(name: &'static str, value: impl Into<String>, http_only: bool) -> Cookie<'static> {
+    Cookie::build((name, value.into()))
+        .path("/")
+        .http_only(http_only)
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .build()
+}
+
+/// Whether the CSRF token submitted in the header of `req` is the one of
+/// its cookie. Requests authenticated by a session cookie but missing
+/// either are forged or stale.
+pub fn csrf_valid(req: &Request<'_>) -> bool {
+    let cookie = req
+        .cookies()
+        .get(CSRF_COOKIE)
+        .map(|c| c.value().to_string());
+    let header = req.headers().get_one(CSRF_HEADER);
+    match (cookie, header) {
+        (Some(cookie), Some(header)) if !cookie.is_empty() => {
+            // compares in constant time so the token cannot be guessed
+            // a byte at a time
+            cookie.len() == header.len()
+                && cookie
+                    .bytes()
+                    .zip(header.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        }
+        _ => false,
+    }
+}
+
+/// API key given in the `X-API-Key` header of a request, sessions being
+/// opened with one and not with the cookie of another session
+pub struct HeaderKey(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for HeaderKey {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match req.headers().get_one("X-API-Key") {
+            Some(key) => Outcome::Success(HeaderKey(key.to_string())),
+            None => {
+                let status = Status::BadRequest;
+                Outcome::Error((
+                    status,
+                    api_error!(status, "sessions are opened with an X-API-Key header"),
+                ))
+            }
+        }
+    }
+}
+
+/// Catcher answering the requests authenticated by a session cookie whose
+/// CSRF token is missing or wrong
+#[rocket::catch(403)]
+pub fn csrf_rejected(_req: &Request<'_>) -> ApiError {
+    ApiError::with_status(Status::Forbidden, "missing or invalid CSRF token")
+        .with_code("csrf_invalid")
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("X-API-Key" = String, Header, description = "The API key the session is opened with"),
+    ),
+    responses(
+        (status = 200, description = "Session opened", body = ApiResponse<String>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "Session",
+    description = "Opens a session for browser clients: the API key given in the `X-API-Key` header is set in an HTTP only, same-site strict and secure cookie authenticating the following requests, so that the frontend does not keep it. The route is only mounted with the session_cookies setting. Requests authenticated by the cookie must submit the token of GET /csrf in an `X-CSRF-Token` header to modify the store. Returns an ApiResponse with the name of the client of the key, or an error message."
+)]
+#[post("/session")]
+pub async fn session_open(
+    principal: Principal,
+    key: Result<HeaderKey, ApiError>,
+    cookies: &CookieJar<'_>,
+) -> ApiResult<String> {
+    let key = key?;
+
+    cookies.add(cookie(SESSION_COOKIE, key.0, true));
+    Ok(ApiData::Some(principal.name().to_string()))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 200, description = "Session closed", body = ApiResponse<bool>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "Session",
+    description = "Closes the session of a browser client, removing its session and CSRF cookies. The route is only mounted with the session_cookies setting. Returns an ApiResponse with true."
+)]
+#[delete("/session")]
+pub async fn session_close(cookies: &CookieJar<'_>) -> ApiResult<bool> {
+    cookies.remove(cookie(SESSION_COOKIE, "", true));
+    cookies.remove(cookie(CSRF_COOKIE, "", false));
+    Ok(ApiData::Some(true))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 200, description = "CSRF token issued", body = ApiResponse<String>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "Session",
+    description = "Issues a CSRF token to the clients authenticated by a session cookie, set in the `ip-story-csrf` same-site strict cookie and returned in the response. The requests of those clients modifying the store must submit it in an `X-CSRF-Token` header, otherwise they are answered with a 403 Forbidden and the csrf_invalid code; a forged request cannot read the token and so cannot submit it. Requests authenticated by an `X-API-Key` header do not need the token. The route is only mounted with the session_cookies setting. Returns an ApiResponse with the token."
+)]
+#[get("/csrf")]
+pub async fn csrf_token(cookies: &CookieJar<'_>) -> ApiResult<String> {
+    let token = Uuid::new_v4().simple().to_string();
+    cookies.add(cookie(CSRF_COOKIE, token.clone(), false));
+    Ok(ApiData::Some(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rocket::{
+        http::Header,
+        local::blocking::{Client, LocalRequest},
+    };
+
+    use super::*;
+    use crate::{
+        api::{self, Writable},
+        config::{ApiKey, Config},
+    };
+
+    #[post("/write")]
+    fn write(_writable: Writable) -> &'static str {
+        "written"
+    }
+
+    fn client() -> Client {
+        let key = ApiKey {
+            name: "soc".into(),
+            read: None,
+            write: None,
+        };
+        let config = Config {
+            api_keys: HashMap::from([("s3cr3t".into(), key)]),
+            session_cookies: true,
+            ..Config::default()
+        };
+        let rocket = rocket::build()
+            .mount(
+                "/",
+                rocket::routes![write, session_open, session_close, csrf_token],
+            )
+            .register("/", rocket::catchers![api::unauthorized, csrf_rejected])
+            .manage(config);
+        Client::tracked(rocket).unwrap()
+    }
+
+    fn with_session(req: LocalRequest<'_>) -> LocalRequest<'_> {
+        req.cookie(Cookie::new(SESSION_COOKIE, "s3cr3t"))
+    }
+
+    fn code(body: String) -> Option<String> {
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        body["code"].as_str().map(String::from)
+    }
+
+    #[test]
+    fn session_writes_without_the_csrf_token_are_rejected() {
+        let client = client();
+
+        let resp = with_session(client.post("/write")).dispatch();
+        assert_eq!(resp.status(), Status::Forbidden);
+        assert_eq!(
+            code(resp.into_string().unwrap()).as_deref(),
+            Some("csrf_invalid")
+        );
+
+        // a forged request may carry the cookie but not guess the token
+        let resp = with_session(client.post("/write"))
+            .cookie(Cookie::new(CSRF_COOKIE, "token"))
+            .header(Header::new(CSRF_HEADER, "guess"))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Forbidden);
+
+        let resp = with_session(client.post("/write"))
+            .header(Header::new(CSRF_HEADER, ""))
+            .cookie(Cookie::new(CSRF_COOKIE, ""))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn session_writes_with_the_csrf_token_are_accepted() {
+        let client = client();
+
+        let resp = with_session(client.post("/write"))
+            .cookie(Cookie::new(CSRF_COOKIE, "token"))
+            .header(Header::new(CSRF_HEADER, "token"))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+    }
+
+    #[test]
+    fn header_keys_need_no_csrf_token() {
+        let client = client();
+
+        let resp = client
+            .post("/write")
+            .header(Header::new("X-API-Key", "s3cr3t"))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+
+        // nor do unknown keys get further than the authentication
+        let resp = client
+            .post("/write")
+            .cookie(Cookie::new(SESSION_COOKIE, "unknown"))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn sessions_last_until_they_are_closed() {
+        let client = client();
+        let resp = client
+            .post("/session")
+            .header(Header::new("X-API-Key", "s3cr3t"))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+
+        let token = client.get("/csrf").dispatch().into_string().unwrap();
+        let token: serde_json::Value = serde_json::from_str(&token).unwrap();
+        let token = token["data"].as_str().unwrap().to_string();
+        let write = || {
+            client
+                .post("/write")
+                .header(Header::new(CSRF_HEADER, token.clone()))
+                .dispatch()
+                .status()
+        };
+        assert_eq!(write(), Status::Ok);
+
+        client.delete("/session").dispatch();
+        assert_eq!(write(), Status::Unauthorized);
+    }
+}
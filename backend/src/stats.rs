@@ -0,0 +1,198 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use ip_story_model::{ApiResponse, DataKind};
+use rocket::{State, get, post};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+use crate::{
+    API_MOUNTPOINT, IpStory,
+    api::{ApiData, ApiError, ApiResult, ErrorResponses, Writable, WriteErrorResponses},
+    api_error,
+    audit::Principal,
+    storage::{ScanCursor, Storage, StorageError},
+    storage_error,
+};
+
+/// Figures requiring to scan the whole store
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct ScanStats {
+    /// Number of entries per kind of data
+    pub kinds: BTreeMap<DataKind, usize>,
+    /// Approximate size of the stored stories, in bytes
+    pub bytes: usize,
+    /// Time at which the store got scanned
+    pub computed_at: DateTime<Utc>,
+}
+
+impl ScanStats {
+    /// Accounts for the serialized story `s`, counting the entries
+    /// `principal` can read. Stories count in full in the bytes stored.
+    pub fn add(&mut self, s: &str, principal: &Principal) -> Result<(), StorageError> {
+        let hip: IpStory = serde_json::from_str(s)?;
+        self.bytes += s.len();
+        for e in hip.history.values().filter(|e| principal.can_read(e)) {
+            *self.kinds.entry(e.data.kind()).or_default() += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Position of a scan given by a client, the start of the store if unset
+pub fn scan_cursor(token: Option<&str>) -> Result<ScanCursor, ApiError> {
+    token
+        .map(str::parse)
+        .transpose()
+        .map_err(|e| api_error!(format!("invalid cursor: {e}")).with_code("cursor_invalid"))
+        .map(Option::unwrap_or_default)
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Stats {
+    /// Number of tracked IP addresses
+    ips: usize,
+    /// Number of entries across all IP addresses, only the ones the
+    /// client can read for API keys restricted to some classifications
+    entries: usize,
+    #[serde(flatten)]
+    scan: ScanStats,
+}
+
+/// Figures of a page of the scan of the store
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StatsPage {
+    /// Number of IP addresses of the page
+    ips: usize,
+    #[serde(flatten)]
+    scan: ScanStats,
+    /// Cursor of the next page, unset once the whole store is scanned
+    next: Option<String>,
+}
+
+/// Last results of the scan of the store, reused until they expire
+pub struct StatsCache {
+    ttl: Duration,
+    /// Results per API key restricting the entries read
+    last: Mutex<HashMap<Option<String>, (Instant, ScanStats)>>,
+}
+
+impl StatsCache {
+    pub fn new(ttl: Duration) -> Self {
+        StatsCache {
+            ttl,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 200, description = "Statistics computed successfully", body = ApiResponse<Stats>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "Statistics",
+    description = "Computes statistics about the store. The numbers of IP addresses and of entries are always up to date while the other figures require to scan the whole store, so they are cached for the duration of the stats_ttl_secs setting. Clients whose API key only reads some classifications only get the entries of those counted, their number of entries then coming from the cached scan too. Returns an ApiResponse with the statistics or an error message."
+)]
+#[get("/stats")]
+pub async fn stats(
+    principal: Principal,
+    cache: &State<StatsCache>,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<Stats> {
+    let ips = db
+        .ip_count()
+        .map_err(|e| storage_error!(e, "failed to count ips"))?;
+
+    let key = principal.read_restriction().map(String::from);
+    let mut last = cache.last.lock().await;
+    let scan = match last.get(&key) {
+        Some((at, scan)) if at.elapsed() < cache.ttl => scan.clone(),
+        _ => {
+            let scan = db
+                .scan_stats(&principal)
+                .map_err(|e| storage_error!(e, "failed to compute stats"))?;
+            last.insert(key.clone(), (Instant::now(), scan.clone()));
+            scan
+        }
+    };
+
+    // the count index holds the entries of every classification
+    let entries = match key {
+        Some(_) => scan.kinds.values().sum(),
+        None => db
+            .entry_total()
+            .map_err(|e| storage_error!(e, "failed to count entries"))?,
+    };
+
+    Ok(ApiData::Some(Stats { ips, entries, scan }))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    params(
+        ("cursor" = Option<String>, Query, description = "Cursor returned as next by the previous page, the scan starts over if unset"),
+    ),
+    responses(
+        (status = 200, description = "Page scanned successfully", body = ApiResponse<StatsPage>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "Statistics",
+    description = "Computes the figures of GET /stats requiring a full scan of the store a page at a time, for clients to render them progressively on large stores. Every call reads storage_scan_count stories at most, starting at cursor, and returns the numbers of IP addresses, of entries per kind the client can read and of bytes of that page only, along with the cursor of the next page, unset once the scan is over. Clients add up the pages themselves. The store is live and not locked between pages: stories modified during the scan may be counted before or after the modification, and stories created or deleted may be missed or, rarely, counted twice, so totals are approximate. Pages may be empty while the scan is not over. Results are not cached. Returns an ApiResponse with the figures of the page, or an error message, with the cursor_invalid code if the cursor is not one returned by a previous page."
+)]
+#[get("/stats/scan?<cursor>")]
+pub async fn stats_scan(
+    cursor: Option<&str>,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<StatsPage> {
+    let at = scan_cursor(cursor)?;
+
+    let (stories, next) = db
+        .scan_page(at)
+        .map_err(|e| storage_error!(e, "failed to scan the store"))?;
+
+    let mut scan = ScanStats::default();
+    for s in stories.iter() {
+        scan.add(s, &principal)
+            .map_err(|e| storage_error!(e, "failed to compute stats"))?;
+    }
+    scan.computed_at = Utc::now();
+
+    Ok(ApiData::Some(StatsPage {
+        ips: stories.len(),
+        scan,
+        next: next.map(|n| n.to_string()),
+    }))
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 200, description = "Index rebuilt successfully", body = ApiResponse<usize>, content_type = "application/json"),
+        ErrorResponses,
+        WriteErrorResponses,
+    ),
+    tag = "Statistics",
+    description = "Rebuilds the index holding the number of entries of every IP address, in case it drifted from the stories. Returns an ApiResponse with the number of IP addresses indexed or an error message. Only unrestricted API keys can run it, the others being answered with a 403 Forbidden and the admin_required code."
+)]
+#[post("/stats/index/rebuild")]
+pub async fn count_index_rebuild(
+    _writable: Writable,
+    principal: Principal,
+    db: &State<Arc<Storage>>,
+) -> ApiResult<usize> {
+    principal.check_admin()?;
+
+    let n = db
+        .rebuild_count_index()
+        .map_err(|e| storage_error!(e, "failed to rebuild count index"))?;
+
+    Ok(ApiData::Some(n))
+}
@@ -0,0 +1,1690 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    hash::{BuildHasher, Hasher, RandomState},
+    net::IpAddr,
+    sync::{Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, anyhow, bail};
+use chrono::{DateTime, Utc};
+use ip_story_model::Cve;
+use redis::{
+    Client, Commands, Connection, FromRedisValue, Pipeline, RedisConnectionInfo, RedisError,
+    RedisResult, TlsMode,
+    sentinel::{Sentinel, SentinelNodeConnectionInfo},
+    streams::StreamRangeReply,
+};
+use rocket::FromFormField;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::Url;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    IpStory,
+    audit::{AuditRecord, Principal},
+    config::HistoryKey,
+    stats::ScanStats,
+};
+
+/// Hash holding the stories in the [`Layout::Hash`] layout
+const MAP_NAME: &str = "ip-story";
+/// Prefix of the keys holding the stories in the [`Layout::Keys`] layout
+const IP_KEY_PREFIX: &str = "ip-story:ip:";
+/// Hash mapping entry uuids to the IP address they belong to
+const UUID_INDEX: &str = "ip-story:uuid";
+/// Prefix of the sets holding the IP addresses having an ASN entry
+const ASN_INDEX_PREFIX: &str = "ip-story:asn:";
+/// Prefix of the sets holding the IP addresses having an entry
+/// mentioning a CVE, by canonical CVE identifier
+const CVE_INDEX_PREFIX: &str = "ip-story:cve:";
+/// Prefix of the sets holding the uuids of the entries linking to an entry
+const BACKLINKS_PREFIX: &str = "ip-story:backlinks:";
+/// Hash mapping IP addresses to the number of entries of their story
+const COUNT_INDEX: &str = "ip-story:count";
+/// Sorted set of the IP addresses having entries, scored by the most
+/// recent creation or modification time of their entries, in microseconds
+const SEEN_INDEX: &str = "ip-story:seen";
+/// Append-only stream of the mutations made on the store
+const AUDIT_STREAM: &str = "ip-story:audit";
+/// Hash the records which cannot be loaded are moved to by a repair,
+/// by the field or key they were stored under
+const QUARANTINE: &str = "ip-story:quarantine";
+/// Number of times a transaction is attempted before giving up
+/// because of concurrent modifications of the keys it watches
+const TRANSACTION_ATTEMPTS: usize = 32;
+/// Share of the maximum story size past which writes are logged
+const STORY_SIZE_WARNING: f64 = 0.8;
+
+/// Failure of a storage operation
+#[derive(Debug, Error)]
+pub enum StorageError {
+    /// Redis could not be reached or failed to run a command, only
+    /// those errors are retried
+    #[error("{0}")]
+    Connection(#[from] RedisError),
+    /// A record of the store cannot be loaded
+    #[error("invalid record: {0}")]
+    Serialization(#[from] serde_json::Error),
+    /// The IP address is not tracked
+    #[error("{0} is not tracked")]
+    NotFound(IpAddr),
+    /// The keys of a transaction kept being modified concurrently
+    #[error("too many concurrent modifications of {0}")]
+    Conflict(String),
+    /// The story of the IP address would grow past the maximum size
+    #[error(
+        "story of {ip} would take {size} bytes, more than the {max} allowed, prune or delete some of its entries"
+    )]
+    TooLarge { ip: IpAddr, size: usize, max: usize },
+}
+
+impl StorageError {
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, StorageError::Connection(e) if e.is_timeout())
+    }
+}
+
+/// Converts a [`StorageError`] into an [`ApiError`](crate::api::ApiError).
+/// Timeouts are reported as such with a `504 Gateway Timeout` status, other
+/// connection failures with a `503 Service Unavailable` one, records
+/// which cannot be loaded with a `500 Internal Server Error` one, untracked IP
+/// addresses with a `404 Not Found`, conflicts with a `409 Conflict` and
+/// stories growing too large with a `413 Payload Too Large`.
+/// Connection and serialization errors are logged.
+#[macro_export]
+macro_rules! storage_error {
+    ($err: expr, $msg: expr) => {{
+        use rocket::http::Status;
+        use $crate::storage::StorageError;
+
+        let err: StorageError = $err;
+        match &err {
+            StorageError::Connection(_) if err.is_timeout() => {
+                log::error!("{}: {err}", $msg);
+                $crate::api_error!(Status::GatewayTimeout, "storage timeout")
+            }
+            StorageError::Connection(_) => {
+                log::error!("{}: {err}", $msg);
+                $crate::api_error!(Status::ServiceUnavailable, $msg)
+                    .with_code("storage_unavailable")
+            }
+            StorageError::Serialization(_) => {
+                log::error!("{}: {err}", $msg);
+                $crate::api_error!(Status::InternalServerError, $msg).with_code("storage_corrupt")
+            }
+            StorageError::NotFound(_) => $crate::api_error!(Status::NotFound, err.to_string()),
+            StorageError::Conflict(_) => $crate::api_error!(Status::Conflict, err.to_string()),
+            StorageError::TooLarge { .. } => {
+                $crate::api_error!(Status::PayloadTooLarge, err.to_string())
+                    .with_code("story_too_large")
+            }
+        }
+    }};
+}
+
+/// Whether `REDIS_TLS` asks for TLS connections
+fn redis_tls() -> anyhow::Result<bool> {
+    match env::var("REDIS_TLS").ok().as_deref() {
+        None | Some("0" | "false" | "no") => Ok(false),
+        Some("1" | "true" | "yes") => Ok(true),
+        Some(v) => bail!("invalid REDIS_TLS value: {v}"),
+    }
+}
+
+/// Builds the Redis connection URL from the environment. `REDIS_URL` is
+/// used when set, otherwise the URL is made out of `REDIS_HOST`,
+/// `REDIS_PORT`, `REDIS_PASSWORD`, `REDIS_DB` and `REDIS_TLS`.
+fn redis_url() -> anyhow::Result<Url> {
+    if let Ok(url) = env::var("REDIS_URL") {
+        return Url::parse(&url).context("invalid REDIS_URL");
+    }
+
+    let Ok(host) = env::var("REDIS_HOST") else {
+        bail!("redis is not configured, either REDIS_URL or REDIS_HOST must be set");
+    };
+
+    let scheme = if redis_tls()? { "rediss" } else { "redis" };
+
+    let mut url = Url::parse(&format!("{scheme}://{host}")).context("invalid REDIS_HOST")?;
+
+    if let Ok(port) = env::var("REDIS_PORT") {
+        let port = port.parse::<u16>().context("invalid REDIS_PORT")?;
+        url.set_port(Some(port))
+            .map_err(|_| anyhow!("cannot set port on redis url"))?;
+    }
+
+    if let Ok(password) = env::var("REDIS_PASSWORD") {
+        url.set_password(Some(&password))
+            .map_err(|_| anyhow!("cannot set password on redis url"))?;
+    }
+
+    if let Ok(db) = env::var("REDIS_DB") {
+        let db = db.parse::<u32>().context("invalid REDIS_DB")?;
+        url.set_path(&format!("/{db}"));
+    }
+
+    Ok(url)
+}
+
+fn open(redis_url: Url) -> anyhow::Result<Client> {
+    if redis_url.scheme() == "rediss" && !cfg!(feature = "tls") {
+        bail!("rediss:// connections require to build with the tls feature");
+    }
+
+    // Create a Redis client
+    let client = Client::open(redis_url).context("failed to create redis client")?;
+
+    Ok(client)
+}
+
+/// Connects to the main Redis instance, through the sentinels of
+/// `REDIS_SENTINELS` if set or to the single node of [`redis_url`]
+pub fn connect_to_redis() -> anyhow::Result<Instance> {
+    if let Ok(sentinels) = env::var("REDIS_SENTINELS") {
+        let master = env::var("REDIS_SENTINEL_MASTER").unwrap_or_else(|_| "mymaster".into());
+        return sentinel(&sentinels, master);
+    }
+    open(redis_url()?).map(Instance::Node)
+}
+
+/// Connects to the Redis instance dedicated to IPv6 addresses, if
+/// `REDIS_IPV6_URL` is set, or if `REDIS_IPV6_SENTINEL_MASTER` names
+/// the master monitored by the sentinels of `REDIS_SENTINELS` storing them
+pub fn connect_to_redis_v6() -> anyhow::Result<Option<Instance>> {
+    if let Ok(master) = env::var("REDIS_IPV6_SENTINEL_MASTER") {
+        let Ok(sentinels) = env::var("REDIS_SENTINELS") else {
+            bail!("REDIS_IPV6_SENTINEL_MASTER requires REDIS_SENTINELS to be set");
+        };
+        return sentinel(&sentinels, master).map(Some);
+    }
+    match env::var("REDIS_IPV6_URL") {
+        Ok(url) => Ok(Some(Instance::Node(open(
+            Url::parse(&url).context("invalid REDIS_IPV6_URL")?,
+        )?))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Instance whose `master` is looked up through the comma separated
+/// `sentinels` URLs. `REDIS_PASSWORD`, `REDIS_DB` and `REDIS_TLS` apply
+/// to the connections to the master.
+fn sentinel(sentinels: &str, master: String) -> anyhow::Result<Instance> {
+    let sentinels = sentinels
+        .split(',')
+        .map(|s| Url::parse(s.trim()).with_context(|| format!("invalid sentinel url: {s}")))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let tls = redis_tls()?;
+    if (tls || sentinels.iter().any(|s| s.scheme() == "rediss")) && !cfg!(feature = "tls") {
+        bail!("rediss:// connections require to build with the tls feature");
+    }
+
+    let db = match env::var("REDIS_DB") {
+        Ok(db) => db.parse().context("invalid REDIS_DB")?,
+        Err(_) => 0,
+    };
+    let node = SentinelNodeConnectionInfo {
+        tls_mode: tls.then_some(TlsMode::Secure),
+        redis_connection_info: Some(RedisConnectionInfo {
+            db,
+            password: env::var("REDIS_PASSWORD").ok(),
+            ..Default::default()
+        }),
+    };
+
+    let sentinel = Sentinel::build(sentinels).context("failed to create sentinel client")?;
+    Ok(Instance::Sentinel {
+        sentinel: Mutex::new(sentinel),
+        master,
+        node,
+    })
+}
+
+/// Redis deployment an instance of the store is reached through
+pub enum Instance {
+    /// Single node
+    Node(Client),
+    /// Master of a replicated deployment, looked up through Redis Sentinel
+    /// for every connection so that failovers are followed
+    Sentinel {
+        sentinel: Mutex<Sentinel>,
+        master: String,
+        node: SentinelNodeConnectionInfo,
+    },
+}
+
+impl Instance {
+    /// Client of the node currently serving the instance
+    fn client(&self) -> RedisResult<Client> {
+        match self {
+            Instance::Node(client) => Ok(client.clone()),
+            Instance::Sentinel {
+                sentinel,
+                master,
+                node,
+            } => sentinel
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .master_for(master, Some(node)),
+        }
+    }
+}
+
+fn ip_key(field: &str) -> String {
+    format!("{IP_KEY_PREFIX}{field}")
+}
+
+/// Page of about `count` (field, value) pairs of the stories hash starting
+/// at `cursor`, read with `HSCAN`, along with the cursor of the next page
+fn hscan(
+    con: &mut Connection,
+    cursor: u64,
+    count: usize,
+) -> RedisResult<(u64, Vec<(String, String)>)> {
+    redis::cmd("HSCAN")
+        .arg(MAP_NAME)
+        .arg(cursor)
+        .arg("COUNT")
+        .arg(count)
+        .query(con)
+}
+
+/// Page of about `count` keys of the stories of the [`Layout::Keys`]
+/// layout starting at `cursor`, read with `SCAN`, along with the cursor
+/// of the next page
+fn scan_keys(con: &mut Connection, cursor: u64, count: usize) -> RedisResult<(u64, Vec<String>)> {
+    redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("MATCH")
+        .arg(format!("{IP_KEY_PREFIX}*"))
+        .arg("COUNT")
+        .arg(count)
+        .query(con)
+}
+
+/// Items of a scan driven by a cursor, fetching the page at a cursor with
+/// `page` only once the previous page is consumed. The scan starts at the
+/// cursor 0 and is over once `page` returns it again, or fails.
+fn paged<T, E>(
+    mut page: impl FnMut(u64) -> Result<(u64, Vec<T>), E>,
+) -> impl Iterator<Item = Result<T, E>> {
+    let mut items = Vec::new().into_iter();
+    let (mut cursor, mut done) = (0, false);
+    std::iter::from_fn(move || {
+        loop {
+            if let Some(item) = items.next() {
+                return Some(Ok(item));
+            }
+            if done {
+                return None;
+            }
+            match page(cursor) {
+                Ok((next, page)) => {
+                    items = page.into_iter();
+                    done = next == 0;
+                    cursor = next;
+                }
+                Err(e) => {
+                    done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    })
+}
+
+/// Position of a scan of the store carried out a page at a time, handed
+/// to clients as an opaque `<instance>-<cursor>` token
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScanCursor {
+    /// Instance being scanned, 0 for the main one, 1 for the IPv6 one
+    instance: usize,
+    /// `HSCAN`/`SCAN` cursor within the instance
+    cursor: u64,
+}
+
+impl std::fmt::Display for ScanCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.instance, self.cursor)
+    }
+}
+
+impl std::str::FromStr for ScanCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (instance, cursor) = s.split_once('-').context("missing separator")?;
+        Ok(ScanCursor {
+            instance: instance.parse()?,
+            cursor: cursor.parse()?,
+        })
+    }
+}
+
+/// How the stories are laid out in Redis, indexes are
+/// the same whatever the layout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, FromFormField, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    /// All the stories are fields of a single hash. Operations on the
+    /// whole store are atomic, but modifications of different IP
+    /// addresses conflict with each other and the store cannot be
+    /// spread across nodes.
+    #[default]
+    Hash,
+    /// Every story is a key of its own (`ip-story:ip:<ip>`). Modifications
+    /// of different IP addresses do not conflict and keys can be spread
+    /// across nodes, but enumerating the stories requires to scan the
+    /// keyspace and operations on the whole store are not atomic.
+    Keys,
+}
+
+impl Layout {
+    /// Key to watch to detect concurrent modifications of the story `field`
+    fn watch_key(self, field: &str) -> String {
+        match self {
+            Layout::Hash => MAP_NAME.into(),
+            Layout::Keys => ip_key(field),
+        }
+    }
+
+    fn get<T: FromRedisValue>(self, con: &mut Connection, field: &str) -> RedisResult<T> {
+        match self {
+            Layout::Hash => con.hget(MAP_NAME, field),
+            Layout::Keys => con.get(ip_key(field)),
+        }
+    }
+
+    /// Serialized stories of `fields`, `None` for the missing ones
+    fn get_many(self, con: &mut Connection, fields: &[String]) -> RedisResult<Vec<Option<String>>> {
+        match self {
+            Layout::Hash => redis::cmd("HMGET").arg(MAP_NAME).arg(fields).query(con),
+            Layout::Keys => redis::cmd("MGET")
+                .arg(fields.iter().map(|f| ip_key(f)).collect::<Vec<_>>())
+                .query(con),
+        }
+    }
+
+    fn set(self, pipe: &mut Pipeline, field: &str, story: &str) {
+        match self {
+            Layout::Hash => pipe.hset(MAP_NAME, field, story),
+            Layout::Keys => pipe.set(ip_key(field), story),
+        }
+        .ignore();
+    }
+
+    fn set_nx(self, con: &mut Connection, field: &str, story: &str) -> RedisResult<bool> {
+        match self {
+            Layout::Hash => con.hset_nx(MAP_NAME, field, story),
+            Layout::Keys => con.set_nx(ip_key(field), story),
+        }
+    }
+
+    /// Same as [`Layout::set_nx`], queued on `pipe`
+    fn queue_set_nx(self, pipe: &mut Pipeline, field: &str, story: &str) {
+        match self {
+            Layout::Hash => pipe.hset_nx(MAP_NAME, field, story),
+            Layout::Keys => pipe.set_nx(ip_key(field), story),
+        };
+    }
+
+    fn del(self, con: &mut Connection, field: &str) -> RedisResult<()> {
+        match self {
+            Layout::Hash => con.hdel(MAP_NAME, field),
+            Layout::Keys => con.del(ip_key(field)),
+        }
+    }
+
+    fn exists(self, pipe: &mut Pipeline, field: &str) {
+        match self {
+            Layout::Hash => pipe.hexists(MAP_NAME, field),
+            Layout::Keys => pipe.exists(ip_key(field)),
+        };
+    }
+
+    /// IP addresses of all the stories, as stored, scanned `count` at a
+    /// time so that Redis is not blocked on a large store
+    fn fields(self, con: &mut Connection, count: usize) -> RedisResult<Vec<String>> {
+        match self {
+            Layout::Hash => paged(|cursor| hscan(con, cursor, count))
+                .map(|pair| pair.map(|(f, _)| f))
+                .collect(),
+            Layout::Keys => paged(|cursor| scan_keys(con, cursor, count))
+                .filter_map(|key| match key {
+                    Ok(k) => k.strip_prefix(IP_KEY_PREFIX).map(|f| Ok(f.to_string())),
+                    Err(e) => Some(Err(e)),
+                })
+                .collect(),
+        }
+    }
+
+    fn count(self, con: &mut Connection, count: usize) -> RedisResult<usize> {
+        match self {
+            Layout::Hash => con.hlen(MAP_NAME),
+            Layout::Keys => Ok(self.fields(con, count)?.len()),
+        }
+    }
+
+    /// Page of about `count` stories starting at `cursor`, as (IP address,
+    /// serialized story) pairs, along with the cursor of the next page, 0
+    /// once the scan is over
+    fn scan(
+        self,
+        con: &mut Connection,
+        cursor: u64,
+        count: usize,
+    ) -> RedisResult<(u64, Vec<(String, String)>)> {
+        match self {
+            Layout::Hash => hscan(con, cursor, count),
+            Layout::Keys => {
+                let (next, keys) = scan_keys(con, cursor, count)?;
+                if keys.is_empty() {
+                    return Ok((next, vec![]));
+                }
+                let stories: Vec<Option<String>> = con.mget(&keys)?;
+                // stories deleted since the scan are skipped
+                let pairs = keys
+                    .iter()
+                    .zip(stories)
+                    .filter_map(|(k, s)| Some((k.strip_prefix(IP_KEY_PREFIX)?.to_string(), s?)))
+                    .collect();
+                Ok((next, pairs))
+            }
+        }
+    }
+
+    /// Every story, as (IP address, serialized story) pairs, read a page
+    /// of about `count` stories at a time on `con`, so that neither Redis
+    /// is blocked nor the whole store is held in memory
+    fn iter(
+        self,
+        con: &mut Connection,
+        count: usize,
+    ) -> impl Iterator<Item = RedisResult<(String, String)>> + '_ {
+        paged(move |cursor| self.scan(con, cursor, count))
+    }
+}
+
+/// Outcome of the migration of the stories from one layout to another
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct Migration {
+    /// Number of stories moved
+    pub moved: usize,
+    /// IP addresses having a different story in both layouts, which
+    /// are left in place
+    #[schema(value_type = Vec<String>)]
+    pub conflicts: Vec<IpAddr>,
+}
+
+/// Record of the store which cannot be loaded as a story
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CorruptRecord {
+    /// Field, or key without its prefix, the record is stored under
+    pub field: String,
+    pub error: String,
+}
+
+/// Outcome of the check of the records of the store
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct Repair {
+    /// Number of records checked
+    pub checked: usize,
+    pub corrupt: Vec<CorruptRecord>,
+    /// Whether the corrupt records got moved to the quarantine hash
+    pub quarantined: bool,
+}
+
+/// Reason why the record stored under `field` cannot be loaded, if any
+fn check_record(field: &str, s: &str) -> Option<String> {
+    let ip: IpAddr = match field.parse() {
+        Ok(ip) => ip,
+        Err(_) => return Some("field is not an ip address".into()),
+    };
+    match serde_json::from_str::<IpStory>(s) {
+        Ok(hip) if hip.ip != ip => Some(format!("story of {} stored under {ip}", hip.ip)),
+        Ok(_) => None,
+        Err(e) => Some(format!("invalid story: {e}")),
+    }
+}
+
+fn asn_key(asn: u64) -> String {
+    format!("{ASN_INDEX_PREFIX}{asn}")
+}
+
+fn cve_key(cve: &Cve) -> String {
+    format!("{CVE_INDEX_PREFIX}{cve}")
+}
+
+fn backlinks_key(uuid: Uuid) -> String {
+    format!("{BACKLINKS_PREFIX}{uuid}")
+}
+
+/// How storage operations failing because of connection
+/// issues are retried
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    /// Maximum number of retries
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for every retry
+    pub base_delay: Duration,
+    /// Time after which an operation is not retried anymore
+    pub max_time: Duration,
+}
+
+impl Retry {
+    /// Delay before the retry following `attempt`, randomized by up
+    /// to 50% so that clients do not retry all at once
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(1 << attempt.min(16));
+        let jitter = RandomState::new().build_hasher().finish() % 1000;
+        delay.mul_f64(0.5 + jitter as f64 / 2000.0)
+    }
+
+    /// Runs `f` until it succeeds, retrying with an exponential backoff
+    /// when it fails because of the connection, until the policy gives
+    /// up. Timeouts and other errors are returned right away.
+    fn run<T>(&self, mut f: impl FnMut() -> Result<T, StorageError>) -> Result<T, StorageError> {
+        let start = Instant::now();
+        let mut attempt = 0;
+
+        loop {
+            let err = match f() {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+
+            let transient = match &err {
+                StorageError::Connection(e) => {
+                    !e.is_timeout()
+                        && (e.is_io_error()
+                            || e.is_connection_dropped()
+                            || e.is_connection_refusal())
+                }
+                _ => false,
+            };
+
+            let delay = self.delay(attempt);
+            attempt += 1;
+            if !transient || attempt > self.attempts || start.elapsed() + delay > self.max_time {
+                return Err(err);
+            }
+
+            log::warn!("transient storage error, retrying in {delay:?}: {err}");
+            sleep(delay);
+        }
+    }
+}
+
+/// Waits for `delay` without stalling the other tasks of the runtime,
+/// which get moved to another worker meanwhile
+fn sleep(delay: Duration) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(h) if h.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| thread::sleep(delay))
+        }
+        _ => thread::sleep(delay),
+    }
+}
+
+/// Stories and their indexes, stored on a single Redis instance or
+/// split by IP version across two of them. Every story, along with the
+/// index entries derived from it, lives on the instance of its IP
+/// address so that it is modified in a single transaction, lookups on
+/// the indexes query both instances and merge their answers.
+pub struct Storage {
+    /// Stores the IPv4 addresses, and the IPv6 ones if `v6` is not set,
+    /// along with the audit trail
+    client: Instance,
+    v6: Option<Instance>,
+    timeout: Option<Duration>,
+    retry: Retry,
+    layout: Layout,
+    /// Timestamp the histories are keyed on, stories are rekeyed
+    /// as they get modified
+    history_key: HistoryKey,
+    /// Maximum size, in bytes, of a serialized story
+    max_story_size: usize,
+    /// `COUNT` hint of the scans of the whole store
+    scan_count: usize,
+}
+
+impl Storage {
+    /// Creates a new storage, laying the stories out according to
+    /// `layout`, storing IPv6 addresses on `v6` if set and everything else
+    /// on `client`. Every operation fails if it does not complete within
+    /// `timeout` and is retried on connection failures according to
+    /// `retry`. Histories are keyed on their `history_key` timestamp and
+    /// modifications growing a story past `max_story_size` bytes fail.
+    /// The whole store is scanned `scan_count` stories at a time.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        client: Instance,
+        v6: Option<Instance>,
+        timeout: Option<Duration>,
+        retry: Retry,
+        layout: Layout,
+        history_key: HistoryKey,
+        max_story_size: usize,
+        scan_count: usize,
+    ) -> Self {
+        Storage {
+            client,
+            v6,
+            timeout,
+            retry,
+            layout,
+            history_key,
+            max_story_size,
+            scan_count: scan_count.max(1),
+        }
+    }
+
+    /// Instance holding the story of `ip`
+    fn client(&self, ip: IpAddr) -> &Instance {
+        match (ip, &self.v6) {
+            (IpAddr::V6(_), Some(v6)) => v6,
+            _ => &self.client,
+        }
+    }
+
+    /// Runs `f` on every instance, returns their results in order
+    fn on_all<T>(
+        &self,
+        f: impl FnMut(&Instance) -> Result<T, StorageError>,
+    ) -> Result<Vec<T>, StorageError> {
+        std::iter::once(&self.client)
+            .chain(self.v6.iter())
+            .map(f)
+            .collect()
+    }
+
+    fn connection(&self, instance: &Instance) -> Result<Connection, RedisError> {
+        let client = instance.client()?;
+        let Some(timeout) = self.timeout else {
+            return client.get_connection();
+        };
+
+        let con = client.get_connection_with_timeout(timeout)?;
+        con.set_read_timeout(Some(timeout))?;
+        con.set_write_timeout(Some(timeout))?;
+        Ok(con)
+    }
+
+    /// Runs `f` on a connection to `client`, retried according to the
+    /// retry policy when the connection fails
+    fn with_retry<T, E: Into<StorageError>>(
+        &self,
+        client: &Instance,
+        mut f: impl FnMut(&mut Connection) -> Result<T, E>,
+    ) -> Result<T, StorageError> {
+        self.retry.run(|| {
+            let mut con = self.connection(client)?;
+            f(&mut con).map_err(Into::into)
+        })
+    }
+
+    /// Runs `f` in a transaction watching `keys`, `f` returning `None`
+    /// when its pipeline got aborted by a concurrent modification of
+    /// them, in which case it is run again. Gives up with a
+    /// [`StorageError::Conflict`] after [`TRANSACTION_ATTEMPTS`] attempts.
+    fn transaction<T>(
+        con: &mut Connection,
+        keys: &[String],
+        mut f: impl FnMut(&mut Connection, &mut Pipeline) -> Result<Option<T>, StorageError>,
+    ) -> Result<T, StorageError> {
+        for _ in 0..TRANSACTION_ATTEMPTS {
+            redis::cmd("WATCH").arg(keys).exec(con)?;
+            if let Some(res) = f(con, redis::pipe().atomic())? {
+                redis::cmd("UNWATCH").exec(con)?;
+                return Ok(res);
+            }
+        }
+        Err(StorageError::Conflict(keys.join(", ")))
+    }
+
+    /// Runs `f` in a transaction watching all the stories, which is only
+    /// possible with the hash layout. With the keys layout the
+    /// transaction does not watch anything, so concurrent modifications
+    /// of the stories go unnoticed.
+    fn store_transaction<T>(
+        &self,
+        con: &mut Connection,
+        mut f: impl FnMut(&mut Connection, &mut Pipeline) -> Result<Option<T>, StorageError>,
+    ) -> Result<T, StorageError> {
+        match self.layout {
+            Layout::Hash => Self::transaction(con, &[MAP_NAME.into()], f),
+            Layout::Keys => loop {
+                if let Some(res) = f(con, redis::pipe().atomic())? {
+                    return Ok(res);
+                }
+            },
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(%ip))]
+    pub fn get_hip(&self, ip: IpAddr) -> Result<IpStory, StorageError> {
+        let s: Option<String> =
+            self.with_retry(self.client(ip), |con| self.layout.get(con, &ip.to_string()))?;
+        let s = s.ok_or(StorageError::NotFound(ip))?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    /// Stories of `ips`, fetched with a single command per instance,
+    /// the IP addresses which are not tracked are left out
+    #[tracing::instrument(skip_all)]
+    pub fn get_hips(&self, ips: &[IpAddr]) -> Result<BTreeMap<IpAddr, IpStory>, StorageError> {
+        let (v6, v4): (Vec<IpAddr>, Vec<IpAddr>) =
+            ips.iter().partition(|ip| ip.is_ipv6() && self.v6.is_some());
+
+        let mut hips = BTreeMap::new();
+        for ips in [v4, v6] {
+            let Some(&first) = ips.first() else {
+                continue;
+            };
+
+            let fields: Vec<String> = ips.iter().map(|ip| ip.to_string()).collect();
+            let stories =
+                self.with_retry(self.client(first), |con| self.layout.get_many(con, &fields))?;
+
+            for (ip, s) in ips.into_iter().zip(stories) {
+                if let Some(s) = s {
+                    hips.insert(ip, serde_json::from_str(&s)?);
+                }
+            }
+        }
+
+        Ok(hips)
+    }
+
+    /// Number of tracked IP addresses
+    #[tracing::instrument(skip_all)]
+    pub fn ip_count(&self) -> Result<usize, StorageError> {
+        let counts =
+            self.on_all(|c| self.with_retry(c, |con| self.layout.count(con, self.scan_count)))?;
+        Ok(counts.into_iter().sum())
+    }
+
+    /// Number of entries of `ip`, read from the count index,
+    /// `None` if the IP address is not tracked
+    #[tracing::instrument(skip_all, fields(%ip))]
+    pub fn entry_count(&self, ip: IpAddr) -> Result<Option<usize>, StorageError> {
+        let field = ip.to_string();
+        let (tracked, count): (bool, Option<usize>) = self.with_retry(self.client(ip), |con| {
+            let mut pipe = redis::pipe();
+            self.layout.exists(&mut pipe, &field);
+            pipe.hget(COUNT_INDEX, &field).query(con)
+        })?;
+        // stories never updated have no count yet
+        Ok(tracked.then(|| count.unwrap_or_default()))
+    }
+
+    /// Number of entries across all IP addresses, read from the count index
+    #[tracing::instrument(skip_all)]
+    pub fn entry_total(&self) -> Result<usize, StorageError> {
+        let counts =
+            self.on_all(|c| self.with_retry(c, |con| con.hvals::<_, Vec<usize>>(COUNT_INDEX)))?;
+        Ok(counts.into_iter().flatten().sum())
+    }
+
+    /// Scans all the stories to compute the statistics of the store,
+    /// as `principal` can read it
+    #[tracing::instrument(skip_all)]
+    pub fn scan_stats(&self, principal: &Principal) -> Result<ScanStats, StorageError> {
+        let mut stats = ScanStats::default();
+
+        for s in self.scan_raw() {
+            stats.add(&s?, principal)?;
+        }
+
+        stats.computed_at = Utc::now();
+        Ok(stats)
+    }
+
+    /// Page of the stories of the `instance`th instance (0 for the main
+    /// one, 1 for the IPv6 one) starting at `cursor`, as serialized,
+    /// along with the cursor of the next page, 0 once the scan of the
+    /// instance is over. `None` if there is no such instance.
+    #[tracing::instrument(skip_all)]
+    pub fn scan_hips(
+        &self,
+        instance: usize,
+        cursor: u64,
+    ) -> Result<Option<(u64, Vec<String>)>, StorageError> {
+        let Some(client) = std::iter::once(&self.client)
+            .chain(self.v6.iter())
+            .nth(instance)
+        else {
+            return Ok(None);
+        };
+        let (next, pairs) =
+            self.with_retry(client, |con| self.layout.scan(con, cursor, self.scan_count))?;
+        Ok(Some((next, pairs.into_iter().map(|(_, s)| s).collect())))
+    }
+
+    /// Page of the stories found at `at`, as serialized, along with the
+    /// position of the next page, `None` once the whole store is scanned.
+    /// Pages may be empty while the scan is not over.
+    pub fn scan_page(
+        &self,
+        at: ScanCursor,
+    ) -> Result<(Vec<String>, Option<ScanCursor>), StorageError> {
+        let Some((next, stories)) = self.scan_hips(at.instance, at.cursor)? else {
+            return Ok((vec![], None));
+        };
+        let next = match next {
+            0 if at.instance + 1 > usize::from(self.v6.is_some()) => None,
+            0 => Some(ScanCursor {
+                instance: at.instance + 1,
+                cursor: 0,
+            }),
+            cursor => Some(ScanCursor {
+                instance: at.instance,
+                cursor,
+            }),
+        };
+        Ok((stories, next))
+    }
+
+    /// Every story of `instance` laid out according to `layout`, as (IP
+    /// address, serialized story) pairs, read a page of about `scan_count`
+    /// stories at a time with `HSCAN` or `SCAN` so that Redis is never
+    /// blocked by a single large read. Pages are read, and retried, one
+    /// at a time as the pairs are consumed.
+    fn scan_instance<'a>(
+        &'a self,
+        instance: &'a Instance,
+        layout: Layout,
+    ) -> impl Iterator<Item = Result<(String, String), StorageError>> + 'a {
+        paged(move |cursor| {
+            self.with_retry(instance, |con| layout.scan(con, cursor, self.scan_count))
+        })
+    }
+
+    /// Every story of the store as serialized, see [`Storage::scan_instance`]
+    fn scan_raw(&self) -> impl Iterator<Item = Result<String, StorageError>> + '_ {
+        std::iter::once(&self.client)
+            .chain(self.v6.iter())
+            .flat_map(|c| self.scan_instance(c, self.layout))
+            .map(|pair| pair.map(|(_, s)| s))
+    }
+
+    /// Every story of the store along with its IP address, read
+    /// incrementally. The scan is not a snapshot: stories modified while
+    /// it runs may be missed or returned twice.
+    pub fn scan_all(&self) -> impl Iterator<Item = Result<(IpAddr, IpStory), StorageError>> + '_ {
+        self.scan_raw().map(|s| {
+            let hip: IpStory = serde_json::from_str(&s?)?;
+            Ok((hip.ip, hip))
+        })
+    }
+
+    /// Runs `f` on every story of the store
+    #[tracing::instrument(skip_all)]
+    pub fn for_each_hip(&self, mut f: impl FnMut(&IpStory)) -> Result<(), StorageError> {
+        for hip in self.scan_all() {
+            f(&hip?.1);
+        }
+        Ok(())
+    }
+
+    /// Tracked IP addresses
+    #[tracing::instrument(skip_all)]
+    pub fn ips(&self) -> Result<Vec<IpAddr>, StorageError> {
+        let ips =
+            self.on_all(|c| self.with_retry(c, |con| self.layout.fields(con, self.scan_count)))?;
+        Ok(ips
+            .into_iter()
+            .flatten()
+            .filter_map(|ip| ip.parse().ok())
+            .collect())
+    }
+
+    /// IP addresses whose entries were last created or modified at or
+    /// after `since` and before `before`, along with that time, read from
+    /// the last-seen index. IP addresses without entries are left out.
+    #[tracing::instrument(skip_all)]
+    pub fn seen_ips(
+        &self,
+        since: Option<DateTime<Utc>>,
+        before: Option<DateTime<Utc>>,
+    ) -> Result<Vec<(IpAddr, DateTime<Utc>)>, StorageError> {
+        let min = since.map_or("-inf".into(), |t| t.timestamp_micros().to_string());
+        let max = before.map_or("+inf".into(), |t| format!("({}", t.timestamp_micros()));
+
+        let seen = self.on_all(|c| {
+            self.with_retry(c, |con| {
+                con.zrangebyscore_withscores::<_, _, _, Vec<(String, f64)>>(SEEN_INDEX, &min, &max)
+            })
+        })?;
+        Ok(seen
+            .into_iter()
+            .flatten()
+            .filter_map(|(ip, t)| {
+                Some((ip.parse().ok()?, DateTime::from_timestamp_micros(t as i64)?))
+            })
+            .collect())
+    }
+
+    /// Stores `hip` unless a story already exists for its IP address,
+    /// returns whether it got stored. The secondary indexes are not
+    /// maintained so it must only be used to create new (empty) stories,
+    /// use [`Storage::update_hip`] to modify existing ones.
+    #[tracing::instrument(skip_all)]
+    pub fn create_hip(&self, hip: IpStory) -> Result<bool, StorageError> {
+        let s = serde_json::to_string(&hip).unwrap();
+        self.with_retry(self.client(hip.ip), |con| {
+            self.layout.set_nx(con, &hip.ip.to_string(), &s)
+        })
+    }
+
+    /// Creates empty stories for those of `ips` not tracked yet, with a
+    /// single pipeline per instance, returns whether each got created
+    #[tracing::instrument(skip_all)]
+    pub fn create_hips(&self, ips: &[IpAddr]) -> Result<BTreeMap<IpAddr, bool>, StorageError> {
+        let (v6, v4): (Vec<IpAddr>, Vec<IpAddr>) =
+            ips.iter().partition(|ip| ip.is_ipv6() && self.v6.is_some());
+
+        let mut created = BTreeMap::new();
+        for ips in [v4, v6] {
+            let Some(&first) = ips.first() else {
+                continue;
+            };
+
+            let stories: Vec<(String, String)> = ips
+                .iter()
+                .map(|&ip| {
+                    let s = serde_json::to_string(&IpStory::new(ip)).unwrap();
+                    (ip.to_string(), s)
+                })
+                .collect();
+            let res: Vec<bool> = self.with_retry(self.client(first), |con| {
+                let mut pipe = redis::pipe();
+                for (field, s) in &stories {
+                    self.layout.queue_set_nx(&mut pipe, field, s);
+                }
+                pipe.query(con)
+            })?;
+
+            created.extend(ips.into_iter().zip(res));
+        }
+
+        Ok(created)
+    }
+
+    /// Loads the story of `ip`, applies `f` on it and stores the result along
+    /// with the secondary indexes, in a single transaction. The story is
+    /// watched while being modified so that a concurrent modification
+    /// restarts the whole operation, hence `f` may be called several times.
+    /// Nothing is stored if `f` fails or leaves the story unchanged, nor if
+    /// it grows the story past the maximum size.
+    #[tracing::instrument(skip_all, fields(%ip))]
+    pub fn update_hip<T, E>(
+        &self,
+        ip: IpAddr,
+        mut f: impl FnMut(&mut IpStory) -> Result<T, E>,
+    ) -> Result<Result<T, E>, StorageError> {
+        self.with_retry(self.client(ip), |con| {
+            let field = ip.to_string();
+
+            Self::transaction(con, &[self.layout.watch_key(&field)], |con, pipe| {
+                let s: Option<String> = self.layout.get(con, &field)?;
+                let s = s.ok_or(StorageError::NotFound(ip))?;
+                let mut hip: IpStory = serde_json::from_str(&s)?;
+
+                let prev_uuids = hip.uuids();
+                let prev_asns = hip.asns();
+                let prev_cves = hip.cves();
+                let prev_links = hip.links();
+                let prev_count = hip.history.len();
+                let prev_seen = hip.mtime();
+
+                let res = match f(&mut hip) {
+                    Ok(res) => res,
+                    Err(e) => return Ok(Some(Err(e))),
+                };
+                hip.rekey(self.history_key);
+
+                if serde_json::to_string(&hip).unwrap() == s {
+                    return Ok(Some(Ok(res)));
+                }
+                hip.revise(&serde_json::from_str(&s)?);
+                let new = serde_json::to_string(&hip).unwrap();
+
+                // stories only shrinking are let through so that they can be pruned
+                if new.len() > self.max_story_size && new.len() > s.len() {
+                    return Err(StorageError::TooLarge {
+                        ip,
+                        size: new.len(),
+                        max: self.max_story_size,
+                    });
+                }
+                if new.len() as f64 > self.max_story_size as f64 * STORY_SIZE_WARNING {
+                    log::warn!(
+                        "story of {ip} takes {} bytes, close to the {} allowed",
+                        new.len(),
+                        self.max_story_size
+                    );
+                }
+
+                let uuids = hip.uuids();
+                let removed: Vec<String> = prev_uuids
+                    .difference(&uuids)
+                    .map(|u| u.to_string())
+                    .collect();
+                let added: Vec<(String, &str)> = uuids
+                    .difference(&prev_uuids)
+                    .map(|u| (u.to_string(), field.as_str()))
+                    .collect();
+
+                self.layout.set(pipe, &field, &new);
+                if !removed.is_empty() {
+                    pipe.hdel(UUID_INDEX, removed).ignore();
+                }
+                if !added.is_empty() {
+                    pipe.hset_multiple(UUID_INDEX, &added).ignore();
+                }
+
+                if hip.history.len() != prev_count {
+                    pipe.hset(COUNT_INDEX, &field, hip.history.len()).ignore();
+                }
+
+                let seen = hip.mtime();
+                if seen != prev_seen {
+                    match seen {
+                        Some(t) => pipe.zadd(SEEN_INDEX, &field, t.timestamp_micros()).ignore(),
+                        None => pipe.zrem(SEEN_INDEX, &field).ignore(),
+                    };
+                }
+
+                let asns = hip.asns();
+                for asn in prev_asns.difference(&asns) {
+                    pipe.srem(asn_key(*asn), &field).ignore();
+                }
+                for asn in asns.difference(&prev_asns) {
+                    pipe.sadd(asn_key(*asn), &field).ignore();
+                }
+
+                let cves = hip.cves();
+                for cve in prev_cves.difference(&cves) {
+                    pipe.srem(cve_key(cve), &field).ignore();
+                }
+                for cve in cves.difference(&prev_cves) {
+                    pipe.sadd(cve_key(cve), &field).ignore();
+                }
+
+                let links = hip.links();
+                for (from, to) in prev_links.difference(&links) {
+                    pipe.srem(backlinks_key(*to), from.to_string()).ignore();
+                }
+                for (from, to) in links.difference(&prev_links) {
+                    pipe.sadd(backlinks_key(*to), from.to_string()).ignore();
+                }
+
+                // None means the transaction got aborted by a concurrent write
+                Ok(pipe.query::<Option<()>>(con)?.map(|_| Ok(res)))
+            })
+        })
+    }
+
+    /// Resolves the IP address an entry belongs to from its uuid
+    #[tracing::instrument(skip_all)]
+    pub fn entry_ip(&self, uuid: Uuid) -> Result<Option<IpAddr>, StorageError> {
+        let ips = self.on_all(|c| {
+            self.with_retry(c, |con| {
+                con.hget::<_, _, Option<String>>(UUID_INDEX, uuid.to_string())
+            })
+        })?;
+        // an unparsable value is treated as a missing one, the index
+        // needs to be rebuilt anyway
+        Ok(ips.into_iter().flatten().find_map(|ip| ip.parse().ok()))
+    }
+
+    /// Rebuilds the uuid index from the stories, returns the number
+    /// of entries indexed
+    #[tracing::instrument(skip_all)]
+    pub fn rebuild_uuid_index(&self) -> Result<usize, StorageError> {
+        let counts = self.on_all(|c| {
+            self.with_retry(c, |con| {
+                self.store_transaction(con, |con, pipe| {
+                    let mut index: Vec<(String, String)> = vec![];
+                    for pair in self.layout.iter(con, self.scan_count) {
+                        let (ip, s) = pair?;
+                        let hip: IpStory = serde_json::from_str(&s)?;
+                        index.extend(hip.uuids().into_iter().map(|u| (u.to_string(), ip.clone())));
+                    }
+
+                    pipe.del(UUID_INDEX).ignore();
+                    if !index.is_empty() {
+                        pipe.hset_multiple(UUID_INDEX, &index).ignore();
+                    }
+
+                    Ok(pipe.query::<Option<()>>(con)?.map(|_| index.len()))
+                })
+            })
+        })?;
+        Ok(counts.into_iter().sum())
+    }
+
+    /// Uuids of the entries linking to the entry `uuid`
+    #[tracing::instrument(skip_all)]
+    pub fn backlinks(&self, uuid: Uuid) -> Result<Vec<Uuid>, StorageError> {
+        let uuids = self.on_all(|c| {
+            self.with_retry(c, |con| con.smembers::<_, Vec<String>>(backlinks_key(uuid)))
+        })?;
+        Ok(uuids
+            .into_iter()
+            .flatten()
+            .filter_map(|u| u.parse().ok())
+            .collect())
+    }
+
+    /// Rebuilds the backlinks index from the stories, returns the
+    /// number of links indexed
+    #[tracing::instrument(skip_all)]
+    pub fn rebuild_backlinks_index(&self) -> Result<usize, StorageError> {
+        let counts = self.on_all(|c| {
+            self.with_retry(c, |con| {
+                self.store_transaction(con, |con, pipe| {
+                    let mut indexed = 0;
+                    let mut index: HashMap<Uuid, Vec<String>> = HashMap::new();
+                    for pair in self.layout.iter(con, self.scan_count) {
+                        let hip: IpStory = serde_json::from_str(&pair?.1)?;
+                        for (from, to) in hip.links() {
+                            indexed += 1;
+                            index.entry(to).or_default().push(from.to_string());
+                        }
+                    }
+
+                    let keys: Vec<String> =
+                        con.scan_match(format!("{BACKLINKS_PREFIX}*"))?.collect();
+                    if !keys.is_empty() {
+                        pipe.del(keys).ignore();
+                    }
+                    for (to, from) in index {
+                        pipe.sadd(backlinks_key(to), from).ignore();
+                    }
+
+                    Ok(pipe.query::<Option<()>>(con)?.map(|_| indexed))
+                })
+            })
+        })?;
+        Ok(counts.into_iter().sum())
+    }
+
+    /// IP addresses having an entry with the given ASN
+    #[tracing::instrument(skip_all)]
+    pub fn asn_ips(&self, asn: u64) -> Result<Vec<IpAddr>, StorageError> {
+        let ips = self
+            .on_all(|c| self.with_retry(c, |con| con.smembers::<_, Vec<String>>(asn_key(asn))))?;
+        // same as for the uuid index, unparsable values are skipped
+        Ok(ips
+            .into_iter()
+            .flatten()
+            .filter_map(|ip| ip.parse().ok())
+            .collect())
+    }
+
+    /// Rebuilds the ASN index from the stories, returns the number
+    /// of IP addresses indexed
+    #[tracing::instrument(skip_all)]
+    pub fn rebuild_asn_index(&self) -> Result<usize, StorageError> {
+        let counts = self.on_all(|c| {
+            self.with_retry(c, |con| {
+                self.store_transaction(con, |con, pipe| {
+                    let mut indexed = 0;
+                    let mut index: HashMap<u64, Vec<String>> = HashMap::new();
+                    for pair in self.layout.iter(con, self.scan_count) {
+                        let (ip, s) = pair?;
+                        let hip: IpStory = serde_json::from_str(&s)?;
+                        let asns = hip.asns();
+                        indexed += usize::from(!asns.is_empty());
+                        for asn in asns {
+                            index.entry(asn).or_default().push(ip.clone());
+                        }
+                    }
+
+                    let keys: Vec<String> =
+                        con.scan_match(format!("{ASN_INDEX_PREFIX}*"))?.collect();
+                    if !keys.is_empty() {
+                        pipe.del(keys).ignore();
+                    }
+                    for (asn, ips) in index {
+                        pipe.sadd(asn_key(asn), ips).ignore();
+                    }
+
+                    Ok(pipe.query::<Option<()>>(con)?.map(|_| indexed))
+                })
+            })
+        })?;
+        Ok(counts.into_iter().sum())
+    }
+
+    /// Rebuilds the count index from the stories, returns the
+    /// number of IP addresses indexed
+    #[tracing::instrument(skip_all)]
+    pub fn rebuild_count_index(&self) -> Result<usize, StorageError> {
+        let counts = self.on_all(|c| {
+            self.with_retry(c, |con| {
+                self.store_transaction(con, |con, pipe| {
+                    let index = self
+                        .layout
+                        .iter(con, self.scan_count)
+                        .map(|pair| {
+                            let (ip, s) = pair?;
+                            let hip: IpStory = serde_json::from_str(&s)?;
+                            Ok((ip, hip.history.len()))
+                        })
+                        .collect::<Result<Vec<(String, usize)>, StorageError>>()?;
+
+                    pipe.del(COUNT_INDEX).ignore();
+                    if !index.is_empty() {
+                        pipe.hset_multiple(COUNT_INDEX, &index).ignore();
+                    }
+
+                    Ok(pipe.query::<Option<()>>(con)?.map(|_| index.len()))
+                })
+            })
+        })?;
+        Ok(counts.into_iter().sum())
+    }
+
+    /// Rebuilds the last-seen index from the stories, returns the
+    /// number of IP addresses indexed
+    #[tracing::instrument(skip_all)]
+    pub fn rebuild_seen_index(&self) -> Result<usize, StorageError> {
+        let counts = self.on_all(|c| {
+            self.with_retry(c, |con| {
+                self.store_transaction(con, |con, pipe| {
+                    let mut index: Vec<(i64, String)> = vec![];
+                    for pair in self.layout.iter(con, self.scan_count) {
+                        let (ip, s) = pair?;
+                        let hip: IpStory = serde_json::from_str(&s)?;
+                        if let Some(t) = hip.mtime() {
+                            index.push((t.timestamp_micros(), ip));
+                        }
+                    }
+
+                    pipe.del(SEEN_INDEX).ignore();
+                    if !index.is_empty() {
+                        pipe.zadd_multiple(SEEN_INDEX, &index).ignore();
+                    }
+
+                    Ok(pipe.query::<Option<()>>(con)?.map(|_| index.len()))
+                })
+            })
+        })?;
+        Ok(counts.into_iter().sum())
+    }
+
+    /// Checks that every record of the store can be loaded as a story,
+    /// and if `fix`, moves those which cannot to the quarantine hash
+    /// of their instance so that they stop breaking the reads
+    #[tracing::instrument(skip_all)]
+    pub fn repair(&self, fix: bool) -> Result<Repair, StorageError> {
+        let mut repair = Repair {
+            quarantined: fix,
+            ..Default::default()
+        };
+
+        self.on_all(|c| {
+            for pair in self.scan_instance(c, self.layout) {
+                let (field, s) = pair?;
+                repair.checked += 1;
+
+                let Some(error) = check_record(&field, &s) else {
+                    continue;
+                };
+
+                if fix {
+                    self.with_retry(c, |con| {
+                        con.hset::<_, _, _, ()>(QUARANTINE, &field, &s)?;
+                        self.layout.del(con, &field)
+                    })?;
+                }
+                repair.corrupt.push(CorruptRecord { field, error });
+            }
+            Ok(())
+        })?;
+
+        Ok(repair)
+    }
+
+    /// Moves the stories laid out according to `from` to the layout
+    /// of the storage. Stories already present in both layouts are
+    /// only removed from `from` if they are the same, so that an
+    /// interrupted migration can be resumed.
+    #[tracing::instrument(skip_all)]
+    pub fn migrate(&self, from: Layout) -> Result<Migration, StorageError> {
+        let mut migration = Migration::default();
+        if from == self.layout {
+            return Ok(migration);
+        }
+
+        // a scan returns every element present from its start to its end,
+        // so stories are not missed as those already returned are deleted
+        self.on_all(|c| {
+            for pair in self.scan_instance(c, from) {
+                let (field, s) = pair?;
+                self.with_retry(c, |con| {
+                    if !self.layout.set_nx(con, &field, &s)? {
+                        let current: Option<String> = self.layout.get(con, &field)?;
+                        if current.as_ref() != Some(&s) {
+                            if let Ok(ip) = field.parse() {
+                                migration.conflicts.push(ip);
+                            }
+                            return Ok::<_, RedisError>(());
+                        }
+                    }
+
+                    from.del(con, &field)?;
+                    migration.moved += 1;
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        })?;
+
+        Ok(migration)
+    }
+
+    /// IP addresses having an entry mentioning `cve`
+    #[tracing::instrument(skip_all)]
+    pub fn cve_ips(&self, cve: &Cve) -> Result<Vec<IpAddr>, StorageError> {
+        let ips = self
+            .on_all(|c| self.with_retry(c, |con| con.smembers::<_, Vec<String>>(cve_key(cve))))?;
+        Ok(ips
+            .into_iter()
+            .flatten()
+            .filter_map(|ip| ip.parse().ok())
+            .collect())
+    }
+
+    /// Rebuilds the CVE index from the stories, returns the number
+    /// of IP addresses indexed
+    #[tracing::instrument(skip_all)]
+    pub fn rebuild_cve_index(&self) -> Result<usize, StorageError> {
+        let counts = self.on_all(|c| {
+            self.with_retry(c, |con| {
+                self.store_transaction(con, |con, pipe| {
+                    let mut indexed = 0;
+                    let mut index: HashMap<Cve, Vec<String>> = HashMap::new();
+                    for pair in self.layout.iter(con, self.scan_count) {
+                        let (ip, s) = pair?;
+                        let hip: IpStory = serde_json::from_str(&s)?;
+                        let cves = hip.cves();
+                        indexed += usize::from(!cves.is_empty());
+                        for cve in cves {
+                            index.entry(cve).or_default().push(ip.clone());
+                        }
+                    }
+
+                    let keys: Vec<String> =
+                        con.scan_match(format!("{CVE_INDEX_PREFIX}*"))?.collect();
+                    if !keys.is_empty() {
+                        pipe.del(keys).ignore();
+                    }
+                    for (cve, ips) in index {
+                        pipe.sadd(cve_key(&cve), ips).ignore();
+                    }
+
+                    Ok(pipe.query::<Option<()>>(con)?.map(|_| indexed))
+                })
+            })
+        })?;
+        Ok(counts.into_iter().sum())
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn append_audit(&self, record: &AuditRecord) -> Result<(), StorageError> {
+        let s = serde_json::to_string(record).unwrap();
+        let _: String = self.with_retry(&self.client, |con| {
+            con.xadd(AUDIT_STREAM, "*", &[("record", &s)])
+        })?;
+        Ok(())
+    }
+
+    /// Reads the audit records written between `from` and `to`
+    #[tracing::instrument(skip_all)]
+    pub fn audit_range(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<AuditRecord>, StorageError> {
+        // stream ids start with the insertion time in milliseconds
+        let from = from.map_or("-".into(), |t| t.timestamp_millis().to_string());
+        let to = to.map_or("+".into(), |t| t.timestamp_millis().to_string());
+
+        let reply: StreamRangeReply =
+            self.with_retry(&self.client, |con| con.xrange(AUDIT_STREAM, &from, &to))?;
+
+        Ok(reply
+            .ids
+            .iter()
+            .filter_map(|id| id.get::<String>("record"))
+            .filter_map(|s| serde_json::from_str(&s).ok())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+impl Storage {
+    /// Storage whose Redis node cannot be reached, every operation failing
+    /// with a connection error
+    pub fn unreachable() -> Self {
+        let client = Client::open("redis://127.0.0.1:1/").unwrap();
+        let retry = Retry {
+            attempts: 0,
+            base_delay: Duration::ZERO,
+            max_time: Duration::ZERO,
+        };
+        Storage::new(
+            Instance::Node(client),
+            None,
+            Some(Duration::from_millis(100)),
+            retry,
+            Layout::Hash,
+            HistoryKey::Ctime,
+            usize::MAX,
+            100,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    const RETRY: Retry = Retry {
+        attempts: 3,
+        base_delay: Duration::from_millis(1),
+        max_time: Duration::from_secs(10),
+    };
+
+    fn io_error(kind: io::ErrorKind) -> StorageError {
+        RedisError::from(io::Error::from(kind)).into()
+    }
+
+    #[test]
+    fn retry_recovers_from_a_transient_error() {
+        let mut calls = 0;
+        let res = RETRY.run(|| {
+            calls += 1;
+            if calls == 1 {
+                return Err(io_error(io::ErrorKind::ConnectionReset));
+            }
+            Ok(42)
+        });
+
+        assert_eq!(res.unwrap(), 42);
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn retry_gives_up_after_the_attempts() {
+        let mut calls = 0;
+        let res: Result<(), _> = RETRY.run(|| {
+            calls += 1;
+            Err(io_error(io::ErrorKind::ConnectionRefused))
+        });
+
+        assert!(matches!(res, Err(StorageError::Connection(_))));
+        assert_eq!(calls, RETRY.attempts + 1);
+    }
+
+    #[test]
+    fn retry_skips_timeouts_and_logical_errors() {
+        for err in [
+            || io_error(io::ErrorKind::TimedOut),
+            || StorageError::NotFound("1.2.3.4".parse().unwrap()),
+            || StorageError::Conflict("ip-story".into()),
+        ] {
+            let mut calls = 0;
+            let res: Result<(), _> = RETRY.run(|| {
+                calls += 1;
+                Err(err())
+            });
+
+            assert!(res.is_err());
+            assert_eq!(calls, 1);
+        }
+    }
+
+    #[test]
+    fn storage_errors_map_to_statuses_and_codes() {
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let corrupt = serde_json::from_str::<IpStory>("{").unwrap_err();
+        let cases = [
+            (io_error(io::ErrorKind::TimedOut), 504, "storage_timeout"),
+            (
+                io_error(io::ErrorKind::ConnectionRefused),
+                503,
+                "storage_unavailable",
+            ),
+            (corrupt.into(), 500, "storage_corrupt"),
+            (StorageError::NotFound(ip), 404, "not_found"),
+            (StorageError::Conflict(ip.to_string()), 409, "conflict"),
+            (
+                StorageError::TooLarge {
+                    ip,
+                    size: 2,
+                    max: 1,
+                },
+                413,
+                "story_too_large",
+            ),
+        ];
+
+        for (err, status, code) in cases {
+            let err = crate::storage_error!(err, "failed to get data from db");
+            assert_eq!(err.status().code, status, "{code}");
+            assert_eq!(err.code(), code);
+        }
+    }
+
+    /// Pages of the cursors 0, 1 and 2, the latter being the last one
+    fn page(cursor: u64) -> Result<(u64, Vec<u64>), StorageError> {
+        match cursor {
+            0 => Ok((1, vec![1, 2])),
+            // pages may be empty while the scan is not over
+            1 => Ok((2, vec![])),
+            2 => Ok((0, vec![3])),
+            _ => unreachable!("cursor {cursor} is never returned"),
+        }
+    }
+
+    #[test]
+    fn paged_scans_follow_the_cursor() {
+        let items: Vec<u64> = paged(page).collect::<Result<_, _>>().unwrap();
+        assert_eq!(items, [1, 2, 3]);
+    }
+
+    #[test]
+    fn paged_scans_read_pages_as_consumed() {
+        let mut cursors = vec![];
+        let mut scan = paged(|cursor| {
+            cursors.push(cursor);
+            page(cursor)
+        });
+
+        assert_eq!(scan.next().unwrap().unwrap(), 1);
+        assert_eq!(scan.next().unwrap().unwrap(), 2);
+        drop(scan);
+        assert_eq!(cursors, [0]);
+    }
+
+    #[test]
+    fn paged_scans_stop_at_the_first_error() {
+        let mut scan = paged(|cursor| match cursor {
+            0 => Ok((1, vec![1])),
+            _ => Err(io_error(io::ErrorKind::ConnectionReset)),
+        });
+
+        assert_eq!(scan.next().unwrap().unwrap(), 1);
+        assert!(scan.next().unwrap().is_err());
+        assert!(scan.next().is_none());
+    }
+
+    /// Times scans of the whole store with several COUNT hints, every
+    /// page being a round trip to Redis: a small COUNT multiplies the
+    /// round trips, a large one makes every `HSCAN` or `SCAN` hold Redis,
+    /// and the page in memory, for longer. Scanning the store is thus
+    /// a trade-off between its duration and the latency the other
+    /// requests see meanwhile. It needs a Redis database it can flush:
+    /// `BENCH_REDIS_URL=redis://localhost:6379/15 cargo test --release -p
+    /// ip-story scan_count_benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn scan_count_benchmark() {
+        const STORIES: u32 = 20_000;
+
+        let url = env::var("BENCH_REDIS_URL").expect("BENCH_REDIS_URL must be set");
+        let client = open(Url::parse(&url).unwrap()).unwrap();
+        let mut con = client.get_connection().unwrap();
+        let flush = |con: &mut Connection| redis::cmd("FLUSHDB").query::<()>(con).unwrap();
+
+        for layout in [Layout::Hash, Layout::Keys] {
+            flush(&mut con);
+            let mut pipe = redis::pipe();
+            for i in 0..STORIES {
+                let mut ipst = IpStory::new(IpAddr::from((0x0a00_0000 + i).to_be_bytes()));
+                let entry = ip_story_model::Entry::new(ip_story_model::Data::Text(
+                    "scanned our network".into(),
+                ));
+                ipst.history.insert(Utc::now(), entry);
+                let story = serde_json::to_string(&ipst).unwrap();
+                layout.set(&mut pipe, &ipst.ip.to_string(), &story);
+            }
+            pipe.query::<()>(&mut con).unwrap();
+
+            for count in [10, 100, 1000] {
+                let db = Storage::new(
+                    Instance::Node(client.clone()),
+                    None,
+                    None,
+                    RETRY,
+                    layout,
+                    HistoryKey::Ctime,
+                    usize::MAX,
+                    count,
+                );
+                let start = Instant::now();
+                let scanned = db.scan_all().map(Result::unwrap).count();
+                let elapsed = start.elapsed();
+
+                assert_eq!(scanned, STORIES as usize);
+                println!(
+                    "{layout:?} layout, COUNT {count}: {STORIES} stories in {elapsed:?}, {} pages",
+                    STORIES as usize / count
+                );
+            }
+        }
+        flush(&mut con);
+    }
+}
@@ -0,0 +1,86 @@
+//! Export of the traces of the requests over OTLP
+
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use rocket::{
+    Data, Request, Route,
+    route::{Handler, Outcome},
+};
+use tracing::{Instrument, Level, field::Empty, info_span};
+use tracing_subscriber::{Layer, filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::config::Config;
+
+/// Starts exporting spans to the OTLP collector of the configuration,
+/// returns the provider to shut down once the server stops, or `None`
+/// if no collector is configured
+pub fn init(config: &Config) -> anyhow::Result<Option<SdkTracerProvider>> {
+    let Some(endpoint) = config.otel_endpoint.as_ref() else {
+        return Ok(None);
+    };
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.otel_service_name.clone())
+                .build(),
+        )
+        .build();
+
+    // spans of the dependencies (hyper ...) are left out
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+        .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::TRACE));
+    tracing_subscriber::registry().with(layer).try_init()?;
+
+    Ok(Some(provider))
+}
+
+/// Handler running the one of a route within the span of the request,
+/// storage operations getting child spans of it
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let route = req.route().map(|r| r.uri.to_string()).unwrap_or_default();
+        let span = info_span!(
+            "request",
+            otel.name = format!("{} {route}", req.method()),
+            http.request.method = %req.method(),
+            http.route = route,
+            url.path = %req.uri().path(),
+            http.response.status_code = Empty,
+        );
+
+        let outcome = self.0.handle(req, data).instrument(span.clone()).await;
+
+        let status = match &outcome {
+            Outcome::Success(resp) => resp.status(),
+            Outcome::Error(status) => *status,
+            Outcome::Forward((_, status)) => *status,
+        };
+        span.record("http.response.status_code", status.code);
+
+        outcome
+    }
+}
+
+/// Traces the requests handled by `routes`
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Traced(route.handler));
+            route
+        })
+        .collect()
+}
@@ -0,0 +1,92 @@
+//! Rendering of the text entries written as templates, ex:
+//! `blocked at the firewall on {date} by {principal}`
+
+use std::{collections::HashMap, net::IpAddr};
+
+use chrono::{SecondsFormat, Utc};
+use ip_story_model::Data;
+
+use crate::{api::ApiError, api_error, audit::Principal};
+
+/// Variables set by the server, which clients cannot override
+const SERVER_VARIABLES: [&str; 4] = ["now", "date", "principal", "ip"];
+
+fn template_error(msg: String) -> ApiError {
+    api_error!(msg).with_code("template_invalid")
+}
+
+/// Replaces the `{name}` variables of `template` by their value, `{{` and
+/// `}}` standing for literal braces
+fn render(template: &str, vars: &HashMap<&str, String>) -> Result<String, ApiError> {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('{') if name.is_empty() => {
+                            out.push('{');
+                            break;
+                        }
+                        Some('}') => {
+                            let value = vars.get(name.trim()).ok_or_else(|| {
+                                template_error(format!("unknown template variable: {name}"))
+                            })?;
+                            out.push_str(value);
+                            break;
+                        }
+                        Some(c) => name.push(c),
+                        None => return Err(template_error("unclosed template variable".into())),
+                    }
+                }
+            }
+            '}' => {
+                if chars.next() != Some('}') {
+                    return Err(template_error("unmatched } in template".into()));
+                }
+                out.push('}');
+            }
+            c => out.push(c),
+        }
+    }
+
+    Ok(out)
+}
+
+/// Renders the text `data` of an entry of `ip` created by `principal`,
+/// with the server variables and the ones given by the client
+pub fn render_text(
+    data: &mut Data,
+    ip: IpAddr,
+    principal: &Principal,
+    client: &HashMap<String, String>,
+) -> Result<(), ApiError> {
+    let Data::Text(text) = data else {
+        return Err(template_error("only text entries can be templates".into()));
+    };
+
+    if let Some(name) = client
+        .keys()
+        .find(|k| SERVER_VARIABLES.contains(&k.as_str()))
+    {
+        return Err(template_error(format!(
+            "template variable {name} is set by the server"
+        )));
+    }
+
+    let now = Utc::now();
+    let mut vars: HashMap<&str, String> = client
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.clone()))
+        .collect();
+    vars.insert("now", now.to_rfc3339_opts(SecondsFormat::Secs, true));
+    vars.insert("date", now.format("%Y-%m-%d").to_string());
+    vars.insert("principal", principal.name().to_string());
+    vars.insert("ip", ip.to_string());
+
+    *text = render(text, &vars)?;
+    Ok(())
+}
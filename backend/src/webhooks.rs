@@ -0,0 +1,161 @@
+//! Delivery of the changes of the entries to HTTP endpoints
+
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::warn;
+use reqwest::{Client, RequestBuilder, header::CONTENT_TYPE};
+use rocket::tokio::{self, sync::broadcast::error::RecvError};
+use serde::Deserialize;
+use sha2::Sha256;
+use url::Url;
+
+use crate::{config::Config, events::Events};
+
+/// Header holding the signature of a delivery
+const SIGNATURE_HEADER: &str = "X-IP-Story-Signature";
+
+/// Endpoint the changes of the entries are posted to
+#[derive(Clone, Deserialize)]
+pub struct Webhook {
+    pub url: Url,
+    /// Secret the deliveries are signed with, they are not signed if unset
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+impl std::fmt::Debug for Webhook {
+    // the secret must not end up in the logs
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Webhook")
+            .field("url", &self.url.as_str())
+            .field("secret", &self.secret.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
+
+impl Webhook {
+    /// Delivery of `body` made at `timestamp`, in seconds since the epoch
+    fn request(&self, client: &Client, body: String, timestamp: i64) -> RequestBuilder {
+        let mut req = client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(secret) = &self.secret {
+            req = req.header(SIGNATURE_HEADER, signature(secret, timestamp, &body));
+        }
+        req.body(body)
+    }
+}
+
+/// Signature of a delivery of `body` made at `timestamp`, formatted as
+/// `t=<timestamp>,v1=<hex digest>`. The digest is the HMAC-SHA256, keyed
+/// with `secret`, of the timestamp, a dot and the body. Signing the
+/// timestamp lets receivers reject the deliveries replayed later on.
+fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac keys can be of any size");
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(body.as_bytes());
+    format!("t={timestamp},v1={:x}", mac.finalize().into_bytes())
+}
+
+/// Posts the entries created, updated or deleted to the endpoints of the
+/// webhooks setting. Every endpoint gets the changes in order, one at a
+/// time, from its own subscription to `events` so that a slow endpoint
+/// does not delay the others. Failed deliveries are logged and not retried.
+pub fn spawn(events: &Events, config: &Config) -> anyhow::Result<()> {
+    let client = Client::builder()
+        .timeout(Duration::from_millis(config.webhook_timeout_ms))
+        .build()?;
+
+    for webhook in config.webhooks.iter().cloned() {
+        let client = client.clone();
+        let mut rx = events.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                let ev = match rx.recv().await {
+                    Ok(ev) => ev,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("webhook {} too slow, {n} events not delivered", webhook.url);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                let body = match serde_json::to_string(&ev) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        warn!("failed to serialize webhook event: {e}");
+                        continue;
+                    }
+                };
+
+                let res = webhook
+                    .request(&client, body, Utc::now().timestamp())
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status());
+                if let Err(e) = res {
+                    warn!("webhook delivery to {} failed: {e}", webhook.url);
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook(secret: Option<&str>) -> Webhook {
+        Webhook {
+            url: "http://localhost:9000/hook".parse().unwrap(),
+            secret: secret.map(String::from),
+        }
+    }
+
+    #[test]
+    fn signatures_cover_the_timestamp_and_the_body() {
+        let body = r#"{"ip":"192.0.2.1","action":"create"}"#;
+        let sig = signature("s3cr3t", 1700000000, body);
+
+        assert_eq!(
+            sig,
+            "t=1700000000,v1=1efe90d77642e7f284710500117064e8a33f5c598584b61e61354edddb52b81a"
+        );
+        assert_ne!(sig, signature("s3cr3t", 1700000001, body));
+        assert_ne!(sig, signature("s3cr3t", 1700000000, "{}"));
+        assert_ne!(sig, signature("other", 1700000000, body));
+    }
+
+    #[test]
+    fn deliveries_are_only_signed_with_a_secret() {
+        let client = Client::new();
+        let body = String::from("{}");
+
+        let signed = webhook(Some("s3cr3t"))
+            .request(&client, body.clone(), 1700000000)
+            .build()
+            .unwrap();
+        assert_eq!(
+            signed.headers()[SIGNATURE_HEADER],
+            signature("s3cr3t", 1700000000, &body).as_str()
+        );
+        assert_eq!(signed.headers()[CONTENT_TYPE], "application/json");
+
+        let unsigned = webhook(None)
+            .request(&client, body, 1700000000)
+            .build()
+            .unwrap();
+        assert!(unsigned.headers().get(SIGNATURE_HEADER).is_none());
+    }
+
+    #[test]
+    fn secrets_are_not_logged() {
+        assert!(!format!("{:?}", webhook(Some("s3cr3t"))).contains("s3cr3t"));
+    }
+}
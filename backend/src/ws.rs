@@ -0,0 +1,580 @@
+//! WebSocket subscriptions to the changes of the entries of
+//! several IP addresses at once
+
+use std::{collections::HashSet, io, net::IpAddr, pin::Pin, time::Duration};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use rocket::{
+    Request, Response, Shutdown, State,
+    data::{IoHandler, IoStream},
+    get,
+    http::{Header, Status},
+    request::{self, FromRequest, Outcome},
+    response::{self, Responder},
+    tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        select,
+        sync::{
+            broadcast::{self, error::RecvError},
+            mpsc,
+        },
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    API_MOUNTPOINT, ApiResponse,
+    api::{ApiError, ErrorResponses, WithHeaders},
+    api_error,
+    audit::Principal,
+    config::{Config, MappedPolicy},
+    events::{EntryEvent, Events},
+};
+
+/// GUID the handshake key is hashed with (RFC 6455)
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Only version of the protocol spoken
+const VERSION: &str = "13";
+/// Maximum size of the messages of the clients
+const MAX_MESSAGE_LEN: u64 = 64 * 1024;
+/// Time after which a client not reading what it is sent is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Normal closure
+const CLOSE_NORMAL: u16 = 1000;
+/// Server shutting down
+const CLOSE_GOING_AWAY: u16 = 1001;
+/// Frame breaking the protocol, or not supported
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// Client too slow to keep up with the events
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+/// Messages the clients send
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe { ips: Vec<IpAddr> },
+    Unsubscribe { ips: Vec<IpAddr> },
+}
+
+/// Messages sent to the clients
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    /// IP addresses subscribed to after a subscription change
+    Subscribed {
+        ips: &'a HashSet<IpAddr>,
+    },
+    Entry(&'a EntryEvent),
+    Error {
+        error: String,
+    },
+}
+
+/// Frame sent by a client, fragmented frames are not supported
+struct Frame {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+async fn read_frame(r: &mut (impl AsyncRead + Unpin)) -> io::Result<Frame> {
+    let mut head = [0u8; 2];
+    r.read_exact(&mut head).await?;
+
+    let (fin, opcode) = (head[0] & 0x80 != 0, head[0] & 0x0f);
+    let masked = head[1] & 0x80 != 0;
+    let len = match head[1] & 0x7f {
+        126 => u64::from(r.read_u16().await?),
+        127 => r.read_u64().await?,
+        len => u64::from(len),
+    };
+
+    if !fin || opcode == 0 {
+        return Err(invalid("fragmented messages are not supported"));
+    }
+    if !masked {
+        return Err(invalid("client frames must be masked"));
+    }
+    if len > MAX_MESSAGE_LEN {
+        return Err(invalid("message too large"));
+    }
+
+    let mut mask = [0u8; 4];
+    r.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    r.read_exact(&mut payload).await?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+
+    Ok(Frame { opcode, payload })
+}
+
+async fn write_frame(
+    w: &mut (impl AsyncWrite + Unpin),
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= usize::from(u16::MAX) => {
+            frame.push(126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    frame.extend(payload);
+
+    tokio::time::timeout(WRITE_TIMEOUT, async {
+        w.write_all(&frame).await?;
+        w.flush().await
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "client is not reading"))?
+}
+
+async fn write_message(
+    w: &mut (impl AsyncWrite + Unpin),
+    msg: &ServerMessage<'_>,
+) -> io::Result<()> {
+    let s = serde_json::to_string(msg).map_err(io::Error::other)?;
+    write_frame(w, OP_TEXT, s.as_bytes()).await
+}
+
+async fn close(w: &mut (impl AsyncWrite + Unpin), code: u16, reason: &str) -> io::Result<()> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend(reason.as_bytes());
+    write_frame(w, OP_CLOSE, &payload).await
+}
+
+/// `Sec-WebSocket-Accept` answer to the handshake `key`
+fn accept_key(key: &str) -> String {
+    let mut hash = sha1_smol::Sha1::from(key);
+    hash.update(HANDSHAKE_GUID.as_bytes());
+    BASE64_STANDARD.encode(hash.digest().bytes())
+}
+
+/// Whether a page of `origin` can open a WebSocket on the server reached
+/// at `host`, that is if it is served by the server itself or one of the
+/// `allowed` origins
+fn origin_allowed(origin: &str, host: Option<&str>, allowed: &[String]) -> bool {
+    if allowed
+        .iter()
+        .any(|a| a.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    {
+        return true;
+    }
+    // opaque origins, ex: `null`, match no host
+    let Ok(origin) = url::Url::parse(origin) else {
+        return false;
+    };
+    let authority = match (origin.host_str(), origin.port()) {
+        (Some(h), Some(port)) => format!("{h}:{port}"),
+        (Some(h), None) => h.to_string(),
+        (None, _) => return false,
+    };
+    host.is_some_and(|h| h.eq_ignore_ascii_case(&authority))
+}
+
+/// Key of the WebSocket handshake of the request, or why it cannot
+/// be accepted
+pub struct Handshake(Result<String, ApiError>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Handshake {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = req.headers();
+        let upgrade = headers
+            .get("Upgrade")
+            .any(|u| u.eq_ignore_ascii_case("websocket"));
+        // the upgrade can be listed along with other options
+        let connection = headers
+            .get("Connection")
+            .flat_map(|c| c.split(','))
+            .any(|c| c.trim().eq_ignore_ascii_case("upgrade"));
+        let allowed = req
+            .rocket()
+            .state::<Config>()
+            .map(|c| c.ws_origins.as_slice())
+            .unwrap_or_default();
+
+        let key = match headers.get_one("Sec-WebSocket-Key") {
+            Some(key) if upgrade && connection => key,
+            _ => {
+                return Outcome::Success(Handshake(Err(api_error!(
+                    "expected a websocket handshake"
+                ))));
+            }
+        };
+        if headers.get_one("Sec-WebSocket-Version").map(str::trim) != Some(VERSION) {
+            return Outcome::Success(Handshake(Err(api_error!(
+                Status::UpgradeRequired,
+                format!("only version {VERSION} of the websocket protocol is supported")
+            ))));
+        }
+        // browsers always send the origin of the page, and cannot be
+        // trusted with the API key of another one
+        if let Some(origin) = headers.get_one("Origin")
+            && !origin_allowed(origin, headers.get_one("Host"), allowed)
+        {
+            return Outcome::Success(Handshake(Err(api_error!(
+                Status::Forbidden,
+                format!("websockets cannot be opened from {origin}")
+            )
+            .with_code("origin_forbidden"))));
+        }
+
+        Outcome::Success(Handshake(Ok(key.to_string())))
+    }
+}
+
+/// Connection upgraded to a WebSocket, subscribed to the events
+pub struct Subscription {
+    accept: String,
+    rx: broadcast::Receiver<EntryEvent>,
+    principal: Principal,
+    max_ips: usize,
+    ipv4_mapped: MappedPolicy,
+    shutdown: Shutdown,
+}
+
+impl<'r> Responder<'r, 'static> for Subscription {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .raw_header("Sec-WebSocket-Accept", self.accept.clone())
+            .upgrade("websocket", self)
+            .ok()
+    }
+}
+
+/// Applies the message of a client to the IP addresses it subscribed to,
+/// of which there can be at most `max_ips`
+fn apply(
+    ips: &mut HashSet<IpAddr>,
+    max_ips: usize,
+    ipv4_mapped: MappedPolicy,
+    payload: &[u8],
+) -> Result<(), String> {
+    let msg: ClientMessage =
+        serde_json::from_slice(payload).map_err(|e| format!("invalid message: {e}"))?;
+    match msg {
+        ClientMessage::Subscribe { ips: new } => {
+            let new: HashSet<IpAddr> = new.into_iter().map(|ip| ipv4_mapped.unmap(ip)).collect();
+            if ips.union(&new).count() > max_ips {
+                return Err(format!(
+                    "at most {max_ips} ip addresses can be subscribed to"
+                ));
+            }
+            ips.extend(new);
+        }
+        ClientMessage::Unsubscribe { ips: old } => {
+            for ip in old {
+                ips.remove(&ipv4_mapped.unmap(ip));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[rocket::async_trait]
+impl IoHandler for Subscription {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let Subscription {
+            mut rx,
+            principal,
+            max_ips,
+            ipv4_mapped,
+            mut shutdown,
+            ..
+        } = *Pin::into_inner(self);
+        let (mut reader, mut writer) = rocket::tokio::io::split(io);
+
+        // frames are read by a task of their own as reads cannot be
+        // interrupted by the events without losing data
+        let (tx, mut frames) = mpsc::channel(1);
+        let read = tokio::spawn(async move {
+            loop {
+                let frame = read_frame(&mut reader).await;
+                let done = frame.is_err();
+                if tx.send(frame).await.is_err() || done {
+                    break;
+                }
+            }
+        });
+
+        let mut ips = HashSet::new();
+        let res = loop {
+            select! {
+                frame = frames.recv() => {
+                    let frame = match frame {
+                        Some(Ok(frame)) => frame,
+                        Some(Err(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                            break close(&mut writer, CLOSE_PROTOCOL_ERROR, &e.to_string()).await;
+                        }
+                        // the client went away
+                        Some(Err(_)) | None => break Ok(()),
+                    };
+
+                    match frame.opcode {
+                        OP_TEXT => {
+                            let msg = match apply(&mut ips, max_ips, ipv4_mapped, &frame.payload) {
+                                Ok(()) => ServerMessage::Subscribed { ips: &ips },
+                                Err(error) => ServerMessage::Error { error },
+                            };
+                            if let Err(e) = write_message(&mut writer, &msg).await {
+                                break Err(e);
+                            }
+                        }
+                        OP_PING => {
+                            if let Err(e) = write_frame(&mut writer, OP_PONG, &frame.payload).await {
+                                break Err(e);
+                            }
+                        }
+                        OP_PONG => {}
+                        OP_CLOSE => break close(&mut writer, CLOSE_NORMAL, "").await,
+                        _ => {
+                            break close(&mut writer, CLOSE_PROTOCOL_ERROR, "only text messages are supported").await;
+                        }
+                    }
+                }
+                ev = rx.recv() => match ev {
+                    Ok(ev) if ips.contains(&ev.ip) && principal.can_read(&ev.entry) => {
+                        if let Err(e) = write_message(&mut writer, &ServerMessage::Entry(&ev)).await {
+                            break Err(e);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) => {
+                        let reason = format!("too slow, {n} events missed");
+                        break close(&mut writer, CLOSE_POLICY_VIOLATION, &reason).await;
+                    }
+                    Err(RecvError::Closed) => break close(&mut writer, CLOSE_GOING_AWAY, "").await,
+                },
+                _ = &mut shutdown => break close(&mut writer, CLOSE_GOING_AWAY, "").await,
+            }
+        };
+
+        read.abort();
+        res
+    }
+}
+
+#[utoipa::path(
+    context_path = API_MOUNTPOINT,
+    responses(
+        (status = 101, description = "Connection upgraded to a WebSocket"),
+        (status = 403, description = "The page opening the WebSocket is of another origin", body = ApiResponse<String>, content_type = "application/json"),
+        (status = 426, description = "The version of the protocol is not supported", body = ApiResponse<String>, content_type = "application/json"),
+        ErrorResponses,
+    ),
+    tag = "Events",
+    description = "Upgrades the connection to a WebSocket pushing the entries created, updated or deleted on a set of IP addresses. Handshakes of versions other than 13 are answered with a 426 Upgrade Required, and those of pages of origins other than the one of the server or those of the ws_origins setting with a 403 Forbidden and the origin_forbidden code. Clients change the set with `{\"type\": \"subscribe\", \"ips\": [...]}` and `{\"type\": \"unsubscribe\", \"ips\": [...]}` text messages, each answered by a `subscribed` message holding the IP addresses subscribed to, or an `error` one. Changes are pushed as `entry` messages holding the IP address, the action (create, update or delete) and the entry. At most ws_max_ips IP addresses can be subscribed to per connection. Events are buffered up to the stream_buffer setting, clients falling further behind are disconnected with a 1008 close frame."
+)]
+#[get("/stream/ws")]
+pub async fn ws_stream(
+    handshake: Handshake,
+    principal: Principal,
+    config: &State<Config>,
+    events: &State<Events>,
+    shutdown: Shutdown,
+) -> Result<Subscription, WithHeaders<ApiError>> {
+    let key = handshake.0.map_err(|e| {
+        let upgrade = e.status() == Status::UpgradeRequired;
+        let e = WithHeaders::new(e);
+        // tells the client which version to retry with
+        if upgrade {
+            e.header(Header::new("Sec-WebSocket-Version", VERSION))
+        } else {
+            e
+        }
+    })?;
+
+    Ok(Subscription {
+        accept: accept_key(&key),
+        rx: events.subscribe(),
+        principal,
+        max_ips: config.ws_max_ips,
+        ipv4_mapped: config.ipv4_mapped,
+        shutdown,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::blocking::{Client, LocalResponse};
+
+    use super::*;
+
+    /// Frame as sent by a client, masked with `mask`
+    fn client_frame(head: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+        let mut frame = vec![head, 0x80 | payload.len() as u8];
+        frame.extend(mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn handshake_is_accepted_as_in_rfc6455() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn client_frames_are_unmasked() {
+        let payload = br#"{"type": "subscribe", "ips": ["192.0.2.1"]}"#;
+        let frame = client_frame(0x80 | OP_TEXT, payload, [1, 2, 3, 4]);
+
+        let frame = read_frame(&mut frame.as_slice()).await.unwrap();
+        assert_eq!(frame.opcode, OP_TEXT);
+        assert_eq!(frame.payload, payload);
+    }
+
+    #[tokio::test]
+    async fn invalid_client_frames_are_rejected() {
+        let unmasked = [0x80 | OP_TEXT, 2, b'{', b'}'];
+        let fragmented = client_frame(OP_TEXT, b"{}", [1, 2, 3, 4]);
+        let mut too_large = vec![0x80 | OP_TEXT, 0x80 | 127];
+        too_large.extend((MAX_MESSAGE_LEN + 1).to_be_bytes());
+
+        for frame in [&unmasked[..], &fragmented, &too_large] {
+            let err = read_frame(&mut &frame[..]).await.err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_lengths_are_encoded_by_size() {
+        for (len, header) in [(125, 2), (126, 4), (usize::from(u16::MAX) + 1, 10)] {
+            let mut out = vec![];
+            write_frame(&mut out, OP_TEXT, &vec![b'x'; len])
+                .await
+                .unwrap();
+            assert_eq!(out[0], 0x80 | OP_TEXT);
+            assert_eq!(out.len(), header + len);
+        }
+    }
+
+    fn client(ws_origins: &[&str]) -> Client {
+        let config = Config {
+            ws_origins: ws_origins.iter().map(|o| o.to_string()).collect(),
+            ..Config::default()
+        };
+        let rocket = rocket::build()
+            .mount("/", rocket::routes![ws_stream])
+            .manage(config)
+            .manage(Events::new(8));
+        Client::tracked(rocket).unwrap()
+    }
+
+    /// Handshake asking for an upgrade, with the `extra` headers
+    fn handshake<'c>(
+        client: &'c Client,
+        extra: &[(&'static str, &'static str)],
+    ) -> LocalResponse<'c> {
+        let mut req = client
+            .get("/stream/ws")
+            .header(Header::new("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .header(Header::new("Upgrade", "websocket"))
+            .header(Header::new("Host", "ip-story.example:8000"));
+        for (name, value) in extra {
+            req = req.header(Header::new(*name, *value));
+        }
+        req.dispatch()
+    }
+
+    /// Whether the connection is upgraded, which the local client
+    /// does not go through but announces it
+    fn accepted(resp: &LocalResponse<'_>) -> bool {
+        resp.headers().get_one("Sec-WebSocket-Accept") == Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+    }
+
+    fn code(resp: LocalResponse<'_>) -> Option<String> {
+        let body: serde_json::Value = serde_json::from_str(&resp.into_string()?).ok()?;
+        body["code"].as_str().map(String::from)
+    }
+
+    const UPGRADE: (&str, &str) = ("Connection", "keep-alive, Upgrade");
+    const V13: (&str, &str) = ("Sec-WebSocket-Version", "13");
+
+    #[test]
+    fn handshakes_must_be_of_version_13_and_ask_for_an_upgrade() {
+        let client = client(&[]);
+
+        assert!(accepted(&handshake(&client, &[UPGRADE, V13])));
+
+        for version in [&[UPGRADE, ("Sec-WebSocket-Version", "8")][..], &[UPGRADE]] {
+            let resp = handshake(&client, version);
+            assert_eq!(resp.status(), Status::UpgradeRequired);
+            assert_eq!(resp.headers().get_one("Sec-WebSocket-Version"), Some("13"));
+            assert!(!accepted(&resp));
+        }
+
+        // without the connection upgrade this is not a handshake at all
+        let resp = handshake(&client, &[("Connection", "keep-alive"), V13]);
+        assert!(!accepted(&resp));
+        assert!(
+            resp.into_string()
+                .unwrap()
+                .contains("expected a websocket handshake")
+        );
+    }
+
+    #[test]
+    fn handshakes_of_other_origins_are_rejected() {
+        let client = client(&["https://soc.example/"]);
+        let from = |origin: &'static str| handshake(&client, &[UPGRADE, V13, ("Origin", origin)]);
+
+        assert!(accepted(&from("http://ip-story.example:8000")));
+        assert!(accepted(&from("https://SOC.example")));
+        for origin in ["https://evil.example", "http://ip-story.example", "null"] {
+            let resp = from(origin);
+            assert_eq!(resp.status(), Status::Forbidden);
+            assert!(!accepted(&resp));
+            assert_eq!(code(resp).as_deref(), Some("origin_forbidden"));
+        }
+    }
+
+    #[test]
+    fn subscriptions_follow_the_messages() {
+        let mut ips = HashSet::new();
+        let policy = MappedPolicy::Unmap;
+
+        let sub = br#"{"type": "subscribe", "ips": ["192.0.2.1", "::ffff:192.0.2.2"]}"#;
+        apply(&mut ips, 2, policy, sub).unwrap();
+        let expected: HashSet<IpAddr> =
+            ["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()].into();
+        assert_eq!(ips, expected);
+
+        let unsub = br#"{"type": "unsubscribe", "ips": ["192.0.2.1"]}"#;
+        apply(&mut ips, 2, policy, unsub).unwrap();
+        assert_eq!(ips, ["192.0.2.2".parse().unwrap()].into());
+    }
+
+    #[test]
+    fn subscriptions_are_bounded() {
+        let mut ips = HashSet::new();
+        let sub = br#"{"type": "subscribe", "ips": ["192.0.2.1", "192.0.2.2"]}"#;
+
+        assert!(apply(&mut ips, 1, MappedPolicy::Unmap, sub).is_err());
+        assert!(ips.is_empty());
+        assert!(apply(&mut ips, 1, MappedPolicy::Unmap, b"{}").is_err());
+    }
+}
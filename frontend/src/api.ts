@@ -26,13 +26,18 @@ interface ApiConfig {
   };
 }
 
+// Mount point of the API, advertised by the server in index.html
+const apiMountpoint: string =
+  document.querySelector<HTMLMetaElement>('meta[name="api-mountpoint"]')
+    ?.content ?? "/api";
+
 // Example API configuration based on the provided OpenAPI description
 export const api: ApiConfig = {
   baseUrl: null,
   endpoints: {
     openApi: {
       method: "GET",
-      path: "/api/openapi/json",
+      path: `${apiMountpoint}/openapi/json`,
     },
   },
 };
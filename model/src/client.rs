@@ -0,0 +1,474 @@
+//! Thin asynchronous HTTP client of the ip-story API
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+};
+
+use chrono::{DateTime, Utc};
+use reqwest::RequestBuilder;
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::Error;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    ApiResponse, Changes, Data, DataKind, Entry, EntrySummary, IpActivity, IpSortBy, NewIp,
+    SearchOrder, Severity, SortBy, TagMode,
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid url: {0}")]
+    Url(#[from] url::ParseError),
+    /// Error reported by the API, `code` identifying its kind
+    #[error("api error: {message}")]
+    Api {
+        code: Option<String>,
+        message: String,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Criteria of [`Client::search`], unset fields use the server defaults
+#[derive(Debug, Default, Serialize)]
+pub struct SearchParams {
+    pub kind: Option<DataKind>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+    pub order: Option<SearchOrder>,
+    pub sort_by: Option<SortBy>,
+    pub has_tags: Option<bool>,
+    pub has_description: Option<bool>,
+    /// Comma separated tags the entries must have
+    pub tags: Option<String>,
+    /// Whether entries must have any or all of `tags`
+    pub tag_mode: Option<TagMode>,
+    /// Comma separated data fields the entries must have
+    pub has: Option<String>,
+    /// Comma separated data fields the entries must not have
+    pub missing: Option<String>,
+    /// JSONPath expression the JSON data of the entries must match
+    pub jsonpath: Option<String>,
+    /// Minimum severity of the entries
+    pub min_severity: Option<Severity>,
+    /// Minimum confidence of the entries, decayed by their age
+    pub min_confidence: Option<f64>,
+}
+
+/// Entries [`Client::bulk_tags`] applies to, every entry if unset
+#[derive(Debug, Default, Serialize)]
+pub struct TagFilter {
+    pub kind: Option<DataKind>,
+    /// Tag the entries must have
+    pub tag: Option<String>,
+    /// Time the entries must have been created before
+    pub before: Option<DateTime<Utc>>,
+}
+
+/// Criteria of [`Client::ips`], unset fields use the server defaults
+#[derive(Debug, Default, Serialize)]
+pub struct IpListParams {
+    /// Only lists the IP addresses last seen at or after this time
+    pub active_since: Option<DateTime<Utc>>,
+    /// Only lists the IP addresses last seen before this time
+    pub active_before: Option<DateTime<Utc>>,
+    pub sort_by: Option<IpSortBy>,
+    pub order: Option<SearchOrder>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+pub struct Client {
+    http: reqwest::Client,
+    api: Url,
+}
+
+impl Client {
+    /// Creates a client of the API mounted at `api`
+    /// (ex: `http://localhost:8000/api`)
+    pub fn new(mut api: Url) -> Self {
+        // make url joins relative to the mount point
+        if !api.path().ends_with('/') {
+            api.set_path(&format!("{}/", api.path()));
+        }
+
+        Client {
+            http: reqwest::Client::new(),
+            api,
+        }
+    }
+
+    fn url(&self, path: &str) -> Result<Url> {
+        Ok(self.api.join(path)?)
+    }
+
+    async fn send<T: DeserializeOwned>(req: RequestBuilder) -> Result<Option<T>> {
+        let resp: ApiResponse<T> = req.send().await?.json().await?;
+        match resp.error {
+            Some(message) => Err(Error::Api {
+                code: resp.code,
+                message,
+            }),
+            None => Ok(resp.data),
+        }
+    }
+
+    /// Starts tracking `ip`, doing nothing if it is already tracked
+    pub async fn new_ip(&self, ip: IpAddr) -> Result<Option<NewIp>> {
+        Self::send(self.http.put(self.url(&format!("ip/{ip}"))?)).await
+    }
+
+    /// Starts tracking `ip`, failing if it is already tracked
+    pub async fn create_ip(&self, ip: IpAddr) -> Result<Option<NewIp>> {
+        Self::send(
+            self.http
+                .put(self.url(&format!("ip/{ip}"))?)
+                .header("If-None-Match", "*"),
+        )
+        .await
+    }
+
+    /// Lists the tracked IP addresses along with their last-seen time
+    pub async fn ips(&self, params: &IpListParams) -> Result<Option<Vec<IpActivity>>> {
+        Self::send(self.http.get(self.url("ips")?).query(params)).await
+    }
+
+    /// Starts tracking all of `ips` at once, returns whether each got
+    /// created or was already tracked
+    pub async fn new_ips(&self, ips: &[IpAddr]) -> Result<Option<BTreeMap<IpAddr, bool>>> {
+        Self::send(self.http.put(self.url("ip")?).json(ips)).await
+    }
+
+    /// Adds `entry` to the history of `ip`, returns the entry as stored
+    pub async fn add_entry(&self, ip: IpAddr, entry: &Entry) -> Result<Option<Entry>> {
+        Self::send(
+            self.http
+                .post(self.url(&format!("ip/{ip}/entry"))?)
+                .json(entry),
+        )
+        .await
+    }
+
+    /// Same as [`Client::add_entry`] but renders the text of `entry` as a
+    /// template, with the variables `vars` along with the server ones
+    pub async fn add_entry_template(
+        &self,
+        ip: IpAddr,
+        entry: &Entry,
+        vars: &HashMap<String, String>,
+    ) -> Result<Option<Entry>> {
+        let vars: Vec<(String, &String)> =
+            vars.iter().map(|(k, v)| (format!("vars.{k}"), v)).collect();
+        Self::send(
+            self.http
+                .post(self.url(&format!("ip/{ip}/entry"))?)
+                .query(&[("template", true)])
+                .query(&vars)
+                .json(entry),
+        )
+        .await
+    }
+
+    /// Replaces the entry of `ip` having the same uuid as `entry`
+    pub async fn update_entry(&self, ip: IpAddr, entry: &Entry) -> Result<Option<bool>> {
+        Self::send(
+            self.http
+                .post(self.url(&format!("ip/{ip}/entry/update"))?)
+                .json(entry),
+        )
+        .await
+    }
+
+    /// Creates the entry `uuid` of `ip` from `entry`, or updates it if it
+    /// already exists, returns the entry as stored. Updates changing the
+    /// kind of data are rejected unless `allow_kind_change`.
+    pub async fn upsert_entry(
+        &self,
+        ip: IpAddr,
+        uuid: Uuid,
+        entry: &Entry,
+        allow_kind_change: bool,
+    ) -> Result<Option<Entry>> {
+        Self::send(
+            self.http
+                .put(self.url(&format!("ip/{ip}/entry/{uuid}"))?)
+                .query(&[("allow_kind_change", allow_kind_change)])
+                .json(entry),
+        )
+        .await
+    }
+
+    /// Replaces the data of the entry `uuid` of `ip`, returns the updated
+    /// entry. Data of another kind is rejected unless `allow_kind_change`.
+    pub async fn set_entry_data(
+        &self,
+        ip: IpAddr,
+        uuid: Uuid,
+        data: &Data,
+        allow_kind_change: bool,
+    ) -> Result<Option<Entry>> {
+        Self::send(
+            self.http
+                .put(self.url(&format!("ip/{ip}/entry/{uuid}/data"))?)
+                .query(&[("allow_kind_change", allow_kind_change)])
+                .json(data),
+        )
+        .await
+    }
+
+    pub async fn search(&self, ip: IpAddr, params: &SearchParams) -> Result<Option<Vec<Entry>>> {
+        Self::send(
+            self.http
+                .get(self.url(&format!("ip/{ip}/entry/search"))?)
+                .query(params),
+        )
+        .await
+    }
+
+    /// Same as [`Client::search`] but only returns summaries of the entries
+    pub async fn search_summaries(
+        &self,
+        ip: IpAddr,
+        params: &SearchParams,
+    ) -> Result<Option<Vec<EntrySummary>>> {
+        Self::send(
+            self.http
+                .get(self.url(&format!("ip/{ip}/entry/search"))?)
+                .query(params)
+                .query(&[("summary", true)]),
+        )
+        .await
+    }
+
+    /// Same as [`Client::search`] but also returns the timestamp every
+    /// entry is stored under, which can differ from its ctime
+    pub async fn search_keyed(
+        &self,
+        ip: IpAddr,
+        params: &SearchParams,
+    ) -> Result<Option<Vec<(DateTime<Utc>, Entry)>>> {
+        Self::send(
+            self.http
+                .get(self.url(&format!("ip/{ip}/entry/search"))?)
+                .query(params)
+                .query(&[("include_key", true)]),
+        )
+        .await
+    }
+
+    /// Searches the entries of several IP addresses at once, untracked
+    /// ones are left out of the result
+    pub async fn batch_search(
+        &self,
+        ips: &[IpAddr],
+        params: &SearchParams,
+    ) -> Result<Option<BTreeMap<IpAddr, Vec<Entry>>>> {
+        #[derive(Serialize)]
+        struct BatchSearch<'a> {
+            ips: &'a [IpAddr],
+            #[serde(flatten)]
+            params: &'a SearchParams,
+        }
+
+        Self::send(
+            self.http
+                .post(self.url("ip/search")?)
+                .json(&BatchSearch { ips, params }),
+        )
+        .await
+    }
+
+    /// Current state of the entries `uuids` of `ip`, in the same order,
+    /// the entries no longer present being left out
+    pub async fn fetch_entries(&self, ip: IpAddr, uuids: &[Uuid]) -> Result<Option<Vec<Entry>>> {
+        Self::send(
+            self.http
+                .post(self.url(&format!("ip/{ip}/entry/fetch"))?)
+                .json(uuids),
+        )
+        .await
+    }
+
+    /// Changes of the history of `ip` since the revision `since`, or its
+    /// whole history if unset
+    pub async fn changes(&self, ip: IpAddr, since: Option<u64>) -> Result<Option<Changes>> {
+        Self::send(
+            self.http
+                .get(self.url(&format!("ip/{ip}/entry/changes"))?)
+                .query(&[("since", since)]),
+        )
+        .await
+    }
+
+    /// Adds the tags `add` to and removes the tags `remove` from every entry
+    /// of `ip` matching `filter`, returns the number of entries modified
+    pub async fn bulk_tags(
+        &self,
+        ip: IpAddr,
+        add: &[&str],
+        remove: &[&str],
+        filter: &TagFilter,
+    ) -> Result<Option<usize>> {
+        #[derive(Serialize)]
+        struct BulkTags<'a> {
+            add: &'a [&'a str],
+            remove: &'a [&'a str],
+            #[serde(rename = "where")]
+            filter: &'a TagFilter,
+        }
+
+        Self::send(
+            self.http
+                .post(self.url(&format!("ip/{ip}/tags/bulk"))?)
+                .json(&BulkTags {
+                    add,
+                    remove,
+                    filter,
+                }),
+        )
+        .await
+    }
+
+    /// Bumps the modification time of the entry `uuid` of `ip` without
+    /// changing it, returns the entry
+    pub async fn touch_entry(&self, ip: IpAddr, uuid: Uuid) -> Result<Option<Entry>> {
+        Self::send(
+            self.http
+                .post(self.url(&format!("ip/{ip}/entry/{uuid}/touch"))?),
+        )
+        .await
+    }
+
+    /// Copies the entry `uuid` of `ip` to `dst`, linking the copy to it
+    /// if `link` is set, returns the copy
+    pub async fn copy_entry(
+        &self,
+        ip: IpAddr,
+        uuid: Uuid,
+        dst: IpAddr,
+        link: bool,
+    ) -> Result<Option<Entry>> {
+        Self::send(
+            self.http
+                .post(self.url(&format!("ip/{ip}/entry/{uuid}/copy/{dst}"))?)
+                .query(&[("link", link)]),
+        )
+        .await
+    }
+
+    /// Entries linked by the entry `uuid` of `ip`
+    pub async fn links(&self, ip: IpAddr, uuid: Uuid) -> Result<Option<Vec<Entry>>> {
+        Self::send(
+            self.http
+                .get(self.url(&format!("ip/{ip}/entry/{uuid}/links"))?),
+        )
+        .await
+    }
+
+    /// Entry of `ip` whose uuid starts with `prefix`, failing if several
+    /// match unless `first` is set
+    pub async fn entry_by_prefix(
+        &self,
+        ip: IpAddr,
+        prefix: &str,
+        first: bool,
+    ) -> Result<Option<Entry>> {
+        Self::send(
+            self.http
+                .get(self.url(&format!("ip/{ip}/entry"))?)
+                .query(&[("uuid_prefix", prefix)])
+                .query(&[("first", first)]),
+        )
+        .await
+    }
+
+    /// Most recent entry of `ip`, of the given kind if any
+    pub async fn latest(&self, ip: IpAddr, kind: Option<DataKind>) -> Result<Option<Entry>> {
+        Self::send(
+            self.http
+                .get(self.url(&format!("ip/{ip}/latest"))?)
+                .query(&[("kind", kind)]),
+        )
+        .await
+    }
+
+    /// Most recent creation or modification time of the entries of `ip`
+    pub async fn mtime(&self, ip: IpAddr) -> Result<Option<DateTime<Utc>>> {
+        Self::send(self.http.get(self.url(&format!("ip/{ip}/mtime"))?)).await
+    }
+
+    /// Notes kept about `ip` itself, apart from its history
+    pub async fn meta(&self, ip: IpAddr) -> Result<Option<serde_json::Value>> {
+        Self::send(self.http.get(self.url(&format!("ip/{ip}/meta"))?)).await
+    }
+
+    /// Replaces the notes kept about `ip`, `None` removing them
+    pub async fn set_meta(
+        &self,
+        ip: IpAddr,
+        meta: Option<&serde_json::Value>,
+    ) -> Result<Option<serde_json::Value>> {
+        Self::send(
+            self.http
+                .put(self.url(&format!("ip/{ip}/meta"))?)
+                .json(&meta),
+        )
+        .await
+    }
+
+    /// Number of entries of `ip`, `None` if it is not tracked
+    pub async fn count(&self, ip: IpAddr) -> Result<Option<usize>> {
+        Self::send(self.http.get(self.url(&format!("ip/{ip}/count"))?)).await
+    }
+
+    /// Number of entries of `ip` matching the filters of `params`, paging
+    /// and sorting being ignored
+    pub async fn count_matching(&self, ip: IpAddr, params: &SearchParams) -> Result<Option<usize>> {
+        Self::send(
+            self.http
+                .get(self.url(&format!("ip/{ip}/entry/count"))?)
+                .query(params),
+        )
+        .await
+    }
+
+    /// Deletes an entry, returns the deleted entry
+    pub async fn delete_entry(&self, ip: IpAddr, uuid: Uuid) -> Result<Option<Entry>> {
+        Self::send(
+            self.http
+                .delete(self.url(&format!("ip/{ip}/entry/{uuid}"))?),
+        )
+        .await
+    }
+
+    /// Deletes an entry only if it was not modified since `mtime`, its
+    /// modification time (or creation time) as last read, returns the
+    /// deleted entry
+    pub async fn delete_entry_if_unmodified(
+        &self,
+        ip: IpAddr,
+        uuid: Uuid,
+        mtime: DateTime<Utc>,
+    ) -> Result<Option<Entry>> {
+        Self::send(
+            self.http
+                .delete(self.url(&format!("ip/{ip}/entry/{uuid}"))?)
+                .header("If-Match", format!("\"{}\"", mtime.to_rfc3339())),
+        )
+        .await
+    }
+
+    /// IP addresses having an ASN entry with the given AS number
+    pub async fn asn_ips(&self, asn: u64) -> Result<Option<Vec<IpAddr>>> {
+        Self::send(self.http.get(self.url(&format!("asn/{asn}/ips"))?)).await
+    }
+
+    /// Retrieves an entry from its uuid only
+    pub async fn entry(&self, uuid: Uuid) -> Result<Option<Entry>> {
+        Self::send(self.http.get(self.url(&format!("entry/{uuid}"))?)).await
+    }
+}
@@ -0,0 +1,883 @@
+//! Types exchanged with the ip-story API, usable without pulling the server
+//! dependencies. The `client` feature provides an HTTP client of the API.
+
+use std::{cmp::Ordering, collections::HashSet, net::IpAddr};
+
+use chrono::Utc;
+#[cfg(feature = "rocket")]
+use rocket::{FromFormField, request::FromParam};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::Url;
+#[cfg(feature = "schema")]
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[cfg(feature = "client")]
+pub mod client;
+pub mod country;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+pub struct Owner {
+    pub name: String,
+    pub address: Option<String>,
+    /// ISO 3166-1 alpha-2 code, once normalized
+    pub country: Option<String>,
+    /// Country as originally given, when it got normalized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country_raw: Option<String>,
+    /// Abuse contacts, a single string is accepted for backward compatibility
+    #[serde(default, deserialize_with = "string_or_vec")]
+    pub abuse: Vec<String>,
+    pub phone: Option<String>,
+}
+
+impl Owner {
+    /// Normalizes the country to its ISO 3166-1 alpha-2 code, keeping the
+    /// original value aside. Unknown countries are left as they are,
+    /// unless `strict` which rejects them.
+    pub fn normalize_country(&mut self, strict: bool) -> Result<(), InvalidData> {
+        // a blank country is a missing one rather than an unknown one
+        let Some(country) = self.country.as_mut().filter(|c| !c.trim().is_empty()) else {
+            return Ok(());
+        };
+
+        match country::alpha2(country) {
+            Some(code) if code != country => {
+                self.country_raw = Some(std::mem::replace(country, code.into()));
+            }
+            Some(_) => {}
+            None if strict => return Err(InvalidData::UnknownCountry(country.clone())),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Unsets the optional fields holding blank strings and removes the
+    /// blank abuse contacts
+    pub fn drop_empty(&mut self) {
+        for field in [
+            &mut self.address,
+            &mut self.country,
+            &mut self.country_raw,
+            &mut self.phone,
+        ] {
+            field.take_if(|s| s.trim().is_empty());
+        }
+        self.abuse.retain(|a| !a.trim().is_empty());
+    }
+}
+
+/// Deserializes either a single (optional) string or a sequence of strings
+fn string_or_vec<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrVec {
+        String(String),
+        Vec(Vec<String>),
+    }
+
+    Ok(match Option::<StringOrVec>::deserialize(deserializer)? {
+        Some(StringOrVec::String(s)) => vec![s],
+        Some(StringOrVec::Vec(v)) => v,
+        None => vec![],
+    })
+}
+
+/// Identifier of a ticket, either `{"id": 42}` for trackers using
+/// numeric ids or `{"uuid": "5f0c…"}` for those using UUIDs
+#[derive(Debug, Serialize, Clone)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum TicketId {
+    Id(u64),
+    Uuid(Uuid),
+}
+
+impl<'de> Deserialize<'de> for TicketId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        // numbers are deserialized as any JSON value so that
+        // invalid ones can be reported explicitly
+        #[derive(Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        enum Raw {
+            Id(serde_json::Value),
+            Uuid(Uuid),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Id(v) => v.as_u64().map(TicketId::Id).ok_or_else(|| {
+                D::Error::custom(format!(
+                    "ticket id must be an integer between 0 and {}, got {v}",
+                    u64::MAX
+                ))
+            }),
+            Raw::Uuid(u) => Ok(TicketId::Uuid(u)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+pub struct MispEvent {
+    pub server: Option<Url>,
+    pub uuid: Uuid,
+}
+
+/// Ticket of a tracker, ex: `{"server": "https://tracker.example", "id": {"id": 42}}`.
+/// The server is mandatory for numeric ids which are ambiguous across trackers.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+pub struct Ticket {
+    pub server: Option<Url>,
+    pub id: TicketId,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum Data {
+    Owner(Owner),
+    Asn(u64),
+    MispEvent(MispEvent),
+    Ticket(Ticket),
+    Vulnerable(String),
+    Text(String),
+    Json(serde_json::Value),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "rocket", derive(FromFormField))]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum SearchOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "rocket", derive(FromFormField))]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum SortBy {
+    Ctime,
+    Mtime,
+    Kind,
+    Severity,
+}
+
+/// How the tags searched for are combined
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rocket", derive(FromFormField))]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum TagMode {
+    /// Entries must have at least one of the tags
+    Any,
+    /// Entries must have all the tags
+    #[default]
+    All,
+}
+
+impl SortBy {
+    /// Compares two entries on the sort field, entries missing
+    /// the field always come last whatever the order
+    pub fn compare(&self, a: &Entry, b: &Entry, order: &SearchOrder) -> Ordering {
+        fn cmp<T: Ord>(a: Option<T>, b: Option<T>, order: &SearchOrder) -> Ordering {
+            match (a, b) {
+                (Some(a), Some(b)) => match order {
+                    SearchOrder::Asc => a.cmp(&b),
+                    SearchOrder::Desc => b.cmp(&a),
+                },
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            }
+        }
+
+        match self {
+            Self::Ctime => cmp(a.ctime, b.ctime, order),
+            Self::Mtime => cmp(a.mtime, b.mtime, order),
+            Self::Kind => cmp(Some(a.data.kind()), Some(b.data.kind()), order),
+            Self::Severity => cmp(a.severity, b.severity, order),
+        }
+    }
+}
+
+/// Importance of an entry, from the least to the most important
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+#[cfg_attr(feature = "rocket", derive(FromFormField))]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    Info,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+#[cfg_attr(feature = "rocket", derive(FromFormField))]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum DataKind {
+    Owner,
+    Asn,
+    // for FromFormField
+    #[cfg_attr(feature = "rocket", field(value = "misp-event"))]
+    MispEvent,
+    Ticket,
+    Vulnerable,
+    Text,
+    Json,
+}
+
+#[cfg(feature = "rocket")]
+impl<'r> FromParam<'r> for DataKind {
+    type Error = &'r str;
+
+    fn from_param(param: &'r str) -> Result<Self, Self::Error> {
+        match param {
+            "owner" => Ok(DataKind::Owner),
+            "asn" => Ok(DataKind::Asn),
+            "misp-event" => Ok(DataKind::MispEvent),
+            "ticket" => Ok(DataKind::Ticket),
+            "vulnerable" => Ok(DataKind::Vulnerable),
+            "text" => Ok(DataKind::Text),
+            "json" => Ok(DataKind::Json),
+            _ => Err(param),
+        }
+    }
+}
+
+/// Data which can be deserialized but is not meaningful
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum InvalidData {
+    #[error("a ticket with a numeric id must have a server")]
+    TicketWithoutServer,
+    #[error("unknown country: {0}")]
+    UnknownCountry(String),
+}
+
+/// Rewrites `value` in a canonical form, so that equal payloads are stored,
+/// and compared, the same way: object keys are sorted and floats holding an
+/// integer become integers, ex: `{"b": 1.0, "a": -0.0}` becomes
+/// `{"a": 0, "b": 1}`
+pub fn canonical_json(value: &mut serde_json::Value) {
+    use serde_json::Value;
+
+    match value {
+        Value::Number(n) => {
+            if let Some(f) = n.as_f64()
+                && n.is_f64()
+                && f.fract() == 0.0
+            {
+                if f >= 0.0 && f < u64::MAX as f64 {
+                    *n = (f as u64).into();
+                } else if f < 0.0 && f >= i64::MIN as f64 {
+                    *n = (f as i64).into();
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(canonical_json),
+        Value::Object(map) => {
+            // only needed when serde_json preserves the order of the keys
+            map.sort_keys();
+            map.values_mut().for_each(canonical_json);
+        }
+        _ => {}
+    }
+}
+
+impl Data {
+    /// Checks the constraints which cannot be expressed by the types
+    pub fn validate(&self) -> Result<(), InvalidData> {
+        match self {
+            Data::Ticket(Ticket {
+                server: None,
+                id: TicketId::Id(_),
+            }) => Err(InvalidData::TicketWithoutServer),
+            _ => Ok(()),
+        }
+    }
+
+    /// Normalizes the data, see [`Owner::normalize_country`] and
+    /// [`canonical_json`]
+    pub fn normalize(&mut self, strict: bool) -> Result<(), InvalidData> {
+        match self {
+            Data::Owner(owner) => owner.normalize_country(strict),
+            Data::Json(value) => {
+                canonical_json(value);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Drops the empty optional fields of the data, see [`Owner::drop_empty`]
+    pub fn drop_empty(&mut self) {
+        if let Data::Owner(owner) = self {
+            owner.drop_empty();
+        }
+    }
+
+    pub fn kind(&self) -> DataKind {
+        match self {
+            Self::Owner(_) => DataKind::Owner,
+            Self::Asn(_) => DataKind::Asn,
+            Self::MispEvent(_) => DataKind::MispEvent,
+            Self::Ticket(_) => DataKind::Ticket,
+            Self::Vulnerable(_) => DataKind::Vulnerable,
+            Self::Text(_) => DataKind::Text,
+            Self::Json(_) => DataKind::Json,
+        }
+    }
+
+    /// Severity given to the entries holding this data when none
+    /// is set: ownership data is informational while a vulnerability
+    /// is important, other kinds depend on their content
+    pub fn default_severity(&self) -> Option<Severity> {
+        match self {
+            Self::Owner(_) | Self::Asn(_) => Some(Severity::Info),
+            Self::Vulnerable(_) => Some(Severity::High),
+            _ => None,
+        }
+    }
+
+    /// Whether the payload has a non-empty `field` (ex: `country` for an
+    /// owner), data which are not made of fields have none
+    pub fn has_field(&self, field: &str) -> bool {
+        let Ok(serde_json::Value::Object(data)) = serde_json::to_value(self) else {
+            return false;
+        };
+
+        // data are serialized as {"<kind>": <payload>}
+        match data.values().next().and_then(|p| p.get(field)) {
+            None | Some(serde_json::Value::Null) => false,
+            Some(serde_json::Value::String(s)) => !s.is_empty(),
+            Some(serde_json::Value::Array(a)) => !a.is_empty(),
+            Some(serde_json::Value::Object(o)) => !o.is_empty(),
+            Some(_) => true,
+        }
+    }
+
+    /// CVE identifiers mentioned by the data
+    pub fn cves(&self) -> Vec<Cve> {
+        match self {
+            Self::Vulnerable(text) => Cve::find_all(text),
+            _ => vec![],
+        }
+    }
+}
+
+/// CVE identifier in its canonical form (`CVE-2021-44228`)
+#[derive(Hash, Debug, PartialEq, Eq, Clone)]
+pub struct Cve(String);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("invalid CVE identifier")]
+pub struct InvalidCve;
+
+impl Cve {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Parses the identifier starting `s`, whatever its case and
+    /// separators, returns it with the number of bytes it spans
+    fn parse_prefix(s: &[u8]) -> Option<(Cve, usize)> {
+        let is_sep = |b: &u8| matches!(b, b'-' | b'_' | b' ');
+        let digits = |from: usize| s[from..].iter().take_while(|b| b.is_ascii_digit()).count();
+
+        if !s.get(..3)?.eq_ignore_ascii_case(b"cve") {
+            return None;
+        }
+        let mut i = 3 + usize::from(s.get(3).is_some_and(is_sep));
+
+        let year = i..i + 4;
+        if digits(i) != 4 || !s.get(year.end).is_some_and(is_sep) {
+            return None;
+        }
+        i = year.end + 1;
+
+        let number = i..i + digits(i);
+        if number.len() < 4 || s.get(number.end).is_some_and(u8::is_ascii_alphanumeric) {
+            return None;
+        }
+
+        // only ASCII digits were matched
+        let cve = format!(
+            "CVE-{}-{}",
+            std::str::from_utf8(&s[year]).ok()?,
+            std::str::from_utf8(&s[number.clone()]).ok()?
+        );
+        Some((Cve(cve), number.end))
+    }
+
+    /// All the distinct identifiers found in a free text, ex:
+    /// `Log4Shell (cve-2021-44228)`
+    pub fn find_all(text: &str) -> Vec<Cve> {
+        let bytes = text.as_bytes();
+        let mut found = vec![];
+
+        let mut i = 0;
+        while i < bytes.len() {
+            let boundary = i == 0 || !bytes[i - 1].is_ascii_alphanumeric();
+            match Self::parse_prefix(&bytes[i..]).filter(|_| boundary) {
+                Some((cve, len)) => {
+                    if !found.contains(&cve) {
+                        found.push(cve);
+                    }
+                    i += len;
+                }
+                None => i += 1,
+            }
+        }
+
+        found
+    }
+}
+
+impl TryFrom<&str> for Cve {
+    type Error = InvalidCve;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let value = value.trim();
+        match Self::parse_prefix(value.as_bytes()) {
+            Some((cve, len)) if len == value.len() => Ok(cve),
+            _ => Err(InvalidCve),
+        }
+    }
+}
+
+impl std::fmt::Display for Cve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Lowercase tag, without leading, trailing or repeated whitespaces
+#[derive(Hash, Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+pub struct Tag(String);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum InvalidTag {
+    #[error("empty tag")]
+    Empty,
+    #[error("tag longer than {} characters", Tag::MAX_LEN)]
+    TooLong,
+}
+
+impl Tag {
+    /// Maximum length, in characters, of a tag
+    pub const MAX_LEN: usize = 256;
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Length of the tag in characters
+    pub fn len(&self) -> usize {
+        self.0.chars().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl TryFrom<&str> for Tag {
+    type Error = InvalidTag;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let tag = value
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+
+        if tag.is_empty() {
+            return Err(InvalidTag::Empty);
+        }
+
+        let tag = Tag(tag);
+        if tag.len() > Self::MAX_LEN {
+            return Err(InvalidTag::TooLong);
+        }
+        Ok(tag)
+    }
+}
+
+impl TryFrom<String> for Tag {
+    type Error = InvalidTag;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Tag::try_from(value.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Tag {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Tag::try_from(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Tag {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
+/// Confidence in an entry, from 0 (none) to 1 (certain)
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+#[cfg_attr(feature = "schema", schema(value_type = f64))]
+pub struct Confidence(f64);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("confidence must be between 0 and 1")]
+pub struct InvalidConfidence;
+
+impl Confidence {
+    pub fn value(self) -> f64 {
+        self.0
+    }
+}
+
+impl TryFrom<f64> for Confidence {
+    type Error = InvalidConfidence;
+
+    fn try_from(value: f64) -> Result<Self, Self::Error> {
+        // NaN is not in the range either
+        if !(0.0..=1.0).contains(&value) {
+            return Err(InvalidConfidence);
+        }
+        Ok(Confidence(value))
+    }
+}
+
+impl<'de> Deserialize<'de> for Confidence {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Confidence::try_from(f64::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Confidence {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+pub struct Entry {
+    pub uuid: Option<Uuid>,
+    pub description: Option<String>,
+    /// Creation timestamp
+    pub ctime: Option<chrono::DateTime<Utc>>,
+    /// Modification timestamp
+    pub mtime: Option<chrono::DateTime<Utc>>,
+    /// Tags are normalized as they are deserialized, so case and
+    /// whitespace variants of a tag end up stored once
+    pub tags: Option<HashSet<Tag>>,
+    /// UUIDs of related entries, of any IP address
+    pub links: Option<Vec<Uuid>>,
+    /// Time after which the entry is left out of searches, until
+    /// it gets pruned
+    pub expires_at: Option<chrono::DateTime<Utc>>,
+    pub severity: Option<Severity>,
+    /// Sensitivity of the entry, ex: a TLP level such as `tlp:amber`.
+    /// Only the API keys cleared for it, regardless of its case, can
+    /// read and write the entry, unclassified entries being open to all.
+    pub classification: Option<String>,
+    /// Confidence of the source in the entry, stored as given. Searches
+    /// can decay it with the time elapsed since the entry was modified.
+    #[serde(default)]
+    pub confidence: Option<Confidence>,
+    /// Revision of the story the entry was last created or modified at,
+    /// set by the server
+    #[serde(default)]
+    pub revision: Option<u64>,
+    pub data: Data,
+}
+
+impl Entry {
+    /// Creates a new entry holding `data`, with the default severity
+    /// of the data, other fields are left empty and filled by the
+    /// server on insertion
+    pub fn new(data: Data) -> Self {
+        Entry {
+            uuid: None,
+            description: None,
+            ctime: None,
+            mtime: None,
+            tags: None,
+            links: None,
+            expires_at: None,
+            severity: data.default_severity(),
+            classification: None,
+            confidence: None,
+            revision: None,
+            data,
+        }
+    }
+
+    /// Unsets the blank description and classification, the empty tags
+    /// and links, and drops the empty optional fields of the data
+    pub fn drop_empty(&mut self) {
+        self.description.take_if(|d| d.trim().is_empty());
+        self.classification.take_if(|c| c.trim().is_empty());
+        self.tags.take_if(|t| t.is_empty());
+        self.links.take_if(|l| l.is_empty());
+        self.data.drop_empty();
+    }
+
+    /// Whether the entry expired at `now`
+    pub fn is_expired(&self, now: chrono::DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+}
+
+/// Projection of an [`Entry`] leaving its data out, for list views
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+pub struct EntrySummary {
+    pub uuid: Option<Uuid>,
+    /// Creation timestamp
+    pub ctime: Option<chrono::DateTime<Utc>>,
+    /// Kind of the data of the entry
+    pub kind: DataKind,
+    pub description: Option<String>,
+    pub severity: Option<Severity>,
+}
+
+impl From<&Entry> for EntrySummary {
+    fn from(entry: &Entry) -> Self {
+        EntrySummary {
+            uuid: entry.uuid,
+            ctime: entry.ctime,
+            kind: entry.data.kind(),
+            description: entry.description.clone(),
+            severity: entry.severity,
+        }
+    }
+}
+
+/// Result of the creation of an IP address
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+pub struct NewIp {
+    #[cfg_attr(feature = "schema", schema(value_type = String))]
+    pub ip: IpAddr,
+    /// False if the IP address was already tracked
+    pub created: bool,
+}
+
+/// Field the tracked IP addresses are listed by
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "rocket", derive(FromFormField))]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum IpSortBy {
+    #[default]
+    Ip,
+    LastSeen,
+}
+
+/// A tracked IP address and the last time its history changed
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+pub struct IpActivity {
+    #[cfg_attr(feature = "schema", schema(value_type = String))]
+    pub ip: IpAddr,
+    /// Most recent creation or modification time of the entries,
+    /// unset if the IP address has no entries
+    pub last_seen: Option<chrono::DateTime<Utc>>,
+}
+
+/// Changes of the history of an IP address since a revision
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+pub struct Changes {
+    /// Current revision of the story, to ask for the next changes from
+    pub revision: u64,
+    /// Entries created or modified since the revision
+    pub entries: Vec<Entry>,
+    /// UUIDs of the entries deleted since the revision
+    pub deleted: Vec<Uuid>,
+}
+
+/// Shape of every API response, `error` is set if the request failed
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(ToSchema))]
+pub struct ApiResponse<D> {
+    pub error: Option<String>,
+    /// Stable identifier of the error, ex: `timestamp_conflict`,
+    /// set along with `error`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    pub data: Option<D>,
+    /// Questionable inputs which were accepted anyway, absent if none
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(value: serde_json::Value) -> Entry {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn confidences_are_between_0_and_1() {
+        let e = entry(serde_json::json!({"confidence": 0.8, "data": {"text": "x"}}));
+        assert_eq!(e.confidence.map(Confidence::value), Some(0.8));
+        assert!(
+            entry(serde_json::json!({"data": {"text": "x"}}))
+                .confidence
+                .is_none()
+        );
+
+        for value in [-0.1, 1.5, f64::NAN] {
+            assert_eq!(Confidence::try_from(value), Err(InvalidConfidence));
+        }
+        let invalid = serde_json::json!({"confidence": 2, "data": {"text": "x"}});
+        assert!(serde_json::from_value::<Entry>(invalid).is_err());
+    }
+
+    #[test]
+    fn tag_variants_are_stored_once() {
+        let e = entry(serde_json::json!({
+            "tags": ["Botnet", "BOTNET", " botnet "],
+            "data": {"text": "x"}
+        }));
+
+        let tags = e.tags.unwrap();
+        assert_eq!(tags.len(), 1);
+        assert!(tags.contains(&Tag::try_from("botnet").unwrap()));
+    }
+
+    fn canonical(mut value: serde_json::Value) -> String {
+        canonical_json(&mut value);
+        value.to_string()
+    }
+
+    #[test]
+    fn canonical_json_ignores_key_order() {
+        let a = serde_json::json!({"b": {"y": 1, "x": [2, 1]}, "a": true});
+        let b = serde_json::json!({"a": true, "b": {"x": [2, 1], "y": 1}});
+
+        assert_eq!(canonical(a.clone()), canonical(b));
+        assert_eq!(canonical(a), r#"{"a":true,"b":{"x":[2,1],"y":1}}"#);
+    }
+
+    #[test]
+    fn canonical_json_turns_integral_floats_into_integers() {
+        assert_eq!(
+            canonical(serde_json::json!({"b": 1.0, "a": -0.0})),
+            r#"{"a":0,"b":1}"#
+        );
+        assert_eq!(canonical(serde_json::json!([-3.0, [7.0]])), "[-3,[7]]");
+        // these are not integers or cannot be represented as such
+        assert_eq!(canonical(serde_json::json!([1.5, -0.25])), "[1.5,-0.25]");
+        assert_eq!(canonical(serde_json::json!(1e300)), "1e300");
+    }
+
+    #[test]
+    fn json_data_is_normalized_to_its_canonical_form() {
+        let mut data = Data::Json(serde_json::json!({"z": 2.0, "a": "x"}));
+        data.normalize(false).unwrap();
+
+        assert_eq!(
+            serde_json::to_string(&data).unwrap(),
+            r#"{"json":{"a":"x","z":2}}"#
+        );
+    }
+
+    fn owner(abuse: &[&str]) -> Owner {
+        Owner {
+            name: "Example Hosting".into(),
+            address: Some("  ".into()),
+            country: Some(String::new()),
+            country_raw: Some("France".into()),
+            abuse: abuse.iter().map(|a| a.to_string()).collect(),
+            phone: Some("\t".into()),
+        }
+    }
+
+    #[test]
+    fn drop_empty_unsets_the_blank_fields_of_entries() {
+        let mut e = Entry {
+            description: Some(" ".into()),
+            classification: Some(String::new()),
+            tags: Some(HashSet::new()),
+            links: Some(vec![]),
+            ..Entry::new(Data::Text("x".into()))
+        };
+        e.drop_empty();
+
+        assert!(e.description.is_none());
+        assert!(e.classification.is_none());
+        assert!(e.tags.is_none());
+        assert!(e.links.is_none());
+    }
+
+    #[test]
+    fn drop_empty_keeps_the_filled_fields_of_entries() {
+        let link = Uuid::from_u128(1);
+        let mut e = Entry {
+            description: Some(" seen scanning ".into()),
+            classification: Some("tlp:amber".into()),
+            tags: Some(HashSet::from([Tag::try_from("botnet").unwrap()])),
+            links: Some(vec![link]),
+            ..Entry::new(Data::Text("x".into()))
+        };
+        e.drop_empty();
+
+        assert_eq!(e.description.as_deref(), Some(" seen scanning "));
+        assert_eq!(e.classification.as_deref(), Some("tlp:amber"));
+        assert_eq!(e.tags.map(|t| t.len()), Some(1));
+        assert_eq!(e.links, Some(vec![link]));
+    }
+
+    #[test]
+    fn drop_empty_cleans_the_owner_data() {
+        let mut e = Entry::new(Data::Owner(owner(&["", "abuse@example.com", " "])));
+        e.drop_empty();
+
+        let Data::Owner(owner) = e.data else {
+            panic!("owner data expected");
+        };
+        assert_eq!(owner.name, "Example Hosting");
+        assert!(owner.address.is_none());
+        assert!(owner.country.is_none());
+        assert_eq!(owner.country_raw.as_deref(), Some("France"));
+        assert_eq!(owner.abuse, ["abuse@example.com"]);
+        assert!(owner.phone.is_none());
+    }
+}